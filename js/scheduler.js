@@ -11,19 +11,24 @@ async function get_scheduler() {
     return Scheduler.new();	  
 }
 
-// This ad-hoc way of implementing a sleep function isn't
-// precise enough for musical timing, so we need some way
-// to compensate for it in the scheduler (see there).
-function sleep(ms) {
-    return new Promise(resolve => setTimeout(resolve, ms));
-}
+// keep commands that arrive while the scheduler is still loading
+let pendingCommands = [];
 
-self.sleep = sleep;
+// tick hooks are code, so they only come from the page, over the port it hands over first
+let hookPort = null;
+self.onmessage = function(e) {
+    pendingCommands.push(e);
+}
 
 // fetch the scheduler instance
 get_scheduler().then(scheduler => {
     self.scheduler = scheduler;
 
+    // beats and bars are passed on to the page, for beat-synced visuals
+    self.scheduler.on_beat((position, timestamp) => postMessage({ type: 'beat', position: position, timestamp: timestamp }));
+    self.scheduler.on_bar((position, timestamp) => postMessage({ type: 'bar', position: position, timestamp: timestamp }));
+    self.scheduler.on_cycle((position, timestamp) => postMessage({ type: 'cycle', position: position, timestamp: timestamp }));
+
     // now that we have a scheduler, set scheduler controls
     self.onmessage = function(e) {
 	console.log("scheduler command: " + e.data.cmd);
@@ -31,17 +36,224 @@ get_scheduler().then(scheduler => {
 	case 'start':
 	    self.scheduler.start(e.data.timestamp, performance.now());
 	    break;
+	case 'run_for':
+	    self.scheduler.run_for(e.data.bars);
+	    break;
+	case 'run_until':
+	    self.scheduler.run_until(e.data.timestamp);
+	    break;
 	case 'stop':
 	    self.scheduler.stop();
 	    break;
+	case 'pause':
+	    self.scheduler.pause();
+	    break;
+	case 'resume':
+	    self.scheduler.resume(e.data.timestamp, performance.now());
+	    break;
+	case 'panic':
+	    self.scheduler.panic();
+	    break;
+	case 'lock':
+	    self.scheduler.lock();
+	    break;
+	case 'unlock':
+	    self.scheduler.unlock();
+	    break;
 	case 'evaluate_loop':
-	    self.scheduler.evaluate(e.data.loop_data);
+	    // input with syntax errors is thrown back, as [{ line, column, message }, ...]
+	    try {
+		let warnings = self.scheduler.evaluate(e.data.loop_data);
+		if (warnings.length > 0) {
+		    postMessage({ type: 'warnings', warnings: warnings });
+		}
+	    } catch (errors) {
+		postMessage({ type: 'errors', errors: errors });
+	    }
+	    break;
+	case 'queue_loop': {
+	    // evaluated on the next bar line, warnings (and errors) come later if there are any
+	    try {
+		let warnings = self.scheduler.queue(e.data.loop_data);
+		if (warnings.length > 0) {
+		    postMessage({ type: 'warnings', warnings: warnings });
+		}
+	    } catch (errors) {
+		postMessage({ type: 'errors', errors: errors });
+	    }
+	    break;
+	}
+	case 'set_timecode':
+	    self.scheduler.set_timecode(e.data.fps);
+	    break;
+	case 'map_controller':
+	    self.scheduler.map_controller(e.data.id, e.data.target, e.data.min, e.data.max);
+	    break;
+	case 'set_controller_value':
+	    self.scheduler.set_controller_value(e.data.id, e.data.value);
+	    break;
+	case 'set_mod_input':
+	    self.scheduler.set_mod_input(e.data.name, e.data.value);
+	    break;
+	case 'set_seed':
+	    // u64 on the rust side
+	    self.scheduler.set_seed(BigInt(e.data.seed));
+	    break;
+	case 'set_energy':
+	    self.scheduler.set_energy(e.data.energy);
+	    break;
+	case 'hook_port':
+	    if (hookPort) {
+		console.warn("the tick hook port is already set, ignored");
+		break;
+	    }
+	    hookPort = e.data.port;
+	    // the hook comes as source, functions can't be posted
+	    hookPort.onmessage = h => self.scheduler.set_tick_hook(h.data.source ? new Function('events', 'step', h.data.source) : undefined);
+	    break;
+	case 'set_tick_hook':
+	    console.warn("tick hooks only come over the page's hook port, ignored");
+	    break;
+	case 'set_event_order':
+	    self.scheduler.set_event_order(e.data.order);
+	    break;
+	case 'set_event_ring':
+	    self.scheduler.set_event_ring(e.data.buffer || undefined);
+	    break;
+	case 'set_swing':
+	    self.scheduler.set_swing(e.data.amount);
+	    break;
+	case 'set_line_swing':
+	    self.scheduler.set_line_swing(e.data.label, e.data.amount);
+	    break;
+	case 'set_line_offset':
+	    self.scheduler.set_line_offset(e.data.label, e.data.ms);
+	    break;
+	case 'set_sequence_speed':
+	    self.scheduler.set_sequence_speed(e.data.idx, e.data.speed);
+	    break;
+	case 'start_sequence':
+	    self.scheduler.start_sequence(e.data.idx, !!e.data.quantized);
+	    break;
+	case 'stop_sequence':
+	    self.scheduler.stop_sequence(e.data.idx);
+	    break;
+	case 'mute':
+	    self.scheduler.mute(e.data.idx);
+	    break;
+	case 'unmute':
+	    self.scheduler.unmute(e.data.idx);
+	    break;
+	case 'solo':
+	    self.scheduler.solo(e.data.idx);
+	    break;
+	case 'clear_solo':
+	    self.scheduler.clear_solo();
+	    break;
+	case 'mute_group':
+	    self.scheduler.mute_group(e.data.tag, e.data.muted);
+	    break;
+	case 'set_group_gain':
+	    self.scheduler.set_group_gain(e.data.tag, e.data.gain);
+	    break;
+	case 'set_group_param':
+	    self.scheduler.set_group_param(e.data.tag, e.data.param, e.data.value);
+	    break;
+	case 'save_snapshot':
+	    self.scheduler.save_snapshot(e.data.name);
+	    break;
+	case 'morph_to':
+	    self.scheduler.morph_to(e.data.name, e.data.beats);
+	    break;
+	case 'set_bus_level':
+	    self.scheduler.set_bus_level(e.data.bus, e.data.level);
+	    break;
+	case 'freeze':
+	    self.scheduler.freeze(e.data.label, e.data.frozen);
+	    break;
+	case 'stutter':
+	    self.scheduler.stutter(e.data.fraction, e.data.repeats);
+	    break;
+	case 'resample_master':
+	    self.scheduler.resample_master(e.data.name, e.data.bars);
+	    break;
+	case 'record_take':
+	    self.scheduler.record_take(e.data.name, e.data.from_bar, e.data.to_bar, e.data.stem || undefined);
+	    break;
+	case 'chop':
+	    self.scheduler.chop(e.data.sample, e.data.slices, e.data.style, e.data.duration);
+	    break;
+	case 'set_syntax':
+	    self.scheduler.set_syntax(e.data.syntax);
+	    break;
+	case 'set_eval_quantization':
+	    self.scheduler.set_eval_quantization(e.data.quantization);
+	    break;
+	case 'set_priming':
+	    self.scheduler.set_priming(e.data.enabled);
+	    break;
+	case 'register_sample':
+	    self.scheduler.register_sample(e.data.name);
+	    break;
+	case 'register_stream':
+	    self.scheduler.register_stream(e.data.name);
+	    break;
+	case 'unregister_sample':
+	    self.scheduler.unregister_sample(e.data.name);
+	    break;
+	case 'register_voice':
+	    self.scheduler.register_voice(e.data.name);
 	    break;
 	case 'set_tempo':
 	    self.scheduler.set_tempo(e.data.tempo);
 	    break;
+	case 'set_bpm':
+	    self.scheduler.set_bpm(e.data.bpm);
+	    break;
+	case 'set_subdivision':
+	    self.scheduler.set_subdivision(e.data.subdivision);
+	    break;
+	case 'set_beats_per_bar':
+	    self.scheduler.set_beats_per_bar(e.data.beats);
+	    break;
+	case 'load_instrument':
+	    self.scheduler.load_instrument(e.data.name, e.data.sfz, e.data.url);
+	    break;
+	case 'load_soundfont':
+	    self.scheduler.load_soundfont(e.data.name, e.data.url, e.data.data);
+	    break;
+	case 'set_ppqn':
+	    self.scheduler.set_ppqn(e.data.ppqn);
+	    break;
+	case 'get_bpm':
+	    postMessage({ type: 'bpm', bpm: self.scheduler.get_bpm() });
+	    break;
+	case 'get_sequence_ids':
+	    postMessage({ type: 'sequence_ids', ids: Array.from(self.scheduler.sequence_ids()) });
+	    break;
+	case 'get_position': {
+	    const steps = Array.from(self.scheduler.sequence_ids(), (_, idx) => self.scheduler.current_step(idx));
+	    postMessage({ type: 'position', bar: self.scheduler.current_bar(), beat: self.scheduler.current_beat(), phase: self.scheduler.phase_in_step(), steps });
+	    break;
+	}
+	case 'get_session_stats':
+	    postMessage({ type: 'session_stats', stats: self.scheduler.get_session_stats() });
+	    break;
+	case 'sync_audio_clock':
+	    // the page's browser time is on its own time origin
+	    self.scheduler.sync_audio_clock(e.data.audio_time, e.data.browser_time - performance.timeOrigin);
+	    break;
+	case 'set_schedule_interval':
+	    self.scheduler.set_schedule_interval(e.data.interval);
+	    break;
+	case 'set_lookahead':
+	    self.scheduler.set_lookahead(e.data.lookahead);
+	    break;
 	}
     }
+
+    pendingCommands.forEach(e => self.onmessage(e));
+    pendingCommands = [];
 });
 
 
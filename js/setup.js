@@ -4,6 +4,8 @@ const sourceType = Object.freeze({
     "SineSynth" : 2,
     "LFSawSynth" : 3,
     "LFSquareSynth" : 4,
+    "CustomVoice" : 5,
+    "Streamer" : 6,
 });
 
 const sourceParameter = Object.freeze({
@@ -37,6 +39,17 @@ const sourceParameter = Object.freeze({
     "Samplerate" : 27,
     "StereoPosition" : 28,
     "Sustain" : 29,
+    "BitDepth" : 30,
+    "CompressorThreshold" : 31,
+    "CompressorRatio" : 32,
+    "Age" : 33,
+    "Freeze" : 34,
+    "Smear" : 35,
+    "PitchShift" : 36,
+    "Oversampling" : 37,
+    "Interpolation" : 38,
+    "Azimuth" : 39,
+    "Elevation" : 40,
 });
 
 const sourceParameterShortName = Object.freeze({
@@ -70,8 +83,46 @@ const sourceParameterShortName = Object.freeze({
     "sr" : 27,
     "pos" : 28,
     "sus" : 29,
+    "bits" : 30,
+    "thresh" : 31,
+    "ratio" : 32,
+    "age" : 33,
+    "hold" : 34,
+    "smear" : 35,
+    "shift" : 36,
+    "os" : 37,
+    "interp" : 38,
+    "azi" : 39,
+    "elev" : 40,
 });
 
+const nodeType = Object.freeze({
+    "sine" : 0,
+    "saw" : 1,
+    "sqr" : 2,
+    "lpf" : 3,
+    "env" : 4,
+    "add" : 5,
+    "mul" : 6,
+    "const" : 7,
+});
+
+const insertType = Object.freeze({
+    "crush" : 0,
+    "lpf" : 1,
+    "comp" : 2,
+    "lofi" : 3,
+    "freeze" : 4,
+    "pitch" : 5,
+});
+
+
+// lower-powered devices can trade fidelity and latency for stability,
+// i.e. { samplerate: 22050, blockSize: 512 }, spatial: 'binaural' renders for headphones,
+// spatial: 'ambisonic' outputs first-order B-format on four channels,
+// eventRing: true has the scheduler write the events to the sampler directly,
+// which takes a cross-origin isolated page (for SharedArrayBuffer)
+const engineSettings = { samplerate: 44100, blockSize: 128, spatial: 'stereo', eventRing: false };
 
 const ctx = new AudioContext({
     sampleRate: 44100,
@@ -91,10 +142,24 @@ if (ctx.audioWorklet === undefined) {
 	    // first, get the audio worklet node of the current context ...
 	    const n = new AudioWorkletNode(ctx, 'ruffbox-processor', { numberOfInputs: 1,
 								       numberOfOutputs: 1,
-								       outputChannelCount: [2], } );
+								       outputChannelCount: [engineSettings.spatial === 'ambisonic' ? 4 : 2], } );
+
+	    // the B-format channels go out as they are, to a decoder downstream
+	    if (engineSettings.spatial === 'ambisonic') {
+		if (ctx.destination.maxChannelCount >= 4) {
+		    ctx.destination.channelCount = 4;
+		    ctx.destination.channelInterpretation = 'discrete';
+		    n.channelInterpretation = 'discrete';
+		} else {
+		    console.warn("ambisonic mode needs an output with at least four channels");
+		}
+	    }
 	    // connect to output to activate ...
 	    n.connect(ctx.destination);
 	    	    	   
+	    // has to arrive before the wasm module
+	    n.port.postMessage({ type: 'configureEngine', settings: engineSettings });
+
 	    // now, get the WASM module file and post the raw binary to
 	    // the worklet, where it'll be instantiated.
 	    // unfortunately, as of now, the audio worklet doesn't allow fetching
@@ -108,26 +173,121 @@ if (ctx.audioWorklet === undefined) {
 	    //////////////////
 
 	    // fetch sample files, decode and post them to worklet ...
-	    fetch('audio/bd.flac?t=' + new Date().getTime())
-		.then(r => r.arrayBuffer())
-		.then(r => ctx.decodeAudioData(r)
-		      .then(r => n.port.postMessage({ type: 'loadSample', samples: r.getChannelData(0), length: r.length, sample_id: 'bd' })))
+	    // progress is reported as 'ruffbox-sample' events on the window,
+	    // with the state being 'loading', 'loaded' or 'failed'
+	    function loadSample(name, url) {
+		const progress = state => window.dispatchEvent(new CustomEvent('ruffbox-sample', { detail: { sample_id: name, url: url, state: state } }));
+		progress('loading');
+		return fetch(url)
+		    .then(r => r.arrayBuffer())
+		    .then(r => ctx.decodeAudioData(r))
+		    .then(r => {
+			n.port.postMessage({ type: 'loadSample', samples: r.getChannelData(0), length: r.length, sample_id: name, channels: r.numberOfChannels, samplerate: r.sampleRate });
+			scheduler.postMessage({ cmd: 'register_sample', name: name });
+			progress('loaded');
+		    })
+		    .catch(err => {
+			console.warn("couldn't load sample " + name + " from " + url + ": " + err);
+			progress('failed');
+		    })
+	    }
 
-	    fetch('audio/sn.flac?t=' + new Date().getTime())
-		.then(r => r.arrayBuffer())
-		.then(r => ctx.decodeAudioData(r)
-		      .then(r => n.port.postMessage({ type: 'loadSample', samples: r.getChannelData(0), length: r.length, sample_id: 'sn' })))
+	    // long files (DJ tracks, field recordings) are streamed into the synth in chunks
+	    // as they're played, instead of being loaded into its memory as a whole, i.e.
+	    // loadStream('set', 'https://example.com/set.mp3'), then "set;start=0.5" plays from the middle
+	    function loadStream(name, url) {
+		const progress = state => window.dispatchEvent(new CustomEvent('ruffbox-sample', { detail: { sample_id: name, url: url, state: state } }));
+		progress('loading');
+		return fetch(url)
+		    .then(r => r.arrayBuffer())
+		    .then(r => ctx.decodeAudioData(r))
+		    .then(r => {
+			n.port.postMessage({ type: 'loadStream', samples: r.getChannelData(0), sample_id: name });
+			scheduler.postMessage({ cmd: 'register_stream', name: name });
+			progress('loaded');
+		    })
+		    .catch(err => {
+			console.warn("couldn't load stream " + name + " from " + url + ": " + err);
+			progress('failed');
+		    })
+	    }
 
-	    // fetch sample files, decode and post them to worklet ...
-	    fetch('audio/hh.flac?t=' + new Date().getTime())
-		.then(r => r.arrayBuffer())
-		.then(r => ctx.decodeAudioData(r)
-		      .then(r => n.port.postMessage({ type: 'loadSample', samples: r.getChannelData(0), length: r.length, sample_id: 'hh' })))
+	    window.loadStream = loadStream;
 
-	    fetch('audio/casio.flac?t=' + new Date().getTime())
-		.then(r => r.arrayBuffer())
-		.then(r => ctx.decodeAudioData(r)
-		      .then(r => n.port.postMessage({ type: 'loadSample', samples: r.getChannelData(0), length: r.length, sample_id: 'casio' })))
+	    // i.e. loadSample('bd', 'https://example.com/kick.wav') from the console,
+	    // a name that's loaded already gets the new sample without cutting off what's playing
+	    window.loadSample = loadSample;
+
+	    // samples loaded together as a bank, to be unloaded together,
+	    // i.e. loadBank('909', { bd: 'https://example.com/bd.wav', sn: 'https://example.com/sn.wav' })
+	    let banks = {};
+	    
+	    window.loadBank = function(bank, samples) {
+		banks[bank] = Object.keys(samples);
+		for (let [name, url] of Object.entries(samples)) {
+		    loadSample(name, url);
+		}
+	    }
+
+	    // where a loaded sample loops (in seconds) for events with the loop flag, and how long
+	    // the crossfade into the loop start is, i.e. setSampleLoop('pad', 0.5, 2.5, 0.2),
+	    // then "pad;loop;sus=8" holds it for eight seconds, releasing with the envelope
+	    window.setSampleLoop = function(name, start, end, crossfade) {
+		n.port.postMessage({ type: 'setSampleLoop', sample_id: name, start: start, end: end, crossfade: crossfade || 0.0 });
+	    }
+
+	    // free the memory of samples that aren't needed anymore,
+	    // voices still playing them finish first
+	    window.unloadSample = function(name) {
+		n.port.postMessage({ type: 'unloadSample', sample_id: name });
+		scheduler.postMessage({ cmd: 'unregister_sample', name: name });
+	    }
+
+	    window.unloadBank = function(bank) {
+		(banks[bank] || []).forEach(window.unloadSample);
+		delete banks[bank];
+	    }
+
+	    // multi-sampled instruments, each zone is a range of keys (midi notes) played
+	    // by a sample recorded at the root key, i.e.
+	    // defineInstrument('keys', [{ sample_id: 'keys.lo', url: '...', lokey: 0, hikey: 59, root: 48 }, ...]),
+	    // then "keys;deg=2" plays the third degree from the zone it falls into
+	    window.defineInstrument = function(name, zones) {
+		Promise.all(zones.map(zone => loadSample(zone.sample_id, zone.url))).then(() => {
+		    n.port.postMessage({ type: 'defineInstrument', name: name, zones: zones });
+		    scheduler.postMessage({ cmd: 'register_sample', name: name });
+		});
+	    }
+
+	    // the same from the regions of an SFZ file, i.e.
+	    // loadInstrument('piano', 'https://example.com/piano/piano.sfz')
+	    window.loadInstrument = function(name, url) {
+		fetch(url)
+		    .then(r => r.text())
+		    .then(sfz => scheduler.postMessage({ cmd: 'load_instrument', name: name, sfz: sfz, url: url }))
+		    .catch(err => console.warn("couldn't load instrument " + name + " from " + url + ": " + err));
+	    }
+
+	    // every preset of an SF2 file as an instrument, named after the font and the preset, i.e.
+	    // loadSoundFont('gm', 'https://example.com/gm.sf2'), then "gm.acoustic_grand_piano;deg=2",
+	    // the scheduler reads the file, the sampler only gets the samples and zones
+	    window.loadSoundFont = function(name, url) {
+		window.dispatchEvent(new CustomEvent('ruffbox-sample', { detail: { sample_id: name, url: url, state: 'loading' } }));
+		fetch(url)
+		    .then(r => r.arrayBuffer())
+		    .then(data => scheduler.postMessage({ cmd: 'load_soundfont', name: name, url: url, data: new Uint8Array(data) }, [data]))
+		    .catch(err => {
+			console.warn("couldn't load soundfont " + name + " from " + url + ": " + err);
+			window.dispatchEvent(new CustomEvent('ruffbox-sample', { detail: { sample_id: name, url: url, state: 'failed' } }));
+		    });
+	    }
+
+	    window.addEventListener('ruffbox-sample', e => console.log("sample " + e.detail.sample_id + ": " + e.detail.state));
+	    
+	    loadSample('bd', 'audio/bd.flac?t=' + new Date().getTime());
+	    loadSample('sn', 'audio/sn.flac?t=' + new Date().getTime());
+	    loadSample('hh', 'audio/hh.flac?t=' + new Date().getTime());
+	    loadSample('casio', 'audio/casio.flac?t=' + new Date().getTime());
 
 	    //////////////////////////
 	    // LOAD EVENT SCHEDULER //
@@ -136,19 +296,494 @@ if (ctx.audioWorklet === undefined) {
 	    // this only works in latest chrome/chromium with experimental features flag enabled ...
 	    let scheduler = new Worker('js/scheduler.js', { type : "module"});
 
+	    // tick hooks only go over this channel, it's handed to the scheduler before anything
+	    // else, so commands from elsewhere (a remote editor bridge) can't run code in it
+	    let hookChannel = new MessageChannel();
+	    scheduler.postMessage({ cmd: 'hook_port', port: hookChannel.port2 }, [hookChannel.port2]);
+
+	    // the events skip the main thread, the ones that don't fit still come as messages
+	    if (engineSettings.eventRing) {
+		if (typeof SharedArrayBuffer !== 'undefined' && self.crossOriginIsolated) {
+		    // header and 64k slots of events
+		    let ring = new SharedArrayBuffer(8 + 8 * 65536);
+		    n.port.postMessage({ type: 'eventRing', buffer: ring });
+		    scheduler.postMessage({ cmd: 'set_event_ring', buffer: ring });
+		} else {
+		    console.warn("the event ring needs SharedArrayBuffer, the page isn't cross-origin isolated");
+		}
+	    }
+
 	    // set event scheduler message callback
 	    scheduler.onmessage = function(event) {
-		// dispatch to sampler module ...
-		n.port.postMessage({ type: 'trigger', event: event.data })
+		if (event.data.type === 'warnings') {
+		    event.data.warnings.forEach(warning => console.warn(warning));
+		} else if (event.data.type === 'errors') {
+		    // these lines play on as they were, an editor can underline where they went wrong
+		    event.data.errors.forEach(err => console.warn('line ' + err.line + ', column ' + err.column + ': ' + err.message));
+		    window.dispatchEvent(new CustomEvent('ruffbox-errors', { detail: { errors: event.data.errors } }));
+		} else if (event.data.type === 'timecode') {
+		    // for anything that wants to chase the performance,
+		    // enable with scheduler.postMessage({ cmd: 'set_timecode', fps: 25 })
+		    window.dispatchEvent(new CustomEvent('ruffbox-timecode', { detail: event.data }));
+		} else if (event.data.type === 'light_cue') {
+		    // for a DMX bridge to pick up (channel, value, fade in seconds, timestamp)
+		    window.dispatchEvent(new CustomEvent('ruffbox-light', { detail: event.data }));
+		} else if (event.data.type === 'master_param') {
+		    // from a mapped controller
+		    let param = n.parameters.get(event.data.name);
+		    if (param) {
+			param.value = event.data.value;
+		    }
+		} else if (event.data.type === 'insert_chain') {
+		    // a line's insert effects changed
+		    n.port.postMessage({ type: 'insertChain', bus: event.data.bus, effects: event.data.effects, follow: event.data.follow });
+		} else if (event.data.type === 'insert_param') {
+		    // a single insert effect param, i.e. from freeze(label)
+		    n.port.postMessage({ type: 'insertParam', bus: event.data.bus, effect: event.data.effect, param: event.data.param, value: event.data.value });
+		} else if (event.data.type === 'gate') {
+		    // tempo-synced gain of a line's bus, one step (or beat) at a time
+		    n.port.postMessage({ type: 'gate', bus: event.data.bus, timestamp: event.data.timestamp, length: event.data.length, level: event.data.level, shape: event.data.shape });
+		} else if (event.data.type === 'chop') {
+		    // a generated line, added to the end of the text buffer
+		    let input = document.getElementById('code_input');
+		    input.value = input.value.replace(/\s*$/, '') + '\n' + event.data.line + '\n';
+		} else if (event.data.type === 'prime') {
+		    // the samples the lines play, to warm up before the first downbeat
+		    n.port.postMessage({ type: 'prime', samples: event.data.samples });
+		} else if (event.data.type === 'stutter') {
+		    // beat repeat on the master bus, from stutter() or a stutter event
+		    n.port.postMessage({ type: 'stutter', timestamp: event.data.timestamp, length: event.data.length, repeats: event.data.repeats });
+		} else if (event.data.type === 'resample_master') {
+		    // the master output, from the next bar on, into a new sample
+		    n.port.postMessage({ type: 'capture', name: event.data.name, start: event.data.timestamp, duration: event.data.duration });
+		} else if (event.data.type === 'take_start') {
+		    // punch-in, of the master (bus 0) or of a line's bus
+		    n.port.postMessage({ type: 'startTake', name: event.data.name, bus: event.data.bus, timestamp: event.data.timestamp });
+		} else if (event.data.type === 'take_stop') {
+		    n.port.postMessage({ type: 'stopTake', name: event.data.name, timestamp: event.data.timestamp });
+		} else if (event.data.type === 'bpm') {
+		    // the answer to { cmd: 'get_bpm' }, for tempo displays
+		    window.dispatchEvent(new CustomEvent('ruffbox-bpm', { detail: { bpm: event.data.bpm } }));
+		} else if (event.data.type === 'run_complete') {
+		    // a run_for or run_until limit was reached, the scheduler stopped by itself
+		    window.dispatchEvent(new CustomEvent('ruffbox-run-complete', { detail: { step: event.data.step, timestamp: event.data.timestamp } }));
+		} else if (event.data.type === 'beat' || event.data.type === 'bar' || event.data.type === 'cycle') {
+		    // ruffbox-beat, ruffbox-bar and ruffbox-cycle events, with the position and audio time
+		    window.dispatchEvent(new CustomEvent('ruffbox-' + event.data.type, { detail: { position: event.data.position, timestamp: event.data.timestamp } }));
+		} else if (event.data.type === 'sequence_ids') {
+		    // the answer to { cmd: 'get_sequence_ids' }, one id per playing line
+		    window.dispatchEvent(new CustomEvent('ruffbox-sequences', { detail: { ids: event.data.ids } }));
+		} else if (event.data.type === 'position') {
+		    // the answer to { cmd: 'get_position' }, for playheads
+		    window.dispatchEvent(new CustomEvent('ruffbox-position', { detail: { bar: event.data.bar, beat: event.data.beat, phase: event.data.phase, steps: event.data.steps } }));
+		} else if (event.data.type === 'session_stats') {
+		    // the answer to { cmd: 'get_session_stats' }, what's been played since the start
+		    window.dispatchEvent(new CustomEvent('ruffbox-session-stats', { detail: event.data.stats }));
+		} else if (event.data.type === 'load_instrument') {
+		    // the zones read from an SFZ file
+		    window.defineInstrument(event.data.name, event.data.zones);
+		} else if (event.data.type === 'load_soundfont') {
+		    // the samples and presets read from an SF2 file, no presets if it couldn't be read
+		    let presets = event.data.presets;
+		    (event.data.samples || []).forEach(sample => {
+			n.port.postMessage({ type: 'loadSample', samples: sample.data, length: sample.data.length, sample_id: sample.sample_id, channels: 1, samplerate: sample.samplerate }, [sample.data.buffer]);
+		    });
+		    (presets || []).forEach(preset => {
+			n.port.postMessage({ type: 'defineInstrument', name: preset.name, zones: preset.zones });
+			scheduler.postMessage({ cmd: 'register_sample', name: preset.name });
+		    });
+		    let names = presets ? presets.map(preset => preset.name) : null;
+		    window.dispatchEvent(new CustomEvent('ruffbox-sample', { detail: { sample_id: event.data.name, state: presets ? 'loaded' : 'failed', presets: names } }));
+		} else if (event.data.type === 'load_sample') {
+		    // requested by a @load directive
+		    loadSample(event.data.sample_id, event.data.url);
+		} else if (event.data.type === 'events') {
+		    // all sound events of a step, lines tagged #remote play on peers
+		    let events = unpackEvents(event.data.strings, event.data.data);
+		    events.filter(ev => ev.remote).forEach(sendToPeers);
+		    // dispatch to sampler module ...
+		    n.port.postMessage({ type: 'trigger', events: events.filter(ev => !ev.remote) });
+		}
 	    };
 
+	    // the scheduler packs the events of a step into a Float64Array, names
+	    // are indices into the string table (see ruffbox-core/src/batch.rs)
+	    function unpackEvents(strings, data) {
+		let events = [];
+		let pos = 0;
+		while (pos < data.length) {
+		    let flags = data[pos + 4];
+		    let paramCount = data[pos + 7];
+		    let rampCount = data[pos + 8];
+		    let ev = {
+			timestamp: data[pos],
+			source_type: strings[data[pos + 1]],
+			sample_id: strings[data[pos + 2]],
+			bus: data[pos + 3],
+			remote: (flags & 1) !== 0,
+			expendable: (flags & 2) !== 0,
+			// seconds until the end of its step (or the next event on it), plus ties
+			duration: data[pos + 6],
+			params: {},
+			ramps: {},
+		    };
+		    if (flags & 4) {
+			ev.fit_key = data[pos + 5];
+		    }
+		    pos += 9;
+		    for (let i = 0; i < paramCount; i++, pos += 2) {
+			ev.params[strings[data[pos]]] = data[pos + 1];
+		    }
+		    for (let i = 0; i < rampCount; i++, pos += 2) {
+			ev.ramps[strings[data[pos]]] = data[pos + 1];
+		    }
+		    events.push(ev);
+		}
+		return events;
+	    }
+
+	    // the scheduler keeps its timestamps locked to the audio clock, which
+	    // drifts from the browser clock, and stalls while the context is suspended
+	    const AUDIO_CLOCK_SYNC_INTERVAL = 100; // ms
+	    setInterval(() => {
+		scheduler.postMessage({ cmd: 'sync_audio_clock', audio_time: ctx.currentTime, browser_time: performance.timeOrigin + performance.now() });
+	    }, AUDIO_CLOCK_SYNC_INTERVAL);
+
+	    /////////////////
+	    // PEER BRIDGE //
+	    /////////////////
+
+	    // events from lines tagged #remote are sent to peers over WebRTC data
+	    // channels and played on their sampler. Signaling is up to the page, i.e.
+	    // connectPeer(peerConnection.createDataChannel('ruffbox')) on one end and
+	    // peerConnection.ondatachannel = e => connectPeer(e.channel) on the other.
+	    // Timestamps are translated to the peer's audio clock, using the offset
+	    // measured by regular pings.
+	    const PEER_PING_INTERVAL = 1000; // ms
+	    const PEER_PING_HISTORY = 8;
+	    let peers = [];
+
+	    // in ensemble mode, all peers schedule their events with the same
+	    // lookahead (the "performance horizon"), long enough for events to
+	    // reach the slowest peer in time, so everyone hears the same bar at
+	    // the same time. Each peer proposes a horizon from its own measured
+	    // latencies, the largest proposal wins.
+	    const DEFAULT_HORIZON = 0.1; // seconds, same as the scheduler's default lookahead
+	    const HORIZON_MARGIN = 0.05; // seconds, for jitter
+	    let ensembleMode = false;
+	    let horizon = DEFAULT_HORIZON;
+
+	    // proposals are only sent on our own measurements, not in reply to
+	    // other proposals
+	    function updateHorizon(propose) {
+		let proposal = Math.max(DEFAULT_HORIZON, ...peers.filter(peer => peer.rtt !== null).map(peer => peer.rtt / 2.0 + HORIZON_MARGIN));
+		if (propose) {
+		    peers.filter(peer => peer.channel.readyState === 'open').forEach(peer => {
+			peer.channel.send(JSON.stringify({ type: 'horizon', horizon: ensembleMode ? proposal : null }));
+		    });
+		}
+
+		let agreed = ensembleMode ? Math.max(proposal, ...peers.filter(peer => peer.horizon).map(peer => peer.horizon)) : DEFAULT_HORIZON;
+		// don't bother the scheduler with jitter
+		if (Math.abs(agreed - horizon) > 0.005) {
+		    horizon = agreed;
+		    scheduler.postMessage({ cmd: 'set_lookahead', lookahead: horizon * 1000.0 });
+		    window.dispatchEvent(new CustomEvent('ruffbox-horizon', { detail: { horizon: horizon, peers: peers.map(peer => ({ rtt: peer.rtt, horizon: peer.horizon })) } }));
+		}
+	    }
+
+	    window.setEnsembleMode = function(on) {
+		ensembleMode = on;
+		updateHorizon(true);
+	    }
+
+	    window.connectPeer = function(channel) {
+		let peer = { channel: channel, offset: null, rtt: null, horizon: null, pongs: [] };
+		peers.push(peer);
+
+		const ping = () => {
+		    if (channel.readyState === 'open') {
+			channel.send(JSON.stringify({ type: 'ping', sent: ctx.currentTime }));
+		    }
+		};
+
+		channel.onmessage = e => {
+		    let msg = JSON.parse(e.data);
+		    if (msg.type === 'ping') {
+			channel.send(JSON.stringify({ type: 'pong', sent: msg.sent, received: ctx.currentTime }));
+		    } else if (msg.type === 'pong') {
+			// the recent round trip with the least delay gives the best estimate
+			peer.pongs.push({ rtt: ctx.currentTime - msg.sent, sent: msg.sent, received: msg.received });
+			peer.pongs = peer.pongs.slice(-PEER_PING_HISTORY);
+			let best = peer.pongs.reduce((a, b) => b.rtt < a.rtt ? b : a);
+			peer.rtt = best.rtt;
+			peer.offset = best.received - (best.sent + best.rtt / 2.0);
+			updateHorizon(true);
+		    } else if (msg.type === 'horizon') {
+			// proposed by the peer, null if it's not in ensemble mode
+			peer.horizon = msg.horizon;
+			updateHorizon(false);
+		    } else if (msg.type === 'event') {
+			// already on our clock
+			n.port.postMessage({ type: 'trigger', event: msg.event });
+		    }
+		};
+
+		let pinger = setInterval(ping, PEER_PING_INTERVAL);
+		channel.onopen = ping;
+		channel.onclose = () => {
+		    clearInterval(pinger);
+		    peers = peers.filter(p => p !== peer);
+		    updateHorizon(false);
+		};
+		ping();
+	    }
+
+	    function sendToPeers(event) {
+		peers.filter(peer => peer.offset !== null && peer.channel.readyState === 'open').forEach(peer => {
+		    let remoteEvent = Object.assign({}, event, { timestamp: event.timestamp + peer.offset });
+		    peer.channel.send(JSON.stringify({ type: 'event', event: remoteEvent }));
+		});
+	    }
+
 	    ///////////////////
 	    // LOAD MAPPINGS //
 	    ///////////////////
 
 	    n.port.postMessage({ type: 'sourceType', content: sourceType});
 	    n.port.postMessage({ type: 'sourceParameter', content: sourceParameterShortName});
+	    n.port.postMessage({ type: 'insertType', content: insertType});
+	    n.port.postMessage({ type: 'nodeType', content: nodeType});
+
+	    ///////////////////
+	    // CUSTOM VOICES //
+	    ///////////////////
+
+	    // i.e. a ring-modulated sine, playable as 'ring' in patterns:
+	    // defineVoice('ring', [
+	    //     { type: 'sine', value: 1.0 },          // 0: ratio to the event's freq
+	    //     { type: 'sine', value: 1.5 },          // 1
+	    //     { type: 'mul', inputs: [0, 1] },       // 2
+	    //     { type: 'lpf', inputs: [2], value: 2000 }, // 3: the last node is the output
+	    // ])
+	    // nodes can only take inputs from nodes before them
+	    window.defineVoice = function(name, nodes) {
+		n.port.postMessage({ type: 'defineVoice', name: name, nodes: nodes });
+		scheduler.postMessage({ cmd: 'register_voice', name: name });
+	    }
+
+	    // default interpolation of repitched samples: 'none', 'linear', 'cubic' or 'sinc',
+	    // can be overridden per line with the interp param
+	    window.setInterpolation = function(mode) {
+		n.port.postMessage({ type: 'setInterpolation', mode: ['none', 'linear', 'cubic', 'sinc'].indexOf(mode) });
+	    }
+
+	    // how long (in ms) voices take to fade out when they're cut by another
+	    // voice of their cut group (the cut param), 5 ms by default, for all
+	    // groups or for the given one, i.e. setCutFade(30, 1) for open hi-hats
+	    window.setCutFade = function(ms, group) {
+		n.port.postMessage({ type: 'setCutFade', fade: ms / 1000.0, group: group });
+	    }
+
+	    // on sustained overload, stop voices (lines tagged #expendable first,
+	    // then the quietest, then the oldest) instead of crackling
+	    window.setVoiceThinning = function(enabled) {
+		n.port.postMessage({ type: 'setVoiceThinning', enabled: enabled });
+	    }
+
+	    // latch the spectrum of a line with a freeze insert, i.e. freeze('pads'),
+	    // and let go of it again
+	    window.freeze = function(label) {
+		scheduler.postMessage({ cmd: 'freeze', label: label, frozen: true });
+	    }
+
+	    window.release = function(label) {
+		scheduler.postMessage({ cmd: 'freeze', label: label, frozen: false });
+	    }
+
+	    // a function that gets the sound events of each step (and the step) before they're
+	    // played, and returns the ones to play, i.e.
+	    // setTickHook((events, step) => events.filter(ev => ev.sample_id !== 'hh' || step % 4 == 0)),
+	    // it runs in the scheduler's worker, so it can't use anything from the page,
+	    // setTickHook() removes it
+	    window.setTickHook = function(hook) {
+		hookChannel.port1.postMessage({ source: hook ? 'return (' + hook.toString() + ')(events, step);' : null });
+	    }
+
+	    // evaluated input takes over on the next 'beat' or 'bar' instead of right away
+	    // ('immediate'), i.e. setEvalQuantization('bar') for a live set
+	    window.setEvalQuantization = function(quantization) {
+		scheduler.postMessage({ cmd: 'set_eval_quantization', quantization: quantization });
+	    }
+
+	    // warm up on start, so the first bar doesn't come out rough
+	    window.setPriming = function(enabled) {
+		scheduler.postMessage({ cmd: 'set_priming', enabled: enabled });
+	    }
+
+	    // repeat the last bit of the master output on the next step,
+	    // i.e. stutter(1/8, 4) repeats the last eighth note four times
+	    window.stutter = function(fraction, repeats) {
+		scheduler.postMessage({ cmd: 'stutter', fraction: fraction, repeats: repeats });
+	    }
+
+	    // record the master output (or a line, by label, as a stem) from one bar line
+	    // to another, counted since the start, i.e. recordTake('v1', 8, 16), recordTake('bass', 8, 16, 'b')
+	    window.recordTake = function(name, fromBar, toBar, stem) {
+		scheduler.postMessage({ cmd: 'record_take', name: name, from_bar: fromBar, to_bar: toBar, stem: stem });
+	    }
+
+	    // record the next bars of the master output into a sample,
+	    // i.e. resampleMaster('loop', 2), then "loop ~ ~ ~" plays it
+	    window.resampleMaster = function(name, bars) {
+		scheduler.postMessage({ cmd: 'resample_master', name: name, bars: bars });
+	    }
+
+	    ///////////////////////
+	    // SAMPLE INFO QUERY //
+	    ///////////////////////
+
+	    // i.e. getSampleInfo('bd').then(info => console.log(info))
+	    // resolves to null if there's no such sample
+	    let sampleInfoRequests = {};
+	    let memoryUsageRequests = [];
+	    let recordingRequests = [];
+	    let takeRequests = {};
+	    
+	    n.port.onmessage = e => {
+		if (e.data.type === 'sampleInfo') {
+		    let waiting = sampleInfoRequests[e.data.sample_id] || [];
+		    waiting.forEach(resolve => resolve(e.data.info));
+		    delete sampleInfoRequests[e.data.sample_id];
+		} else if (e.data.type === 'memoryUsage') {
+		    memoryUsageRequests.forEach(resolve => resolve(e.data.usage));
+		    memoryUsageRequests = [];
+		} else if (e.data.type === 'recording') {
+		    let wav = e.data.wav ? new Blob([e.data.wav], { type: 'audio/wav' }) : null;
+		    recordingRequests.forEach(resolve => resolve(wav));
+		    recordingRequests = [];
+		} else if (e.data.type === 'take') {
+		    let wav = e.data.wav ? new Blob([e.data.wav], { type: 'audio/wav' }) : null;
+		    (takeRequests[e.data.name] || []).forEach(resolve => resolve(wav));
+		    delete takeRequests[e.data.name];
+		} else if (e.data.type === 'captured') {
+		    // a resampled bit of the master output, ready to be played
+		    scheduler.postMessage({ cmd: 'register_sample', name: e.data.name });
+		    window.dispatchEvent(new CustomEvent('ruffbox-sample', { detail: { sample_id: e.data.name, state: 'loaded' } }));
+		} else if (e.data.type === 'load') {
+		    // the share of the time available spent on processing (0..1)
+		    window.dispatchEvent(new CustomEvent('ruffbox-load', { detail: { load: e.data.load } }));
+		} else if (e.data.type === 'loudness') {
+		    // of the master, short-term (3s) and integrated in LUFS, true peak in dBTP
+		    window.dispatchEvent(new CustomEvent('ruffbox-loudness', { detail: { shortTerm: e.data.shortTerm, integrated: e.data.integrated, truePeak: e.data.truePeak } }));
+		} else if (e.data.type === 'overload') {
+		    // sustained high load, time to thin out some voices
+		    console.warn("overload: dsp load at " + Math.round(e.data.load * 100) + "%");
+		    window.dispatchEvent(new CustomEvent('ruffbox-overload', { detail: { load: e.data.load } }));
+		} else if (e.data.type === 'busLevels') {
+		    // envelopes of followed lines, as modulation inputs
+		    for (let [bus, level] of Object.entries(e.data.levels)) {
+			scheduler.postMessage({ cmd: 'set_bus_level', bus: Number(bus), level: level });
+		    }
+		}
+	    }
+
+	    window.getSampleInfo = function(name) {
+		return new Promise(resolve => {
+		    if(!sampleInfoRequests[name]) {
+			sampleInfoRequests[name] = [];
+		    }
+		    sampleInfoRequests[name].push(resolve);
+		    n.port.postMessage({ type: 'getSampleInfo', sample_id: name });
+		});
+	    }
+
+
+	    // i.e. getMemoryUsage().then(usage => console.log(usage)), all in bytes:
+	    // samples (loaded sample data), voices (unloaded samples still playing), streams (streamed chunks),
+	    // heap (all of the synth), plus the frames of streams that played as silence as streamUnderruns
+	    window.getMemoryUsage = function() {
+		return new Promise(resolve => {
+		    memoryUsageRequests.push(resolve);
+		    n.port.postMessage({ type: 'getMemoryUsage' });
+		});
+	    }
+
+	    // start the integrated loudness and the true peak over, i.e. at the start of a set
+	    window.resetLoudness = function() {
+		n.port.postMessage({ type: 'resetLoudness' });
+	    }
+
+	    // record the output, i.e. startRecording(), then stopRecording({ bitDepth: 24, samplerate: 48000 })
+	    // resolves to a WAV file (Blob), 16 bit (the default, dithered), 24 bit or 32 bit float,
+	    // at the output samplerate unless another one is given
+	    window.startRecording = function() {
+		n.port.postMessage({ type: 'startRecording' });
+	    }
+
+	    window.stopRecording = function(options) {
+		let settings = options || {};
+		return new Promise(resolve => {
+		    recordingRequests.push(resolve);
+		    n.port.postMessage({ type: 'stopRecording', bitDepth: settings.bitDepth || 16, samplerate: settings.samplerate || 0 });
+		});
+	    }
+
+	    // stop recording and download the file, i.e. exportRecording({ bitDepth: 24 }, 'take1.wav')
+	    window.exportRecording = function(options, name) {
+		window.stopRecording(options).then(wav => {
+		    if (!wav) {
+			console.warn("nothing recorded");
+			return;
+		    }
+		    let link = document.createElement('a');
+		    link.href = URL.createObjectURL(wav);
+		    link.download = name || 'ruffbox.wav';
+		    link.click();
+		    URL.revokeObjectURL(link.href);
+		});
+	    }
 	    	    
+	    // a take as a WAV file (Blob), as far as it's recorded, null if there's no such take,
+	    // i.e. getTake('v1', { bitDepth: 24 }), the options are those of stopRecording
+	    window.getTake = function(name, options) {
+		let settings = options || {};
+		return new Promise(resolve => {
+		    if(!takeRequests[name]) {
+			takeRequests[name] = [];
+		    }
+		    takeRequests[name].push(resolve);
+		    n.port.postMessage({ type: 'exportTake', name: name, bitDepth: settings.bitDepth || 16, samplerate: settings.samplerate || 0 });
+		});
+	    }
+
+	    // download a take, i.e. exportTake('v1', { bitDepth: 24 }), as 'v1.wav' unless named otherwise
+	    window.exportTake = function(name, options, fileName) {
+		window.getTake(name, options).then(wav => {
+		    if (!wav) {
+			console.warn("no take " + name);
+			return;
+		    }
+		    let link = document.createElement('a');
+		    link.href = URL.createObjectURL(wav);
+		    link.download = fileName || name + '.wav';
+		    link.click();
+		    URL.revokeObjectURL(link.href);
+		});
+	    }
+
+	    // slice a loaded break and get a line playing the slices, i.e.
+	    // chop('amen', 8, 'shuffled'), styles are 'straight', 'shuffled' and '2-step'
+	    window.chop = function(sample, slices, style) {
+		window.getSampleInfo(sample).then(info => {
+		    if (!info) {
+			console.warn("no sample " + sample + " to chop");
+			return;
+		    }
+		    scheduler.postMessage({ cmd: 'chop', sample: sample, slices: slices, style: style || 'straight', duration: info.duration });
+		});
+	    }
+	    
 	    ///////////////////////////
 	    // INIT CONTROL ELEMENTS //
 	    ///////////////////////////
@@ -183,6 +818,18 @@ if (ctx.audioWorklet === undefined) {
 		n.parameters.get('delay_cutoff').value = e.target.value
 	    })
 	    	    
+	    // global energy macro
+	    const energy = document.getElementById('energy-knob')
+	    energy.addEventListener('input', e => {
+		scheduler.postMessage({ cmd: 'set_energy', energy: e.target.value });
+	    })
+	    
+	    // lockdown mode, blocks destructive operations
+	    const lockdown = document.getElementById('lockdown')
+	    lockdown.addEventListener('change', e => {
+		scheduler.postMessage({ cmd: e.target.value === 1 ? 'lock' : 'unlock' });
+	    })
+	    
 	    // scheduler controls
 	    const startSched = document.getElementById('start-scheduler')	   
 	    startSched.addEventListener('change', e => {
@@ -215,13 +862,63 @@ if (ctx.audioWorklet === undefined) {
 		} 
 	    })
 
+	    //////////////////////
+	    // GAMEPAD POLLING  //
+	    //////////////////////
+
+	    // values are sent as 'gamepad<index>_axis<index>' (0..1) and
+	    // 'gamepad<index>_button<index>' (0 or 1), what they do is up
+	    // to the mappings (i.e. '@map gamepad0_axis1 energy')
+	    let lastControllerValues = {};
+	    
+	    function pollGamepads() {
+		for (const pad of navigator.getGamepads ? navigator.getGamepads() : []) {
+		    if (!pad) {
+			continue;
+		    }
+		    let values = {};
+		    pad.axes.forEach((v, i) => values['gamepad' + pad.index + '_axis' + i] = (v + 1.0) / 2.0);
+		    pad.buttons.forEach((b, i) => values['gamepad' + pad.index + '_button' + i] = b.value);
+		    for (const [id, value] of Object.entries(values)) {
+			if (lastControllerValues[id] !== value) {
+			    lastControllerValues[id] = value;
+			    scheduler.postMessage({ cmd: 'set_controller_value', id: id, value: value });
+			}
+		    }
+		}
+		requestAnimationFrame(pollGamepads);
+	    }
+	    
+	    requestAnimationFrame(pollGamepads);
+
+	    //////////////////////////
+	    // MODULATION INPUTS    //
+	    //////////////////////////
+
+	    // device tilt is sent as 'tilt_x' and 'tilt_y' (0..1), params
+	    // can follow them (i.e. 'lp-freq=mod(tilt_x, 200, 4000)'),
+	    // other sources can call window.setModInput(name, value)
+	    window.setModInput = function(name, value) {
+		scheduler.postMessage({ cmd: 'set_mod_input', name: name, value: value });
+	    }
+
+	    window.addEventListener('deviceorientation', function(e) {
+		if (e.gamma !== null && e.beta !== null) {
+		    window.setModInput('tilt_x', (e.gamma + 90.0) / 180.0);
+		    window.setModInput('tilt_y', (e.beta + 180.0) / 360.0);
+		}
+	    });
+	    
 	    ///////////////////////////	    
 	    // SET KEYBOARD TRIGGERS //
 	    ///////////////////////////
 	    
 	    window.onkeydown = function(e) {
 		var key = e.keyCode ? e.keyCode : e.which;
-		if(e.ctrlKey && key == 13) {
+		if(e.ctrlKey && e.shiftKey && key == 13) {
+		    // switch on the next bar line, with a fill if there's a fill style
+		    scheduler.postMessage({ cmd: 'queue_loop' , loop_data: document.getElementById('code_input').value });
+		} else if(e.ctrlKey && key == 13) {
 		    if(!(document.getElementById('tempo').value === tempo)){
 			tempo = document.getElementById('tempo').value;
 			scheduler.postMessage({ cmd: 'set_tempo' , tempo: tempo });
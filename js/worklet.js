@@ -24,12 +24,8 @@ class RuffboxProcessor extends AudioWorkletProcessor {
 	]
     }
    
-    loadSample(sampleData, sampleSize, id){
+    loadSample(sampleData, sampleSize, id, channels, samplerate){
 	
-	if(!this._sampleBuffers){
-	    this._sampleBuffers = [];
-	}
-
 	let sampleSizeForInterpolation = sampleSize + 3;
 	
 	let samplePtr = this._wasm.exports.alloc(sampleSizeForInterpolation);	
@@ -42,15 +38,224 @@ class RuffboxProcessor extends AudioWorkletProcessor {
 	// copy to wasm buffer, offset one for interpolation
 	sampleBuf.set(sampleData, 1);
 	//console.log("LOADED size: " + sampleSize + " -- data: " + sampleData );
-	let bufNum = this._wasm.exports.load(samplePtr, sampleSizeForInterpolation);
-
 	if(!this._sampleMapping) {
 	    this._sampleMapping = {};
 	}
 
+	// loading a name again swaps the buffer, voices still playing the old one finish with it
+	let bufNum = id in this._sampleMapping ?
+	    this._wasm.exports.replace(this._sampleMapping[id], samplePtr, sampleSizeForInterpolation) :
+	    this._wasm.exports.load(samplePtr, sampleSizeForInterpolation);
+
 	this._sampleMapping[id] = bufNum;
-		
-	this._sampleBuffers.push([samplePtr, sampleBuf, sampleSizeForInterpolation]);	
+
+	if(!this._sampleFormats) {
+	    this._sampleFormats = {};
+	}
+
+	// format of the original file, only the first channel is loaded
+	this._sampleFormats[id] = [channels, samplerate];
+
+	// the synth keeps its own copy
+	this._wasm.exports.dealloc(samplePtr, sampleSizeForInterpolation);
+    }
+        
+    // long samples stay here, outside of the wasm heap, the synth asks for
+    // the chunks it needs (see ruffbox-synth/src/ruffbox/streams.rs)
+    loadStream(samples, id) {
+	// a name that's streamed already is streamed anew
+	this._streams[id] = { num: this._wasm.exports.create_stream(samples.length), samples: samples };
+    }
+
+    // hand over the chunk each stream needs the soonest, if it's missing one
+    feedStreams() {
+	const chunkFrames = 65536;
+	for (let stream of Object.values(this._streams)) {
+	    let chunk = this._wasm.exports.get_stream_request(stream.num);
+	    if (chunk < 0) {
+		continue;
+	    }
+	    let data = stream.samples.subarray(chunk * chunkFrames, (chunk + 1) * chunkFrames);
+	    let chunkPtr = this._wasm.exports.alloc(data.length);
+	    new Float32Array(this._wasm.exports.memory.buffer, chunkPtr, data.length).set(data);
+	    this._wasm.exports.load_stream_chunk(stream.num, chunk, chunkPtr, data.length);
+	    this._wasm.exports.dealloc(chunkPtr, data.length);
+	}
+    }
+
+    // the scheduler writes the events here directly, if there's a ring
+    // (see ruffbox-pattern/src/ring.rs for the layout)
+    drainEventRing() {
+	let ring = this._eventRing;
+	let written = Atomics.load(ring.positions, 0);
+	let pos = Atomics.load(ring.positions, 1);
+	let readString = () => {
+	    let length = ring.data[pos++];
+	    let string = String.fromCharCode(...ring.data.subarray(pos, pos + length));
+	    pos += length;
+	    return string;
+	};
+	while (pos !== written) {
+	    // the rest is skipped, the next event is at the start
+	    if (ring.data[pos] === -1) {
+		pos = 0;
+		continue;
+	    }
+	    let next = pos + ring.data[pos];
+	    let flags = ring.data[pos + 3];
+	    let ev = {
+		timestamp: ring.data[pos + 1],
+		bus: ring.data[pos + 2],
+		remote: (flags & 1) !== 0,
+		expendable: (flags & 2) !== 0,
+		duration: ring.data[pos + 5],
+		params: {},
+		ramps: {},
+	    };
+	    if (flags & 4) {
+		ev.fit_key = ring.data[pos + 4];
+	    }
+	    pos += 6;
+	    ev.source_type = readString();
+	    ev.sample_id = readString();
+	    for (let values of [ev.params, ev.ramps]) {
+		let count = ring.data[pos++];
+		for (let i = 0; i < count; i++) {
+		    let name = readString();
+		    values[name] = ring.data[pos++];
+		}
+	    }
+	    this.trigger(ev);
+	    pos = next;
+	}
+	Atomics.store(ring.positions, 1, pos);
+    }
+
+    defineInstrument(name, zones) {
+	// redefining an instrument creates a new one, like with voices
+	let instrument = this._wasm.exports.define_instrument();
+	zones.forEach(zone => {
+	    if (!(zone.sample_id in this._sampleMapping) ||
+		!this._wasm.exports.add_key_zone(instrument, zone.lokey, zone.hikey, zone.root, this._sampleMapping[zone.sample_id])) {
+		console.warn("instrument " + name + ": sample " + zone.sample_id + " isn't loaded");
+	    } else if ('lovel' in zone) {
+		// zones of SF2 presets come with velocity layers, tuning and loop points (in frames)
+		this._wasm.exports.tune_key_zone(instrument, zone.lovel, zone.hivel, zone.tune, zone.samplerate, zone.attenuation);
+		if (zone.loop_end > zone.loop_start) {
+		    this._wasm.exports.set_sample_loop(this._sampleMapping[zone.sample_id], zone.loop_start / sampleRate, zone.loop_end / sampleRate, 0.0);
+		}
+	    }
+	});
+	this._instrumentMapping[name] = instrument;
+    }
+
+    trigger(event) {
+	let params = event.params;
+	// i.e. 'break/onset3', the sample from its fourth onset to the next
+	let sampleId = event.sample_id;
+	let onset = null;
+	let slice = event.source_type === 'Sampler' ? /^(.+)\/onset(\d+)$/.exec(sampleId) : null;
+	if (slice) {
+	    sampleId = slice[1];
+	    onset = parseInt(slice[2]);
+	}
+	let instrument = event.source_type === 'Sampler' ? this._instrumentMapping[sampleId] : undefined;
+	// samples might still be loading
+	if (event.source_type === 'Sampler' && instrument === undefined && !(sampleId in this._sampleMapping)) {
+	    return;
+	}
+	if (event.source_type === 'CustomVoice' && !(sampleId in this._voiceMapping)) {
+	    return;
+	}
+	if (event.source_type === 'Streamer' && !(sampleId in this._streams)) {
+	    return;
+	}
+	let bufNum = event.source_type === 'CustomVoice' ? this._voiceMapping[sampleId] :
+	    event.source_type === 'Streamer' ? this._streams[sampleId].num : this._sampleMapping[sampleId];
+	if (instrument !== undefined) {
+	    // the note picks the sample, middle c without one, the level the velocity layer
+	    let freq = params.freq || 261.63;
+	    let level = params.lvl === undefined ? 1.0 : params.lvl;
+	    let velocity = Math.round(Math.min(Math.max(level, 0.0), 1.0) * 127);
+	    bufNum = this._wasm.exports.get_key_zone_sample(instrument, freq, velocity);
+	    if (bufNum < 0) {
+		return;
+	    }
+	    params.rate = (params.rate || 1.0) * this._wasm.exports.get_key_zone_rate(instrument, freq, velocity);
+	    params.lvl = level * this._wasm.exports.get_key_zone_gain(instrument, freq, velocity);
+	    delete params.freq;
+	}
+	let instance_id = this._wasm.exports.prepare(this._sourceType[event.source_type], event.timestamp, bufNum);
+	if (onset !== null) {
+	    this._wasm.exports.set_instance_onset_slice(instance_id, bufNum, onset);
+	}
+	// repitch into the key of the session, on top of the rate given
+	if (event.source_type === 'Sampler' && event.fit_key !== undefined) {
+	    params.rate = (params.rate || 1.0) * this._wasm.exports.get_sample_key_fit_rate(bufNum, event.fit_key);
+	}
+	for (let [key, value] of Object.entries(params)) {
+	    if (key === 'cut') {
+		// starting this voice stops the others of its cut group
+		this._wasm.exports.set_instance_cut_group(instance_id, value);
+	    } else if (key.includes('.')) {
+		// i.e. 'lpf.lp-freq', for the line's insert effect
+		let [effect, param] = key.split('.');
+		let idx = (this._insertChains[event.bus] || []).indexOf(effect);
+		if (idx >= 0) {
+		    this._wasm.exports.set_insert_parameter(event.bus, idx, this._sourceParameter[param], value);
+		}
+	    } else {
+		this._wasm.exports.set_instance_parameter(instance_id, this._sourceParameter[key], value);
+	    }
+	}
+	// params moving over the duration of the voice, i.e. pos=-1..1
+	for (let [key, value] of Object.entries(event.ramps || {})) {
+	    if (key in this._sourceParameter && key in params) {
+		this._wasm.exports.set_instance_ramp(instance_id, this._sourceParameter[key], params[key], value);
+	    }
+	}
+	if (event.bus) {
+	    this._wasm.exports.set_instance_bus(instance_id, event.bus);
+	}
+	if (event.expendable) {
+	    this._wasm.exports.set_instance_expendable(instance_id);
+	}
+	this._wasm.exports.trigger(instance_id);
+    }
+
+    configureEngine() {
+	let settings = this._engineSettings || {};
+	// rendered at once, multiples of the 128 samples the worklet asks for
+	this._size = Math.max(1, Math.round((settings.blockSize || 128) / 128)) * 128;
+	this._blockPos = 0;
+	this._wasm.exports.configure_engine(settings.samplerate || sampleRate, sampleRate);
+	// 'stereo', 'binaural' (for headphones) or 'ambisonic' (four channels of B-format)
+	let spatialMode = ['stereo', 'binaural', 'ambisonic'].indexOf(settings.spatial || 'stereo');
+	if (spatialMode >= 0) {
+	    this._wasm.exports.set_spatial_mode(spatialMode);
+	}
+	this._ambisonic = settings.spatial === 'ambisonic';
+    }
+
+    allocateOutput() {
+	// why always last ??
+	this._outPtr_r = this._wasm.exports.alloc(this._size)		    
+	this._outBuf_r = new Float32Array (
+	    this._wasm.exports.memory.buffer,
+	    this._outPtr_r,
+	    this._size
+	)
+	this._outPtr_l = this._wasm.exports.alloc(this._size)		    
+	this._outBuf_l = new Float32Array (
+	    this._wasm.exports.memory.buffer,
+	    this._outPtr_l,
+	    this._size
+	)
+	if (this._ambisonic) {
+	    // all allocated before any view is made, in case the memory grows in between
+	    this._outPtrs_bformat = [0, 1, 2, 3].map(() => this._wasm.exports.alloc(this._size));
+	    this._outBufs_bformat = this._outPtrs_bformat.map(ptr => new Float32Array(this._wasm.exports.memory.buffer, ptr, this._size));
+	}
     }
         
     constructor(options) {
@@ -72,59 +277,242 @@ class RuffboxProcessor extends AudioWorkletProcessor {
 		    this._wasm = w.instance
 		    // grow memory to accomodate full sample ... 
 		    this._wasm.exports.memory.grow(250)
-		    this._size = 128
+
+		    // before loading any samples, as they'd be dropped
+		    this.configureEngine();
 		    		    
 		    if(this._samples) {
 			this._samples.forEach(
 			    function(sampleInfo) {
-				this.loadSample(sampleInfo[0], sampleInfo[1], sampleInfo[2]);
+				this.loadSample(sampleInfo[0], sampleInfo[1], sampleInfo[2], sampleInfo[3], sampleInfo[4]);
 			    }, this);
 			this._samples = [];			
 		    }
 
-		    // why always last ??
-		    this._outPtr_r = this._wasm.exports.alloc(this._size)		    
-		    this._outBuf_r = new Float32Array (
-			this._wasm.exports.memory.buffer,
-			this._outPtr_r,
-			this._size
-		    )
-		    this._outPtr_l = this._wasm.exports.alloc(this._size)		    
-		    this._outBuf_l = new Float32Array (
-			this._wasm.exports.memory.buffer,
-			this._outPtr_l,
-			this._size
-		    )
+		    (this._pendingStreams || []).forEach(stream => this.loadStream(stream.samples, stream.sample_id));
+		    this._pendingStreams = [];
+
+		    // instruments defined before their samples were in
+		    (this._pendingInstruments || []).forEach(instrument => this.defineInstrument(instrument.name, instrument.zones));
+		    this._pendingInstruments = [];
+
+		    this.allocateOutput();
 		})		
+	    } else if (e.data.type === 'configureEngine') {
+		// internal samplerate and block size, lower/larger for weak devices
+		this._engineSettings = e.data.settings;
+		if(this._wasm) {
+		    this.configureEngine();
+		    this.allocateOutput();
+		}
 	    } else if (e.data.type === 'loadSample') {
 				
 		let sampleSize = e.data.length;
 		let sampleData = e.data.samples;
 		let sampleId = e.data.sample_id;
+		let channels = e.data.channels;
+		let samplerate = e.data.samplerate;
 		
 		if(!this._samples){
 		    this._samples = [];
 		}
 		
 		if(this._wasm){
-		    loadSample(sampleData, sampleSize, sampleId);
+		    this.loadSample(sampleData, sampleSize, sampleId, channels, samplerate);
+		} else {
+		    this._samples.push([sampleData, sampleSize, sampleId, channels, samplerate]);
+		}
+	    } else if (e.data.type === 'loadStream') {
+		if(this._wasm) {
+		    this.loadStream(e.data.samples, e.data.sample_id);
+		} else {
+		    this._pendingStreams = (this._pendingStreams || []).concat([{ samples: e.data.samples, sample_id: e.data.sample_id }]);
+		}
+	    } else if (e.data.type === 'defineInstrument') {
+		// zones are keys (midi notes) mapped to loaded samples
+		if(this._wasm) {
+		    this.defineInstrument(e.data.name, e.data.zones);
 		} else {
-		    this._samples.push([sampleData, sampleSize, sampleId]);
+		    this._pendingInstruments = (this._pendingInstruments || []).concat([{ name: e.data.name, zones: e.data.zones }]);
+		}
+	    } else if (e.data.type === 'getSampleInfo') {
+		let sampleId = e.data.sample_id;
+		let info = null;
+		if(this._wasm && sampleId in this._sampleMapping) {
+		    let bufNum = this._sampleMapping[sampleId];
+		    let onsets = [];
+		    for(let i = 0; i < this._wasm.exports.get_sample_onset_count(bufNum); i++) {
+			onsets.push(this._wasm.exports.get_sample_onset(bufNum, i));
+		    }
+		    info = {
+			duration: this._wasm.exports.get_sample_duration(bufNum),
+			channels: this._sampleFormats[sampleId][0],
+			samplerate: this._sampleFormats[sampleId][1],
+			peak: this._wasm.exports.get_sample_peak(bufNum),
+			rms: this._wasm.exports.get_sample_rms(bufNum),
+			// in Hz, null for samples without a clear pitch
+			pitch: this._wasm.exports.get_sample_pitch(bufNum) || null,
+			onsets: onsets,
+		    };
+		}
+		this.port.postMessage({ type: 'sampleInfo', sample_id: sampleId, info: info });
+	    } else if (e.data.type === 'setSampleLoop') {
+		if(this._wasm && e.data.sample_id in this._sampleMapping) {
+		    this._wasm.exports.set_sample_loop(this._sampleMapping[e.data.sample_id], e.data.start, e.data.end, e.data.crossfade);
+		}
+	    } else if (e.data.type === 'unloadSample') {
+		let sampleId = e.data.sample_id;
+		if(this._wasm && sampleId in this._sampleMapping) {
+		    this._wasm.exports.unload(this._sampleMapping[sampleId]);
+		    delete this._sampleMapping[sampleId];
+		    delete this._sampleFormats[sampleId];
+		}
+	    } else if (e.data.type === 'getMemoryUsage') {
+		let usage = null;
+		if(this._wasm) {
+		    usage = {
+			samples: this._wasm.exports.get_sample_memory(),
+			// unloaded or replaced samples that are still playing
+			voices: this._wasm.exports.get_voice_memory(),
+			// chunks of streamed samples
+			streams: this._wasm.exports.get_stream_memory(),
+			// frames of streams that weren't there in time
+			streamUnderruns: this._wasm.exports.get_stream_underruns(),
+			heap: this._wasm.exports.memory.buffer.byteLength,
+		    };
+		}
+		this.port.postMessage({ type: 'memoryUsage', usage: usage });
+	    } else if (e.data.type === 'setNormalization') {
+		// 0 = off, 1 = peak, 2 = rms
+		if(this._wasm) {
+		    this._wasm.exports.set_normalization(e.data.mode);
+		}
+	    } else if (e.data.type === 'setCutFade') {
+		if(this._wasm) {
+		    if (e.data.group === undefined) {
+			this._wasm.exports.set_cut_fade(e.data.fade);
+		    } else {
+			this._wasm.exports.set_cut_group_fade(e.data.group, e.data.fade);
+		    }
+		}
+	    } else if (e.data.type === 'resetLoudness') {
+		if(this._wasm) {
+		    this._wasm.exports.reset_loudness();
+		}
+	    } else if (e.data.type === 'startRecording') {
+		if(this._wasm) {
+		    this._wasm.exports.start_recording();
+		}
+	    } else if (e.data.type === 'stopRecording') {
+		let wav = null;
+		if(this._wasm) {
+		    // 0 = 16 bit (dithered), 1 = 24 bit, 2 = 32 bit float
+		    let depth = [16, 24, 32].indexOf(e.data.bitDepth);
+		    let size = this._wasm.exports.stop_recording(e.data.samplerate || 0, depth >= 0 ? depth : 0);
+		    // copied, the memory is reused for the next recording
+		    wav = size > 0 ? new Uint8Array(this._wasm.exports.memory.buffer, this._wasm.exports.get_recording_ptr(), size).slice().buffer : null;
+		}
+		this.port.postMessage({ type: 'recording', wav: wav }, wav ? [wav] : []);
+	    } else if (e.data.type === 'setInterpolation') {
+		// 0 = none, 1 = linear, 2 = cubic, 3 = sinc
+		if(this._wasm && e.data.mode >= 0) {
+		    this._wasm.exports.set_interpolation(e.data.mode);
+		}
+	    } else if (e.data.type === 'setVoiceThinning') {
+		if(this._wasm) {
+		    this._wasm.exports.set_voice_thinning(e.data.enabled);
+		}
+	    } else if (e.data.type === 'gate') {
+		let shape = ['square', 'pump'].indexOf(e.data.shape);
+		if(this._wasm && shape >= 0) {
+		    this._wasm.exports.gate(e.data.bus, e.data.timestamp, e.data.length, e.data.level, shape);
+		}
+	    } else if (e.data.type === 'startTake') {
+		if(this._wasm) {
+		    // a take of the same name is replaced
+		    if (e.data.name in this._takes) {
+			this._wasm.exports.discard_take(this._takes[e.data.name]);
+		    }
+		    this._takes[e.data.name] = this._wasm.exports.start_take(e.data.bus > 0 ? e.data.bus : -1, e.data.timestamp);
+		}
+	    } else if (e.data.type === 'stopTake') {
+		if(this._wasm && e.data.name in this._takes) {
+		    this._wasm.exports.stop_take(this._takes[e.data.name], e.data.timestamp);
+		}
+	    } else if (e.data.type === 'exportTake') {
+		let wav = null;
+		if(this._wasm && e.data.name in this._takes) {
+		    let depth = [16, 24, 32].indexOf(e.data.bitDepth);
+		    let size = this._wasm.exports.export_take(this._takes[e.data.name], e.data.samplerate || 0, depth >= 0 ? depth : 0);
+		    // copied, like recordings
+		    wav = size > 0 ? new Uint8Array(this._wasm.exports.memory.buffer, this._wasm.exports.get_recording_ptr(), size).slice().buffer : null;
+		}
+		this.port.postMessage({ type: 'take', name: e.data.name, wav: wav }, wav ? [wav] : []);
+	    } else if (e.data.type === 'capture') {
+		if(this._wasm) {
+		    // stored under the name once it's done, see process()
+		    this._captures[this._wasm.exports.start_capture(e.data.start, e.data.duration)] = e.data.name;
+		}
+	    } else if (e.data.type === 'prime') {
+		if(this._wasm) {
+		    // read through the samples, and trigger each once (silently),
+		    // so neither the memory nor the trigger path are cold on the downbeat
+		    e.data.samples.filter(sampleId => sampleId in this._sampleMapping).forEach(sampleId => {
+			this._wasm.exports.prime_sample(this._sampleMapping[sampleId]);
+			this.trigger({ timestamp: 0, source_type: 'Sampler', sample_id: sampleId, params: { lvl: 0, atk: 0.001, sus: 0.001, rel: 0.001 } });
+		    });
+		}
+	    } else if (e.data.type === 'stutter') {
+		if(this._wasm) {
+		    this._wasm.exports.stutter(e.data.timestamp, e.data.length, e.data.repeats);
 		}
 	    } else if (e.data.type === 'sourceType') {
 		this._sourceType = e.data.content;
 	    } else if (e.data.type === 'sourceParameter') {
 		this._sourceParameter = e.data.content;
-	    } else if (e.data.type === 'trigger') {
+	    } else if (e.data.type === 'insertType') {
+		this._insertType = e.data.content;
+	    } else if (e.data.type === 'nodeType') {
+		this._nodeType = e.data.content;
+	    } else if (e.data.type === 'defineVoice') {
+		if(this._wasm) {
+		    // redefining a voice creates a new one, instances of the old one play on
+		    let voice = this._wasm.exports.define_voice();
+		    e.data.nodes.forEach((node, idx) => {
+			let inputs = node.inputs || [];
+			let added = this._wasm.exports.add_voice_node(voice, this._nodeType[node.type], inputs.length > 0 ? inputs[0] : -1, inputs.length > 1 ? inputs[1] : -1, node.value === undefined ? 1.0 : node.value);
+			if (added < 0) {
+			    console.warn("voice " + e.data.name + ": invalid node " + idx);
+			}
+		    });
+		    this._voiceMapping[e.data.name] = voice;
+		}
+	    } else if (e.data.type === 'insertChain') {
+		// effects are addressed by their position in the chain
+		this._insertChains[e.data.bus] = e.data.effects;
 		if(this._wasm) {
-		    let event = e.data.event;
-		    let params = e.data.event.params;
-		    console.log(event);
-		    let instance_id = this._wasm.exports.prepare(this._sourceType[event.source_type], event.timestamp, this._sampleMapping[event.sample_id]);
-		    for (let [key, value] of Object.entries(params)) {
-		    	this._wasm.exports.set_instance_parameter(instance_id, this._sourceParameter[key], value);
+		    this._wasm.exports.clear_insert_chain(e.data.bus);
+		    e.data.effects.forEach(effect => this._wasm.exports.add_insert_effect(e.data.bus, this._insertType[effect]));
+		    if (e.data.follow) {
+			this._wasm.exports.follow_bus(e.data.bus);
 		    }
-		    this._wasm.exports.trigger(instance_id);
+		}
+		if (e.data.follow) {
+		    this._followedBuses.add(e.data.bus);
+		} else {
+		    this._followedBuses.delete(e.data.bus);
+		}
+	    } else if (e.data.type === 'insertParam') {
+		let idx = (this._insertChains[e.data.bus] || []).indexOf(e.data.effect);
+		if(this._wasm && idx >= 0) {
+		    this._wasm.exports.set_insert_parameter(e.data.bus, idx, this._sourceParameter[e.data.param], e.data.value);
+		}
+	    } else if (e.data.type === 'eventRing') {
+		this._eventRing = e.data.buffer ? { positions: new Int32Array(e.data.buffer, 0, 2), data: new Float64Array(e.data.buffer, 8) } : null;
+	    } else if (e.data.type === 'trigger') {
+		if(this._wasm) {
+		    // all events of a step from the scheduler, or a single one from a peer
+		    (e.data.events || [e.data.event]).forEach(event => this.trigger(event));
 		}
 	    }
 	}
@@ -132,6 +520,21 @@ class RuffboxProcessor extends AudioWorkletProcessor {
 	if(!this._sampleMapping) {
 	    this._sampleMapping = {};
 	}
+
+	if(!this._sampleFormats) {
+	    this._sampleFormats = {};
+	}
+
+	this._insertChains = {};
+	this._captures = {};
+	// take ids by name
+	this._takes = {};
+	this._streams = {};
+	this._voiceMapping = {};
+	this._instrumentMapping = {};
+	this._followedBuses = new Set();
+	this._eventRing = null;
+	this._blockCount = 0;
     }
     
     process(inputs, outputs, parameters) {
@@ -166,9 +569,66 @@ class RuffboxProcessor extends AudioWorkletProcessor {
 	    this._last_delay_cutoff = parameters.delay_cutoff[0];
 	}
 	
-	this._wasm.exports.process(this._outPtr_l, this._outPtr_r, this._size, currentTime);
-	output[0].set(this._outBuf_l)
-	output[1].set(this._outBuf_r)
+	if (this._eventRing) {
+	    this.drainEventRing();
+	}
+
+	// larger blocks are rendered ahead and handed out 128 samples at a time
+	if (this._blockPos === 0) {
+	    this.feedStreams();
+	    // there's no performance.now() in every worklet scope
+	    let start = globalThis.performance ? performance.now() : Date.now();
+	    if (this._ambisonic) {
+		this._wasm.exports.process_bformat(...this._outPtrs_bformat, this._size, currentTime);
+	    } else {
+		this._wasm.exports.process(this._outPtr_l, this._outPtr_r, this._size, currentTime);
+	    }
+	    let end = globalThis.performance ? performance.now() : Date.now();
+	    if (this._wasm.exports.report_dsp_time((end - start) / 1000.0)) {
+		this.port.postMessage({ type: 'overload', load: this._wasm.exports.get_load() });
+	    }
+	}
+	if (this._ambisonic) {
+	    output.forEach((channel, idx) => channel.set(this._outBufs_bformat[idx].subarray(this._blockPos, this._blockPos + 128)));
+	} else {
+	    output[0].set(this._outBuf_l.subarray(this._blockPos, this._blockPos + 128))
+	    output[1].set(this._outBuf_r.subarray(this._blockPos, this._blockPos + 128))
+	}
+	this._blockPos = (this._blockPos + 128) % this._size;
+
+	this._blockCount++;
+
+	// a capture of the master output that's done becomes a sample,
+	// replacing one of the same name
+	let capture = this._wasm.exports.get_finished_capture();
+	if (capture >= 0) {
+	    let name = this._captures[capture];
+	    let bufNum = this._wasm.exports.store_capture(capture, name in this._sampleMapping ? this._sampleMapping[name] : -1);
+	    delete this._captures[capture];
+	    if (bufNum >= 0) {
+		this._sampleMapping[name] = bufNum;
+		this._sampleFormats[name] = [1, sampleRate];
+		this.port.postMessage({ type: 'captured', name: name });
+	    }
+	}
+
+	// load and loudness telemetry, about every 300ms
+	if (this._blockCount % 100 === 0) {
+	    this.port.postMessage({ type: 'load', load: this._wasm.exports.get_load() });
+	    this.port.postMessage({
+		type: 'loudness',
+		shortTerm: this._wasm.exports.get_loudness_short_term(),
+		integrated: this._wasm.exports.get_loudness_integrated(),
+		truePeak: this._wasm.exports.get_true_peak(),
+	    });
+	}
+
+	// report followed envelopes about every 12ms
+	if (this._followedBuses.size > 0 && this._blockCount % 4 === 0) {
+	    let levels = {};
+	    this._followedBuses.forEach(bus => levels[bus] = this._wasm.exports.get_bus_level(bus));
+	    this.port.postMessage({ type: 'busLevels', levels: levels });
+	}
 
 	return true
     }
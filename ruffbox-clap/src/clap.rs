@@ -0,0 +1,185 @@
+#![allow(non_camel_case_types)]
+
+// CLAP ABI
+// The parts of the CLAP headers (https://github.com/free-audio/clap, 1.x) the
+// plugin uses, written out by hand, with the names of the headers so they can
+// be checked against them. Everything here is `#[repr(C)]` and only ever
+// shared with the host by pointer.
+
+use std::os::raw::{c_char, c_void};
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct clap_version {
+    pub major: u32,
+    pub minor: u32,
+    pub revision: u32,
+}
+
+pub const CLAP_VERSION: clap_version = clap_version { major: 1, minor: 2, revision: 0 };
+
+pub const CLAP_PLUGIN_FACTORY_ID: &[u8] = b"clap.plugin-factory\0";
+pub const CLAP_EXT_STATE: &[u8] = b"clap.state\0";
+pub const CLAP_EXT_AUDIO_PORTS: &[u8] = b"clap.audio-ports\0";
+pub const CLAP_PORT_STEREO: &[u8] = b"stereo\0";
+
+pub const CLAP_NAME_SIZE: usize = 256;
+pub const CLAP_INVALID_ID: u32 = u32::MAX;
+pub const CLAP_AUDIO_PORT_IS_MAIN: u32 = 1;
+
+pub const CLAP_PROCESS_ERROR: i32 = 0;
+pub const CLAP_PROCESS_CONTINUE: i32 = 1;
+
+/// song positions in beats and seconds are fixed point numbers
+pub const CLAP_BEATTIME_FACTOR: f64 = (1i64 << 31) as f64;
+
+pub const CLAP_TRANSPORT_HAS_TEMPO: u32 = 1 << 0;
+pub const CLAP_TRANSPORT_HAS_BEATS_TIMELINE: u32 = 1 << 1;
+pub const CLAP_TRANSPORT_HAS_TIME_SIGNATURE: u32 = 1 << 3;
+pub const CLAP_TRANSPORT_IS_PLAYING: u32 = 1 << 4;
+
+#[repr(C)]
+pub struct clap_plugin_entry {
+    pub clap_version: clap_version,
+    pub init: unsafe extern "C" fn(plugin_path: *const c_char) -> bool,
+    pub deinit: unsafe extern "C" fn(),
+    pub get_factory: unsafe extern "C" fn(factory_id: *const c_char) -> *const c_void,
+}
+
+#[repr(C)]
+pub struct clap_plugin_factory {
+    pub get_plugin_count: unsafe extern "C" fn(factory: *const clap_plugin_factory) -> u32,
+    pub get_plugin_descriptor: unsafe extern "C" fn(factory: *const clap_plugin_factory, index: u32) -> *const clap_plugin_descriptor,
+    pub create_plugin: unsafe extern "C" fn(factory: *const clap_plugin_factory, host: *const clap_host, plugin_id: *const c_char) -> *const clap_plugin,
+}
+
+#[repr(C)]
+pub struct clap_plugin_descriptor {
+    pub clap_version: clap_version,
+    pub id: *const c_char,
+    pub name: *const c_char,
+    pub vendor: *const c_char,
+    pub url: *const c_char,
+    pub manual_url: *const c_char,
+    pub support_url: *const c_char,
+    pub version: *const c_char,
+    pub description: *const c_char,
+    /// null terminated
+    pub features: *const *const c_char,
+}
+
+#[repr(C)]
+pub struct clap_host {
+    pub clap_version: clap_version,
+    pub host_data: *mut c_void,
+    pub name: *const c_char,
+    pub vendor: *const c_char,
+    pub url: *const c_char,
+    pub version: *const c_char,
+    pub get_extension: unsafe extern "C" fn(host: *const clap_host, extension_id: *const c_char) -> *const c_void,
+    pub request_restart: unsafe extern "C" fn(host: *const clap_host),
+    pub request_process: unsafe extern "C" fn(host: *const clap_host),
+    pub request_callback: unsafe extern "C" fn(host: *const clap_host),
+}
+
+#[repr(C)]
+pub struct clap_plugin {
+    pub desc: *const clap_plugin_descriptor,
+    pub plugin_data: *mut c_void,
+    pub init: unsafe extern "C" fn(plugin: *const clap_plugin) -> bool,
+    pub destroy: unsafe extern "C" fn(plugin: *const clap_plugin),
+    pub activate: unsafe extern "C" fn(plugin: *const clap_plugin, sample_rate: f64, min_frames_count: u32, max_frames_count: u32) -> bool,
+    pub deactivate: unsafe extern "C" fn(plugin: *const clap_plugin),
+    pub start_processing: unsafe extern "C" fn(plugin: *const clap_plugin) -> bool,
+    pub stop_processing: unsafe extern "C" fn(plugin: *const clap_plugin),
+    pub reset: unsafe extern "C" fn(plugin: *const clap_plugin),
+    pub process: unsafe extern "C" fn(plugin: *const clap_plugin, process: *const clap_process) -> i32,
+    pub get_extension: unsafe extern "C" fn(plugin: *const clap_plugin, id: *const c_char) -> *const c_void,
+    pub on_main_thread: unsafe extern "C" fn(plugin: *const clap_plugin),
+}
+
+#[repr(C)]
+pub struct clap_process {
+    pub steady_time: i64,
+    pub frames_count: u32,
+    /// null if the host has no transport (i.e. it's free running)
+    pub transport: *const clap_event_transport,
+    pub audio_inputs: *const clap_audio_buffer,
+    pub audio_outputs: *mut clap_audio_buffer,
+    pub audio_inputs_count: u32,
+    pub audio_outputs_count: u32,
+    pub in_events: *const c_void,
+    pub out_events: *const c_void,
+}
+
+#[repr(C)]
+pub struct clap_audio_buffer {
+    pub data32: *mut *mut f32,
+    pub data64: *mut *mut f64,
+    pub channel_count: u32,
+    pub latency: u32,
+    pub constant_mask: u64,
+}
+
+#[repr(C)]
+pub struct clap_event_header {
+    pub size: u32,
+    pub time: u32,
+    pub space_id: u16,
+    pub type_: u16,
+    pub flags: u32,
+}
+
+#[repr(C)]
+pub struct clap_event_transport {
+    pub header: clap_event_header,
+    pub flags: u32,
+    pub song_pos_beats: i64,
+    pub song_pos_seconds: i64,
+    pub tempo: f64,
+    pub tempo_inc: f64,
+    pub loop_start_beats: i64,
+    pub loop_end_beats: i64,
+    pub loop_start_seconds: i64,
+    pub loop_end_seconds: i64,
+    pub bar_start: i64,
+    pub bar_number: i32,
+    pub tsig_num: u16,
+    pub tsig_denom: u16,
+}
+
+#[repr(C)]
+pub struct clap_istream {
+    pub ctx: *mut c_void,
+    /// the number of bytes read, 0 at the end, -1 on errors
+    pub read: unsafe extern "C" fn(stream: *const clap_istream, buffer: *mut c_void, size: u64) -> i64,
+}
+
+#[repr(C)]
+pub struct clap_ostream {
+    pub ctx: *mut c_void,
+    /// the number of bytes written, -1 on errors
+    pub write: unsafe extern "C" fn(stream: *const clap_ostream, buffer: *const c_void, size: u64) -> i64,
+}
+
+#[repr(C)]
+pub struct clap_plugin_state {
+    pub save: unsafe extern "C" fn(plugin: *const clap_plugin, stream: *const clap_ostream) -> bool,
+    pub load: unsafe extern "C" fn(plugin: *const clap_plugin, stream: *const clap_istream) -> bool,
+}
+
+#[repr(C)]
+pub struct clap_audio_port_info {
+    pub id: u32,
+    pub name: [c_char; CLAP_NAME_SIZE],
+    pub flags: u32,
+    pub channel_count: u32,
+    pub port_type: *const c_char,
+    pub in_place_pair: u32,
+}
+
+#[repr(C)]
+pub struct clap_plugin_audio_ports {
+    pub count: unsafe extern "C" fn(plugin: *const clap_plugin, is_input: bool) -> u32,
+    pub get: unsafe extern "C" fn(plugin: *const clap_plugin, index: u32, is_input: bool, info: *mut clap_audio_port_info) -> bool,
+}
@@ -0,0 +1,435 @@
+mod clap;
+mod session;
+
+use std::env;
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_void};
+use std::path::Path;
+use std::ptr;
+use parking_lot::Mutex;
+
+use crate::clap::*;
+use crate::session::{Session, Transport};
+
+// CLAP
+// ruffbox as a CLAP plugin: an instrument with a stereo output that plays its
+// pattern in time with the host (see session.rs). The pattern text is the
+// plugin's state, the host saves it with the project (or as a preset) and
+// loads it back. There's no editor (yet), and the samples are the WAV files of
+// the directory in RUFFBOX_SAMPLES, like ruffbox-native's sample dir.
+//
+// The host calls in on its main thread, but process (and reset) on its audio
+// thread, a state loaded while the plugin is active is evaluated there, on the
+// next block.
+
+/// the directory the samples are loaded from, when the plugin is activated
+const SAMPLE_DIR_VAR: &str = "RUFFBOX_SAMPLES";
+
+/// For statics that point to constant C strings, they're never written.
+struct Shared<T>(T);
+unsafe impl<T> Sync for Shared<T> {}
+
+static FEATURES: Shared<[*const c_char; 5]> = Shared([
+    b"instrument\0".as_ptr() as *const c_char,
+    b"synthesizer\0".as_ptr() as *const c_char,
+    b"sampler\0".as_ptr() as *const c_char,
+    b"stereo\0".as_ptr() as *const c_char,
+    ptr::null(),
+]);
+
+static DESCRIPTOR: Shared<clap_plugin_descriptor> = Shared(clap_plugin_descriptor {
+    clap_version: CLAP_VERSION,
+    id: b"de.parkellipsen.ruffbox\0".as_ptr() as *const c_char,
+    name: b"ruffbox\0".as_ptr() as *const c_char,
+    vendor: b"parkellipsen\0".as_ptr() as *const c_char,
+    url: b"\0".as_ptr() as *const c_char,
+    manual_url: b"\0".as_ptr() as *const c_char,
+    support_url: b"\0".as_ptr() as *const c_char,
+    version: b"0.1.0\0".as_ptr() as *const c_char,
+    description: b"text-based step sequencer with a sampler/synth\0".as_ptr() as *const c_char,
+    features: &FEATURES.0 as *const [*const c_char; 5] as *const *const c_char,
+});
+
+struct Plugin {
+    /// what the host sees, it points back to this
+    clap: clap_plugin,
+    /// the pattern text, as it's saved
+    pattern: Mutex<String>,
+    /// a pattern text that's been loaded, to be evaluated on the next block
+    loaded: Mutex<Option<String>>,
+    /// only while the plugin is active
+    session: Mutex<Option<Session>>,
+}
+
+/// the plugin behind what the host sees
+unsafe fn plugin<'a>(clap: *const clap_plugin) -> &'a Plugin {
+    &*((*clap).plugin_data as *const Plugin)
+}
+
+unsafe extern "C" fn plugin_init(_clap: *const clap_plugin) -> bool {
+    true
+}
+
+unsafe extern "C" fn plugin_destroy(clap: *const clap_plugin) {
+    drop(Box::from_raw((*clap).plugin_data as *mut Plugin));
+}
+
+unsafe extern "C" fn plugin_activate(clap: *const clap_plugin, sample_rate: f64, _min_frames: u32, _max_frames: u32) -> bool {
+    let plugin = plugin(clap);
+    let sample_dir = env::var_os(SAMPLE_DIR_VAR);
+    let mut session = Session::new(sample_rate as f32, sample_dir.as_ref().map(Path::new));
+    plugin.loaded.lock().take();
+    session.evaluate(&plugin.pattern.lock());
+    *plugin.session.lock() = Some(session);
+    true
+}
+
+unsafe extern "C" fn plugin_deactivate(clap: *const clap_plugin) {
+    plugin(clap).session.lock().take();
+}
+
+unsafe extern "C" fn plugin_start_processing(_clap: *const clap_plugin) -> bool {
+    true
+}
+
+unsafe extern "C" fn plugin_stop_processing(_clap: *const clap_plugin) {}
+
+unsafe extern "C" fn plugin_reset(clap: *const clap_plugin) {
+    if let Some(session) = plugin(clap).session.lock().as_mut() {
+        session.reset();
+    }
+}
+
+/// the host's transport, in beats and beats per minute
+unsafe fn transport(transport: *const clap_event_transport) -> Transport {
+    let transport = match transport.as_ref() {
+        Some(transport) => transport,
+        None => return Transport::default(),
+    };
+    let has = |flag: u32| transport.flags & flag != 0;
+    Transport {
+        playing: has(CLAP_TRANSPORT_IS_PLAYING),
+        bpm: Some(transport.tempo).filter(|_| has(CLAP_TRANSPORT_HAS_TEMPO)),
+        position: Some((transport.song_pos_beats as f64 / CLAP_BEATTIME_FACTOR, transport.bar_start as f64 / CLAP_BEATTIME_FACTOR))
+            .filter(|_| has(CLAP_TRANSPORT_HAS_BEATS_TIMELINE)),
+        beats_per_bar: Some(transport.tsig_num as u32).filter(|_| has(CLAP_TRANSPORT_HAS_TIME_SIGNATURE)),
+    }
+}
+
+unsafe extern "C" fn plugin_process(clap: *const clap_plugin, process: *const clap_process) -> i32 {
+    let plugin = plugin(clap);
+    let process = &*process;
+    let mut session = plugin.session.lock();
+    let session = match session.as_mut() {
+        Some(session) => session,
+        None => return CLAP_PROCESS_ERROR,
+    };
+    if process.audio_outputs_count < 1 || (*process.audio_outputs).channel_count < 2 {
+        return CLAP_PROCESS_ERROR;
+    }
+
+    // the main thread might be loading another one, it waits for the next block then
+    if let Some(pattern) = plugin.loaded.try_lock().and_then(|mut loaded| loaded.take()) {
+        session.evaluate(&pattern);
+    }
+
+    let output = &*process.audio_outputs;
+    let frames = process.frames_count as usize;
+    let left = std::slice::from_raw_parts_mut(*output.data32, frames);
+    let right = std::slice::from_raw_parts_mut(*output.data32.add(1), frames);
+    session.process(&transport(process.transport), left, right);
+    for channel in 2..output.channel_count as usize {
+        std::slice::from_raw_parts_mut(*output.data32.add(channel), frames).iter_mut().for_each(|sample| *sample = 0.0);
+    }
+    CLAP_PROCESS_CONTINUE
+}
+
+unsafe extern "C" fn plugin_get_extension(_clap: *const clap_plugin, id: *const c_char) -> *const c_void {
+    let id = CStr::from_ptr(id).to_bytes_with_nul();
+    if id == CLAP_EXT_STATE {
+        &STATE as *const clap_plugin_state as *const c_void
+    } else if id == CLAP_EXT_AUDIO_PORTS {
+        &AUDIO_PORTS as *const clap_plugin_audio_ports as *const c_void
+    } else {
+        ptr::null()
+    }
+}
+
+unsafe extern "C" fn plugin_on_main_thread(_clap: *const clap_plugin) {}
+
+// STATE
+// The state is the pattern text, as UTF-8, nothing else.
+
+unsafe extern "C" fn state_save(clap: *const clap_plugin, stream: *const clap_ostream) -> bool {
+    let pattern = plugin(clap).pattern.lock();
+    let mut bytes = pattern.as_bytes();
+    // the host might take it bit by bit
+    while !bytes.is_empty() {
+        let written = ((*stream).write)(stream, bytes.as_ptr() as *const c_void, bytes.len() as u64);
+        if written <= 0 {
+            return false;
+        }
+        bytes = &bytes[written as usize..];
+    }
+    true
+}
+
+unsafe extern "C" fn state_load(clap: *const clap_plugin, stream: *const clap_istream) -> bool {
+    let plugin = plugin(clap);
+    let mut bytes = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        match ((*stream).read)(stream, chunk.as_mut_ptr() as *mut c_void, chunk.len() as u64) {
+            0 => break,
+            read if read < 0 => return false,
+            read => bytes.extend_from_slice(&chunk[..read as usize]),
+        }
+    }
+    let pattern = match String::from_utf8(bytes) {
+        Ok(pattern) => pattern,
+        Err(_) => return false,
+    };
+    *plugin.loaded.lock() = Some(pattern.clone());
+    *plugin.pattern.lock() = pattern;
+    true
+}
+
+static STATE: clap_plugin_state = clap_plugin_state { save: state_save, load: state_load };
+
+// AUDIO PORTS
+// A stereo output, no inputs.
+
+unsafe extern "C" fn audio_ports_count(_clap: *const clap_plugin, is_input: bool) -> u32 {
+    if is_input { 0 } else { 1 }
+}
+
+unsafe extern "C" fn audio_ports_get(_clap: *const clap_plugin, index: u32, is_input: bool, info: *mut clap_audio_port_info) -> bool {
+    if is_input || index != 0 {
+        return false;
+    }
+    let info = &mut *info;
+    info.id = 0;
+    info.name = [0; CLAP_NAME_SIZE];
+    for (to, from) in info.name.iter_mut().zip(b"main".iter()) {
+        *to = *from as c_char;
+    }
+    info.flags = CLAP_AUDIO_PORT_IS_MAIN;
+    info.channel_count = 2;
+    info.port_type = CLAP_PORT_STEREO.as_ptr() as *const c_char;
+    info.in_place_pair = CLAP_INVALID_ID;
+    true
+}
+
+static AUDIO_PORTS: clap_plugin_audio_ports = clap_plugin_audio_ports { count: audio_ports_count, get: audio_ports_get };
+
+// FACTORY
+// There's one plugin, as many instances as the host likes.
+
+unsafe extern "C" fn factory_get_plugin_count(_factory: *const clap_plugin_factory) -> u32 {
+    1
+}
+
+unsafe extern "C" fn factory_get_plugin_descriptor(_factory: *const clap_plugin_factory, index: u32) -> *const clap_plugin_descriptor {
+    if index == 0 { &DESCRIPTOR.0 } else { ptr::null() }
+}
+
+unsafe extern "C" fn factory_create_plugin(_factory: *const clap_plugin_factory, _host: *const clap_host, plugin_id: *const c_char) -> *const clap_plugin {
+    if CStr::from_ptr(plugin_id) != CStr::from_ptr(DESCRIPTOR.0.id) {
+        return ptr::null();
+    }
+    let plugin = Box::into_raw(Box::new(Plugin {
+        clap: clap_plugin {
+            desc: &DESCRIPTOR.0,
+            plugin_data: ptr::null_mut(),
+            init: plugin_init,
+            destroy: plugin_destroy,
+            activate: plugin_activate,
+            deactivate: plugin_deactivate,
+            start_processing: plugin_start_processing,
+            stop_processing: plugin_stop_processing,
+            reset: plugin_reset,
+            process: plugin_process,
+            get_extension: plugin_get_extension,
+            on_main_thread: plugin_on_main_thread,
+        },
+        pattern: Mutex::new(String::new()),
+        loaded: Mutex::new(None),
+        session: Mutex::new(None),
+    }));
+    (*plugin).clap.plugin_data = plugin as *mut c_void;
+    &(*plugin).clap
+}
+
+static FACTORY: clap_plugin_factory = clap_plugin_factory {
+    get_plugin_count: factory_get_plugin_count,
+    get_plugin_descriptor: factory_get_plugin_descriptor,
+    create_plugin: factory_create_plugin,
+};
+
+unsafe extern "C" fn entry_init(_plugin_path: *const c_char) -> bool {
+    true
+}
+
+unsafe extern "C" fn entry_deinit() {}
+
+unsafe extern "C" fn entry_get_factory(factory_id: *const c_char) -> *const c_void {
+    if CStr::from_ptr(factory_id).to_bytes_with_nul() == CLAP_PLUGIN_FACTORY_ID {
+        &FACTORY as *const clap_plugin_factory as *const c_void
+    } else {
+        ptr::null()
+    }
+}
+
+/// What the host looks for in the library.
+#[no_mangle]
+#[allow(non_upper_case_globals)]
+pub static clap_entry: clap_plugin_entry = clap_plugin_entry {
+    clap_version: CLAP_VERSION,
+    init: entry_init,
+    deinit: entry_deinit,
+    get_factory: entry_get_factory,
+};
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    /// a plugin, created the way a host does it
+    unsafe fn create() -> *const clap_plugin {
+        let factory = (clap_entry.get_factory)(CLAP_PLUGIN_FACTORY_ID.as_ptr() as *const c_char) as *const clap_plugin_factory;
+        assert_eq!(((*factory).get_plugin_count)(factory), 1);
+        let descriptor = ((*factory).get_plugin_descriptor)(factory, 0);
+        let clap = ((*factory).create_plugin)(factory, ptr::null(), (*descriptor).id);
+        assert!(((*clap).init)(clap));
+        clap
+    }
+
+    /// streams over a byte vector, reading and writing at most 3 bytes at a time
+    struct Bytes {
+        bytes: Vec<u8>,
+        pos: usize,
+    }
+
+    unsafe extern "C" fn read(stream: *const clap_istream, buffer: *mut c_void, size: u64) -> i64 {
+        let bytes = &mut *((*stream).ctx as *mut Bytes);
+        let read = (size as usize).min(3).min(bytes.bytes.len() - bytes.pos);
+        ptr::copy_nonoverlapping(bytes.bytes[bytes.pos..].as_ptr(), buffer as *mut u8, read);
+        bytes.pos += read;
+        read as i64
+    }
+
+    unsafe extern "C" fn write(stream: *const clap_ostream, buffer: *const c_void, size: u64) -> i64 {
+        let bytes = &mut *((*stream).ctx as *mut Bytes);
+        let written = (size as usize).min(3);
+        bytes.bytes.extend_from_slice(std::slice::from_raw_parts(buffer as *const u8, written));
+        written as i64
+    }
+
+    unsafe fn load(clap: *const clap_plugin, state: &[u8]) -> bool {
+        let mut bytes = Bytes { bytes: state.to_vec(), pos: 0 };
+        let stream = clap_istream { ctx: &mut bytes as *mut Bytes as *mut c_void, read };
+        let state = ((*clap).get_extension)(clap, CLAP_EXT_STATE.as_ptr() as *const c_char) as *const clap_plugin_state;
+        ((*state).load)(clap, &stream)
+    }
+
+    unsafe fn save(clap: *const clap_plugin) -> Vec<u8> {
+        let mut bytes = Bytes { bytes: Vec::new(), pos: 0 };
+        let stream = clap_ostream { ctx: &mut bytes as *mut Bytes as *mut c_void, write };
+        let state = ((*clap).get_extension)(clap, CLAP_EXT_STATE.as_ptr() as *const c_char) as *const clap_plugin_state;
+        assert!(((*state).save)(clap, &stream));
+        bytes.bytes
+    }
+
+    #[test]
+    fn test_state() {
+        unsafe {
+            let clap = create();
+            assert_eq!(save(clap), b"");
+
+            let pattern = "sine ~ ~ ~\nbd ~ sn ~ # kick";
+            assert!(load(clap, pattern.as_bytes()));
+            assert_eq!(save(clap), pattern.as_bytes());
+
+            // not a text, the pattern stays
+            assert!(!load(clap, &[0xff, 0xfe]));
+            assert_eq!(save(clap), pattern.as_bytes());
+
+            ((*clap).destroy)(clap);
+        }
+    }
+
+    #[test]
+    fn test_process() {
+        unsafe {
+            let clap = create();
+            assert!(load(clap, b"sine ~ ~ ~"));
+
+            let mut info: clap_audio_port_info = std::mem::zeroed();
+            let ports = ((*clap).get_extension)(clap, CLAP_EXT_AUDIO_PORTS.as_ptr() as *const c_char) as *const clap_plugin_audio_ports;
+            assert_eq!(((*ports).count)(clap, true), 0);
+            assert_eq!(((*ports).count)(clap, false), 1);
+            assert!(((*ports).get)(clap, 0, false, &mut info));
+            assert_eq!(info.channel_count, 2);
+
+            let mut left = vec![0.0f32; 480];
+            let mut right = vec![0.0f32; 480];
+            let mut channels = [left.as_mut_ptr(), right.as_mut_ptr()];
+            let mut output = clap_audio_buffer {
+                data32: channels.as_mut_ptr(),
+                data64: ptr::null_mut(),
+                channel_count: 2,
+                latency: 0,
+                constant_mask: 0,
+            };
+            // playing at 120 bpm, on the first beat of the bar
+            let mut transport = clap_event_transport {
+                header: std::mem::zeroed(),
+                flags: CLAP_TRANSPORT_IS_PLAYING | CLAP_TRANSPORT_HAS_TEMPO | CLAP_TRANSPORT_HAS_BEATS_TIMELINE | CLAP_TRANSPORT_HAS_TIME_SIGNATURE,
+                song_pos_beats: 0,
+                song_pos_seconds: 0,
+                tempo: 120.0,
+                tempo_inc: 0.0,
+                loop_start_beats: 0,
+                loop_end_beats: 0,
+                loop_start_seconds: 0,
+                loop_end_seconds: 0,
+                bar_start: 0,
+                bar_number: 0,
+                tsig_num: 4,
+                tsig_denom: 4,
+            };
+            let mut process = clap_process {
+                steady_time: 0,
+                frames_count: 480,
+                transport: &transport,
+                audio_inputs: ptr::null(),
+                audio_outputs: &mut output,
+                audio_inputs_count: 0,
+                audio_outputs_count: 1,
+                in_events: ptr::null(),
+                out_events: ptr::null(),
+            };
+
+            // not active yet
+            assert_eq!(((*clap).process)(clap, &process), CLAP_PROCESS_ERROR);
+            assert!(((*clap).activate)(clap, 48000.0, 480, 480));
+
+            // the bar line is too close, the next one is two seconds in
+            let mut heard = false;
+            for block in 0..250 {
+                transport.song_pos_beats = (block as f64 * 0.02 * CLAP_BEATTIME_FACTOR) as i64;
+                transport.bar_start = (block / 200) as i64 * 4 * (1 << 31);
+                process.transport = &transport;
+                process.steady_time += 480;
+                assert_eq!(((*clap).process)(clap, &process), CLAP_PROCESS_CONTINUE);
+                heard |= left.iter().chain(right.iter()).any(|sample| *sample != 0.0);
+            }
+            assert!(heard);
+
+            ((*clap).deactivate)(clap);
+            ((*clap).destroy)(clap);
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use std::path::Path;
+use std::sync::Arc;
+use parking_lot::Mutex;
+
+use ruffbox_core::Clock;
+use ruffbox_core::scheduler::SchedulerCore;
+use ruffbox_native::player::NativePlayer;
+use ruffbox_synth::Engine;
+
+// SESSION
+// The scheduler and the synth as an active plugin has them, driven block by
+// block from the host's process call. The clock is the audio that's been
+// rendered, like natively (see ruffbox-native), only the routine is called on
+// the first block its wakeup falls into, instead of by a sleeping thread.
+//
+// The host's transport has the say: its tempo and time signature are the
+// scheduler's, once it plays, the scheduler starts so that its first step is
+// heard on the host's next bar line, and when it stops, so does the scheduler.
+// If the host jumps (looping, seeking) to somewhere else in the bar than where
+// the scheduler is, the scheduler starts over on the next bar line, jumps to
+// the same place in the bar (i.e. a loop over whole bars) go on seamlessly.
+
+/// in beats, tempo changes within a block move the host a bit off where it's expected
+const JUMP: f64 = 0.05;
+
+/// bars have four beats unless the host says otherwise
+const DEFAULT_BEATS_PER_BAR: u32 = 4;
+
+/// The host's transport, as of the first sample of a block.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Transport {
+    pub playing: bool,
+    pub bpm: Option<f64>,
+    /// the song position and the start of its bar, in beats
+    pub position: Option<(f64, f64)>,
+    pub beats_per_bar: Option<u32>,
+}
+
+/// The time of the audio that's been rendered, in milliseconds.
+struct BlockClock {
+    frames: u64,
+    samplerate: f64,
+    /// until the scheduler's routine is called again, in milliseconds
+    wakeup: f64,
+}
+
+impl Clock for BlockClock {
+    fn now(&self) -> f64 {
+        self.frames as f64 / self.samplerate * 1000.0
+    }
+
+    /// the routine is called on the first block after the delay
+    fn wake_up_in(&mut self, delay: f64) {
+        self.wakeup = delay;
+    }
+}
+
+pub struct Session {
+    scheduler: SchedulerCore<NativePlayer, BlockClock>,
+    engine: Arc<Mutex<Engine>>,
+    /// when the routine is called next, in milliseconds
+    next_wakeup: f64,
+    /// the audio time (in seconds) the scheduler starts at, once the host plays
+    start_at: Option<f64>,
+    /// the host's transport on the last block
+    playing: bool,
+    bpm: Option<f64>,
+    beats_per_bar: u32,
+    /// where in the bar the host should be on the next block, in beats
+    expected: Option<f64>,
+}
+
+impl Session {
+    /// A session at the host's samplerate, with the WAV files of the
+    /// directory (if any) as its samples.
+    pub fn new(samplerate: f32, sample_dir: Option<&Path>) -> Self {
+        let engine = Arc::new(Mutex::new(Engine::new(samplerate, samplerate)));
+        let mut player = NativePlayer::new(engine.clone(), samplerate);
+        let samples = match sample_dir.map(|dir| player.load_samples(dir)) {
+            Some(Ok(samples)) => samples,
+            Some(Err(err)) => {
+                eprintln!("couldn't read the sample directory: {}", err);
+                Vec::new()
+            },
+            None => Vec::new(),
+        };
+
+        let clock = BlockClock { frames: 0, samplerate: samplerate as f64, wakeup: 0.0 };
+        let mut scheduler = SchedulerCore::new(player, clock);
+        for name in samples {
+            scheduler.register_sample(name);
+        }
+
+        Session {
+            scheduler,
+            engine,
+            next_wakeup: 0.0,
+            start_at: None,
+            playing: false,
+            bpm: None,
+            beats_per_bar: DEFAULT_BEATS_PER_BAR,
+            expected: None,
+        }
+    }
+
+    /// Evaluate a pattern text, lines with errors keep playing what they played.
+    pub fn evaluate(&mut self, pattern: &str) {
+        match self.scheduler.evaluate(Some(pattern.to_string())) {
+            Ok(warnings) => warnings.iter().for_each(|warning| eprintln!("{}", warning)),
+            Err(errors) => errors.iter().for_each(|err| eprintln!("{}", err)),
+        }
+    }
+
+    /// Stop, and start over on the next bar line if the host is playing.
+    pub fn reset(&mut self) {
+        self.scheduler.panic();
+        self.start_at = None;
+        self.playing = false;
+        self.expected = None;
+    }
+
+    /// Follow the transport, and render a block (both channels of the same length).
+    pub fn process(&mut self, transport: &Transport, left: &mut [f32], right: &mut [f32]) {
+        let now = self.scheduler.clock.now();
+        let block_start = now / 1000.0;
+        let block_duration = left.len() as f64 / self.scheduler.clock.samplerate;
+        self.follow(transport, block_start, block_duration);
+
+        if let Some(start_at) = self.start_at.filter(|start_at| *start_at < block_start + block_duration) {
+            self.start_at = None;
+            self.scheduler.start(start_at, start_at * 1000.0);
+            self.next_wakeup = start_at * 1000.0 + self.scheduler.clock.wakeup;
+        }
+        if now >= self.next_wakeup {
+            self.scheduler.scheduler_routine(now);
+            self.next_wakeup = now + self.scheduler.clock.wakeup;
+        }
+
+        self.engine.lock().render(block_start, left, right);
+        self.scheduler.clock.frames += left.len() as u64;
+    }
+
+    fn follow(&mut self, transport: &Transport, block_start: f64, block_duration: f64) {
+        if let Some(bpm) = transport.bpm.filter(|bpm| Some(*bpm) != self.bpm) {
+            self.scheduler.set_bpm(bpm);
+            self.bpm = Some(bpm);
+        }
+        if let Some(beats_per_bar) = transport.beats_per_bar.filter(|beats| *beats > 0 && *beats != self.beats_per_bar) {
+            self.scheduler.set_beats_per_bar(beats_per_bar);
+            self.beats_per_bar = beats_per_bar;
+        }
+
+        let bar = self.beats_per_bar as f64;
+        let in_bar = transport.position.map(|(position, bar_start)| (position - bar_start).rem_euclid(bar));
+        let jumped = match (in_bar, self.expected) {
+            (Some(in_bar), Some(expected)) => {
+                let off = (in_bar - expected).rem_euclid(bar);
+                off > JUMP && off < bar - JUMP
+            },
+            _ => false,
+        };
+
+        match (self.playing, transport.playing) {
+            (false, true) => self.start_at = Some(self.next_bar_line(in_bar, block_start)),
+            (true, true) if jumped => {
+                self.scheduler.panic();
+                self.start_at = Some(self.next_bar_line(in_bar, block_start));
+            },
+            // even in lockdown, the host's transport has the last word
+            (true, false) => {
+                self.scheduler.panic();
+                self.start_at = None;
+            },
+            _ => (),
+        }
+        self.playing = transport.playing;
+
+        self.expected = match (in_bar, self.bpm) {
+            (Some(in_bar), Some(bpm)) if transport.playing => Some(in_bar + block_duration * bpm / 60.0),
+            _ => None,
+        };
+    }
+
+    /// The audio time to start at, so the first step is heard on the host's
+    /// next bar line that's at least a lookahead away. Right away, if the host
+    /// has no timeline.
+    fn next_bar_line(&self, in_bar: Option<f64>, block_start: f64) -> f64 {
+        let lookahead = self.scheduler.get_lookahead();
+        match (in_bar, self.bpm) {
+            (Some(in_bar), Some(bpm)) => {
+                let beat = 60.0 / bpm;
+                let bar = self.beats_per_bar as f64;
+                let mut to_bar_line = (bar - in_bar) % bar * beat;
+                while to_bar_line < lookahead {
+                    to_bar_line += bar * beat;
+                }
+                block_start + to_bar_line - lookahead
+            },
+            _ => block_start,
+        }
+    }
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    const SAMPLERATE: f32 = 48000.0;
+    const BLOCK: usize = 480;
+
+    /// Render blocks of 10ms with the host at 120 bpm, playing from the given
+    /// block on, the song position starting at the given beat. Returns the time
+    /// (in seconds) of the first sample that's heard, if any.
+    fn first_heard(session: &mut Session, blocks: usize, play_from: usize, position: f64) -> Option<f64> {
+        let mut left = vec![0.0; BLOCK];
+        let mut right = vec![0.0; BLOCK];
+        let mut heard = None;
+        for block in 0..blocks {
+            let playing = block >= play_from;
+            let beats = position + (block.saturating_sub(play_from) * BLOCK) as f64 / SAMPLERATE as f64 * 2.0;
+            let transport = Transport {
+                playing,
+                bpm: Some(120.0),
+                position: Some((beats, (beats / 4.0).floor() * 4.0)),
+                beats_per_bar: Some(4),
+            };
+            session.process(&transport, &mut left, &mut right);
+            if heard.is_none() {
+                heard = left.iter().position(|sample| *sample != 0.0).map(|idx| (block * BLOCK + idx) as f64 / SAMPLERATE as f64);
+            }
+        }
+        heard
+    }
+
+    #[test]
+    fn test_starts_on_the_host_bar_line() {
+        let mut session = Session::new(SAMPLERATE, None);
+        session.evaluate("sine ~ ~ ~");
+
+        // half a beat into the bar, so three and a half beats (1.75 seconds) to the bar line
+        let heard = first_heard(&mut session, 300, 10, 0.5).expect("nothing heard");
+        assert!((heard - (0.1 + 1.75)).abs() < 0.005, "heard at {}", heard);
+        assert_eq!(session.scheduler.get_bpm(), 120.0);
+    }
+
+    #[test]
+    fn test_stays_silent_while_the_host_stops() {
+        let mut session = Session::new(SAMPLERATE, None);
+        session.evaluate("sine ~ ~ ~");
+        assert_eq!(first_heard(&mut session, 300, 300, 0.0), None);
+    }
+
+    #[test]
+    fn test_host_without_timeline() {
+        let mut session = Session::new(SAMPLERATE, None);
+        session.evaluate("sine ~ ~ ~");
+
+        // starts right away, heard a lookahead later
+        let mut left = vec![0.0; BLOCK];
+        let mut right = vec![0.0; BLOCK];
+        let transport = Transport { playing: true, ..Default::default() };
+        let mut heard = None;
+        for block in 0..30 {
+            session.process(&transport, &mut left, &mut right);
+            if heard.is_none() && left.iter().any(|sample| *sample != 0.0) {
+                heard = Some(block);
+            }
+        }
+        assert_eq!(heard, Some(10));
+    }
+
+    #[test]
+    fn test_jumps() {
+        let mut session = Session::new(SAMPLERATE, None);
+        let bar_line = |in_bar: f64| session.next_bar_line(Some(in_bar), 1.0);
+        // no bpm from the host yet, so right away
+        assert_eq!(bar_line(1.0), 1.0);
+
+        session.follow(&Transport { playing: true, bpm: Some(120.0), position: Some((4.0, 4.0)), beats_per_bar: Some(4) }, 1.0, 0.5);
+        // a bar line closer than the lookahead is too close
+        assert_eq!(session.start_at, Some(1.0 + 2.0 - 0.1));
+        assert_eq!(session.expected, Some(1.0));
+
+        // on a loop back to the start of a bar, the phase is the same
+        session.follow(&Transport { playing: true, bpm: Some(120.0), position: Some((1.0, 0.0)), beats_per_bar: Some(4) }, 1.5, 0.5);
+        assert_eq!(session.start_at, Some(1.0 + 2.0 - 0.1));
+        session.start_at = None;
+
+        // to somewhere else, it's not
+        session.follow(&Transport { playing: true, bpm: Some(120.0), position: Some((3.0, 0.0)), beats_per_bar: Some(4) }, 2.0, 0.5);
+        assert_eq!(session.start_at, Some(2.0 + 0.5 - 0.1));
+
+        session.follow(&Transport { playing: false, bpm: Some(120.0), position: Some((3.0, 0.0)), beats_per_bar: Some(4) }, 2.5, 0.5);
+        assert_eq!(session.start_at, None);
+        assert_eq!(session.expected, None);
+    }
+}
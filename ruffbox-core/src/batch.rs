@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+
+use crate::EventSink;
+
+// EVENT BATCHES
+// All sound events of a tick go out in one message instead of one message
+// each: { type: 'events', strings: [...], data: Float64Array }. Names
+// (source types, sample ids, params) are stored once in the string table,
+// the data refers to them by index. Each event in the data is laid out as
+//
+//   timestamp, source type, sample id, bus, flags, fit key, duration, P, R,
+//   P times (param name, value), R times (param name, ramp target)
+//
+// with the flags 1 (remote), 2 (expendable) and 4 (fit key given), and the
+// duration (in seconds) of the event's own step, or until the next event on
+// it, plus the steps it's tied over ("saw _ _"). Rests don't hold it.
+//
+// Events that share a timestamp go out in a fixed order, not in the order of
+// the lines they come from: first the ones that stop others (voices in a cut
+// group), then automation (gates, stutters, light cues, which go out as
+// messages of their own, before or after the events), then the notes. The
+// synth starts voices of the same timestamp in the order they come in.
+
+pub const FLAG_REMOTE: u32 = 1;
+pub const FLAG_EXPENDABLE: u32 = 2;
+pub const FLAG_FIT_KEY: u32 = 4;
+
+/// What an event does, for the order of events sharing a timestamp.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventClass {
+    /// sound events in a cut group, they stop the other voices of the group
+    Cut,
+    Automation,
+    Note,
+}
+
+impl EventClass {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "cut" => Some(EventClass::Cut),
+            "automation" => Some(EventClass::Automation),
+            "note" | "notes" => Some(EventClass::Note),
+            _ => None,
+        }
+    }
+
+    /// sound events go by whether they're in a cut group
+    pub fn of_sound(params: &HashMap<String, f32>) -> Self {
+        if params.contains_key("cut") { EventClass::Cut } else { EventClass::Note }
+    }
+}
+
+/// The order of the classes of events sharing a timestamp.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EventOrder {
+    classes: Vec<EventClass>,
+}
+
+impl EventOrder {
+    /// cuts, automation, notes
+    pub fn new() -> Self {
+        EventOrder { classes: vec![EventClass::Cut, EventClass::Automation, EventClass::Note] }
+    }
+
+    /// i.e. "automation cut note", classes left out go last, in the default order
+    pub fn from_names(names: &str) -> Option<Self> {
+        let mut classes = Vec::new();
+        for name in names.split(|c: char| c == ',' || c.is_whitespace()).filter(|name| !name.is_empty()) {
+            let class = EventClass::from_name(name)?;
+            if !classes.contains(&class) {
+                classes.push(class);
+            }
+        }
+        for class in EventOrder::new().classes {
+            if !classes.contains(&class) {
+                classes.push(class);
+            }
+        }
+        Some(EventOrder { classes })
+    }
+
+    pub fn rank(&self, class: EventClass) -> usize {
+        self.classes.iter().position(|c| *c == class).unwrap_or(self.classes.len())
+    }
+}
+
+impl Default for EventOrder {
+    fn default() -> Self {
+        EventOrder::new()
+    }
+}
+
+/// A sound event, as it goes into a batch.
+pub struct BatchEvent<'a> {
+    pub timestamp: f64,
+    pub source_type: &'a str,
+    pub sample_id: &'a str,
+    pub bus: u32,
+    pub remote: bool,
+    pub expendable: bool,
+    /// root of the session's key, for samples to be repitched into it
+    pub fit_key: Option<i32>,
+    pub params: &'a HashMap<String, f32>,
+    pub ramps: &'a HashMap<String, f32>,
+    /// in seconds
+    pub duration: f64,
+}
+
+/// The events of a tick, packed.
+pub struct EventBatch {
+    strings: Vec<String>,
+    string_idx: HashMap<String, usize>,
+    data: Vec<f64>,
+    /// timestamp, class and where in the data each event is
+    events: Vec<(f64, EventClass, usize, usize)>,
+}
+
+impl EventBatch {
+    pub fn new() -> Self {
+        EventBatch {
+            strings: Vec::new(),
+            string_idx: HashMap::new(),
+            data: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    /// index of a string in the table, added if it isn't there yet
+    fn intern(&mut self, string: &str) -> f64 {
+        if let Some(idx) = self.string_idx.get(string) {
+            return *idx as f64;
+        }
+        self.strings.push(string.to_string());
+        self.string_idx.insert(string.to_string(), self.strings.len() - 1);
+        (self.strings.len() - 1) as f64
+    }
+
+    /// Put the events in order of their timestamps, events sharing one in the
+    /// given order of their classes, and otherwise as they were pushed. A
+    /// timestamp that's not a number goes last, instead of taking the step down.
+    pub fn sort(&mut self, order: &EventOrder) {
+        self.events.sort_by(|a, b| a.0.total_cmp(&b.0).then(order.rank(a.1).cmp(&order.rank(b.1))));
+
+        let mut data = Vec::with_capacity(self.data.len());
+        for (_, _, start, end) in self.events.iter_mut() {
+            let moved = data.len();
+            data.extend_from_slice(&self.data[*start..*end]);
+            *end = moved + (*end - *start);
+            *start = moved;
+        }
+        self.data = data;
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    pub fn strings(&self) -> &[String] {
+        &self.strings
+    }
+
+    pub fn data(&self) -> &[f64] {
+        &self.data
+    }
+}
+
+/// A batch takes any number of events.
+impl EventSink for EventBatch {
+    fn push(&mut self, event: &BatchEvent) -> bool {
+        let mut flags = 0;
+        if event.remote {
+            flags |= FLAG_REMOTE;
+        }
+        if event.expendable {
+            flags |= FLAG_EXPENDABLE;
+        }
+        if event.fit_key.is_some() {
+            flags |= FLAG_FIT_KEY;
+        }
+
+        let start = self.data.len();
+        let source_type = self.intern(event.source_type);
+        let sample_id = self.intern(event.sample_id);
+        self.data.extend_from_slice(&[
+            event.timestamp,
+            source_type,
+            sample_id,
+            event.bus as f64,
+            flags as f64,
+            event.fit_key.unwrap_or(0) as f64,
+            event.duration,
+            event.params.len() as f64,
+            event.ramps.len() as f64,
+        ]);
+
+        for values in [event.params, event.ramps].iter() {
+            // same order every time, for the same events to pack the same
+            let mut entries: Vec<(&String, &f32)> = values.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            for (name, value) in entries {
+                let name = self.intern(name);
+                self.data.push(name);
+                self.data.push(*value as f64);
+            }
+        }
+
+        self.events.push((event.timestamp, EventClass::of_sound(event.params), start, self.data.len()));
+        true
+    }
+}
+
+impl Default for EventBatch {
+    fn default() -> Self {
+        EventBatch::new()
+    }
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    fn params(entries: &[(&str, f32)]) -> HashMap<String, f32> {
+        entries.iter().map(|(param, value)| (param.to_string(), *value)).collect()
+    }
+
+    #[test]
+    fn test_layout() {
+        let mut batch = EventBatch::new();
+        assert!(batch.is_empty());
+
+        let kick_params = params(&[("rate", 1.0), ("lvl", 0.5)]);
+        batch.push(&BatchEvent {
+            timestamp: 1.25,
+            source_type: "Sampler",
+            sample_id: "bd",
+            bus: 0,
+            remote: false,
+            expendable: true,
+            fit_key: Some(2),
+            params: &kick_params,
+            ramps: &HashMap::new(),
+            duration: 0.25,
+        });
+
+        let sine_params = params(&[("freq", 110.0)]);
+        batch.push(&BatchEvent {
+            timestamp: 1.5,
+            source_type: "SineSynth",
+            sample_id: "sine",
+            bus: 3,
+            remote: true,
+            expendable: false,
+            fit_key: None,
+            params: &sine_params,
+            ramps: &params(&[("freq", 220.0)]),
+            duration: 0.75,
+        });
+
+        assert_eq!(batch.len(), 2);
+        assert_eq!(batch.strings(), &["Sampler", "bd", "lvl", "rate", "SineSynth", "sine", "freq"]);
+        assert_eq!(batch.data(), &[
+            1.25, 0.0, 1.0, 0.0, 6.0, 2.0, 0.25, 2.0, 0.0, 2.0, 0.5, 3.0, 1.0,
+            1.5, 4.0, 5.0, 3.0, 1.0, 0.0, 0.75, 1.0, 1.0, 6.0, 110.0, 6.0, 220.0,
+        ]);
+    }
+
+    #[test]
+    fn test_simultaneous_order() {
+        let mut batch = EventBatch::new();
+        let push = |batch: &mut EventBatch, timestamp: f64, sample_id: &str, params: &HashMap<String, f32>| {
+            batch.push(&BatchEvent {
+                timestamp,
+                source_type: "Sampler",
+                sample_id,
+                bus: 0,
+                remote: false,
+                expendable: false,
+                fit_key: None,
+                params,
+                ramps: &HashMap::new(),
+                duration: 0.25,
+            });
+        };
+        push(&mut batch, 2.0, "sn", &HashMap::new());
+        push(&mut batch, 1.0, "bd", &HashMap::new());
+        push(&mut batch, 1.0, "oh", &params(&[("cut", 1.0)]));
+        push(&mut batch, 1.0, "hh", &HashMap::new());
+
+        // by time, cuts first, then as they came
+        let sample_ids = |batch: &EventBatch| -> Vec<String> {
+            batch.events.iter().map(|(_, _, start, _)| batch.strings()[batch.data()[start + 2] as usize].clone()).collect()
+        };
+        batch.sort(&EventOrder::new());
+        assert_eq!(sample_ids(&batch), vec!["oh", "bd", "hh", "sn"]);
+        assert_eq!(batch.data()[0], 1.0);
+        assert_eq!(batch.data().len(), 4 * 9 + 2);
+
+        batch.sort(&EventOrder::from_names("note").unwrap());
+        assert_eq!(sample_ids(&batch), vec!["bd", "hh", "oh", "sn"]);
+
+        push(&mut batch, f64::NAN, "cp", &HashMap::new());
+        push(&mut batch, 0.5, "hh", &HashMap::new());
+        batch.sort(&EventOrder::new());
+        assert_eq!(sample_ids(&batch), vec!["hh", "oh", "bd", "hh", "sn", "cp"]);
+    }
+
+    #[test]
+    fn test_event_order() {
+        let order = EventOrder::from_names("automation, note").unwrap();
+        assert_eq!(order.rank(EventClass::Automation), 0);
+        assert_eq!(order.rank(EventClass::Cut), 2);
+        assert!(EventOrder::from_names("cut drums").is_none());
+        assert_eq!(EventOrder::from_names("").unwrap(), EventOrder::new());
+    }
+}
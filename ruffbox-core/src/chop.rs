@@ -0,0 +1,116 @@
+use rand::Rng;
+use rand::seq::SliceRandom;
+
+// CHOPS
+// chop('amen', 8, 'shuffled') slices a loaded break into eight pieces and
+// writes a line playing them, i.e.
+// "amen_chop: amen;start=0.375;sus=0.2 ~ amen;start=0;sus=0.2 ~ ...",
+// to be taken apart further from there.
+
+/// A two-step beat, as positions in the break (0..1) per step, on top of a
+/// break with the kick on the one and the snare on the two (like most).
+const TWO_STEP: [Option<f32>; 16] = [
+    Some(0.0), None, Some(0.125), None, Some(0.25), None, Some(0.375), None,
+    None, None, Some(0.0), None, Some(0.75), None, Some(0.875), None,
+];
+
+/// How the slices are arranged.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChopStyle {
+    /// in order, like the original
+    Straight,
+    /// each slice once, in random order
+    Shuffled,
+    /// a bar of two-step garage
+    TwoStep,
+}
+
+impl ChopStyle {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "straight" => Some(ChopStyle::Straight),
+            "shuffled" => Some(ChopStyle::Shuffled),
+            "2-step" | "two-step" => Some(ChopStyle::TwoStep),
+            _ => None,
+        }
+    }
+}
+
+/// The event that plays a slice of a sample.
+fn slice_event(sample: &str, slice: usize, slices: usize, slice_duration: f32) -> String {
+    format!("{};start={};sus={}", sample, (slice as f32 / slices as f32 * 10000.0).round() / 10000.0, (slice_duration * 10000.0).round() / 10000.0)
+}
+
+/// A pattern line playing a sample (with the given duration in seconds) cut into
+/// equal slices, each slice taking as many steps (of the given length in seconds)
+/// as it lasts.
+pub fn chop_line<R: Rng>(sample: &str, slices: usize, style: ChopStyle, duration: f32, step_duration: f32, rng: &mut R) -> String {
+    let slices = slices.max(1);
+    let slice_duration = duration / slices as f32;
+    let steps_per_slice = ((slice_duration / step_duration).round() as usize).max(1);
+
+    let order: Vec<Option<usize>> = match style {
+        ChopStyle::Straight => (0..slices).map(Some).collect(),
+        ChopStyle::Shuffled => {
+            let mut order: Vec<Option<usize>> = (0..slices).map(Some).collect();
+            order.shuffle(rng);
+            order
+        },
+        ChopStyle::TwoStep => TWO_STEP.iter().map(|pos| pos.map(|pos| ((pos * slices as f32) as usize).min(slices - 1))).collect(),
+    };
+
+    let mut events: Vec<String> = Vec::new();
+    for slice in order.iter() {
+        events.push(match slice {
+            Some(slice) => slice_event(sample, *slice, slices, slice_duration),
+            None => "~".to_string(),
+        });
+        // the two-step template is in steps already
+        if style != ChopStyle::TwoStep {
+            events.extend(std::iter::repeat("~".to_string()).take(steps_per_slice - 1));
+        }
+    }
+
+    format!("{}_chop: {}", sample, events.join(" "))
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    #[test]
+    fn test_chop_straight() {
+        let mut rng = StdRng::seed_from_u64(42);
+        // a two second break, eight slices of two steps each
+        let line = chop_line("amen", 8, ChopStyle::Straight, 2.0, 0.125, &mut rng);
+        assert!(line.starts_with("amen_chop: amen;start=0;sus=0.25 ~ amen;start=0.125;sus=0.25 ~"));
+        assert_eq!(line.split(' ').count(), 1 + 16);
+    }
+
+    #[test]
+    fn test_chop_shuffled() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let line = chop_line("amen", 4, ChopStyle::Shuffled, 1.0, 0.25, &mut rng);
+
+        // every slice once
+        let mut starts: Vec<&str> = line.split(' ').skip(1).map(|ev| ev.split(';').nth(1).unwrap()).collect();
+        starts.sort();
+        assert_eq!(starts, vec!["start=0", "start=0.25", "start=0.5", "start=0.75"]);
+    }
+
+    #[test]
+    fn test_chop_two_step() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let line = chop_line("amen", 8, ChopStyle::TwoStep, 2.0, 0.125, &mut rng);
+        let events: Vec<&str> = line.split(' ').skip(1).collect();
+        assert_eq!(events.len(), 16);
+        // the kick comes back late in the bar
+        assert_eq!(events[10], events[0]);
+        assert_eq!(events[8], "~");
+        assert_eq!(ChopStyle::from_name("2-step"), Some(ChopStyle::TwoStep));
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+
+// CONTROLLERS
+// External controllers (i.e. gamepads) send raw values (0..1) under their own
+// ids, like "gamepad0_axis1" or "gamepad0_button3". Mappings connect those ids
+// to things that can be controlled, either through the API or with directive
+// lines like "@map gamepad0_axis1 param:pads:lp-freq 200 4000".
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ControlTarget {
+    Energy,
+    /// in bpm
+    Tempo,
+    Swing,
+    /// mutes the group while the button is held
+    Mute(String),
+    GroupGain(String),
+    GroupParam(String, String),
+    /// master effect parameters, handled by the main thread
+    Master(String),
+}
+
+impl ControlTarget {
+    /// "energy", "tempo", "swing", "mute:drums", "gain:drums",
+    /// "param:drums:lp-freq" or "master:reverb_roomsize"
+    pub fn from_name(name: &str) -> Option<Self> {
+        let parts: Vec<&str> = name.split(':').collect();
+        match parts.as_slice() {
+            ["energy"] => Some(ControlTarget::Energy),
+            ["tempo"] => Some(ControlTarget::Tempo),
+            ["swing"] => Some(ControlTarget::Swing),
+            ["mute", tag] => Some(ControlTarget::Mute(tag.to_string())),
+            ["gain", tag] => Some(ControlTarget::GroupGain(tag.to_string())),
+            ["param", tag, param] => Some(ControlTarget::GroupParam(tag.to_string(), param.to_string())),
+            ["master", param] => Some(ControlTarget::Master(param.to_string())),
+            _ => None,
+        }
+    }
+
+    /// The range a controller covers if none is given.
+    pub fn default_range(&self) -> (f32, f32) {
+        match self {
+            ControlTarget::Tempo => (60.0, 180.0),
+            ControlTarget::Swing => (0.0, 0.5),
+            _ => (0.0, 1.0),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ControllerMapping {
+    pub target: ControlTarget,
+    pub min: f32,
+    pub max: f32,
+}
+
+pub struct ControllerMap {
+    mappings: HashMap<String, Vec<ControllerMapping>>,
+}
+
+impl ControllerMap {
+    pub fn new() -> Self {
+        ControllerMap {
+            mappings: HashMap::new(),
+        }
+    }
+
+    /// Map a controller to a target, a controller can have several targets.
+    pub fn map(&mut self, id: &str, target: ControlTarget, range: Option<(f32, f32)>) {
+        let (min, max) = range.unwrap_or_else(|| target.default_range());
+        let targets = self.mappings.entry(id.to_string()).or_insert_with(Vec::new);
+        
+        targets.retain(|mapping| mapping.target != target);
+        targets.push(ControllerMapping { target, min, max });
+    }
+
+    /// The targets of a controller, with the controller value scaled to their range.
+    pub fn resolve(&self, id: &str, value: f32) -> Vec<(ControlTarget, f32)> {
+        let value = value.max(0.0).min(1.0);
+        
+        match self.mappings.get(id) {
+            Some(targets) => targets.iter()
+                .map(|mapping| (mapping.target.clone(), mapping.min + value * (mapping.max - mapping.min)))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+// TEST TEST TEST 
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_target_names() {
+        assert_eq!(ControlTarget::from_name("energy"), Some(ControlTarget::Energy));
+        assert_eq!(ControlTarget::from_name("mute:drums"), Some(ControlTarget::Mute("drums".to_string())));
+        assert_eq!(ControlTarget::from_name("param:pads:lp-freq"), Some(ControlTarget::GroupParam("pads".to_string(), "lp-freq".to_string())));
+        assert_eq!(ControlTarget::from_name("param:pads"), None);
+        assert_eq!(ControlTarget::from_name("volume"), None);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let mut controllers = ControllerMap::new();
+        controllers.map("gamepad0_axis0", ControlTarget::Tempo, None);
+        controllers.map("gamepad0_axis0", ControlTarget::GroupParam("pads".to_string(), "lp-freq".to_string()), Some((200.0, 4000.0)));
+
+        let resolved = controllers.resolve("gamepad0_axis0", 0.5);
+        assert_eq!(resolved.len(), 2);
+        assert_eq!(resolved[0], (ControlTarget::Tempo, 120.0));
+        assert_eq!(resolved[1].1, 2100.0);
+
+        // out of range values are clamped
+        assert_eq!(controllers.resolve("gamepad0_axis0", 2.0)[0].1, 180.0);
+        assert!(controllers.resolve("gamepad0_axis1", 0.5).is_empty());
+
+        // re-mapping replaces the old range
+        controllers.map("gamepad0_axis0", ControlTarget::Tempo, Some((100.0, 140.0)));
+        assert_eq!(controllers.resolve("gamepad0_axis0", 0.5).len(), 2);
+    }
+}
@@ -0,0 +1,243 @@
+use std::hash::{Hash, Hasher};
+
+use nom::{
+    branch::alt,
+    number::complete::float,
+    bytes::complete::tag,
+    character::complete::{char, one_of, space0},
+    combinator::{map, opt},
+    multi::many0,
+    sequence::{delimited, pair, preceded},
+    IResult,
+};
+
+// EXPRESSIONS
+// Params can be computed from counters when the event is played, i.e.
+// "saw;lp-freq=200+($bar%8)*250" opens the filter a bit more every bar,
+// over eight bars. The counters are $step, $beat and $bar (since the
+// scheduler was started) and $cycle (how often the line went around).
+
+/// Where the music is, as the counters an expression can refer to.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Counters {
+    pub step: usize,
+    pub beat: usize,
+    pub bar: usize,
+    pub cycle: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Hash)]
+pub enum Counter {
+    Step,
+    Beat,
+    Bar,
+    Cycle,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Num(f32),
+    Var(Counter),
+    /// operator (one of + - * / %) and operands
+    Op(char, Box<Expr>, Box<Expr>),
+}
+
+impl Hash for Expr {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Expr::Num(num) => num.to_bits().hash(state),
+            Expr::Var(counter) => counter.hash(state),
+            Expr::Op(op, left, right) => {
+                op.hash(state);
+                left.hash(state);
+                right.hash(state);
+            },
+        }
+    }
+}
+
+impl Expr {
+    pub fn eval(&self, counters: &Counters) -> f32 {
+        match self {
+            Expr::Num(num) => *num,
+            Expr::Var(Counter::Step) => counters.step as f32,
+            Expr::Var(Counter::Beat) => counters.beat as f32,
+            Expr::Var(Counter::Bar) => counters.bar as f32,
+            Expr::Var(Counter::Cycle) => counters.cycle as f32,
+            Expr::Op(op, left, right) => {
+                let (left, right) = (left.eval(counters), right.eval(counters));
+                match op {
+                    '+' => left + right,
+                    '-' => left - right,
+                    '*' => left * right,
+                    // nothing to divide by is nothing
+                    '/' if right != 0.0 => left / right,
+                    '%' if right != 0.0 => left.rem_euclid(right),
+                    _ => 0.0,
+                }
+            },
+        }
+    }
+
+    /// whether there's more to it than a number
+    pub fn is_const(&self) -> bool {
+        matches!(self, Expr::Num(_))
+    }
+}
+
+fn counter_name(input: &str) -> IResult<&str, Counter> {
+    alt((map(tag("step"), |_| Counter::Step),
+         map(tag("beat"), |_| Counter::Beat),
+         map(tag("bar"), |_| Counter::Bar),
+         map(tag("cycle"), |_| Counter::Cycle)))(input)
+}
+
+/// in params counters need the '$', in conditions (bare) they don't
+fn counter(input: &str, bare: bool) -> IResult<&str, Counter> {
+    if bare {
+        preceded(opt(char('$')), counter_name)(input)
+    } else {
+        preceded(char('$'), counter_name)(input)
+    }
+}
+
+fn factor(input: &str, bare: bool) -> IResult<&str, Expr> {
+    alt((map(|i| counter(i, bare), Expr::Var),
+         map(float, Expr::Num),
+         delimited(pair(char('('), space0), |i| sum(i, bare), pair(space0, char(')')))))(input)
+}
+
+/// fold operands and operators of the same precedence from the left
+fn fold(first: Expr, rest: Vec<(char, Expr)>) -> Expr {
+    rest.into_iter().fold(first, |left, (op, right)| Expr::Op(op, Box::new(left), Box::new(right)))
+}
+
+fn term(input: &str, bare: bool) -> IResult<&str, Expr> {
+    let (rest, first) = factor(input, bare)?;
+    let (rest, ops) = many0(pair(delimited(space0, one_of("*/%"), space0), |i| factor(i, bare)))(rest)?;
+    Ok((rest, fold(first, ops)))
+}
+
+fn sum(input: &str, bare: bool) -> IResult<&str, Expr> {
+    let (rest, first) = term(input, bare)?;
+    let (rest, ops) = many0(pair(delimited(space0, one_of("+-"), space0), |i| term(i, bare)))(rest)?;
+    Ok((rest, fold(first, ops)))
+}
+
+// 200+($bar%8)*250
+pub fn expr(input: &str) -> IResult<&str, Expr> {
+    sum(input, false)
+}
+
+// CONDITIONS
+// Lines can play something else depending on where the music is, i.e.
+// "if cycle%8<4: bd ~ sn ~ else: bd bd sn [sn sn]" plays the first half
+// for four cycles, then the second for four. Counters don't need the '$' here.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Condition {
+    pub comparison: Comparison,
+    pub left: Expr,
+    pub right: Expr,
+}
+
+impl Condition {
+    pub fn holds(&self, counters: &Counters) -> bool {
+        let (left, right) = (self.left.eval(counters), self.right.eval(counters));
+        match self.comparison {
+            Comparison::Less => left < right,
+            Comparison::LessEqual => left <= right,
+            Comparison::Greater => left > right,
+            Comparison::GreaterEqual => left >= right,
+            Comparison::Equal => left == right,
+            Comparison::NotEqual => left != right,
+        }
+    }
+}
+
+fn comparison(input: &str) -> IResult<&str, Comparison> {
+    // the longer ones first
+    alt((map(tag("<="), |_| Comparison::LessEqual),
+         map(tag(">="), |_| Comparison::GreaterEqual),
+         map(tag("=="), |_| Comparison::Equal),
+         map(tag("!="), |_| Comparison::NotEqual),
+         map(char('<'), |_| Comparison::Less),
+         map(char('>'), |_| Comparison::Greater)))(input)
+}
+
+// cycle%8<4
+pub fn condition(input: &str) -> IResult<&str, Condition> {
+    let (rest, left) = sum(input, true)?;
+    let (rest, comparison) = delimited(space0, comparison, space0)(rest)?;
+    let (rest, right) = sum(rest, true)?;
+    Ok((rest, Condition { comparison, left, right }))
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_precedence() {
+        let (rest, filter) = expr("200+($bar%8)*250").unwrap();
+        assert_eq!(rest, "");
+
+        let counters = Counters { bar: 11, ..Counters::default() };
+        assert_eq!(filter.eval(&counters), 200.0 + 3.0 * 250.0);
+
+        let (_, level) = expr("1 - $cycle / 4").unwrap();
+        assert_eq!(level.eval(&Counters { cycle: 2, ..Counters::default() }), 0.5);
+    }
+
+    #[test]
+    fn test_counters() {
+        let (_, beat) = expr("$beat%4").unwrap();
+        let (_, step) = expr("$step*2").unwrap();
+        let counters = Counters { step: 21, beat: 5, bar: 1, cycle: 0 };
+        assert_eq!(beat.eval(&counters), 1.0);
+        assert_eq!(step.eval(&counters), 42.0);
+
+        // nothing to divide by
+        let (_, div) = expr("10/($bar-1)").unwrap();
+        assert_eq!(div.eval(&counters), 0.0);
+    }
+
+    #[test]
+    fn test_not_an_expression() {
+        // a chord after a param isn't part of it
+        let (rest, num) = expr("0.5+hh").unwrap();
+        assert_eq!(rest, "+hh");
+        assert!(num.is_const());
+        assert!(expr("$tempo").is_err());
+        assert!(expr("bar").is_err());
+    }
+
+    #[test]
+    fn test_conditions() {
+        let (rest, first_half) = condition("cycle%8<4").unwrap();
+        assert_eq!(rest, "");
+        assert!(first_half.holds(&Counters { cycle: 3, ..Counters::default() }));
+        assert!(!first_half.holds(&Counters { cycle: 4, ..Counters::default() }));
+        assert!(first_half.holds(&Counters { cycle: 8, ..Counters::default() }));
+
+        let (_, odd) = condition("$bar % 2 != 0").unwrap();
+        assert!(odd.holds(&Counters { bar: 1, ..Counters::default() }));
+        assert!(!odd.holds(&Counters { bar: 2, ..Counters::default() }));
+
+        let (_, late) = condition("step >= 16").unwrap();
+        assert_eq!(late.comparison, Comparison::GreaterEqual);
+        assert!(condition("cycle%8").is_err());
+    }
+}
@@ -0,0 +1,69 @@
+// FILLS
+// "@fill roll" has the lines tagged #fill play a one-bar fill right before
+// a queued pattern switch, "@fill rise" bends them up instead, "@fill off"
+// lets them play as written.
+
+/// What the lines tagged for fills play in the bar before a switch.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FillStyle {
+    /// the line's first sound on every step, louder towards the end,
+    /// doubled up in the last beat
+    Roll,
+    /// the line as written, pitched up to an octave by the end of the bar
+    Rise,
+}
+
+impl FillStyle {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "roll" => Some(FillStyle::Roll),
+            "rise" => Some(FillStyle::Rise),
+            _ => None,
+        }
+    }
+}
+
+/// The hits of a roll on a step, as offset (in steps) and level (relative to
+/// the line's level). Progress is how far into the fill bar the step is (0..1).
+pub fn roll_hits(progress: f32) -> Vec<(f64, f32)> {
+    let level = 0.4 + 0.6 * progress;
+    if progress >= 0.75 {
+        vec![(0.0, level), (0.5, level)]
+    } else {
+        vec![(0.0, level)]
+    }
+}
+
+/// Pitch factor of a rise, at the given progress (0..1).
+pub fn rise_factor(progress: f32) -> f32 {
+    2.0_f32.powf(progress)
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_fill_style_names() {
+        assert_eq!(FillStyle::from_name("roll"), Some(FillStyle::Roll));
+        assert_eq!(FillStyle::from_name("rise"), Some(FillStyle::Rise));
+        assert_eq!(FillStyle::from_name("off"), None);
+    }
+
+    #[test]
+    fn test_roll_builds_up() {
+        assert_eq!(roll_hits(0.0), vec![(0.0, 0.4)]);
+        // sixteenths first, thirty-seconds in the last beat
+        assert_eq!(roll_hits(0.5).len(), 1);
+        assert_eq!(roll_hits(0.75).len(), 2);
+        assert!(roll_hits(0.9375)[0].1 > roll_hits(0.5)[0].1);
+    }
+
+    #[test]
+    fn test_rise() {
+        assert_eq!(rise_factor(0.0), 1.0);
+        assert!((rise_factor(1.0) - 2.0).abs() < 0.0001);
+    }
+}
@@ -0,0 +1,111 @@
+use std::collections::{HashMap, HashSet};
+
+// FLOOD GUARD
+// Identical events at the same time only make a louder, comb-filtered hit,
+// so they're played once. Beyond that, a sample only gets so many voices
+// starting at the same time, so runaway patterns can't flood the sampler.
+// Events stacked on one line (chords) count as one voice, they're meant.
+
+/// voices of the same sample starting at the same time, at most
+pub const MAX_VOICES_PER_TIMESTAMP: usize = 4;
+
+/// timestamps closer than a microsecond are the same
+fn time_key(timestamp: f64) -> i64 {
+    (timestamp * 1_000_000.0).round() as i64
+}
+
+/// Keeps track of the events of a step.
+pub struct FloodGuard {
+    /// timestamp, sample, bus and params of the events played
+    played: HashSet<(i64, String, u32, Vec<(String, u32)>)>,
+    /// the lines (by id) with a voice, per timestamp and sample
+    voices: HashMap<(i64, String), HashSet<u32>>,
+    dropped: usize,
+}
+
+impl FloodGuard {
+    pub fn new() -> Self {
+        FloodGuard {
+            played: HashSet::new(),
+            voices: HashMap::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Check whether an event of a line (by id) is to be played, false for duplicates
+    /// and for too many voices of a sample at once.
+    pub fn admit(&mut self, timestamp: f64, sample: &str, bus: u32, line: u32, params: &HashMap<String, f32>) -> bool {
+        let mut param_bits: Vec<(String, u32)> = params.iter().map(|(param, value)| (param.to_string(), value.to_bits())).collect();
+        param_bits.sort();
+
+        let time = time_key(timestamp);
+        let lines = self.voices.entry((time, sample.to_string())).or_default();
+        let excess = !lines.contains(&line) && lines.len() >= MAX_VOICES_PER_TIMESTAMP;
+        if excess || !self.played.insert((time, sample.to_string(), bus, param_bits)) {
+            self.dropped += 1;
+            return false;
+        }
+
+        lines.insert(line);
+        true
+    }
+
+    /// how many events were kept from playing
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+}
+
+impl Default for FloodGuard {
+    fn default() -> Self {
+        FloodGuard::new()
+    }
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    fn params(entries: &[(&str, f32)]) -> HashMap<String, f32> {
+        entries.iter().map(|(param, value)| (param.to_string(), *value)).collect()
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let mut guard = FloodGuard::new();
+        assert!(guard.admit(1.0, "bd", 0, 0, &params(&[("rate", 1.0)])));
+        assert!(!guard.admit(1.0, "bd", 0, 0, &params(&[("rate", 1.0)])));
+        // from another line, too
+        assert!(!guard.admit(1.0, "bd", 0, 1, &params(&[("rate", 1.0)])));
+
+        // different params, bus or time is a different event
+        assert!(guard.admit(1.0, "bd", 0, 0, &params(&[("rate", 2.0)])));
+        assert!(guard.admit(1.0, "bd", 1, 0, &params(&[("rate", 1.0)])));
+        assert!(guard.admit(1.125, "bd", 0, 0, &params(&[("rate", 1.0)])));
+        assert!(guard.admit(1.0, "sn", 0, 0, &params(&[("rate", 1.0)])));
+        assert_eq!(guard.dropped(), 2);
+    }
+
+    #[test]
+    fn test_voice_cap() {
+        let mut guard = FloodGuard::new();
+        let admitted = (0..10).filter(|idx| guard.admit(1.0, "hh", 0, *idx, &params(&[("lvl", *idx as f32 * 0.1)]))).count();
+        assert_eq!(admitted, MAX_VOICES_PER_TIMESTAMP);
+        assert_eq!(guard.dropped(), 10 - MAX_VOICES_PER_TIMESTAMP);
+    }
+
+    #[test]
+    fn test_chords_are_one_voice() {
+        let mut guard = FloodGuard::new();
+        // a six note chord on one line
+        let admitted = (0..6).filter(|idx| guard.admit(1.0, "piano", 0, 0, &params(&[("freq", 220.0 + *idx as f32 * 50.0)]))).count();
+        assert_eq!(admitted, 6);
+
+        // other lines still only get so many voices
+        let admitted = (1..10).filter(|line| guard.admit(1.0, "piano", 0, *line, &params(&[("freq", 110.0 + *line as f32)]))).count();
+        assert_eq!(admitted, MAX_VOICES_PER_TIMESTAMP - 1);
+        assert!(guard.admit(1.0, "piano", 0, 0, &params(&[("freq", 880.0)])));
+    }
+}
@@ -0,0 +1,406 @@
+use std::collections::HashMap;
+
+// GROOVE
+// The scheduler wakes up at a perfectly regular interval, one step at a time,
+// no matter what. Swing (and everything else that makes a line feel late or
+// early) only shifts the timestamps of the events it sends out, so the
+// groove can change all it wants without the tempo wavering.
+// Logical time is kept as a count of ticks (see meter.rs) and only turned into
+// audio or browser time when asked for, from the ticks since the last tempo
+// change. Nothing is summed up step by step, so there's no drift, even after
+// days of running, and positions can be compared exactly.
+// The scheduler doesn't wake up for every step, but at a regular interval,
+// and schedules all steps that are due before the next wakeup, so a fast
+// tempo doesn't need a fast timer.
+// The audio clock is the one that counts in the end, and it doesn't run in
+// step with the browser clock: it drifts a little, and stalls when the
+// AudioContext is suspended. When it's reported, the audio time of the grid
+// is rebased on it, in small steps for drift, at once for a jump.
+
+/// differences between the audio clock and the grid up to this (in seconds) are drift ...
+pub const MAX_DRIFT: f64 = 0.02;
+/// ... which is corrected by this share on each report, to smooth out the jitter
+pub const DRIFT_CORRECTION: f64 = 0.1;
+
+/// Where the scheduler is on the straight grid, in ticks.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LogicalClock {
+    /// ticks since the start
+    tick: u64,
+    /// tick and times of the last tempo change
+    origin_tick: u64,
+    /// in seconds (AudioContext.currentTime)
+    origin_audio_time: f64,
+    /// in milliseconds (performance.now())
+    origin_browser_time: f64,
+    /// in milliseconds
+    tick_duration: f64,
+}
+
+impl LogicalClock {
+    pub fn new(audio_time: f64, browser_time: f64) -> Self {
+        LogicalClock {
+            tick: 0,
+            origin_tick: 0,
+            origin_audio_time: audio_time,
+            origin_browser_time: browser_time,
+            tick_duration: 0.0,
+        }
+    }
+
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    /// Audio time (in seconds) of the given tick, at the current tempo.
+    pub fn audio_time_at(&self, tick: u64) -> f64 {
+        self.origin_audio_time + (tick as i64 - self.origin_tick as i64) as f64 * self.tick_duration / 1000.0
+    }
+
+    /// Audio time (in seconds) of the current tick.
+    pub fn audio_time(&self) -> f64 {
+        self.audio_time_at(self.tick)
+    }
+
+    /// Browser time (in milliseconds) of the current tick.
+    pub fn browser_time(&self) -> f64 {
+        self.origin_browser_time + (self.tick - self.origin_tick) as f64 * self.tick_duration
+    }
+
+    /// The (fractional) tick the grid was at, at the given browser time (in milliseconds),
+    /// at most the current one.
+    pub fn tick_at(&self, browser_time: f64) -> f64 {
+        if self.tick_duration <= 0.0 {
+            return self.tick as f64;
+        }
+        (self.origin_tick as f64 + (browser_time - self.origin_browser_time) / self.tick_duration).clamp(0.0, self.tick as f64)
+    }
+
+    /// Move on by a step of the given duration (in milliseconds) and number of ticks.
+    pub fn advance(&mut self, step_duration: f64, ticks_per_step: u32) {
+        let tick_duration = step_duration / ticks_per_step as f64;
+        if tick_duration != self.tick_duration {
+            self.origin_audio_time = self.audio_time();
+            self.origin_browser_time = self.browser_time();
+            self.origin_tick = self.tick;
+            self.tick_duration = tick_duration;
+        }
+
+        self.tick += ticks_per_step as u64;
+    }
+
+    /// Rebase the audio time on the audio clock, which read the given audio
+    /// time (in seconds) at the given browser time (in milliseconds). Returns
+    /// the difference (in seconds) between the grid and the audio clock.
+    pub fn sync(&mut self, audio_time: f64, browser_time: f64) -> f64 {
+        // where the audio clock is at the current tick, if it keeps running
+        let measured = audio_time + (self.browser_time() - browser_time) / 1000.0;
+        let drift = measured - self.audio_time();
+        self.origin_audio_time += if drift.abs() > MAX_DRIFT { drift } else { drift * DRIFT_CORRECTION };
+        drift
+    }
+
+    /// Whether the current step is to be scheduled on a wakeup that lasts
+    /// until the given browser time (in milliseconds).
+    pub fn is_due(&self, horizon: f64) -> bool {
+        self.browser_time() <= horizon
+    }
+
+    /// How long (in milliseconds) to wait for the next wakeup, once everything
+    /// up to the horizon is scheduled. That's the interval, or longer if the next
+    /// step is further out, so slow tempi don't wake up for nothing. Wakeups are
+    /// most likely late, but never early, and nothing is late as long as that's
+    /// less than the lookahead.
+    pub fn next_wakeup(&self, horizon: f64, interval: f64) -> f64 {
+        (self.browser_time() - horizon).max(interval)
+    }
+
+    /// Go on after a pause with the current tick at the given audio (in seconds)
+    /// and browser time (in milliseconds), the tick and tempo are where they were.
+    /// Everything in between, drift included, is left behind.
+    pub fn resume(&mut self, audio_time: f64, browser_time: f64) {
+        self.origin_tick = self.tick;
+        self.origin_audio_time = audio_time;
+        self.origin_browser_time = browser_time;
+    }
+}
+
+/// How late the events of a step are played (in seconds), with every other
+/// step delayed by a fraction of the step duration (in milliseconds).
+pub fn swing_offset(step: usize, swing: f64, step_duration: f64) -> f64 {
+    if step % 2 == 1 {
+        swing * step_duration / 1000.0
+    } else {
+        0.0
+    }
+}
+
+/// The swing of all lines (a fraction of a step, up to half of it),
+/// and that of the lines that swing on their own, by label.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Swing {
+    global: f64,
+    lines: HashMap<String, f64>,
+}
+
+impl Swing {
+    pub fn new() -> Self {
+        Swing::default()
+    }
+
+    pub fn set(&mut self, amount: f64) {
+        self.global = amount.max(0.0).min(0.5);
+    }
+
+    /// A negative amount has the line follow the global swing again.
+    pub fn set_line(&mut self, label: &str, amount: f64) {
+        if amount < 0.0 {
+            self.lines.remove(label);
+        } else {
+            self.lines.insert(label.to_string(), amount.min(0.5));
+        }
+    }
+
+    pub fn global(&self) -> f64 {
+        self.global
+    }
+
+    /// the swing of a line, by label, if it has one
+    pub fn of_line(&self, label: Option<&String>) -> f64 {
+        label.and_then(|label| self.lines.get(label)).cloned().unwrap_or(self.global)
+    }
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    /// Run the clock step by step. Returns the clock times and the event times per step.
+    fn run(steps: usize, swing: f64, step_duration: f64) -> (Vec<f64>, Vec<f64>) {
+        let mut clock = LogicalClock::new(2.0, 1000.0);
+        let mut ticks = Vec::new();
+        let mut events = Vec::new();
+        for step in 0..steps {
+            events.push(clock.audio_time() + swing_offset(step, swing, step_duration));
+            ticks.push(clock.audio_time());
+            clock.advance(step_duration, 24);
+        }
+        (ticks, events)
+    }
+
+    /// Wake up like the scheduler does, with wakeups arriving late by a varying
+    /// amount (up to 16 ms), for the given time. Returns the number of wakeups and,
+    /// for each step, when it was scheduled and when it's due.
+    fn wake_up(step_duration: f64, interval: f64, duration: f64) -> (usize, Vec<(f64, f64)>) {
+        let mut clock = LogicalClock::new(0.0, 0.0);
+        let mut now = 0.0;
+        let mut wakeups = 0;
+        let mut steps = Vec::new();
+        while now < duration {
+            wakeups += 1;
+            let horizon = now + interval;
+            while clock.is_due(horizon) {
+                steps.push((now, clock.browser_time()));
+                clock.advance(step_duration, 24);
+            }
+            now += clock.next_wakeup(horizon, interval) + (wakeups % 5) as f64 * 4.0;
+        }
+        (wakeups, steps)
+    }
+
+    #[test]
+    fn test_regular_interval() {
+        let mut clock = LogicalClock::new(0.0, 500.0);
+        clock.advance(125.0, 24);
+        clock.advance(125.0, 24);
+        assert_eq!(clock.browser_time(), 750.0);
+        assert_eq!(clock.audio_time(), 0.25);
+        assert_eq!(clock.tick(), 48);
+
+        // up to the horizon, and the next wakeup an interval away ...
+        assert!(clock.is_due(750.0));
+        assert!(!clock.is_due(749.0));
+        assert_eq!(clock.next_wakeup(700.0, 100.0), 100.0);
+        // ... or when the next step is due
+        assert_eq!(clock.next_wakeup(500.0, 100.0), 250.0);
+    }
+
+    #[test]
+    fn test_audio_clock_sync() {
+        let mut clock = LogicalClock::new(1.0, 0.0);
+        for _ in 0..8 {
+            clock.advance(125.0, 24);
+        }
+        assert_eq!(clock.audio_time(), 2.0);
+
+        // in step, nothing changes
+        assert_eq!(clock.sync(1.75, 750.0), 0.0);
+        assert_eq!(clock.audio_time(), 2.0);
+
+        // the audio clock running a little slow is made up for bit by bit ...
+        let mut drift = clock.sync(1.99, 1000.0);
+        assert!((drift + 0.01).abs() < 1e-9);
+        for _ in 0..100 {
+            drift = clock.sync(1.99, 1000.0);
+        }
+        assert!(drift.abs() < 1e-6);
+        assert!((clock.audio_time() - 1.99).abs() < 1e-6);
+
+        // ... a stall at once, the next steps are played where the audio clock is
+        let drift = clock.sync(1.99 - 0.5, 1000.0);
+        assert!((drift + 0.5).abs() < 1e-6);
+        assert!((clock.audio_time() - 1.49).abs() < 1e-6);
+        clock.advance(125.0, 24);
+        assert!((clock.audio_time() - 1.615).abs() < 1e-6);
+
+        // the browser time isn't touched
+        assert_eq!(clock.browser_time(), 1125.0);
+    }
+
+    #[test]
+    fn test_lookahead_window() {
+        // 32nd notes at 200 bpm, several steps per wakeup
+        let (wakeups, steps) = wake_up(37.5, 100.0, 10000.0);
+        assert!(wakeups <= 100);
+        assert!(steps.len() >= (10000.0 / 37.5) as usize);
+
+        // at 30 bpm, a wakeup per step
+        let (slow_wakeups, slow_steps) = wake_up(500.0, 100.0, 10000.0);
+        assert!(slow_wakeups <= 21);
+        assert!(slow_steps.len() >= 20);
+
+        // each step is scheduled once, at most an interval ahead, and
+        // no later than the wakeup was late
+        for (scheduled, due) in steps.iter().chain(slow_steps.iter()) {
+            assert!(*scheduled >= due - 100.0);
+            assert!(*scheduled <= due + 16.0);
+        }
+        for window in steps.windows(2).chain(slow_steps.windows(2)) {
+            assert!(window[1].1 > window[0].1);
+        }
+    }
+
+    #[test]
+    fn test_tick_positions() {
+        let mut clock = LogicalClock::new(1.0, 0.0);
+        for _ in 0..4 {
+            clock.advance(125.0, 24);
+        }
+        let bar = clock.tick();
+        assert_eq!(bar, 96);
+
+        // a finer grid, and a different tempo, don't move what's been played
+        for _ in 0..2 {
+            clock.advance(100.0, 120);
+        }
+        assert_eq!(clock.tick(), 336);
+        assert_eq!(clock.audio_time_at(bar), 1.5);
+        assert_eq!(clock.audio_time(), 1.7);
+    }
+
+    #[test]
+    fn test_resume() {
+        let mut clock = LogicalClock::new(1.0, 0.0);
+        for _ in 0..6 {
+            clock.advance(125.0, 24);
+        }
+        clock.sync(1.74, 750.0);
+
+        // paused for a while, on from the same tick at the same tempo
+        clock.resume(30.0, 29000.0);
+        assert_eq!(clock.tick(), 144);
+        assert_eq!(clock.audio_time(), 30.0);
+        assert_eq!(clock.browser_time(), 29000.0);
+        clock.advance(125.0, 24);
+        assert_eq!(clock.audio_time(), 30.125);
+        assert_eq!(clock.browser_time(), 29125.0);
+        assert!(clock.is_due(29125.0));
+    }
+
+    #[test]
+    fn test_tick_at() {
+        let mut clock = LogicalClock::new(0.0, 1000.0);
+        assert_eq!(clock.tick_at(1500.0), 0.0);
+        for _ in 0..4 {
+            clock.advance(125.0, 24);
+        }
+
+        // halfway through the second step, never before the start or past the grid
+        assert_eq!(clock.tick_at(1187.5), 36.0);
+        assert_eq!(clock.tick_at(900.0), 0.0);
+        assert_eq!(clock.tick_at(2000.0), 96.0);
+    }
+
+    #[test]
+    fn test_line_swing() {
+        let mut swing = Swing::new();
+        swing.set(0.7);
+        assert_eq!(swing.global(), 0.5);
+        swing.set(0.2);
+
+        // lines follow the global swing, unless they have their own
+        let drums = "drums".to_string();
+        let bass = "bass".to_string();
+        swing.set_line("drums", 0.33);
+        assert_eq!(swing.of_line(Some(&drums)), 0.33);
+        assert_eq!(swing.of_line(Some(&bass)), 0.2);
+        assert_eq!(swing.of_line(None), 0.2);
+
+        // straight is a swing of its own
+        swing.set_line("bass", 0.0);
+        swing.set(0.4);
+        assert_eq!(swing.of_line(Some(&bass)), 0.0);
+        assert_eq!(swing.of_line(Some(&drums)), 0.33);
+
+        // a negative amount goes back to the global swing
+        swing.set_line("drums", -1.0);
+        assert_eq!(swing.of_line(Some(&drums)), 0.4);
+        swing.set_line("drums", 0.9);
+        assert_eq!(swing.of_line(Some(&drums)), 0.5);
+    }
+
+    #[test]
+    fn test_swing_keeps_tempo() {
+        let (straight_ticks, straight_events) = run(64, 0.0, 125.0);
+        for swing in [0.1, 0.33, 0.5].iter() {
+            let (ticks, events) = run(64, *swing, 125.0);
+
+            // the clock doesn't know about swing
+            assert_eq!(ticks, straight_ticks);
+
+            for (step, (event, straight)) in events.iter().zip(straight_events.iter()).enumerate() {
+                if step % 2 == 0 {
+                    // on the beat, events stay on the grid ...
+                    assert_eq!(event, straight);
+                } else {
+                    // ... offbeats are late by the same amount, it doesn't add up
+                    assert!((event - straight - swing * 0.125).abs() < 1e-9);
+                }
+            }
+        }
+
+        // 64 steps of 125 ms later
+        assert!((straight_ticks[63] + 0.125 - 10.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_soak() {
+        // a week of 16th notes at 130 bpm (1500/13 ms a step), with a tempo
+        // change to 140 bpm (750/7 ms) after a day
+        let day: u64 = 748_800;
+        let week = 7 * day;
+        let mut clock = LogicalClock::new(0.5, 0.0);
+        for step in 0..week {
+            let step_duration = if step < day { 1500.0 / 13.0 } else { 750.0 / 7.0 };
+            clock.advance(step_duration, 24);
+        }
+
+        // exactly where it should be, computed in one go
+        let expected = 0.5 + (day * 1500) as f64 / 13.0 / 1000.0 + ((week - day) * 750) as f64 / 7.0 / 1000.0;
+        assert!((clock.audio_time() - expected).abs() < 1e-9);
+        assert!((clock.browser_time() - (expected - 0.5) * 1000.0).abs() < 1e-6);
+        assert_eq!(clock.tick(), week * 24);
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::{HashMap, HashSet};
+
+// GROUPS
+// Lines can be tagged, i.e. "cyc >> bd ~ sn ~ #drums", to operate on all
+// lines sharing a tag at once, either through the API or with command lines:
+// "@mute #drums", "@unmute #drums", "@gain #drums 0.5", "@set #drums lp-freq 800"
+// Gains and overrides can be saved as named snapshots and morphed to step by step.
+
+#[derive(Debug, PartialEq)]
+pub enum GroupCommand<'a> {
+    Mute(&'a str),
+    Unmute(&'a str),
+    Gain(&'a str, f32),
+    Set(&'a str, &'a str, f32),
+}
+
+/// The level a source plays at if no level is given.
+pub fn default_level(source_type: &str) -> f32 {
+    match source_type {
+        "SineSynth" | "CustomVoice" => 0.3,
+        _ => 1.0,
+    }
+}
+
+/// Gains and parameter overrides of all groups at some point.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MixerSnapshot {
+    gains: HashMap<String, f32>,
+    params: HashMap<String, HashMap<String, f32>>,
+}
+
+impl MixerSnapshot {
+    /// Interpolate towards another snapshot (progress 0..1). Gains that are
+    /// only set on one side count as 1.0 on the other, parameters that are only
+    /// set in the target apply right away, the others until the target is reached.
+    pub fn interpolate(&self, target: &MixerSnapshot, progress: f32) -> MixerSnapshot {
+        if progress >= 1.0 {
+            return target.clone();
+        }
+
+        let lerp = |from: f32, to: f32| from + (to - from) * progress;
+
+        let mut gains = HashMap::new();
+        for tag in self.gains.keys().chain(target.gains.keys()) {
+            let from = self.gains.get(tag).cloned().unwrap_or(1.0);
+            let to = target.gains.get(tag).cloned().unwrap_or(1.0);
+            gains.insert(tag.to_string(), lerp(from, to));
+        }
+
+        let mut params = self.params.clone();
+        for (tag, overrides) in target.params.iter() {
+            let current = params.entry(tag.to_string()).or_insert_with(HashMap::new);
+            for (param, to) in overrides.iter() {
+                let from = current.get(param).cloned().unwrap_or(*to);
+                current.insert(param.to_string(), lerp(from, *to));
+            }
+        }
+
+        MixerSnapshot { gains, params }
+    }
+}
+
+struct Morph {
+    from: MixerSnapshot,
+    to: MixerSnapshot,
+    steps: usize,
+    step: usize,
+}
+
+pub struct GroupState {
+    muted: HashSet<String>,
+    gains: HashMap<String, f32>,
+    params: HashMap<String, HashMap<String, f32>>,
+    snapshots: HashMap<String, MixerSnapshot>,
+    morph: Option<Morph>,
+}
+
+impl GroupState {
+    pub fn new() -> Self {
+        GroupState {
+            muted: HashSet::new(),
+            gains: HashMap::new(),
+            params: HashMap::new(),
+            snapshots: HashMap::new(),
+            morph: None,
+        }
+    }
+
+    pub fn snapshot(&self) -> MixerSnapshot {
+        MixerSnapshot { gains: self.gains.clone(), params: self.params.clone() }
+    }
+
+    fn restore(&mut self, snapshot: MixerSnapshot) {
+        self.gains = snapshot.gains;
+        self.params = snapshot.params;
+    }
+
+    pub fn save_snapshot(&mut self, name: &str) {
+        self.snapshots.insert(name.to_string(), self.snapshot());
+    }
+
+    /// Start moving all gains and overrides towards a saved snapshot
+    /// over the given number of steps.
+    pub fn morph_to(&mut self, name: &str, steps: usize) -> Result<(), String> {
+        let target = match self.snapshots.get(name) {
+            Some(snapshot) => snapshot.clone(),
+            None => return Err(format!("no snapshot named {}", name)),
+        };
+
+        if steps == 0 {
+            self.restore(target);
+            self.morph = None;
+        } else {
+            self.morph = Some(Morph { from: self.snapshot(), to: target, steps, step: 0 });
+        }
+        Ok(())
+    }
+
+    /// Advance a running morph by one step.
+    pub fn advance_morph(&mut self) {
+        if let Some(mut morph) = self.morph.take() {
+            morph.step += 1;
+            self.restore(morph.from.interpolate(&morph.to, morph.step as f32 / morph.steps as f32));
+            if morph.step < morph.steps {
+                self.morph = Some(morph);
+            }
+        }
+    }
+
+    pub fn apply_command(&mut self, command: &GroupCommand) {
+        match command {
+            GroupCommand::Mute(tag) => self.mute(tag, true),
+            GroupCommand::Unmute(tag) => self.mute(tag, false),
+            GroupCommand::Gain(tag, gain) => self.set_gain(tag, *gain),
+            GroupCommand::Set(tag, param, value) => self.set_param(tag, param, *value),
+        }
+    }
+
+    pub fn mute(&mut self, tag: &str, muted: bool) {
+        if muted {
+            self.muted.insert(tag.to_string());
+        } else {
+            self.muted.remove(tag);
+        }
+    }
+
+    pub fn set_gain(&mut self, tag: &str, gain: f32) {
+        self.gains.insert(tag.to_string(), gain);
+    }
+
+    /// Override a parameter for all events of a group.
+    pub fn set_param(&mut self, tag: &str, param: &str, value: f32) {
+        self.params.entry(tag.to_string()).or_insert_with(HashMap::new).insert(param.to_string(), value);
+    }
+
+    /// A line is muted if any of its groups is muted.
+    pub fn is_muted(&self, tags: &[String]) -> bool {
+        tags.iter().any(|tag| self.muted.contains(tag))
+    }
+
+    /// Apply gains and parameter overrides of all groups of a line to an event.
+    pub fn transform(&self, tags: &[String], source_type: &str, params: &mut HashMap<String, f32>) {
+        for tag in tags.iter() {
+            if let Some(overrides) = self.params.get(tag) {
+                for (param, value) in overrides.iter() {
+                    params.insert(param.to_string(), *value);
+                }
+            }
+        }
+
+        let gain: f32 = tags.iter().filter_map(|tag| self.gains.get(tag)).product();
+        if gain != 1.0 {
+            let level = params.get("lvl").cloned().unwrap_or_else(|| default_level(source_type));
+            params.insert("lvl".to_string(), level * gain);
+        }
+    }
+}
+
+// TEST TEST TEST 
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_mute() {
+        let mut groups = GroupState::new();
+        let tags = vec!["drums".to_string(), "loud".to_string()];
+        
+        groups.apply_command(&GroupCommand::Mute("loud"));
+        assert!(groups.is_muted(&tags));
+        assert!(!groups.is_muted(&["pads".to_string()]));
+
+        groups.apply_command(&GroupCommand::Unmute("loud"));
+        assert!(!groups.is_muted(&tags));
+    }
+
+    #[test]
+    fn test_transform() {
+        let mut groups = GroupState::new();
+        let tags = vec!["drums".to_string(), "loud".to_string()];
+        
+        groups.apply_command(&GroupCommand::Gain("drums", 0.5));
+        groups.apply_command(&GroupCommand::Gain("loud", 0.5));
+        groups.apply_command(&GroupCommand::Set("drums", "lp-freq", 800.0));
+
+        let mut params = HashMap::new();
+        groups.transform(&tags, "Sampler", &mut params);
+        assert_eq!(params["lvl"], 0.25);
+        assert_eq!(params["lp-freq"], 800.0);
+
+        let mut params = HashMap::new();
+        params.insert("lvl".to_string(), 0.8);
+        groups.transform(&["drums".to_string()], "SineSynth", &mut params);
+        assert_eq!(params["lvl"], 0.4);
+        
+        let mut params = HashMap::new();
+        groups.transform(&["pads".to_string()], "SineSynth", &mut params);
+        assert!(params.is_empty());
+    }
+
+    #[test]
+    fn test_morph() {
+        let mut groups = GroupState::new();
+        groups.apply_command(&GroupCommand::Gain("drums", 0.0));
+        groups.apply_command(&GroupCommand::Set("drums", "lp-freq", 200.0));
+        groups.save_snapshot("intro");
+
+        groups.apply_command(&GroupCommand::Gain("drums", 1.0));
+        groups.apply_command(&GroupCommand::Set("drums", "lp-freq", 1000.0));
+        groups.apply_command(&GroupCommand::Set("drums", "rev", 0.3));
+
+        assert!(groups.morph_to("outro", 4).is_err());
+        groups.morph_to("intro", 4).unwrap();
+
+        groups.advance_morph();
+        let mut params = HashMap::new();
+        groups.transform(&["drums".to_string()], "Sampler", &mut params);
+        assert_eq!(params["lvl"], 0.75);
+        assert_eq!(params["lp-freq"], 800.0);
+        assert_eq!(params["rev"], 0.3);
+
+        for _ in 0..4 {
+            groups.advance_morph();
+        }
+        let mut params = HashMap::new();
+        groups.transform(&["drums".to_string()], "Sampler", &mut params);
+        assert_eq!(params["lvl"], 0.0);
+        assert_eq!(params["lp-freq"], 200.0);
+        assert!(!params.contains_key("rev"));
+    }
+}
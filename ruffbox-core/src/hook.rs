@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+
+use crate::batch::BatchEvent;
+
+// TICK HOOKS
+// Something that gets to see the sound events of each step before they go
+// out, and can drop, change or add some. In the browser, that's a function set
+// from JS (see hook.rs in ruffbox-pattern), i.e.
+//
+//   (events, step) => events.filter(ev => ev.sample_id !== 'hh' || step % 4 == 0)
+//
+// Returning nothing keeps the events as they are. A hook that fails leaves the
+// step as it was. Events it returns without a proper timestamp (infinite, or
+// not a number) are dropped, there's no telling when they'd be played.
+
+/// A sound event of a step, before it goes into the batch.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SoundEvent {
+    pub timestamp: f64,
+    pub source_type: String,
+    pub sample_id: String,
+    pub bus: u32,
+    pub remote: bool,
+    pub expendable: bool,
+    pub fit_key: Option<i32>,
+    pub params: HashMap<String, f32>,
+    pub ramps: HashMap<String, f32>,
+    /// in seconds
+    pub duration: f64,
+}
+
+impl SoundEvent {
+    pub fn as_batch_event(&self) -> BatchEvent<'_> {
+        BatchEvent {
+            timestamp: self.timestamp,
+            source_type: &self.source_type,
+            sample_id: &self.sample_id,
+            bus: self.bus,
+            remote: self.remote,
+            expendable: self.expendable,
+            fit_key: self.fit_key,
+            params: &self.params,
+            ramps: &self.ramps,
+            duration: self.duration,
+        }
+    }
+}
+
+/// Anything that can look at the events of a step, returns the ones to play,
+/// none to keep them as they are.
+pub trait TickHook {
+    fn call(&self, events: &[SoundEvent], step: usize) -> Result<Option<Vec<SoundEvent>>, String>;
+}
+
+impl<F: Fn(&[SoundEvent], usize) -> Result<Option<Vec<SoundEvent>>, String>> TickHook for F {
+    fn call(&self, events: &[SoundEvent], step: usize) -> Result<Option<Vec<SoundEvent>>, String> {
+        self(events, step)
+    }
+}
+
+/// The events of a step, as the hook leaves them. If it fails, they come
+/// back untouched with the error.
+pub fn run_hook(hook: &dyn TickHook, events: Vec<SoundEvent>, step: usize) -> Result<Vec<SoundEvent>, (Vec<SoundEvent>, String)> {
+    match hook.call(&events, step) {
+        Ok(Some(hooked)) => Ok(hooked.into_iter().filter(|event| event.timestamp.is_finite()).collect()),
+        Ok(None) => Ok(events),
+        Err(err) => Err((events, err)),
+    }
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    fn sound(sample_id: &str, timestamp: f64) -> SoundEvent {
+        SoundEvent {
+            timestamp,
+            source_type: "Sampler".to_string(),
+            sample_id: sample_id.to_string(),
+            bus: 0,
+            remote: false,
+            expendable: false,
+            fit_key: None,
+            params: HashMap::new(),
+            ramps: HashMap::new(),
+            duration: 0.0,
+        }
+    }
+
+    #[test]
+    fn test_hook_filters_events() {
+        let hook = |events: &[SoundEvent], step: usize| -> Result<Option<Vec<SoundEvent>>, String> {
+            Ok(Some(events.iter().filter(|ev| ev.sample_id != "hh" || step % 4 == 0).cloned().collect()))
+        };
+
+        let events = vec![sound("bd", 1.0), sound("hh", 1.0)];
+        assert_eq!(run_hook(&hook, events.clone(), 0).unwrap(), events);
+        assert_eq!(run_hook(&hook, events.clone(), 1).unwrap(), vec![sound("bd", 1.0)]);
+    }
+
+    #[test]
+    fn test_hook_timestamps_must_be_finite() {
+        let hook = |events: &[SoundEvent], _: usize| -> Result<Option<Vec<SoundEvent>>, String> {
+            let mut events = events.to_vec();
+            events.push(sound("cp", f64::NAN));
+            events.push(sound("oh", f64::INFINITY));
+            Ok(Some(events))
+        };
+
+        let events = vec![sound("bd", 1.0)];
+        assert_eq!(run_hook(&hook, events.clone(), 0).unwrap(), events);
+    }
+
+    #[test]
+    fn test_hook_returning_nothing_keeps_events() {
+        let hook = |_: &[SoundEvent], _: usize| -> Result<Option<Vec<SoundEvent>>, String> { Ok(None) };
+
+        let events = vec![sound("bd", 1.0), sound("sn", 1.5)];
+        assert_eq!(run_hook(&hook, events.clone(), 3).unwrap(), events);
+    }
+
+    #[test]
+    fn test_failing_hook_keeps_step() {
+        let hook = |_: &[SoundEvent], _: usize| -> Result<Option<Vec<SoundEvent>>, String> { Err("boom".to_string()) };
+
+        let events = vec![sound("bd", 1.0), sound("sn", 1.5)];
+        let (kept, err) = run_hook(&hook, events.clone(), 0).unwrap_err();
+        assert_eq!(kept, events);
+        assert_eq!(err, "boom");
+    }
+}
@@ -0,0 +1,73 @@
+use rand::Rng;
+
+// VELOCITY CONTOURS
+// "humanize(drums): 0.2" lets the level of the line labeled "drums" drift
+// up and down by up to 20% over the course of a phrase, like a drummer
+// who plays some bars a bit harder than others.
+
+/// how far the contour moves per step, relative to its range
+const DRIFT_RATE: f32 = 0.1;
+
+/// A slowly varying gain, a random walk that stays within its bounds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VelocityContour {
+    /// maximum deviation from unity gain
+    amount: f32,
+    drift: f32,
+}
+
+impl VelocityContour {
+    pub fn new(amount: f32) -> Self {
+        VelocityContour {
+            amount: amount.clamp(0.0, 1.0),
+            drift: 0.0,
+        }
+    }
+
+    pub fn amount(&self) -> f32 {
+        self.amount
+    }
+
+    /// Move on by a step, returns the gain for that step.
+    pub fn next_gain<R: Rng>(&mut self, rng: &mut R) -> f32 {
+        self.drift += rng.gen_range(-1.0, 1.0) * self.amount * DRIFT_RATE;
+
+        // bounce off the bounds instead of sticking to them
+        if self.drift > self.amount {
+            self.drift = 2.0 * self.amount - self.drift;
+        } else if self.drift < -self.amount {
+            self.drift = -2.0 * self.amount - self.drift;
+        }
+
+        1.0 + self.drift
+    }
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    #[test]
+    fn test_contour_bounded() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut contour = VelocityContour::new(0.2);
+
+        let gains: Vec<f32> = (0..1000).map(|_| contour.next_gain(&mut rng)).collect();
+        assert!(gains.iter().all(|gain| *gain >= 0.8 && *gain <= 1.2));
+
+        // it does move, but slowly
+        assert!(gains.iter().any(|gain| (gain - 1.0).abs() > 0.1));
+        assert!(gains.windows(2).all(|pair| (pair[0] - pair[1]).abs() <= 0.02 + 0.0001));
+    }
+
+    #[test]
+    fn test_contour_off() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut contour = VelocityContour::new(0.0);
+        assert_eq!(contour.next_gain(&mut rng), 1.0);
+    }
+}
@@ -0,0 +1,155 @@
+// INSTRUMENTS
+// loadInstrument('piano', 'https://example.com/piano/piano.sfz') reads the
+// regions of an SFZ file, the sampler plays each note of "piano;deg=3" from
+// the region it falls into. Only the opcodes that map keys to samples are
+// read (sample, lokey, hikey, key, pitch_keycenter and default_path),
+// everything else is ignored.
+
+/// A sample and the keys it plays, as midi notes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Region {
+    /// path of the sample, relative to the SFZ file
+    pub sample: String,
+    pub lokey: i32,
+    pub hikey: i32,
+    /// the key the sample was recorded at
+    pub root: i32,
+}
+
+/// Midi note from a number or a name, i.e. "60", "c4", "c#4" or "eb3" (c4 being 60).
+fn note_number(value: &str) -> Option<i32> {
+    if let Ok(note) = value.parse() {
+        return Some(note);
+    }
+
+    let value = value.to_ascii_lowercase();
+    let mut chars = value.chars();
+    let base = match chars.next()? {
+        'c' => 0,
+        'd' => 2,
+        'e' => 4,
+        'f' => 5,
+        'g' => 7,
+        'a' => 9,
+        'b' => 11,
+        _ => return None,
+    };
+
+    let rest = chars.as_str();
+    let (accidental, octave) = match rest.strip_prefix('#') {
+        Some(octave) => (1, octave),
+        None => match rest.strip_prefix('b') {
+            Some(octave) => (-1, octave),
+            None => (0, rest),
+        },
+    };
+
+    Some(12 * (octave.parse::<i32>().ok()? + 1) + base + accidental)
+}
+
+/// The opcodes of a header, in order.
+type Opcodes = Vec<(String, String)>;
+
+/// Split SFZ text into headers ("region", "group", ...) and their opcodes.
+/// Values can contain spaces (for sample paths), they run to the next opcode.
+fn headers(text: &str) -> Vec<(String, Opcodes)> {
+    let mut headers: Vec<(String, Opcodes)> = Vec::new();
+
+    for line in text.lines() {
+        let line = line.split("//").next().unwrap_or("");
+        for token in line.split_whitespace() {
+            if let Some(header) = token.strip_prefix('<').and_then(|token| token.strip_suffix('>')) {
+                headers.push((header.to_string(), Vec::new()));
+            } else if let Some((_, opcodes)) = headers.last_mut() {
+                match token.split_once('=') {
+                    Some((opcode, value)) => opcodes.push((opcode.to_string(), value.to_string())),
+                    None => if let Some((_, value)) = opcodes.last_mut() {
+                        value.push(' ');
+                        value.push_str(token);
+                    },
+                }
+            }
+        }
+    }
+
+    headers
+}
+
+/// The regions of an SFZ file, with whatever they inherit from their group
+/// and the global header. Regions without a sample are left out.
+pub fn parse_sfz(text: &str) -> Vec<Region> {
+    let mut regions = Vec::new();
+    let mut default_path = String::new();
+    let mut global: Opcodes = Vec::new();
+    let mut group: Opcodes = Vec::new();
+
+    for (header, opcodes) in headers(text) {
+        match header.as_str() {
+            "control" => if let Some((_, path)) = opcodes.iter().rev().find(|(opcode, _)| opcode == "default_path") {
+                default_path = path.replace('\\', "/");
+            },
+            "global" => global = opcodes,
+            "group" => group = opcodes,
+            "region" => {
+                let mut region = Region { sample: String::new(), lokey: 0, hikey: 127, root: 60 };
+                for (opcode, value) in global.iter().chain(group.iter()).chain(opcodes.iter()) {
+                    match (opcode.as_str(), note_number(value)) {
+                        ("sample", _) => region.sample = format!("{}{}", default_path, value.replace('\\', "/")),
+                        ("lokey", Some(note)) => region.lokey = note,
+                        ("hikey", Some(note)) => region.hikey = note,
+                        ("pitch_keycenter", Some(note)) => region.root = note,
+                        ("key", Some(note)) => {
+                            region.lokey = note;
+                            region.hikey = note;
+                            region.root = note;
+                        },
+                        _ => {},
+                    }
+                }
+                if !region.sample.is_empty() {
+                    regions.push(region);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    regions
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_note_numbers() {
+        assert_eq!(note_number("60"), Some(60));
+        assert_eq!(note_number("c4"), Some(60));
+        assert_eq!(note_number("C#4"), Some(61));
+        assert_eq!(note_number("eb3"), Some(51));
+        assert_eq!(note_number("a-1"), Some(9));
+        assert_eq!(note_number("h4"), None);
+    }
+
+    #[test]
+    fn test_parse_sfz() {
+        let sfz = "
+// a tiny piano
+<control> default_path=samples\\
+<group> lokey=0 hikey=59 amp_veltrack=100
+<region> sample=low c.wav pitch_keycenter=c3
+<group> lovel=0
+<region> sample=mid.wav lokey=c4 hikey=71 pitch_keycenter=64
+<region> sample=high.wav key=76 // the top
+<region> lokey=80
+";
+        let regions = parse_sfz(sfz);
+        assert_eq!(regions, vec![
+            Region { sample: "samples/low c.wav".to_string(), lokey: 0, hikey: 59, root: 48 },
+            Region { sample: "samples/mid.wav".to_string(), lokey: 60, hikey: 71, root: 64 },
+            Region { sample: "samples/high.wav".to_string(), lokey: 76, hikey: 76, root: 76 },
+        ]);
+    }
+}
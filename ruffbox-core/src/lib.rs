@@ -0,0 +1,53 @@
+pub mod seqgen;
+pub mod parser;
+pub mod samples;
+pub mod notation;
+pub mod sexp;
+pub mod groups;
+pub mod scale;
+pub mod timecode;
+pub mod light;
+pub mod controllers;
+pub mod humanize;
+pub mod fills;
+pub mod chop;
+pub mod meter;
+pub mod instruments;
+pub mod soundfont;
+pub mod flood;
+pub mod expr;
+pub mod groove;
+pub mod matching;
+pub mod batch;
+pub mod sequence;
+pub mod stats;
+pub mod hook;
+pub mod scheduler;
+
+use crate::batch::BatchEvent;
+use crate::scheduler::Message;
+
+// CORE
+// Everything about patterns that doesn't need a browser: the notations and
+// their parsers, the sequences, the clock math, the transforms and the scheduler
+// that puts them to work, so it builds (and is tested) natively. What the
+// scheduler needs from its surroundings comes in through these, ruffbox-pattern
+// provides them in a web worker, ruffbox-native on the audio callback.
+
+/// Tells the time, in milliseconds, i.e. the browser's performance.now().
+pub trait Clock {
+    fn now(&self) -> f64;
+    /// Have the scheduler's routine called again after the given delay, in milliseconds.
+    fn wake_up_in(&mut self, delay: f64);
+}
+
+/// Where the sound events end up, i.e. a batch posted to the sampler.
+pub trait EventSink {
+    /// Take an event, returns false if there's no room for it.
+    fn push(&mut self, event: &BatchEvent) -> bool;
+    /// All events of a step are in.
+    fn flush(&mut self) {}
+    /// Take everything else the scheduler has to tell (see scheduler.rs),
+    /// dropped by default.
+    fn post(&mut self, _message: Message) {}
+}
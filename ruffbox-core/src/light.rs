@@ -0,0 +1,56 @@
+use std::collections::HashMap;
+
+// LIGHTS
+// Lines prefixed with "light:" don't play sounds, but send lighting cues,
+// i.e. "light: cyc >> 1;val=255 ~ 2;val=128;fade=0.5 ~", where the event
+// name is the DMX channel, "val" the value (0-255) and "fade" the fade time
+// in seconds. The cues are forwarded to the main thread, where a bridge
+// (Art-Net, USB-DMX, ...) can pick them up.
+
+pub const DMX_CHANNELS: u16 = 512;
+
+#[derive(Debug, PartialEq)]
+pub struct LightCue {
+    pub channel: u16,
+    pub value: u8,
+    pub fade: f32,
+}
+
+/// The DMX channel an event name refers to, if it's a valid one.
+pub fn channel_from_name(name: &str) -> Option<u16> {
+    match name.parse::<u16>() {
+        Ok(channel) if channel >= 1 && channel <= DMX_CHANNELS => Some(channel),
+        _ => None,
+    }
+}
+
+impl LightCue {
+    pub fn from_event(name: &str, params: &HashMap<String, f32>) -> Option<Self> {
+        Some(LightCue {
+            channel: channel_from_name(name)?,
+            value: params.get("val").map_or(255.0, |val| val.max(0.0).min(255.0)).round() as u8,
+            fade: params.get("fade").map_or(0.0, |fade| fade.max(0.0)),
+        })
+    }
+}
+
+// TEST TEST TEST 
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_cue_from_event() {
+        let mut params = HashMap::new();
+        assert_eq!(LightCue::from_event("12", &params), Some(LightCue { channel: 12, value: 255, fade: 0.0 }));
+
+        params.insert("val".to_string(), 300.0);
+        params.insert("fade".to_string(), 0.5);
+        assert_eq!(LightCue::from_event("1", &params), Some(LightCue { channel: 1, value: 255, fade: 0.5 }));
+
+        assert_eq!(LightCue::from_event("0", &params), None);
+        assert_eq!(LightCue::from_event("513", &params), None);
+        assert_eq!(LightCue::from_event("bd", &params), None);
+    }
+}
@@ -0,0 +1,126 @@
+// LINE MATCHING
+// When the input is evaluated again, each line picks up the sequence it was
+// before, so inserting a line above it or moving it around doesn't reset
+// where it is, what it's remembering or which id it goes by. A line is the
+// same as before if it has the same label, otherwise if it reads the same,
+// otherwise if it reads similar enough (i.e. a step was changed).
+
+/// how similar (0..1) an unlabeled line has to be to count as edited, not new
+pub const MIN_SIMILARITY: f32 = 0.5;
+
+/// Similarity of two patterns as written, by the tokens (events, params) they
+/// share in order, from 0 (nothing in common) to 1 (the same).
+pub fn similarity(a: &str, b: &str) -> f32 {
+    let a: Vec<&str> = a.split_whitespace().collect();
+    let b: Vec<&str> = b.split_whitespace().collect();
+    if a.is_empty() && b.is_empty() {
+        return 1.0;
+    }
+
+    // longest common subsequence, row by row
+    let mut row = vec![0; b.len() + 1];
+    for token in a.iter() {
+        let mut diagonal = 0;
+        for (idx, other) in b.iter().enumerate() {
+            let above = row[idx + 1];
+            row[idx + 1] = if token == other { diagonal + 1 } else { above.max(row[idx]) };
+            diagonal = above;
+        }
+    }
+
+    2.0 * row[b.len()] as f32 / (a.len() + b.len()) as f32
+}
+
+/// Match the lines of the new input to the previous ones, both given by label
+/// and pattern as written. Returns, for each new line, the index of the previous
+/// line it continues, if any. Every previous line is matched once at most.
+pub fn match_lines(previous: &[(Option<&str>, &str)], lines: &[(Option<&str>, &str)]) -> Vec<Option<usize>> {
+    let mut matches: Vec<Option<usize>> = vec![None; lines.len()];
+    let mut claimed = vec![false; previous.len()];
+
+    // labels first ...
+    for (line_idx, (label, _)) in lines.iter().enumerate() {
+        if label.is_none() {
+            continue;
+        }
+        if let Some(prev_idx) = (0..previous.len()).find(|idx| !claimed[*idx] && previous[*idx].0 == *label) {
+            matches[line_idx] = Some(prev_idx);
+            claimed[prev_idx] = true;
+        }
+    }
+
+    // ... then lines that haven't changed ...
+    for (line_idx, (_, source)) in lines.iter().enumerate() {
+        if matches[line_idx].is_some() {
+            continue;
+        }
+        if let Some(prev_idx) = (0..previous.len()).find(|idx| !claimed[*idx] && previous[*idx].1 == *source) {
+            matches[line_idx] = Some(prev_idx);
+            claimed[prev_idx] = true;
+        }
+    }
+
+    // ... then the most similar ones, closest in position if it's a tie
+    let mut candidates = Vec::new();
+    for (line_idx, (_, source)) in lines.iter().enumerate().filter(|(line_idx, _)| matches[*line_idx].is_none()) {
+        for (prev_idx, (_, prev_source)) in previous.iter().enumerate().filter(|(prev_idx, _)| !claimed[*prev_idx]) {
+            let score = similarity(source, prev_source);
+            if score >= MIN_SIMILARITY {
+                candidates.push((score, (line_idx as i64 - prev_idx as i64).abs(), line_idx, prev_idx));
+            }
+        }
+    }
+    candidates.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap().then(a.1.cmp(&b.1)));
+
+    for (_, _, line_idx, prev_idx) in candidates {
+        if matches[line_idx].is_none() && !claimed[prev_idx] {
+            matches[line_idx] = Some(prev_idx);
+            claimed[prev_idx] = true;
+        }
+    }
+
+    matches
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_similarity() {
+        assert_eq!(similarity("bd ~ sn ~", "bd ~ sn ~"), 1.0);
+        assert_eq!(similarity("bd ~ sn ~", "bd ~ sn hh"), 0.75);
+        assert_eq!(similarity("bd bd", "hh hh hh"), 0.0);
+        assert_eq!(similarity("", ""), 1.0);
+    }
+
+    #[test]
+    fn test_inserted_line() {
+        let previous = [(None, "bd ~ sn ~"), (None, "hh hh hh hh")];
+        let lines = [(None, "saw:a2 ~ ~ ~"), (None, "bd ~ sn ~"), (None, "hh hh hh hh")];
+        assert_eq!(match_lines(&previous, &lines), vec![None, Some(0), Some(1)]);
+    }
+
+    #[test]
+    fn test_reordered_and_edited() {
+        let previous = [(Some("drums"), "bd ~ sn ~"), (None, "hh hh hh hh"), (None, "saw:a2 ~ ~ ~")];
+
+        // moved up and edited, the label still holds
+        let lines = [(Some("drums"), "bd bd ~ ~"), (None, "saw:a2 ~ saw:c3 ~"), (None, "hh hh hh hh")];
+        assert_eq!(match_lines(&previous, &lines), vec![Some(0), Some(2), Some(1)]);
+
+        // an unlabeled line that's changed too much is a new one
+        let lines = [(Some("drums"), "bd ~ sn ~"), (None, "pluck pluck pluck ~")];
+        assert_eq!(match_lines(&previous, &lines), vec![Some(0), None]);
+    }
+
+    #[test]
+    fn test_duplicate_lines() {
+        // identical lines keep their order
+        let previous = [(None, "bd ~"), (None, "bd ~")];
+        let lines = [(None, "sn ~"), (None, "bd ~"), (None, "bd ~")];
+        assert_eq!(match_lines(&previous, &lines), vec![None, Some(0), Some(1)]);
+    }
+}
@@ -0,0 +1,182 @@
+// METER
+// The scheduler runs on steps, the meter says how they add up to beats and
+// bars. By default there are four steps to the beat (16th notes) and four
+// beats to the bar, so "@tempo 120" plays a step every 125 ms.
+// Whatever happens between steps (subdivided steps, rolls, swing ...) is
+// placed on a finer grid of ticks, 96 to the beat by default ("@ppqn 96").
+
+/// ticks per beat (pulses per quarter note), unless set otherwise
+pub const DEFAULT_PPQN: u32 = 96;
+
+/// When an evaluated input takes over from what's playing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EvalQuant {
+    Immediate,
+    /// on the next beat
+    Beat,
+    /// on the next bar line
+    Bar,
+}
+
+impl EvalQuant {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "immediate" | "off" => Some(EvalQuant::Immediate),
+            "beat" => Some(EvalQuant::Beat),
+            "bar" => Some(EvalQuant::Bar),
+            _ => None,
+        }
+    }
+}
+
+/// How steps are grouped into beats, and beats into bars.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Meter {
+    /// steps per beat
+    subdivision: u32,
+    beats_per_bar: u32,
+    /// ticks per beat
+    ppqn: u32,
+}
+
+impl Meter {
+    pub fn new(subdivision: u32, beats_per_bar: u32) -> Self {
+        Meter {
+            subdivision: subdivision.max(1),
+            beats_per_bar: beats_per_bar.max(1),
+            ppqn: DEFAULT_PPQN,
+        }
+    }
+
+    pub fn set_ppqn(&mut self, ppqn: u32) {
+        self.ppqn = ppqn.max(1);
+    }
+
+    pub fn set_subdivision(&mut self, subdivision: u32) {
+        self.subdivision = subdivision.max(1);
+    }
+
+    pub fn set_beats_per_bar(&mut self, beats_per_bar: u32) {
+        self.beats_per_bar = beats_per_bar.max(1);
+    }
+
+    pub fn steps_per_beat(&self) -> usize {
+        self.subdivision as usize
+    }
+
+    pub fn steps_per_bar(&self) -> usize {
+        (self.subdivision * self.beats_per_bar) as usize
+    }
+
+    /// The first step at or after the given one that's on a boundary of the given kind.
+    pub fn next_boundary(&self, step: usize, quant: EvalQuant) -> usize {
+        let steps = match quant {
+            EvalQuant::Immediate => 1,
+            EvalQuant::Beat => self.steps_per_beat(),
+            EvalQuant::Bar => self.steps_per_bar(),
+        };
+        (step + steps - 1) / steps * steps
+    }
+
+    /// Ticks per step, at least one, so a step always lies on a tick.
+    pub fn ticks_per_step(&self) -> u32 {
+        (self.ppqn / self.subdivision).max(1)
+    }
+
+    /// The tick closest to an offset (in steps).
+    pub fn ticks(&self, steps: f64) -> i64 {
+        (steps * self.ticks_per_step() as f64).round() as i64
+    }
+
+    /// An offset (in steps) snapped to the tick grid.
+    pub fn quantize(&self, steps: f64) -> f64 {
+        self.ticks(steps) as f64 / self.ticks_per_step() as f64
+    }
+
+    /// Time (in seconds) of a number of ticks, for the given duration of
+    /// a step in milliseconds.
+    pub fn tick_time(&self, ticks: i64, step_duration: f64) -> f64 {
+        ticks as f64 * step_duration / (1000.0 * self.ticks_per_step() as f64)
+    }
+
+    /// Duration of a step in milliseconds, at the given beats per minute.
+    pub fn step_duration(&self, bpm: f64) -> f64 {
+        60000.0 / (bpm * self.subdivision as f64)
+    }
+
+    /// Beats per minute, for the given duration of a step in milliseconds.
+    pub fn bpm(&self, step_duration: f64) -> f64 {
+        60000.0 / (step_duration * self.subdivision as f64)
+    }
+}
+
+impl Default for Meter {
+    fn default() -> Self {
+        Meter::new(4, 4)
+    }
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_default_meter() {
+        let meter = Meter::default();
+        assert_eq!(meter.steps_per_beat(), 4);
+        assert_eq!(meter.steps_per_bar(), 16);
+        assert_eq!(meter.step_duration(120.0), 125.0);
+        assert_eq!(meter.bpm(125.0), 120.0);
+    }
+
+    #[test]
+    fn test_triplets() {
+        // triplets, two beats of three steps
+        let mut meter = Meter::default();
+        meter.set_subdivision(3);
+        meter.set_beats_per_bar(2);
+        assert_eq!(meter.steps_per_bar(), 6);
+        assert_eq!(meter.step_duration(100.0), 200.0);
+
+        meter.set_subdivision(0);
+        assert_eq!(meter.steps_per_beat(), 1);
+    }
+
+    #[test]
+    fn test_boundaries() {
+        let mut meter = Meter::default();
+        assert_eq!(meter.next_boundary(5, EvalQuant::Immediate), 5);
+        assert_eq!(meter.next_boundary(5, EvalQuant::Beat), 8);
+        assert_eq!(meter.next_boundary(8, EvalQuant::Beat), 8);
+        assert_eq!(meter.next_boundary(5, EvalQuant::Bar), 16);
+
+        // a bar of 7/8 in eighths
+        meter.set_subdivision(2);
+        meter.set_beats_per_bar(7);
+        assert_eq!(meter.next_boundary(15, EvalQuant::Bar), 28);
+        assert_eq!(EvalQuant::from_name("beat"), Some(EvalQuant::Beat));
+        assert_eq!(EvalQuant::from_name("bars"), None);
+    }
+
+    #[test]
+    fn test_ticks() {
+        let mut meter = Meter::default();
+        assert_eq!(meter.ticks_per_step(), 24);
+
+        // triplets fit the default resolution, quintuplets are snapped to the closest tick
+        assert_eq!(meter.ticks(1.0 / 3.0), 8);
+        assert_eq!(meter.ticks(0.2), 5);
+        assert_eq!(meter.quantize(0.2), 5.0 / 24.0);
+        assert_eq!(meter.tick_time(12, 125.0), 0.0625);
+
+        meter.set_subdivision(3);
+        assert_eq!(meter.ticks_per_step(), 32);
+
+        // coarser than the steps, a step is still a tick
+        meter.set_ppqn(2);
+        assert_eq!(meter.ticks_per_step(), 1);
+        assert_eq!(meter.quantize(0.4), 0.0);
+    }
+}
@@ -0,0 +1,500 @@
+use std::collections::HashMap;
+use nom::combinator::all_consuming;
+
+use crate::expr::Condition;
+use crate::parser;
+use crate::parser::{ChoiceMode, ParamValue, TIE_EVENT, TIE_PARAM};
+use crate::sexp::SexpNotation;
+
+// NOTATIONS
+// Each line can be written in a different notation, as long as it compiles
+// to the same internal pattern representation. The notation is chosen per
+// evaluation, or per line with a prefix, i.e. "%seq cyc >> bd ~ sn ~".
+
+/// A single event, with its position in the input.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PatternEvent {
+    pub name: String,
+    pub params: Vec<(String, ParamValue)>,
+    pub position: usize,
+    /// where within the step it starts (0..1), events with an offset
+    /// share the step with the ones before them
+    pub offset: f64,
+    /// the events of a choice follow each other, with their index in it,
+    /// the first one takes the step
+    pub choice: Option<(ChoiceMode, usize)>,
+    /// plays together with the event before it (chords)
+    pub stacked: bool,
+}
+
+/// A sequence of values for a single parameter.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParamPattern {
+    pub param: String,
+    pub generator: String,
+    pub values: Vec<f32>,
+}
+
+/// The internal pattern representation, independent of the notation it was written in.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pattern {
+    /// some notations can name the line themselves
+    pub label: Option<String>,
+    pub generator: String,
+    pub events: Vec<PatternEvent>,
+    pub params: Vec<ParamPattern>,
+    /// conditional lines play the events (and param sequences) before the else
+    /// part on the cycles the condition holds, and the else part on the others
+    pub condition: Option<(Condition, ElsePart)>,
+}
+
+/// Where the else part of a conditional line starts, in the events and the
+/// param sequences of the pattern, and the generator it has of its own.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ElsePart {
+    pub events: usize,
+    pub params: usize,
+    pub generator: String,
+}
+
+/// Why a line couldn't be parsed, and where in its body.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyntaxError {
+    pub position: usize,
+    pub message: String,
+}
+
+impl SyntaxError {
+    /// at the part of the input that's left
+    pub fn at(input: &str, rest: &str, message: String) -> Self {
+        SyntaxError { position: rest.as_ptr() as usize - input.as_ptr() as usize, message }
+    }
+
+    /// where the parser gave up, or found there's nothing to play (see parser.rs)
+    pub fn from_nom(input: &str, err: nom::Err<(&str, nom::error::ErrorKind)>) -> Self {
+        match err {
+            nom::Err::Error((rest, _)) => SyntaxError::unexpected(input, rest),
+            nom::Err::Failure((rest, _)) => SyntaxError::nothing_to_play(input, rest),
+            nom::Err::Incomplete(_) => SyntaxError { position: input.len(), message: "unexpected end of line".to_string() },
+        }
+    }
+
+    /// at the token the rest of the input starts with
+    pub fn unexpected(input: &str, rest: &str) -> Self {
+        match rest.split_whitespace().next() {
+            Some(token) => SyntaxError::at(input, token, format!("unexpected '{}'", token)),
+            None => SyntaxError { position: input.len(), message: "unexpected end of line".to_string() },
+        }
+    }
+
+    /// at the token the rest of the input starts with, that reads right, but doesn't play anything
+    pub fn nothing_to_play(input: &str, rest: &str) -> Self {
+        match rest.split_whitespace().next() {
+            Some(token) => SyntaxError::at(input, token, format!("nothing to play in '{}'", token)),
+            None => SyntaxError::at(input, input.trim_start(), "nothing to play".to_string()),
+        }
+    }
+}
+
+/// errors that can't be pinned down any further than the line
+impl From<String> for SyntaxError {
+    fn from(message: String) -> Self {
+        SyntaxError { position: 0, message }
+    }
+}
+
+pub trait PatternParser {
+    /// Parse the body of a line (without label and notation prefix).
+    fn parse(&self, input: &str) -> Result<Pattern, SyntaxError>;
+}
+
+/// Turn ties into rests, and hold the last events of the step before them for the
+/// steps tied to it. Ties within a group, or at the start of a line, are just rests.
+fn tie_steps(mut events: Vec<PatternEvent>) -> Vec<PatternEvent> {
+    // where each step starts
+    let starts: Vec<usize> = events.iter().enumerate()
+        .filter(|(_, ev)| ev.offset == 0.0 && !ev.stacked && ev.choice.map_or(true, |(_, idx)| idx == 0))
+        .map(|(idx, _)| idx)
+        .collect();
+
+    let mut held: Option<(usize, usize, f32)> = None;
+    let mut holds = Vec::new();
+    for (step, start) in starts.iter().enumerate() {
+        let end = starts.get(step + 1).cloned().unwrap_or_else(|| events.len());
+        if end - start == 1 && events[*start].name == TIE_EVENT {
+            if let Some((_, _, ties)) = held.as_mut() {
+                *ties += 1.0;
+            }
+        } else {
+            holds.extend(held.take().filter(|(_, _, ties)| *ties > 0.0));
+            held = Some((*start, end, 0.0));
+        }
+    }
+    holds.extend(held.filter(|(_, _, ties)| *ties > 0.0));
+
+    for (start, end, ties) in holds {
+        let last_offset = events[start..end].iter().map(|ev| ev.offset).fold(0.0, f64::max);
+        for ev in events[start..end].iter_mut().filter(|ev| ev.offset == last_offset && ev.name != "~") {
+            ev.params.push((TIE_PARAM.to_string(), ParamValue::Const(ties)));
+        }
+    }
+
+    for ev in events.iter_mut().filter(|ev| ev.name == TIE_EVENT) {
+        ev.name = "~".to_string();
+    }
+    events
+}
+
+/// The original notation, i.e. "cyc >> bd ~ sn ~ @rate: cyc >> 1.0 0.9"
+pub struct SeqNotation;
+
+impl PatternParser for SeqNotation {
+    fn parse(&self, input: &str) -> Result<Pattern, SyntaxError> {
+        // both parts of a conditional line go into the same pattern
+        if let Ok((_, (cond, then_body, else_body))) = parser::conditional_line(input) {
+            let part = |body: &str| -> Result<Pattern, SyntaxError> {
+                let offset = body.as_ptr() as usize - input.as_ptr() as usize;
+                let mut pattern = self.parse(body).map_err(|err| SyntaxError { position: offset + err.position, ..err })?;
+                if pattern.condition.is_some() {
+                    return Err(SyntaxError::at(input, body, "conditional lines can't be nested".to_string()));
+                }
+                for ev in pattern.events.iter_mut() {
+                    ev.position += offset;
+                }
+                Ok(pattern)
+            };
+            let (mut pattern, mut otherwise) = (part(then_body)?, part(else_body)?);
+            let else_part = ElsePart { events: pattern.events.len(), params: pattern.params.len(), generator: otherwise.generator };
+            pattern.events.append(&mut otherwise.events);
+            pattern.params.append(&mut otherwise.params);
+            pattern.condition = Some((cond, else_part));
+            return Ok(pattern);
+        }
+
+        // a chain is just a line referring to other lines
+        match all_consuming(parser::sequence_chain)(input) {
+            Ok((_, segments)) => return Ok(Pattern {
+                label: None,
+                generator: "cyc".to_string(),
+                events: segments.iter().flat_map(|(label, repeat)| vec![PatternEvent {
+                    name: label.to_string(),
+                    params: Vec::new(),
+                    position: label.as_ptr() as usize - input.as_ptr() as usize,
+                    offset: 0.0,
+                    choice: None,
+                    stacked: false,
+                }; *repeat]).collect(),
+                params: Vec::new(),
+                condition: None,
+            }),
+            Err(err @ nom::Err::Failure(_)) => return Err(SyntaxError::from_nom(input, err)),
+            Err(_) => (),
+        }
+        
+        let pattern_event = |offset: f64, (name, params): &(&str, Vec<(&str, ParamValue)>), choice: Option<(ChoiceMode, usize)>, stacked: bool| PatternEvent {
+            name: name.to_string(),
+            params: params.iter().map(|(par, val)| (par.to_string(), val.clone())).collect(),
+            position: name.as_ptr() as usize - input.as_ptr() as usize,
+            offset,
+            choice,
+            stacked,
+        };
+
+        // choices are split up into the events to choose from, chords into the events they stack
+        let pattern_events = |offset: f64, ev: &(&str, Vec<(&str, ParamValue)>)| {
+            if let Ok((_, (mode, options))) = parser::choice(ev.0) {
+                options.iter().enumerate().map(|(idx, option)| pattern_event(offset, option, Some((mode, idx)), false)).collect()
+            } else if let Ok((_, notes)) = parser::chord(ev.0) {
+                notes.iter().enumerate().map(|(idx, note)| pattern_event(offset, note, None, idx > 0)).collect()
+            } else {
+                vec![pattern_event(offset, ev, None, false)]
+            }
+        };
+        
+        match parser::pattern_line(input) {
+            // whatever isn't part of the pattern is a mistake
+            Ok((rest, _)) if !rest.trim().is_empty() => Err(SyntaxError::unexpected(input, rest)),
+            // param sequences alone don't play anything
+            Ok((_, ((_, events), _))) if events.is_empty() => Err(SyntaxError::at(input, input.trim_start(), "nothing to play, there are no events".to_string())),
+            Ok((_, ((generator, events), params))) => Ok(Pattern {
+                label: None,
+                generator: generator.to_string(),
+                // groups are split up into the events that share their step
+                events: tie_steps(events.iter().flat_map(|ev| match parser::subdivide(ev.0) {
+                    Ok((_, subdivision)) => subdivision.iter().flat_map(|(offset, ev)| pattern_events(*offset, ev)).collect(),
+                    Err(_) => pattern_events(0.0, ev),
+                }).collect()),
+                params: params.iter().map(|((param, generator), values)| ParamPattern {
+                    param: param.to_string(),
+                    generator: generator.to_string(),
+                    values: values.clone(),
+                }).collect(),
+                condition: None,
+            }),
+            Err(err) => Err(SyntaxError::from_nom(input, err)),
+        }
+    }
+}
+
+// REFERENCES
+// A line can play other labeled lines as sub-patterns, i.e.
+// "main: intro intro fill", where "intro" and "fill" are labels.
+
+fn flatten_with_stack(label: &str, patterns: &HashMap<String, &Pattern>, stack: &mut Vec<String>) -> Result<Vec<PatternEvent>, String> {
+    if stack.iter().any(|visited| visited == label) {
+        return Err(format!("cyclic reference to '{}'", label));
+    }
+
+    stack.push(label.to_string());
+    
+    let mut flat_events = Vec::new();
+    for ev in patterns[label].events.iter() {
+        if patterns.contains_key(&ev.name) {
+            flat_events.append(&mut flatten_with_stack(&ev.name, patterns, stack)?);
+        } else {
+            flat_events.push(ev.clone());
+        }
+    }
+    
+    stack.pop();
+    
+    Ok(flat_events)
+}
+
+/// Resolve a reference to a labeled line into the events it plays,
+/// nested references included.
+pub fn flatten_reference(label: &str, patterns: &HashMap<String, &Pattern>) -> Result<Vec<PatternEvent>, String> {
+    flatten_with_stack(label, patterns, &mut Vec::new())
+}
+
+/// All available notations, by name.
+pub struct ParserRegistry {
+    parsers: HashMap<String, Box<dyn PatternParser>>,
+    default: String,
+}
+
+impl ParserRegistry {
+    pub fn new() -> Self {
+        let mut parsers: HashMap<String, Box<dyn PatternParser>> = HashMap::new();
+        parsers.insert("seq".to_string(), Box::new(SeqNotation));
+        parsers.insert("sexp".to_string(), Box::new(SexpNotation));
+        
+        ParserRegistry {
+            parsers,
+            default: "seq".to_string(),
+        }
+    }
+
+    pub fn register(&mut self, name: &str, parser: Box<dyn PatternParser>) {
+        self.parsers.insert(name.to_string(), parser);
+    }
+
+    /// Set the notation used for lines without prefix.
+    pub fn set_default(&mut self, name: &str) -> Result<(), String> {
+        if self.parsers.contains_key(name) {
+            self.default = name.to_string();
+            Ok(())
+        } else {
+            Err(format!("unknown notation '{}'", name))
+        }
+    }
+
+    /// Parse a line body in the given notation, or the default one.
+    pub fn parse(&self, notation: Option<&str>, input: &str) -> Result<Pattern, SyntaxError> {
+        let name = notation.unwrap_or(&self.default);
+        match self.parsers.get(name) {
+            Some(parser) => parser.parse(input),
+            None => Err(format!("unknown notation '{}'", name).into()),
+        }
+    }
+}
+
+// TEST TEST TEST 
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    struct StepNotation;
+
+    // "x..x" style steps
+    impl PatternParser for StepNotation {
+        fn parse(&self, input: &str) -> Result<Pattern, SyntaxError> {
+            Ok(Pattern {
+                label: None,
+                generator: "cyc".to_string(),
+                events: input.char_indices().map(|(position, c)| PatternEvent {
+                    name: if c == 'x' { "bd".to_string() } else { "~".to_string() },
+                    params: Vec::new(),
+                    position,
+                    offset: 0.0,
+                    choice: None,
+                    stacked: false,
+                }).collect(),
+                params: Vec::new(),
+                condition: None,
+            })
+        }
+    }
+
+    #[test]
+    fn test_seq_notation() {
+        let pattern = SeqNotation.parse("cyc >> bd;lvl=0.5 ~ sn @rate: rnd >> 1.0 0.9").unwrap();
+        
+        assert_eq!(pattern.generator, "cyc");
+        assert_eq!(pattern.events.len(), 3);
+        assert_eq!(pattern.events[0].params, vec![("lvl".to_string(), ParamValue::Const(0.5))]);
+        assert_eq!(pattern.events[2].name, "sn");
+        assert_eq!(pattern.events[2].position, 20);
+        assert_eq!(pattern.params, vec![ParamPattern { param: "rate".to_string(), generator: "rnd".to_string(), values: vec![1.0, 0.9] }]);
+    }
+
+    #[test]
+    fn test_seq_notation_subdivisions() {
+        let pattern = SeqNotation.parse("bd [sn sn] ~ [hh [hh hh]]").unwrap();
+        let events: Vec<(&str, f64)> = pattern.events.iter().map(|ev| (ev.name.as_str(), ev.offset)).collect();
+
+        assert_eq!(events, vec![("bd", 0.0), ("sn", 0.0), ("sn", 0.5), ("~", 0.0), ("hh", 0.0), ("hh", 0.5), ("hh", 0.75)]);
+        assert_eq!(pattern.events[2].position, 7);
+    }
+
+    #[test]
+    fn test_seq_notation_choices() {
+        let pattern = SeqNotation.parse("<bd sn> [hh [cp|rim]]").unwrap();
+        let events: Vec<(&str, f64, Option<(ChoiceMode, usize)>)> = pattern.events.iter().map(|ev| (ev.name.as_str(), ev.offset, ev.choice)).collect();
+
+        assert_eq!(events, vec![
+            ("bd", 0.0, Some((ChoiceMode::Alternate, 0))),
+            ("sn", 0.0, Some((ChoiceMode::Alternate, 1))),
+            ("hh", 0.0, None),
+            ("cp", 0.5, Some((ChoiceMode::Random, 0))),
+            ("rim", 0.5, Some((ChoiceMode::Random, 1))),
+        ]);
+        assert_eq!(pattern.events[4].position, 16);
+    }
+
+    #[test]
+    fn test_seq_notation_chords() {
+        let pattern = SeqNotation.parse("bd+hh ~ [sn {cp hh}]").unwrap();
+        let events: Vec<(&str, f64, bool)> = pattern.events.iter().map(|ev| (ev.name.as_str(), ev.offset, ev.stacked)).collect();
+
+        assert_eq!(events, vec![
+            ("bd", 0.0, false),
+            ("hh", 0.0, true),
+            ("~", 0.0, false),
+            ("sn", 0.0, false),
+            ("cp", 0.5, false),
+            ("hh", 0.5, true),
+        ]);
+    }
+
+    #[test]
+    fn test_seq_notation_conditional() {
+        let pattern = SeqNotation.parse("if cycle%8<4: bd ~ sn ~ else: bd bd sn [sn sn]").unwrap();
+        let names: Vec<&str> = pattern.events.iter().map(|ev| ev.name.as_str()).collect();
+
+        assert_eq!(names, vec!["bd", "~", "sn", "~", "bd", "bd", "sn", "sn", "sn"]);
+        assert_eq!(pattern.condition.as_ref().map(|(_, else_part)| else_part.events), Some(4));
+        assert_eq!(pattern.events[4].position, 30);
+
+        // each part has its own generator and param sequences
+        let pattern = SeqNotation.parse("if cycle%2==0: rnd >> bd sn @rate: cyc >> 1 2 else: hh hh @lvl: cyc >> 0.5").unwrap();
+        assert_eq!(pattern.generator, "rnd");
+        assert_eq!(pattern.condition.map(|(_, else_part)| else_part), Some(ElsePart { events: 2, params: 1, generator: "cyc".to_string() }));
+        let params: Vec<&str> = pattern.params.iter().map(|param| param.param.as_str()).collect();
+        assert_eq!(params, vec!["rate", "lvl"]);
+
+        assert!(SeqNotation.parse("if cycle>1: bd else: if cycle>2: sn else: hh").is_err());
+    }
+
+    #[test]
+    fn test_seq_notation_ties() {
+        let pattern = SeqNotation.parse("saw _ _ [sn hh] _ ~ _").unwrap();
+        let events: Vec<(&str, Vec<(String, ParamValue)>)> = pattern.events.iter().map(|ev| (ev.name.as_str(), ev.params.clone())).collect();
+        let tied = |steps: f32| vec![(TIE_PARAM.to_string(), ParamValue::Const(steps))];
+
+        assert_eq!(events, vec![
+            ("saw", tied(2.0)),
+            ("~", Vec::new()),
+            ("~", Vec::new()),
+            ("sn", Vec::new()),
+            ("hh", tied(1.0)),
+            ("~", Vec::new()),
+            ("~", Vec::new()),
+            ("~", Vec::new()),
+        ]);
+
+        // nothing to hold
+        let pattern = SeqNotation.parse("_ bd").unwrap();
+        assert_eq!(pattern.events[0].name, "~");
+        assert!(pattern.events[1].params.is_empty());
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        assert_eq!(SeqNotation.parse("bd ~ sn ~ )"), Err(SyntaxError { position: 10, message: "unexpected ')'".to_string() }));
+        assert_eq!(SeqNotation.parse("bd ~ sn(").unwrap_err().position, 7);
+
+        // within a part of a conditional line, from the start of the line
+        assert_eq!(SeqNotation.parse("if cycle%2==0: bd ~ else: sn )").unwrap_err().position, 29);
+
+        // a euclidean rhythm without steps
+        assert_eq!(SeqNotation.parse("bd sn:e(3,0)"), Err(SyntaxError { position: 7, message: "nothing to play in '(3,0)'".to_string() }));
+        assert_eq!(SeqNotation.parse("~ bd(0,0) sn").unwrap_err().position, 4);
+
+        // param sequences without events
+        assert_eq!(SeqNotation.parse(" @rate: cyc >> 1.0 0.9"), Err(SyntaxError { position: 1, message: "nothing to play, there are no events".to_string() }));
+        assert!(SeqNotation.parse("rnd >> @lvl: cyc >> 0.5").is_err());
+        assert!(SeqNotation.parse("if cycle%2==0: bd ~ else: @lvl: cyc >> 0.5").is_err());
+    }
+
+    #[test]
+    fn test_chain() {
+        let pattern = SeqNotation.parse("A*2 >> B*3 >> A").unwrap();
+        let names: Vec<String> = pattern.events.iter().map(|ev| ev.name.clone()).collect();
+        
+        assert_eq!(names, vec!["A", "A", "B", "B", "B", "A"]);
+        assert_eq!(pattern.events[2].position, 7);
+
+        // sections played no times
+        assert_eq!(SeqNotation.parse("intro*0 >> verse*0"), Err(SyntaxError { position: 0, message: "nothing to play in 'intro*0'".to_string() }));
+        assert_eq!(SeqNotation.parse("A*2 >> B*0 >> A").unwrap_err().position, 7);
+    }
+
+    #[test]
+    fn test_flatten_reference() {
+        let intro = SeqNotation.parse("bd ~ sn ~").unwrap();
+        let fill = SeqNotation.parse("sn sn").unwrap();
+        let verse = SeqNotation.parse("intro fill").unwrap();
+        let cyclic = SeqNotation.parse("bd cyclic").unwrap();
+
+        let mut patterns: HashMap<String, &Pattern> = HashMap::new();
+        patterns.insert("intro".to_string(), &intro);
+        patterns.insert("fill".to_string(), &fill);
+        patterns.insert("verse".to_string(), &verse);
+        patterns.insert("cyclic".to_string(), &cyclic);
+
+        let names: Vec<String> = flatten_reference("verse", &patterns).unwrap().iter().map(|ev| ev.name.clone()).collect();
+        assert_eq!(names, vec!["bd", "~", "sn", "~", "sn", "sn"]);
+
+        assert!(flatten_reference("cyclic", &patterns).is_err());
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut registry = ParserRegistry::new();
+        registry.register("steps", Box::new(StepNotation));
+
+        let seq = registry.parse(None, "cyc >> bd ~ ~ bd").unwrap();
+        let steps = registry.parse(Some("steps"), "x..x").unwrap();
+        
+        let names = |pattern: &Pattern| pattern.events.iter().map(|ev| ev.name.clone()).collect::<Vec<String>>();
+        assert_eq!(names(&seq), names(&steps));
+
+        assert!(registry.parse(Some("tab"), "x..x").is_err());
+        assert!(registry.set_default("tab").is_err());
+        
+        registry.set_default("steps").unwrap();
+        assert_eq!(registry.parse(None, "x.x").unwrap().events.len(), 3);
+    }
+}
@@ -0,0 +1,1013 @@
+use nom::{
+    branch::alt,
+    number::complete::float,
+    bytes::complete::{tag, take_while1},
+    character::complete::{char, digit1, space0, space1},
+    combinator::{all_consuming, map, map_res, opt, recognize, verify},
+    multi::{separated_list, separated_nonempty_list, many1, many0},
+    sequence::{separated_pair, preceded, terminated, pair, delimited, tuple},
+    error::ErrorKind,
+    IResult,
+};
+
+use crate::groups::GroupCommand;
+use crate::expr::{self, Condition, Expr};
+
+// Whatever reads right, but leaves nothing to play, fails for good (a nom Failure,
+// rather than an Error that lets the alternatives have a go), so the notation can
+// report it as such instead of the next best thing that doesn't parse.
+fn nothing_to_play<T>(input: &str) -> IResult<&str, T> {
+    Err(nom::Err::Failure((input, ErrorKind::Verify)))
+}
+
+// EVENTS
+// An event is something like "sine;freq=100;dur=100" (an event type followed by a list of parameters)
+// or just the event type. The params can be separated by colons, too, i.e. "bd:gain=0.7:rate=1.5".
+
+// param names can be fixed for now ... 
+// params of a line's insert effects are prefixed with the effect, i.e. "lpf.lp-freq"
+pub fn param_name(input: &str) -> IResult<&str, &str> {
+    recognize(pair(opt(pair(insert_name, char('.'))), base_param_name))(input)
+}
+
+fn base_param_name(input: &str) -> IResult<&str, &str> {
+    alt((tag("atk"),
+         tag("dec"),         
+         tag("del"),         
+         tag("dur"),
+         tag("freq"),         
+         tag("lvl"),
+         tag("lp-freq"),
+         tag("lp-q"),
+         tag("lp-dist"),         
+         tag("pw"),
+         tag("rate"),
+         tag("start"),
+         tag("deg"),
+         tag("val"),
+         tag("fade"),
+         tag("rel"),         
+         tag("rev"),                           
+         tag("pos"),
+         tag("sus"),
+         alt((tag("bits"),
+              tag("thresh"),
+              tag("ratio"),
+              tag("age"),
+              tag("hold"),
+              tag("smear"),
+              tag("shift"),
+              tag("os"),
+              tag("interp"),
+              tag("azi"),
+              tag("elev"),
+              tag("len"),
+              tag("rep"),
+              tag("cut"),
+              tag("gain")))))(input)
+}
+
+// INSERT EFFECTS
+// A labeled line can declare a chain of effects its sounds run through,
+// i.e. "drums [crush lpf comp]: bd ~ sn ~", their params can be set like
+// any other param, i.e. "@lpf.lp-freq: ramp >> 200 4000 16"
+pub fn insert_name(input: &str) -> IResult<&str, &str> {
+    alt((tag("crush"), tag("lpf"), tag("comp"), tag("lofi"), tag("freeze"), tag("pitch")))(input)
+}
+
+pub fn insert_chain(input: &str) -> IResult<&str, Vec<&str>> {
+    delimited(pair(char('['), space0), separated_nonempty_list(space1, insert_name), pair(space0, char(']')))(input)
+}
+
+// PARAM VALUES
+// Besides plain numbers, params can follow external modulation inputs,
+// i.e. "lp-freq=mod(tilt_x, 200, 4000)" maps the input "tilt_x" (0..1)
+// to the range 200..4000, or move over the duration of the event,
+// i.e. "pos=-1..1" sweeps from left to right.
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParamValue {
+    Const(f32),
+    Mod(String, f32, f32),
+    Ramp(f32, f32),
+    /// computed from the counters when the event is played
+    Expr(Expr),
+}
+
+// mod(tilt_x, 200, 4000)
+pub fn mod_input(input: &str) -> IResult<&str, ParamValue> {
+    let comma = || delimited(space0, char(','), space0);
+    map(delimited(pair(tag("mod("), space0),
+                  tuple((take_while1(|c: char| c.is_alphanumeric() || c == '_'), preceded(comma(), float), preceded(comma(), float))),
+                  pair(space0, char(')'))),
+        |(name, min, max)| ParamValue::Mod(name.to_string(), min, max))(input)
+}
+
+// -1..1, the numbers can't end with a dot here
+pub fn ramp(input: &str) -> IResult<&str, ParamValue> {
+    let number = || map_res(recognize(tuple((opt(char('-')), digit1, opt(pair(char('.'), digit1))))), |num: &str| num.parse::<f32>());
+    map(separated_pair(number(), tag(".."), number()), |(from, to)| ParamValue::Ramp(from, to))(input)
+}
+
+pub fn param_value(input: &str) -> IResult<&str, ParamValue> {
+    alt((mod_input, ramp, map(verify(expr::expr, |ex: &Expr| !ex.is_const()), ParamValue::Expr), map(float, ParamValue::Const)))(input)
+}
+
+// FLAGS
+// Params without a value, i.e. "pad;fit_key" repitches a melodic sample
+// into the key set with "@scale", "pad;loop" holds it by looping it
+// between its loop points for as long as the envelope lasts.
+pub fn flag(input: &str) -> IResult<&str, (&str, ParamValue)> {
+    map(alt((tag("fit_key"), tag("loop"))), |name| (name, ParamValue::Const(1.0)))(input)
+}
+
+pub fn param(input: &str) -> IResult<&str, (&str, ParamValue)> {
+    // gain is another name for the level, so group gains apply to it
+    map(alt((separated_pair(param_name, char('='), param_value), flag)),
+        |(name, value)| (if name == "gain" { "lvl" } else { name }, value))(input)
+}
+
+pub fn param_list(input: &str) -> IResult<&str, Vec<(&str, ParamValue)>> {
+    separated_list(tag(";"), param)(input)
+}
+
+// any sample name, or a synth, or a rest ... whether it exists is checked later
+pub fn event_name(input: &str) -> IResult<&str, &str> {
+    alt((take_while1(|c: char| c.is_alphanumeric() || c == '_' || c == '-' || c == '.' || c == '/'), tag("~")))(input)
+}
+
+// TIES
+// "saw ~ _ _" is a rest, "saw _ _ ~" holds the saw for three steps. The tied
+// events carry the number of steps they're held for beyond their own.
+pub const TIE_EVENT: &str = "_";
+pub const TIE_PARAM: &str = "tie";
+
+// CONDITIONS
+// "bd?0.5" plays with a chance of 50% each time its step comes up ("bd?" is
+// the same), "sn!4" only on every 4th cycle of its line, starting with the
+// first. They're passed on as params, and taken off again before the event
+// is played.
+pub const CHANCE_PARAM: &str = "chance";
+pub const EVERY_PARAM: &str = "every";
+
+pub fn condition(input: &str) -> IResult<&str, (&str, ParamValue)> {
+    alt((map(preceded(char('?'), opt(float)), |chance| (CHANCE_PARAM, ParamValue::Const(chance.unwrap_or(0.5)))),
+         map(preceded(char('!'), step_count), |cycles| (EVERY_PARAM, ParamValue::Const(cycles as f32)))))(input)
+}
+
+// the name, with its condition (if it has one) as the first param
+fn conditional_name(input: &str) -> IResult<&str, (&str, Vec<(&str, ParamValue)>)> {
+    map(pair(event_name, opt(condition)), |(name, condition)| (name, condition.into_iter().collect()))(input)
+}
+
+// sine;freq=100.0;dur=200 or sine:freq=100.0:dur=200, the latter needs at least
+// one param, so "bd:e(3,8)" is still a euclidean rhythm
+pub fn event_with_param(input: &str) -> IResult<&str, (&str, Vec<(&str, ParamValue)>)> {
+    map(pair(conditional_name, alt((preceded(char(';'), param_list),
+                                    preceded(char(':'), separated_nonempty_list(char(':'), param))))),
+        |((name, mut params), rest)| {
+            params.extend(rest);
+            (name, params)
+        })(input)
+}
+
+// sine
+pub fn event_without_param(input: &str) -> IResult<&str, (&str, Vec<(&str, ParamValue)>)> {
+    conditional_name(input)
+}
+
+// both of the former
+pub fn event(input: &str) -> IResult<&str, (&str, Vec<(&str, ParamValue)>)> {
+    alt((event_with_param, event_without_param))(input)
+}
+
+// EUCLIDEAN RHYTHMS
+// An event can be spread over a number of steps using a euclidean modifier, i.e.
+// "bd:e(3,8)" yields "bd ~ ~ bd ~ ~ bd ~", while "hh:e_inv(3,8)" fills exactly
+// the steps the former leaves empty ("~ hh hh ~ hh hh ~ hh"), so interlocking
+// rhythms can be written from the same specification. The Tidal way of writing
+// it works, too, "bd(3,8)" is the same as "bd:e(3,8)", and a third number
+// rotates the hits to the left, i.e. "hh(5,16,2)".
+
+/// Distribute k hits over n steps as evenly as possible (Bjorklund's algorithm).
+pub fn euclidean_hits(k: usize, n: usize) -> Vec<bool> {
+    if k >= n {
+        return vec![true; n];
+    } else if k == 0 {
+        return vec![false; n];
+    }
+
+    let mut heads: Vec<Vec<bool>> = vec![vec![true]; k];
+    let mut tails: Vec<Vec<bool>> = vec![vec![false]; n - k];
+
+    while tails.len() > 1 {
+        let pairs = heads.len().min(tails.len());
+        let remainder = if heads.len() > pairs {
+            heads[pairs..].to_vec()
+        } else {
+            tails[pairs..].to_vec()
+        };
+        
+        heads.truncate(pairs);
+        for (head, tail) in heads.iter_mut().zip(tails.iter()) {
+            head.extend(tail);
+        }
+        
+        tails = remainder;
+    }
+
+    heads.concat().into_iter().chain(tails.concat()).collect()
+}
+
+pub fn step_count(input: &str) -> IResult<&str, usize> {
+    map_res(digit1, |digits: &str| digits.parse::<usize>())(input)
+}
+
+// (3,8) or (3,8,2), hits, steps and rotation, (3,0) has no steps to play
+pub fn euclid_args(input: &str) -> IResult<&str, (usize, usize, usize)> {
+    let comma = || delimited(space0, char(','), space0);
+    let (rest, (hits, steps, rotation)) = delimited(char('('), tuple((step_count, preceded(comma(), step_count), opt(preceded(comma(), step_count)))), char(')'))(input)?;
+    if steps == 0 {
+        return nothing_to_play(input);
+    }
+    Ok((rest, (hits, steps, rotation.unwrap_or(0))))
+}
+
+// e(3,8) or e_inv(3,8), the flag marks the complement
+pub fn euclid_modifier(input: &str) -> IResult<&str, (bool, (usize, usize, usize))> {
+    pair(alt((map(tag("e_inv"), |_| true), map(tag("e"), |_| false))), euclid_args)(input)
+}
+
+// bd:e(3,8) or bd(3,8) -> bd ~ ~ bd ~ ~ bd ~
+pub fn euclidean_event(input: &str) -> IResult<&str, Vec<(&str, Vec<(&str, ParamValue)>)>> {
+    let (rest, (ev, (inverse, (hits, steps, rotation)))) = alt((separated_pair(event, char(':'), euclid_modifier),
+                                                                map(pair(event, euclid_args), |(ev, args)| (ev, (false, args)))))(input)?;
+    let mut distribution = euclidean_hits(hits, steps);
+    distribution.rotate_left(rotation % steps);
+    let expanded = distribution.into_iter()
+        .map(|hit| if hit != inverse { ev.clone() } else { ("~", Vec::new()) })
+        .collect();
+    Ok((rest, expanded))
+}
+
+// SUBDIVISIONS
+// A step can be split up into a group of events in brackets, that share
+// the step evenly, i.e. "bd [sn sn] ~ [hh [hh hh]]" plays two snares in
+// the second step, and a hihat followed by two quicker ones in the last.
+// Here, a group is passed on as a single step (with the bracketed source
+// as its name), the notation splits it up with "subdivide".
+
+fn group_element(input: &str) -> IResult<&str, ()> {
+    alt((map(event_group, |_| ()), map(event_or_expansion, |_| ())))(input)
+}
+
+// [sn [hh hh]], the whole source
+pub fn event_group(input: &str) -> IResult<&str, &str> {
+    recognize(delimited(pair(char('['), many0(char(' '))),
+                        separated_nonempty_list(many1(char(' ')), group_element),
+                        pair(many0(char(' ')), char(']'))))(input)
+}
+
+// the events in a group, with the offset within the step they start at (0..1)
+pub fn subdivide(input: &str) -> IResult<&str, Vec<(f64, (&str, Vec<(&str, ParamValue)>))>> {
+    let element = alt((subdivide, map(event_or_expansion, |evs| evs.into_iter().map(|ev| (0.0, ev)).collect())));
+    let (rest, elements) = delimited(pair(char('['), many0(char(' '))),
+                                     separated_nonempty_list(many1(char(' ')), element),
+                                     pair(many0(char(' ')), char(']')))(input)?;
+
+    // euclidean expansions count as a step each, nested groups are squeezed into theirs
+    let mut steps: Vec<Vec<(f64, (&str, Vec<(&str, ParamValue)>))>> = Vec::new();
+    for element in elements.into_iter() {
+        for (offset, ev) in element.into_iter() {
+            if offset == 0.0 {
+                steps.push(Vec::new());
+            }
+            steps.last_mut().unwrap().push((offset, ev));
+        }
+    }
+
+    let step_count = steps.len() as f64;
+    Ok((rest, steps.into_iter().enumerate().flat_map(|(idx, step)| {
+        step.into_iter().map(move |(offset, ev)| ((idx as f64 + offset) / step_count, ev))
+    }).collect()))
+}
+
+// either a euclidean expansion, a group or a single event
+pub fn event_or_expansion(input: &str) -> IResult<&str, Vec<(&str, Vec<(&str, ParamValue)>)>> {
+    alt((euclidean_event,
+         map(event_group, |group| vec![(group, Vec::new())]),
+         map(choice_group, |group| vec![(group, Vec::new())]),
+         map(chord_group, |group| vec![(group, Vec::new())]),
+         map(event, |ev| vec![ev])))(input)
+}
+
+// CHORDS
+// Events that play together on a step, "{bd hh}" or "bd+hh". Passed
+// on as a single step, the notation splits it up with "chord".
+
+// the events played together
+pub fn chord(input: &str) -> IResult<&str, Vec<(&str, Vec<(&str, ParamValue)>)>> {
+    alt((delimited(pair(char('{'), space0),
+                   separated_nonempty_list(space1, event),
+                   pair(space0, char('}'))),
+         verify(separated_nonempty_list(char('+'), event), |events: &Vec<(&str, Vec<(&str, ParamValue)>)>| events.len() > 1)))(input)
+}
+
+pub fn chord_group(input: &str) -> IResult<&str, &str> {
+    recognize(chord)(input)
+}
+
+// CHOICES
+// Like in Tidal, "[bd|sn|hh]" picks one of the events at random every time
+// its step comes up, "<bd sn hh>" takes one after the other, one per cycle.
+// As with subdivisions, a choice is passed on as a single step (with its
+// source as its name), the notation splits it up with "choice".
+
+/// How a choice picks its event.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChoiceMode {
+    Random,
+    Alternate,
+}
+
+// the events to choose from
+pub fn choice(input: &str) -> IResult<&str, (ChoiceMode, Vec<(&str, Vec<(&str, ParamValue)>)>)> {
+    alt((map(delimited(pair(char('['), space0),
+                       separated_nonempty_list(delimited(space0, char('|'), space0), event),
+                       pair(space0, char(']'))),
+             |events| (ChoiceMode::Random, events)),
+         map(delimited(pair(char('<'), space0),
+                       separated_nonempty_list(space1, event),
+                       pair(space0, char('>'))),
+             |events| (ChoiceMode::Alternate, events))))(input)
+}
+
+pub fn choice_group(input: &str) -> IResult<&str, &str> {
+    recognize(choice)(input)
+}
+
+pub fn event_pattern(input: &str) -> IResult<&str, Vec<(&str, Vec<(&str, ParamValue)>)>> {
+    map(separated_list(many1(char(' ')), event_or_expansion), |steps| steps.concat())(input)
+}
+
+// SEQ GENS
+pub fn pattern_func_name(input: &str) -> IResult<&str, &str> {
+    alt((tag("rnd"), tag("cyc"), tag("learn")))(input)
+}
+
+pub fn param_func_name(input: &str) -> IResult<&str, &str> {
+    alt((tag("bounce"), tag("ramp")))(input)
+}
+
+pub fn func_name(input: &str) -> IResult<&str, &str> {
+    alt((param_func_name, pattern_func_name))(input)
+}
+
+// the generator is optional, "bd ~ sn ~" is the same as "cyc >> bd ~ sn ~"
+pub fn pattern_func(input: &str) -> IResult<&str, (&str, Vec<(&str, Vec<(&str, ParamValue)>)>)> {
+    map(pair(opt(terminated(func_name, delimited(many0(char(' ')), tag(">>"), many0(char(' '))))), event_pattern),
+        |(func, events)| (func.unwrap_or("cyc"), events))(input)
+}
+
+pub fn param_func_header(input: &str) -> IResult<&str, &str> {
+    preceded(tag("@"), param_name)(input)
+}
+
+pub fn param_func(input: &str) -> IResult<&str, (&str, &str)> {
+    separated_pair(param_func_header, delimited(many0(char(' ')), char(':'), many0(char(' '))), func_name)(input)
+}
+
+pub fn param_func_with_values(input: &str) -> IResult<&str, ((&str, &str), Vec<f32>)> {
+    separated_pair(param_func, delimited(many0(char(' ')), tag(">>"), many0(char(' '))), separated_list(many1(char(' ')), float))(input)
+}
+
+pub fn pattern_line(input: &str) -> IResult<&str, ((&str, Vec<(&str, Vec<(&str, ParamValue)>)>), Vec<((&str, &str), Vec<f32>)>)> {
+    separated_pair(pattern_func, many0(char(' ')), separated_list(many1(char(' ')), param_func_with_values))(input)
+}
+
+// LABELS
+// A line can be given a label, so other lines can refer to it,
+// i.e. "drums: cyc >> bd ~ sn ~" (the space after the colon is
+// needed to tell labels from event modifiers)
+pub fn line_label(input: &str) -> IResult<&str, &str> {
+    take_while1(|c: char| c.is_alphanumeric() || c == '_' || c == '-')(input)
+}
+
+// NOTATION PREFIX
+// "%seq" selects the notation the rest of the line is written in
+pub fn notation_prefix(input: &str) -> IResult<&str, &str> {
+    terminated(preceded(char('%'), line_label), many1(char(' ')))(input)
+}
+
+// ENERGY CONDITIONS
+// "energy>0.6" only lets a line play while the global energy macro is above 0.6,
+// "energy<0.3" only while it's below 0.3, the bool marks the former
+pub fn energy_condition(input: &str) -> IResult<&str, (bool, f32)> {
+    terminated(preceded(tag("energy"), pair(alt((map(char('>'), |_| true), map(char('<'), |_| false))), float)), many1(char(' ')))(input)
+}
+
+/// Everything in front of the actual pattern.
+#[derive(Debug, PartialEq)]
+pub struct LineHeader<'a> {
+    /// lighting cue line instead of a sound line
+    pub light: bool,
+    pub label: Option<&'a str>,
+    /// insert effects, in order
+    pub inserts: Vec<&'a str>,
+    pub energy: Option<(bool, f32)>,
+    pub notation: Option<&'a str>,
+    /// steps played per step of the master clock
+    pub speed: Option<f32>,
+}
+
+// CONDITIONAL LINES
+// "if cycle%8<4: bd ~ sn ~ else: bd bd sn [sn sn]", which of the two parts
+// plays is decided at the start of every cycle of the line.
+pub fn conditional_line(input: &str) -> IResult<&str, (Condition, &str, &str)> {
+    let (rest, cond) = delimited(pair(tag("if"), space1), expr::condition, pair(space0, pair(char(':'), space0)))(input)?;
+    // the first "else:" with space around it
+    for (idx, _) in rest.char_indices() {
+        let found: IResult<&str, (&str, &str, &str)> = tuple((space1, tag("else:"), space1))(&rest[idx..]);
+        if let Ok((else_body, _)) = found {
+            return Ok(("", (cond, rest[..idx].trim(), else_body.trim())));
+        }
+    }
+    Err(nom::Err::Error((rest, nom::error::ErrorKind::TakeUntil)))
+}
+
+// SPEED
+// "x2: bd sn" runs the line twice as fast as the master clock, a labeled
+// line gets its speed after the label, i.e. "drums x0.5 [lpf]: bd sn",
+// so labels can't look like a speed ("x2", "x0.5")
+fn line_speed(input: &str) -> IResult<&str, f32> {
+    verify(preceded(char('x'), float), |speed: &f32| *speed > 0.0)(input)
+}
+
+fn looks_like_speed(label: &str) -> bool {
+    let speed: IResult<&str, f32> = all_consuming(preceded(char('x'), float))(label);
+    speed.is_ok()
+}
+
+// i.e. "drums x2 [lpf]: energy>0.5 %seq cyc >> bd ~ sn ~", all parts are optional,
+// "light:" takes the place of the label for lighting cue lines
+pub fn line_header(input: &str) -> IResult<&str, LineHeader<'_>> {
+    let light_prefix = map(terminated(tag("light"), pair(char(':'), many1(char(' ')))), |_| (true, None, None, Vec::new()));
+    let speed_prefix = map(terminated(line_speed, pair(char(':'), many1(char(' ')))), |speed| (false, None, Some(speed), Vec::new()));
+    let label = map(opt(terminated(tuple((verify(line_label, |label: &str| !looks_like_speed(label)), opt(preceded(space1, line_speed)), opt(preceded(space1, insert_chain)))), pair(char(':'), many1(char(' '))))),
+                    |label| match label {
+                        Some((label, speed, inserts)) => (false, Some(label), speed, inserts.unwrap_or_default()),
+                        None => (false, None, None, Vec::new()),
+                    });
+    
+    map(tuple((alt((light_prefix, speed_prefix, label)), opt(energy_condition), opt(notation_prefix))),
+        |((light, label, speed, inserts), energy, notation)| LineHeader { light, label, inserts, energy, notation, speed })(input)
+}
+
+// TAGS
+// Tags are put at the end of a line, i.e. "cyc >> bd ~ sn ~ #drums #loud"
+// (a line starting with '#' is a comment)
+
+// COMMENTS
+// A '#' on its own starts a comment that runs to the end of the line, i.e.
+// "bd ~ sn ~ #drums # the main beat". Tags and sharps ("c#4") aren't comments,
+// as there's no space after (or before) the '#'.
+
+/// The line up to its trailing comment, if any.
+pub fn strip_comment(line: &str) -> &str {
+    let mut previous = ' ';
+    for (idx, c) in line.char_indices() {
+        if c == '#' && previous.is_whitespace() && line[idx + 1..].chars().next().map_or(true, char::is_whitespace) {
+            return line[..idx].trim_end();
+        }
+        previous = c;
+    }
+    line
+}
+
+/// Split trailing tags from a line, only those before its comment count.
+pub fn split_tags(line: &str) -> (&str, Vec<&str>) {
+    let mut body = strip_comment(line).trim_end();
+    let mut tags = Vec::new();
+    
+    while let Some(idx) = body.rfind(" #") {
+        let tag_name = &body[idx + 2..];
+        if tag_name.is_empty() || !tag_name.chars().all(|c: char| c.is_alphanumeric() || c == '_' || c == '-') {
+            break;
+        }
+        
+        tags.insert(0, tag_name);
+        body = body[..idx].trim_end();
+    }
+
+    (body, tags)
+}
+
+pub fn group_tag(input: &str) -> IResult<&str, &str> {
+    preceded(char('#'), line_label)(input)
+}
+
+// "@mute #drums", "@unmute #drums", "@gain #drums 0.5", "@set #drums lp-freq 800"
+pub fn group_command(input: &str) -> IResult<&str, GroupCommand<'_>> {
+    alt((map(preceded(pair(tag("@mute"), space1), group_tag), GroupCommand::Mute),
+         map(preceded(pair(tag("@unmute"), space1), group_tag), GroupCommand::Unmute),
+         map(preceded(pair(tag("@gain"), space1), separated_pair(group_tag, space1, float)),
+             |(tag_name, gain)| GroupCommand::Gain(tag_name, gain)),
+         map(preceded(pair(tag("@set"), space1), tuple((group_tag, preceded(space1, param_name), preceded(space1, float)))),
+             |(tag_name, param, value)| GroupCommand::Set(tag_name, param, value))))(input)
+}
+
+// DIRECTIVES
+// Lines that set the session state instead of defining a pattern,
+// so everything can live in (and be restored from) the text buffer:
+// "@tempo 140" (bpm), "@swing 0.12", "@seed 42", "@scale d minor",
+// "@load kick https://example.com/kick.wav", "@map gamepad0_axis1 energy 0 1",
+// "@follow hats" (the line's envelope becomes the modulation input "env_hats"),
+// "@fill roll" (what lines tagged #fill play before a queued switch),
+// "@ppqn 96" (ticks per beat, the resolution of timing within a step)
+
+#[derive(Debug, PartialEq)]
+pub enum Directive<'a> {
+    Tempo(f32),
+    Swing(f32),
+    Seed(u64),
+    Scale(&'a str, &'a str),
+    Load(&'a str, &'a str),
+    Map(&'a str, &'a str, Option<(f32, f32)>),
+    Follow(&'a str),
+    Fill(&'a str),
+    Ppqn(u32),
+}
+
+pub fn directive(input: &str) -> IResult<&str, Directive<'_>> {
+    alt((map(preceded(pair(tag("@tempo"), space1), float), Directive::Tempo),
+         map(preceded(pair(tag("@swing"), space1), float), Directive::Swing),
+         map(preceded(pair(tag("@seed"), space1), map_res(digit1, |digits: &str| digits.parse::<u64>())), Directive::Seed),
+         map(preceded(pair(tag("@scale"), space1), separated_pair(take_while1(|c: char| c.is_alphanumeric() || c == '#'), space1, line_label)),
+             |(root, mode)| Directive::Scale(root, mode)),
+         map(preceded(pair(tag("@load"), space1), separated_pair(event_name, space1, take_while1(|c: char| !c.is_whitespace()))),
+             |(name, url)| Directive::Load(name, url)),
+         map(preceded(pair(tag("@map"), space1), tuple((line_label,
+                                                         preceded(space1, take_while1(|c: char| !c.is_whitespace())),
+                                                         opt(preceded(space1, separated_pair(float, space1, float)))))),
+             |(id, target, range)| Directive::Map(id, target, range)),
+         map(preceded(pair(tag("@follow"), space1), line_label), Directive::Follow),
+         map(preceded(pair(tag("@fill"), space1), line_label), Directive::Fill),
+         map(preceded(pair(tag("@ppqn"), space1), map_res(digit1, |digits: &str| digits.parse::<u32>())), Directive::Ppqn)))(input)
+}
+
+// CHAINS
+// "A*4 >> B*8 >> A*4" plays the line labeled A four times, then
+// the line labeled B eight times, and so on, to write simple song forms,
+// "A*0" plays nothing
+pub fn chain_segment(input: &str) -> IResult<&str, (&str, usize)> {
+    let (rest, segment) = pair(line_label, map(opt(preceded(char('*'), step_count)), |repeat| repeat.unwrap_or(1)))(input)?;
+    if segment.1 == 0 {
+        return nothing_to_play(input);
+    }
+    Ok((rest, segment))
+}
+
+pub fn sequence_chain(input: &str) -> IResult<&str, Vec<(&str, usize)>> {
+    // "cyc >> bd" is a regular pattern, not a chain
+    verify(separated_nonempty_list(delimited(many0(char(' ')), tag(">>"), many0(char(' '))), chain_segment),
+           |segments: &Vec<(&str, usize)>| segments.len() > 1 && func_name(segments[0].0) != Ok(("", segments[0].0)))(input)
+}
+
+// STEP PROBABILITIES
+// "prob(drums): 1 1 .8 .6" assigns a trigger probability to
+// each step of the line labeled "drums"
+pub fn step_probabilities(input: &str) -> IResult<&str, (&str, Vec<f32>)> {
+    separated_pair(delimited(tag("prob("), line_label, char(')')),
+                   delimited(many0(char(' ')), char(':'), many0(char(' '))),
+                   separated_list(many1(char(' ')), float))(input)
+}
+
+// VELOCITY CONTOURS
+// "humanize(drums): 0.2" lets the level of the line labeled "drums" drift by up to 20%
+pub fn velocity_contour(input: &str) -> IResult<&str, (&str, f32)> {
+    separated_pair(delimited(tag("humanize("), line_label, char(')')),
+                   delimited(many0(char(' ')), char(':'), many0(char(' '))),
+                   float)(input)
+}
+
+// GHOST NOTES
+// "ghost(drums, 0.3)" fills about 30% of the empty steps of the line labeled
+// "drums" with quiet hits of its own sounds, placed anew every cycle
+pub fn ghost_notes(input: &str) -> IResult<&str, (&str, f32)> {
+    delimited(tag("ghost("),
+              separated_pair(line_label, delimited(many0(char(' ')), char(','), many0(char(' '))), float),
+              char(')'))(input)
+}
+
+// GATES
+// "gate(pads): 1 0 1 1 0 1 0 1" opens and closes the line labeled "pads"
+// in time, one level per step, "gate(pads): square" does it every other step,
+// "gate(pads): pump" ducks it on every beat (with an optional depth, i.e. "pump 0.6")
+#[derive(Debug, Clone, PartialEq)]
+pub enum GatePattern {
+    /// one level (0..1) per step
+    Steps(Vec<f32>),
+    /// saw-down sidechain pump on every beat, with the given depth
+    Pump(f32),
+}
+
+pub fn gate_pattern(input: &str) -> IResult<&str, (&str, GatePattern)> {
+    separated_pair(delimited(tag("gate("), line_label, char(')')),
+                   delimited(many0(char(' ')), char(':'), many0(char(' '))),
+                   alt((map(preceded(tag("pump"), opt(preceded(many1(char(' ')), float))),
+                            |depth| GatePattern::Pump(depth.unwrap_or(1.0))),
+                        map(tag("square"), |_| GatePattern::Steps(vec![1.0, 0.0])),
+                        map(separated_nonempty_list(many1(char(' ')), float), GatePattern::Steps))))(input)
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+        
+    #[test]
+    fn test_pattern_func() {
+        let res = pattern_func("rnd >> bd ~ ~ sn ~ ~");       
+        println!("Result: {:?}", res);
+        assert!(!res.is_err());
+    }
+
+    #[test]
+    fn test_pattern_func_without_generator() {
+        let res = pattern_func("bd ~ sn ~");
+        assert_eq!(res, Ok(("", ("cyc", vec![("bd", vec![]), ("~", vec![]), ("sn", vec![]), ("~", vec![])]))));
+    }
+
+    #[test]
+    fn test_pattern_line_without_params() {
+        let res = pattern_line("rnd >> bd ~ ~ sn ~ ~");
+        println!("Result: {:?}", res);        
+        assert!(!res.is_err());
+    }
+
+    #[test]
+    fn test_pattern_line_with_one_param() {
+        let res = pattern_line("rnd >> bd ~ ~ ~ sn ~ ~ ~ @rate: cyc >> 1.0 0.9 0.6 0.4");
+        println!("Result: {:?}", res);        
+        assert!(!res.is_err());
+    }
+
+    #[test]
+    fn test_ramp_param() {
+        let res = event("sine;pos=-1..1;azi=0.5..-90;lvl=0.5");
+        assert_eq!(res, Ok(("", ("sine", vec![("pos", ParamValue::Ramp(-1.0, 1.0)),
+                                             ("azi", ParamValue::Ramp(0.5, -90.0)),
+                                             ("lvl", ParamValue::Const(0.5))]))));
+    }
+
+    #[test]
+    fn test_stutter_event() {
+        let res = event("stutter;len=0.0625;rep=8");
+        assert_eq!(res, Ok(("", ("stutter", vec![("len", ParamValue::Const(0.0625)),
+                                                ("rep", ParamValue::Const(8.0))]))));
+    }
+
+    #[test]
+    fn test_colon_params() {
+        let res = event_pattern("bd:gain=0.7:rate=1.5 sine:freq=440 hh:fit_key bd:e(2,4)");
+        assert_eq!(res, Ok(("", vec![("bd", vec![("lvl", ParamValue::Const(0.7)), ("rate", ParamValue::Const(1.5))]),
+                                     ("sine", vec![("freq", ParamValue::Const(440.0))]),
+                                     ("hh", vec![("fit_key", ParamValue::Const(1.0))]),
+                                     ("bd", vec![]), ("~", vec![]), ("bd", vec![]), ("~", vec![])])));
+    }
+
+    #[test]
+    fn test_conditions() {
+        let res = event_pattern("bd?0.25 hh? sn!4;lvl=0.5 bd?0.5(2,4)");
+        assert_eq!(res, Ok(("", vec![("bd", vec![(CHANCE_PARAM, ParamValue::Const(0.25))]),
+                                     ("hh", vec![(CHANCE_PARAM, ParamValue::Const(0.5))]),
+                                     ("sn", vec![(EVERY_PARAM, ParamValue::Const(4.0)), ("lvl", ParamValue::Const(0.5))]),
+                                     ("bd", vec![(CHANCE_PARAM, ParamValue::Const(0.5))]), ("~", vec![]),
+                                     ("bd", vec![(CHANCE_PARAM, ParamValue::Const(0.5))]), ("~", vec![])])));
+    }
+
+    #[test]
+    fn test_flag() {
+        let res = event("drone:loop:sus=8");
+        assert_eq!(res, Ok(("", ("drone", vec![("loop", ParamValue::Const(1.0)), ("sus", ParamValue::Const(8.0))]))));
+
+        let res = event("pad;fit_key;lvl=0.5");
+        assert_eq!(res, Ok(("", ("pad", vec![("fit_key", ParamValue::Const(1.0)),
+                                            ("lvl", ParamValue::Const(0.5))]))));
+    }
+
+    #[test]
+    fn test_mod_param() {
+        let res = event("saw;lp-freq=mod(tilt_x, 200, 4000);lvl=0.5");
+        assert_eq!(res, Ok(("", ("saw", vec![("lp-freq", ParamValue::Mod("tilt_x".to_string(), 200.0, 4000.0)),
+                                            ("lvl", ParamValue::Const(0.5))]))));
+    }
+
+    #[test]
+    fn test_expr_param() {
+        let (rest, (name, params)) = event("saw;lp-freq=200+($bar%8)*250;lvl=0.5+hh").unwrap();
+        assert_eq!(name, "saw");
+        assert!(matches!(params[0], ("lp-freq", ParamValue::Expr(_))));
+        assert_eq!(params[1], ("lvl", ParamValue::Const(0.5)));
+        assert_eq!(rest, "+hh");
+    }
+
+    #[test]
+    fn test_euclidean_hits() {
+        let hits: Vec<bool> = "x..x..x.".chars().map(|c| c == 'x').collect();
+        assert_eq!(euclidean_hits(3, 8), hits);
+
+        let hits: Vec<bool> = "x.xx.xx.".chars().map(|c| c == 'x').collect();
+        assert_eq!(euclidean_hits(5, 8), hits);
+
+        assert_eq!(euclidean_hits(0, 4), vec![false; 4]);
+        assert_eq!(euclidean_hits(5, 4), vec![true; 4]);
+    }
+
+    #[test]
+    fn test_euclidean_complement() {
+        let (_, plain) = event_pattern("bd:e(3,8)").unwrap();
+        let (_, inverse) = event_pattern("hh;lvl=0.5:e_inv(3,8)").unwrap();
+        
+        assert_eq!(plain.len(), 8);
+        assert_eq!(inverse.len(), 8);
+
+        for (a, b) in plain.iter().zip(inverse.iter()) {
+            // exactly one of both voices sounds on each step
+            assert!((a.0 == "~") != (b.0 == "~"));
+        }
+
+        assert_eq!(inverse[1], ("hh", vec![("lvl", ParamValue::Const(0.5))]));
+
+        // no steps, nothing to play
+        assert_eq!(event_pattern("bd:e(3,0)"), Err(nom::Err::Failure(("(3,0)", ErrorKind::Verify))));
+        assert!(matches!(event_pattern("bd ~ hh:e_inv(3,0)"), Err(nom::Err::Failure(_))));
+    }
+
+    #[test]
+    fn test_tidal_euclid() {
+        let (_, plain) = event_pattern("bd(3,8)").unwrap();
+        let (_, modifier) = event_pattern("bd:e(3,8)").unwrap();
+        assert_eq!(plain, modifier);
+
+        // x.x.x.x.x.x.x.x... rotated by two
+        let (_, rotated) = event_pattern("hh;lvl=0.5(5,16,2)").unwrap();
+        let hits: Vec<bool> = rotated.iter().map(|ev| ev.0 == "hh").collect();
+        let mut expected = euclidean_hits(5, 16);
+        expected.rotate_left(2);
+        assert_eq!(hits, expected);
+        assert_eq!(rotated.len(), 16);
+
+        assert_eq!(event_pattern("bd(0,0)"), Err(nom::Err::Failure(("(0,0)", ErrorKind::Verify))));
+    }
+
+    #[test]
+    fn test_pattern_line_with_euclidean_events() {
+        let (rest, ((generator, events), params)) = pattern_line("cyc >> bd:e(3,8) sn ~ @rate: cyc >> 1.0 0.9").unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(generator, "cyc");
+        assert_eq!(events.len(), 10);
+        assert_eq!(params.len(), 1);
+    }
+
+    #[test]
+    fn test_subdivisions() {
+        let (_, steps) = event_pattern("bd [sn sn] ~ [hh [hh;lvl=0.5 hh]]").unwrap();
+        assert_eq!(steps.len(), 4);
+        assert_eq!(steps[1], ("[sn sn]", vec![]));
+        assert_eq!(steps[3].0, "[hh [hh;lvl=0.5 hh]]");
+
+        let (_, events) = subdivide(steps[3].0).unwrap();
+        let offsets: Vec<f64> = events.iter().map(|(offset, _)| *offset).collect();
+        assert_eq!(offsets, vec![0.0, 0.5, 0.75]);
+        assert_eq!(events[1].1, ("hh", vec![("lvl", ParamValue::Const(0.5))]));
+
+        // euclidean expansions in groups are subdivided, too
+        let (_, events) = subdivide("[bd:e(3,8)]").unwrap();
+        assert_eq!(events.len(), 8);
+        assert_eq!(events[3], (0.375, ("bd", vec![])));
+    }
+
+    #[test]
+    fn test_choices() {
+        let (_, steps) = event_pattern("[bd|sn;lvl=0.5|hh] ~ <bd sn> [sn <hh cp>]").unwrap();
+        assert_eq!(steps.len(), 4);
+        assert_eq!(steps[0].0, "[bd|sn;lvl=0.5|hh]");
+        assert_eq!(steps[2].0, "<bd sn>");
+
+        let (_, (mode, events)) = choice(steps[0].0).unwrap();
+        assert_eq!(mode, ChoiceMode::Random);
+        assert_eq!(events[1], ("sn", vec![("lvl", ParamValue::Const(0.5))]));
+
+        let (_, (mode, events)) = choice(steps[2].0).unwrap();
+        assert_eq!(mode, ChoiceMode::Alternate);
+        assert_eq!(events.len(), 2);
+
+        // choices share a step like any other event
+        let (_, events) = subdivide(steps[3].0).unwrap();
+        assert_eq!(events[1], (0.5, ("<hh cp>", vec![])));
+
+        assert!(choice("[bd sn]").is_err());
+    }
+
+    #[test]
+    fn test_chords() {
+        let (_, steps) = event_pattern("{bd hh} sn+hh;lvl=0.5 [~ bd+sn]").unwrap();
+        assert_eq!(steps.len(), 3);
+        assert_eq!(steps[0].0, "{bd hh}");
+        assert_eq!(steps[1].0, "sn+hh;lvl=0.5");
+
+        let (_, events) = chord(steps[1].0).unwrap();
+        assert_eq!(events, vec![("sn", vec![]), ("hh", vec![("lvl", ParamValue::Const(0.5))])]);
+
+        let (_, events) = subdivide(steps[2].0).unwrap();
+        assert_eq!(events[1], (0.5, ("bd+sn", vec![])));
+
+        // a single event isn't a chord
+        assert!(chord("bd").is_err());
+    }
+
+    #[test]
+    fn test_line_header() {
+        let (rest, header) = line_header("drums: cyc >> bd ~ sn ~").unwrap();
+        assert_eq!(header, LineHeader { light: false, label: Some("drums"), inserts: vec![], energy: None, notation: None, speed: None });
+        assert_eq!(rest, "cyc >> bd ~ sn ~");
+        
+        let (rest, header) = line_header("cyc >> bd ~ sn ~").unwrap();
+        assert_eq!(header, LineHeader { light: false, label: None, inserts: vec![], energy: None, notation: None, speed: None });
+        assert_eq!(rest, "cyc >> bd ~ sn ~");
+
+        let (rest, header) = line_header("drums: %steps x..x").unwrap();
+        assert_eq!(header, LineHeader { light: false, label: Some("drums"), inserts: vec![], energy: None, notation: Some("steps"), speed: None });
+        assert_eq!(rest, "x..x");
+
+        let (rest, header) = line_header("drums [crush lpf comp]: bd ~ sn ~").unwrap();
+        assert_eq!(header, LineHeader { light: false, label: Some("drums"), inserts: vec!["crush", "lpf", "comp"], energy: None, notation: None, speed: None });
+        assert_eq!(rest, "bd ~ sn ~");
+
+        assert_eq!(param_name("lpf.lp-freq"), Ok(("", "lpf.lp-freq")));
+        assert_eq!(param_name("comp.thresh"), Ok(("", "comp.thresh")));
+        assert_eq!(param_name("lofi.age"), Ok(("", "lofi.age")));
+        assert_eq!(param_name("freeze.smear"), Ok(("", "freeze.smear")));
+        assert_eq!(param_name("pitch.shift"), Ok(("", "pitch.shift")));
+        assert_eq!(param_name("crush.os"), Ok(("", "crush.os")));
+        assert_eq!(param_name("rev.lp-freq"), Ok((".lp-freq", "rev")));
+
+        let (rest, header) = line_header("bd:e(3,8) sn").unwrap();
+        assert_eq!(header, LineHeader { light: false, label: None, inserts: vec![], energy: None, notation: None, speed: None });
+        assert_eq!(rest, "bd:e(3,8) sn");
+    }
+
+    #[test]
+    fn test_energy_condition() {
+        let (rest, header) = line_header("pads: energy>0.6 cyc >> saw ~").unwrap();
+        assert_eq!(header, LineHeader { light: false, label: Some("pads"), inserts: vec![], energy: Some((true, 0.6)), notation: None, speed: None });
+        assert_eq!(rest, "cyc >> saw ~");
+
+        let (rest, header) = line_header("energy<0.3 %seq bd ~").unwrap();
+        assert_eq!(header, LineHeader { light: false, label: None, inserts: vec![], energy: Some((false, 0.3)), notation: Some("seq"), speed: None });
+        assert_eq!(rest, "bd ~");
+    }
+
+    #[test]
+    fn test_line_speed() {
+        let (rest, header) = line_header("x2: bd sn").unwrap();
+        assert_eq!(header, LineHeader { light: false, label: None, inserts: vec![], energy: None, notation: None, speed: Some(2.0) });
+        assert_eq!(rest, "bd sn");
+
+        let (rest, header) = line_header("drums x0.5 [lpf]: bd sn").unwrap();
+        assert_eq!(header, LineHeader { light: false, label: Some("drums"), inserts: vec!["lpf"], energy: None, notation: None, speed: Some(0.5) });
+        assert_eq!(rest, "bd sn");
+
+        // labels that just start like a speed
+        assert_eq!(line_header("xylo: bd sn").unwrap().1.label, Some("xylo"));
+        assert_eq!(line_header("x2b: bd sn").unwrap().1.label, Some("x2b"));
+
+        // but not a speed, valid or not
+        let (rest, header) = line_header("x0: bd sn").unwrap();
+        assert_eq!(header.label, None);
+        assert_eq!(rest, "x0: bd sn");
+        assert_eq!(line_header("x2 x3: bd sn").unwrap().1.label, None);
+    }
+
+    #[test]
+    fn test_light_header() {
+        let (rest, header) = line_header("light: energy>0.2 cyc >> 1;val=255 ~").unwrap();
+        assert_eq!(header, LineHeader { light: true, label: None, inserts: vec![], energy: Some((true, 0.2)), notation: None, speed: None });
+        assert_eq!(rest, "cyc >> 1;val=255 ~");
+    }
+
+    #[test]
+    fn test_split_tags() {
+        assert_eq!(split_tags("cyc >> bd ~ sn ~ #drums #loud"), ("cyc >> bd ~ sn ~", vec!["drums", "loud"]));
+        assert_eq!(split_tags("cyc >> bd ~ sn ~"), ("cyc >> bd ~ sn ~", vec![]));
+
+        // words of a comment aren't tags
+        assert_eq!(split_tags("bd ~ sn ~ #drums # the #main beat"), ("bd ~ sn ~", vec!["drums"]));
+        assert_eq!(split_tags("bd ~ sn ~ # the #main"), ("bd ~ sn ~", vec![]));
+    }
+
+    #[test]
+    fn test_strip_comment() {
+        assert_eq!(strip_comment("bd ~ sn ~ #drums # the main beat"), "bd ~ sn ~ #drums");
+        assert_eq!(strip_comment("bd ~ sn ~ #"), "bd ~ sn ~");
+        assert_eq!(strip_comment("# a whole line"), "");
+        assert_eq!(strip_comment("@scale c# minor"), "@scale c# minor");
+        assert_eq!(strip_comment("saw:c#4 ~ #bass"), "saw:c#4 ~ #bass");
+    }
+
+    #[test]
+    fn test_group_command() {
+        assert_eq!(group_command("@mute #drums"), Ok(("", GroupCommand::Mute("drums"))));
+        assert_eq!(group_command("@unmute #drums"), Ok(("", GroupCommand::Unmute("drums"))));
+        assert_eq!(group_command("@gain #pads 0.5"), Ok(("", GroupCommand::Gain("pads", 0.5))));
+        assert_eq!(group_command("@set #pads lp-freq 800"), Ok(("", GroupCommand::Set("pads", "lp-freq", 800.0))));
+        assert!(group_command("@rate: cyc >> 1.0").is_err());
+    }
+
+    #[test]
+    fn test_directive() {
+        assert_eq!(directive("@tempo 140"), Ok(("", Directive::Tempo(140.0))));
+        assert_eq!(directive("@swing 0.12"), Ok(("", Directive::Swing(0.12))));
+        assert_eq!(directive("@ppqn 480"), Ok(("", Directive::Ppqn(480))));
+        assert_eq!(directive("@seed 42"), Ok(("", Directive::Seed(42))));
+        assert_eq!(directive("@scale d minor"), Ok(("", Directive::Scale("d", "minor"))));
+        assert_eq!(directive("@scale f# dorian"), Ok(("", Directive::Scale("f#", "dorian"))));
+        assert_eq!(directive("@load kick https://example.com/kick.wav"), Ok(("", Directive::Load("kick", "https://example.com/kick.wav"))));
+        assert_eq!(directive("@map gamepad0_axis1 energy"), Ok(("", Directive::Map("gamepad0_axis1", "energy", None))));
+        assert_eq!(directive("@map gamepad0_axis0 param:pads:lp-freq 200 4000"), Ok(("", Directive::Map("gamepad0_axis0", "param:pads:lp-freq", Some((200.0, 4000.0))))));
+        assert_eq!(directive("@follow hats"), Ok(("", Directive::Follow("hats"))));
+        assert_eq!(directive("@fill roll"), Ok(("", Directive::Fill("roll"))));
+        assert!(directive("@tempo: cyc >> 1.0").is_err());
+    }
+
+    #[test]
+    fn test_sequence_chain() {
+        assert_eq!(sequence_chain("A*4 >> B*8 >> A"), Ok(("", vec![("A", 4), ("B", 8), ("A", 1)])));
+        assert!(sequence_chain("cyc >> bd sn").is_err());
+        assert!(sequence_chain("verse*2").is_err());
+        assert_eq!(sequence_chain("A*4 >> B*0"), Err(nom::Err::Failure(("B*0", ErrorKind::Verify))));
+    }
+
+    #[test]
+    fn test_step_probabilities() {
+        assert_eq!(step_probabilities("prob(drums): 1 1 .8 .6 1 .4"), Ok(("", ("drums", vec![1.0, 1.0, 0.8, 0.6, 1.0, 0.4]))));
+    }
+
+    #[test]
+    fn test_velocity_contour() {
+        assert_eq!(velocity_contour("humanize(drums): 0.2"), Ok(("", ("drums", 0.2))));
+        assert!(velocity_contour("humanize(drums):").is_err());
+    }
+
+    #[test]
+    fn test_ghost_notes() {
+        assert_eq!(ghost_notes("ghost(drums, 0.3)"), Ok(("", ("drums", 0.3))));
+        assert_eq!(ghost_notes("ghost(drums,.5)"), Ok(("", ("drums", 0.5))));
+        assert!(ghost_notes("ghost(drums)").is_err());
+    }
+
+    #[test]
+    fn test_gate_pattern() {
+        let res = gate_pattern("gate(pads): 1 0 1 1 0 1 0 1");
+        assert_eq!(res, Ok(("", ("pads", GatePattern::Steps(vec![1.0, 0.0, 1.0, 1.0, 0.0, 1.0, 0.0, 1.0])))));
+
+        assert_eq!(gate_pattern("gate(pads): pump 0.6"), Ok(("", ("pads", GatePattern::Pump(0.6)))));
+        assert_eq!(gate_pattern("gate(pads): pump"), Ok(("", ("pads", GatePattern::Pump(1.0)))));
+        assert_eq!(gate_pattern("gate(pads): square"), Ok(("", ("pads", GatePattern::Steps(vec![1.0, 0.0])))));
+    }
+
+    #[test]
+    fn test_conditional_line() {
+        let (_, (cond, then_body, else_body)) = conditional_line("if cycle%8<4: bd ~ sn ~ else: bd bd sn [sn sn]").unwrap();
+        assert_eq!(cond.comparison, expr::Comparison::Less);
+        assert_eq!(then_body, "bd ~ sn ~");
+        assert_eq!(else_body, "bd bd sn [sn sn]");
+
+        // any space around the else
+        let (_, (_, then_body, else_body)) = conditional_line("if cycle%2==0: bd ~ sn ~\telse:  hh").unwrap();
+        assert_eq!(then_body, "bd ~ sn ~");
+        assert_eq!(else_body, "hh");
+        assert!(conditional_line("if cycle%2==0: bd ~ sn~else: hh").is_err());
+
+        // both parts are needed
+        assert!(conditional_line("if cycle%2==0: bd ~ sn ~").is_err());
+        assert!(conditional_line("iffy bd ~").is_err());
+    }
+
+    #[test]
+    fn test_param_func() {
+        let res = param_func_with_values("@rate: rnd >> 1.0 0.9 0.6 0.4");
+        println!("Result: {:?}", res);
+        assert!(!res.is_err());
+    }
+
+    #[test]
+    fn test_param_func_header() {
+        let res = param_func_header("@rate");
+        println!("Result: {:?}", res);
+        assert!(!res.is_err());
+    }
+}
+
+
@@ -0,0 +1,165 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::notation::PatternEvent;
+
+/// Event names that are handled by a synth and don't need a sample.
+pub const SYNTH_SOURCES: &[&str] = &["sine", "saw", "sqr", "~"];
+
+/// Event names that trigger an effect instead of a sound.
+pub const EFFECT_EVENTS: &[&str] = &["stutter"];
+
+/// Splits the name of an onset slice, i.e. "break/onset3", the fourth transient
+/// of "break" up to the next one, into sample name and onset index.
+pub fn onset_slice(name: &str) -> Option<(&str, usize)> {
+    let (sample, slice) = name.split_once('/')?;
+    let onset = slice.strip_prefix("onset")?.parse().ok()?;
+    Some((sample, onset))
+}
+
+/// Something that looks wrong in the input, but doesn't keep it from being evaluated.
+#[derive(Debug, PartialEq)]
+pub struct EvaluationWarning {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for EvaluationWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}: {}", self.line, self.column, self.message)
+    }
+}
+
+/// The names of all samples loaded in the sampler.
+pub struct SampleRegistry {
+    samples: HashSet<String>,
+    /// where samples loaded from the text were fetched from
+    sources: HashMap<String, String>,
+}
+
+impl SampleRegistry {
+    pub fn new() -> Self {
+        SampleRegistry {
+            samples: HashSet::new(),
+            sources: HashMap::new(),
+        }
+    }
+
+    /// Note that a sample is to be loaded from a url, so it counts as known while
+    /// it's loading. Returns false if it's already been requested from the same url.
+    pub fn request_load(&mut self, name: &str, url: &str) -> bool {
+        self.samples.insert(name.to_string());
+        self.sources.insert(name.to_string(), url.to_string()).as_deref() != Some(url)
+    }
+
+    pub fn register(&mut self, name: String) {
+        self.samples.insert(name);
+    }
+
+    /// Forget an unloaded sample, so it can be loaded again from the same url.
+    pub fn unregister(&mut self, name: &str) {
+        self.samples.remove(name);
+        self.sources.remove(name);
+    }
+
+    /// Check whether an event name refers to a loaded sample (or a slice of it),
+    /// a synth or an effect.
+    pub fn resolves(&self, name: &str) -> bool {
+        let name = onset_slice(name).map_or(name, |(sample, _)| sample);
+        SYNTH_SOURCES.contains(&name) || EFFECT_EVENTS.contains(&name) || self.samples.contains(name)
+    }
+
+    /// Find the events in a parsed line that don't resolve to anything.
+    /// The offset is where the pattern starts within the line.
+    pub fn unresolved_events(&self, line_number: usize, offset: usize, events: &[PatternEvent]) -> Vec<EvaluationWarning> {
+        let mut warnings: Vec<EvaluationWarning> = Vec::new();
+        
+        for ev in events.iter().filter(|ev| !self.resolves(&ev.name)) {
+            let column = offset + ev.position + 1;
+            
+            // expanded events (i.e. euclidean) share the same position
+            if !warnings.iter().any(|warning| warning.column == column) {
+                warnings.push(EvaluationWarning {
+                    line: line_number,
+                    column,
+                    message: format!("unknown sample '{}'", ev.name),
+                });
+            }
+        }
+
+        warnings
+    }
+}
+
+// TEST TEST TEST 
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::notation::{PatternParser, SeqNotation};
+
+    #[test]
+    fn test_resolves() {
+        let mut registry = SampleRegistry::new();
+        registry.register("bd".to_string());
+        
+        assert!(registry.resolves("bd"));
+        assert!(registry.resolves("sine"));
+        assert!(registry.resolves("~"));
+        assert!(!registry.resolves("kick"));
+    }
+
+    #[test]
+    fn test_onset_slices() {
+        let mut registry = SampleRegistry::new();
+        registry.register("break".to_string());
+
+        assert_eq!(onset_slice("break/onset3"), Some(("break", 3)));
+        assert_eq!(onset_slice("break"), None);
+        assert_eq!(onset_slice("break/slice3"), None);
+        assert!(registry.resolves("break/onset0"));
+        assert!(!registry.resolves("amen/onset0"));
+
+        let pattern = SeqNotation.parse("cyc >> break/onset2 ~ break/onset0").unwrap();
+        assert_eq!(pattern.events[0].name, "break/onset2");
+        assert!(registry.unresolved_events(1, 0, &pattern.events).is_empty());
+    }
+
+    #[test]
+    fn test_request_load() {
+        let mut registry = SampleRegistry::new();
+        
+        assert!(registry.request_load("kick", "https://example.com/kick.wav"));
+        assert!(registry.resolves("kick"));
+        assert!(!registry.request_load("kick", "https://example.com/kick.wav"));
+        assert!(registry.request_load("kick", "https://example.com/kick2.wav"));
+    }
+
+    #[test]
+    fn test_unregister() {
+        let mut registry = SampleRegistry::new();
+        
+        registry.request_load("kick", "https://example.com/kick.wav");
+        registry.unregister("kick");
+        assert!(!registry.resolves("kick"));
+        assert!(registry.request_load("kick", "https://example.com/kick.wav"));
+    }
+
+    #[test]
+    fn test_unresolved_events() {
+        let mut registry = SampleRegistry::new();
+        registry.register("bd".to_string());
+        registry.register("sn".to_string());
+
+        let line = "  cyc >> bd kick:e(3,8) sn snare";
+        let pattern = SeqNotation.parse(line.trim()).unwrap();
+        
+        let warnings = registry.unresolved_events(3, 2, &pattern.events);
+        
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(warnings[0], EvaluationWarning { line: 3, column: 13, message: "unknown sample 'kick'".to_string() });
+        assert_eq!(warnings[1].column, 28);
+        assert_eq!(format!("{}", warnings[1]), "line 3, column 28: unknown sample 'snare'");
+    }
+}
@@ -0,0 +1,107 @@
+// SCALES
+// "@scale d minor" sets the scale the "deg" parameter refers to,
+// so "saw;deg=2" plays the third degree of the scale.
+
+/// A musical scale, starting at a root note.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scale {
+    /// midi note number of the root
+    root: i32,
+    /// semitones above the root
+    intervals: Vec<i32>,
+}
+
+fn root_note(name: &str) -> Option<i32> {
+    let mut chars = name.chars();
+    
+    let base = match chars.next()?.to_ascii_lowercase() {
+        'c' => 60,
+        'd' => 62,
+        'e' => 64,
+        'f' => 65,
+        'g' => 67,
+        'a' => 69,
+        'b' => 71,
+        _ => return None,
+    };
+
+    match chars.as_str() {
+        "" => Some(base),
+        "#" | "s" => Some(base + 1),
+        "b" => Some(base - 1),
+        _ => None,
+    }
+}
+
+fn mode_intervals(name: &str) -> Option<Vec<i32>> {
+    let intervals = match name {
+        "major" | "ionian" => vec![0, 2, 4, 5, 7, 9, 11],
+        "minor" | "aeolian" => vec![0, 2, 3, 5, 7, 8, 10],
+        "dorian" => vec![0, 2, 3, 5, 7, 9, 10],
+        "phrygian" => vec![0, 1, 3, 5, 7, 8, 10],
+        "lydian" => vec![0, 2, 4, 6, 7, 9, 11],
+        "mixolydian" => vec![0, 2, 4, 5, 7, 9, 10],
+        "locrian" => vec![0, 1, 3, 5, 6, 8, 10],
+        "pentatonic" => vec![0, 2, 4, 7, 9],
+        "chromatic" => (0..12).collect(),
+        _ => return None,
+    };
+
+    Some(intervals)
+}
+
+impl Scale {
+    pub fn new(root: &str, mode: &str) -> Option<Self> {
+        Some(Scale {
+            root: root_note(root)?,
+            intervals: mode_intervals(mode)?,
+        })
+    }
+    
+    /// Midi note number of the root.
+    pub fn root(&self) -> i32 {
+        self.root
+    }
+
+    /// Frequency of a scale degree (starting at 0), degrees beyond the
+    /// scale continue in the next octave (or the one below).
+    pub fn degree_to_freq(&self, degree: f32) -> f32 {
+        let degree = degree.round() as i32;
+        let len = self.intervals.len() as i32;
+        let note = self.root + 12 * degree.div_euclid(len) + self.intervals[degree.rem_euclid(len) as usize];
+        
+        440.0 * 2.0_f32.powf((note - 69) as f32 / 12.0)
+    }
+}
+
+impl Default for Scale {
+    fn default() -> Self {
+        Scale::new("c", "major").unwrap()
+    }
+}
+
+// TEST TEST TEST 
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_degrees() {
+        let scale = Scale::new("a", "minor").unwrap();
+        
+        assert!((scale.degree_to_freq(0.0) - 440.0).abs() < 0.01);
+        assert!((scale.degree_to_freq(2.0) - 523.25).abs() < 0.01);
+        assert!((scale.degree_to_freq(7.0) - 880.0).abs() < 0.01);
+        assert!((scale.degree_to_freq(-7.0) - 220.0).abs() < 0.01);
+        assert!((scale.degree_to_freq(-1.0) - 392.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_names() {
+        assert_eq!(Scale::new("c#", "major").unwrap().root, 61);
+        assert_eq!(Scale::new("Eb", "dorian").unwrap().root, 63);
+        assert!(Scale::new("h", "major").is_none());
+        assert!(Scale::new("c", "blues-ish").is_none());
+    }
+}
@@ -0,0 +1,1881 @@
+use std::fmt;
+use std::collections::{HashMap, HashSet};
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+
+use crate::{Clock, EventSink};
+use crate::{chop, fills, flood, hook, instruments, light, matching, notation, parser, samples, soundfont};
+use crate::samples::{SampleRegistry, EvaluationWarning};
+use crate::groups::{GroupState, default_level};
+use crate::scale::Scale;
+use crate::timecode::Timecode;
+use crate::light::LightCue;
+use crate::controllers::{ControllerMap, ControlTarget};
+use crate::humanize::VelocityContour;
+use crate::groove::{LogicalClock, Swing, swing_offset};
+use crate::batch::{EventBatch, EventClass, EventOrder};
+use crate::hook::{SoundEvent, TickHook};
+use crate::sequence::{EventSequence, STUTTER_EVENT};
+use crate::stats::SessionStats;
+use crate::meter::{EvalQuant, Meter};
+use crate::fills::FillStyle;
+use crate::chop::ChopStyle;
+use crate::flood::FloodGuard;
+use crate::expr::Counters;
+use crate::parser::{Directive, GatePattern};
+use crate::notation::{Pattern, PatternEvent, ParserRegistry};
+use crate::soundfont::SoundFont;
+use crate::instruments::Region;
+
+// SCHEDULER
+// A simple time-recursion event scheduler, waking up at a fixed time interval
+// to schedule the steps that are due before the next wakeup. What it needs from
+// its surroundings comes in through a Clock (to be woken up) and an EventSink (to
+// play the sound events and take the messages). In the browser, that's the web
+// worker (see worker.rs in ruffbox-pattern), natively the audio callback (see
+// ruffbox-native). The times passed to it are in milliseconds on the clock's
+// time line (the browser's performance.now()), the timestamps of the events in
+// seconds on the audio time line (AudioContext.currentTime).
+
+/// Where a step is, all but the beat counted since the start, beat within the bar.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Position {
+    pub bar: u32,
+    pub beat: u32,
+    pub step: u32,
+    pub tick: u64,
+}
+
+/// What the scheduler has to tell, besides the sound events the sink takes.
+/// In the browser, they're posted as messages of the type in brackets, or
+/// passed to the callbacks for them.
+pub enum Message {
+    /// the sound events of a step the sink didn't take (events)
+    Events(EventBatch),
+    /// (warnings)
+    Warnings(Vec<String>),
+    /// the syntax errors of a queued evaluation (errors)
+    Errors(Vec<ParseError>),
+    /// of the step played at the timestamp (timecode)
+    Timecode { timecode: Timecode, timestamp: f64 },
+    /// a parameter of the master bus, from a controller (master_param)
+    MasterParam { name: String, value: f32 },
+    /// a sample to be fetched and registered (load_sample)
+    LoadSample { sample_id: String, url: String },
+    /// record the master into a sample, duration in seconds (resample_master)
+    ResampleMaster { name: String, timestamp: f64, duration: f64 },
+    /// (take_start, take_stop)
+    TakeStart { name: String, bus: u32, timestamp: f64 },
+    TakeStop { name: String, timestamp: f64 },
+    /// a line playing the slices of a sample, for the editor (chop)
+    Chop { line: String },
+    /// the key zones of an instrument, by sample id, the sample of each region
+    /// being the url to fetch it from (load_instrument)
+    LoadInstrument { name: String, zones: Vec<(String, Region)> },
+    /// the presets of an SF2 file, none if it can't be read (load_soundfont)
+    LoadSoundfont { name: String, font: Option<SoundFont> },
+    /// (insert_param)
+    InsertParam { bus: u32, effect: String, param: String, value: f32 },
+    /// the insert effects of a bus, and whether its envelope is followed (insert_chain)
+    InsertChain { bus: u32, effects: Vec<String>, follow: bool },
+    /// beat repeat, the length in seconds (stutter)
+    Stutter { timestamp: f64, length: f64, repeats: u32 },
+    /// a gain segment of a bus, the length in seconds (gate)
+    Gate { bus: u32, timestamp: f64, length: f64, level: f32, shape: &'static str },
+    /// (light_cue)
+    LightCue { cue: LightCue, timestamp: f64 },
+    /// the run limit was reached (run_complete)
+    RunComplete { step: usize, timestamp: f64 },
+    /// the samples to warm up before the first downbeat (prime)
+    Prime { samples: Vec<String> },
+    /// a beat or a bar starts at the timestamp (on_beat, on_bar)
+    Beat { position: Position, timestamp: f64 },
+    Bar { position: Position, timestamp: f64 },
+    /// a line (by index, as ordered in the input, and by id) starts
+    /// another cycle at the timestamp (on_cycle)
+    Cycle { line: usize, id: u32, cycle: usize, timestamp: f64 },
+}
+
+/// Tempo changes bigger than this (relative to the current tempo)
+/// are blocked in lockdown mode.
+const MAX_LOCKED_TEMPO_CHANGE: f64 = 0.1;
+
+/// in seconds
+const DEFAULT_LOOKAHEAD: f64 = 0.1;
+
+/// how much later (in seconds) the first downbeat is when priming,
+/// for the sampler to warm up in
+const PRIME_DELAY: f64 = 0.05;
+
+/// time between two wakeups of the scheduler, in milliseconds
+const DEFAULT_SCHEDULE_INTERVAL: f64 = 100.0;
+
+/// lines with this tag are played on the connected peers instead of locally
+const REMOTE_TAG: &str = "remote";
+
+/// the voices of lines with this tag are the first to go when the engine is overloaded
+const EXPENDABLE_TAG: &str = "expendable";
+
+/// samples played with this flag are repitched into the key of the scale
+const FIT_KEY_FLAG: &str = "fit_key";
+/// lines with this tag play a fill before a queued switch, if there's a fill style
+const FILL_TAG: &str = "fill";
+
+/// level of ghost notes, relative to the regular ones
+const GHOST_LEVEL: f32 = 0.3;
+
+/// fraction of a bar
+const DEFAULT_STUTTER_LENGTH: f32 = 0.125;
+const DEFAULT_STUTTER_REPEATS: u32 = 4;
+
+/// the beat repeat message for the sampler, the length is a fraction of a bar,
+/// tempo the duration of a step in milliseconds
+fn stutter_message(timestamp: f64, fraction: f32, repeats: u32, tempo: f64, steps_per_bar: usize) -> Message {
+    Message::Stutter {
+        timestamp,
+        length: fraction.max(0.0) as f64 * steps_per_bar as f64 * tempo / 1000.0,
+        repeats,
+    }
+}
+
+/// the gain segment a gated line's bus starts on the given step, if any,
+/// tempo is the duration of a step in milliseconds
+fn gate_message(gate: &GatePattern, step: usize, timestamp: f64, tempo: f64, steps_per_beat: usize, bus: u32) -> Option<Message> {
+    let (level, steps, shape) = match gate {
+        GatePattern::Steps(levels) => (levels[step % levels.len()], 1, "square"),
+        GatePattern::Pump(depth) if step % steps_per_beat == 0 => (*depth, steps_per_beat, "pump"),
+        GatePattern::Pump(_) => return None,
+    };
+
+    Some(Message::Gate { bus, timestamp, length: steps as f64 * tempo / 1000.0, level, shape })
+}
+
+/// A take to be recorded between two bar lines (as steps), of the master or,
+/// as a stem, of a line (by label).
+struct PlannedTake {
+    name: String,
+    stem: Option<String>,
+    from: usize,
+    to: usize,
+}
+
+/// When the scheduler stops by itself.
+enum RunLimit {
+    /// on the given step
+    Step(usize),
+    /// once the events of a step would be played at or after the given audio time
+    Time(f64),
+}
+
+/// A line that couldn't be parsed, columns count from 1.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}: {}", self.line, self.column, self.message)
+    }
+}
+
+/// A line that's been parsed, but not turned into a sequence yet.
+struct ParsedLine {
+    line_number: usize,
+    /// position of the pattern within the line
+    offset: usize,
+    label: Option<String>,
+    energy: Option<(bool, f32)>,
+    tags: Vec<String>,
+    light: bool,
+    inserts: Vec<String>,
+    speed: Option<f32>,
+    /// the pattern as written
+    source: String,
+    pattern: Pattern,
+}
+
+/// A line of the input by its number, parsed or, if it can't be, its label and
+/// pattern as written.
+type InputLine<'a> = (usize, Result<&'a ParsedLine, (Option<&'a str>, &'a str)>);
+
+/// A simple time-recursion event scheduler, waking up at a fixed time interval
+/// to schedule the steps that are due before the next wakeup.
+pub struct SchedulerCore<S: EventSink, C: Clock> {
+    /// time this scheduler was started (AudioContext.currentTime)
+    audio_start_time: f64,
+    /// time this scheduler was started (performance.now())
+    browser_start_time: f64,    
+    /// where the scheduler is on the straight grid
+    logical_clock: LogicalClock,
+    /// the last reading of the audio clock (AudioContext.currentTime), and the
+    /// browser time it was taken at, to be synced to on the next wakeup
+    audio_clock: Option<(f64, f64)>,
+    next_schedule_time: f64,
+    lookahead: f64, // in seconds
+    schedule_interval: f64, // in milliseconds
+    running: bool,
+    tempo: f64, // the duration of a step, in milliseconds
+    /// steps per beat and beats per bar
+    meter: Meter,
+    event_sequences: Vec<EventSequence>,
+    /// only known once the sampler reports its samples
+    sample_registry: Option<SampleRegistry>,
+    parsers: ParserRegistry,
+    /// global macro (0..1) to bring lines in and out
+    energy: f32,
+    groups: GroupState,
+    /// delay of every other step, as a fraction of the step duration,
+    /// for all lines and for those that swing on their own
+    swing: Swing,
+    /// constant shift of the events of a line (by label), in milliseconds
+    line_offsets: HashMap<String, f64>,
+    /// of events sharing a timestamp
+    event_order: EventOrder,
+    /// steps since the scheduler was started
+    step_count: usize,
+    rng: StdRng,
+    scale: Scale,
+    /// lockdown mode, blocks destructive operations during a performance
+    locked: bool,
+    /// frames per second of the timecode messages, if any
+    timecode_fps: Option<f64>,
+    controllers: ControllerMap,
+    /// external modulation inputs (0..1), by name
+    mod_inputs: HashMap<String, f32>,
+    /// names of the custom voices
+    voices: HashSet<String>,
+    /// names of the long samples that are streamed
+    streams: HashSet<String>,
+    /// insert effects (and whether the envelope is followed) by bus (minus one),
+    /// as the sampler knows them
+    insert_chains: Vec<(Vec<String>, bool)>,
+    /// labels of the lines whose envelope is followed
+    followed: HashSet<String>,
+    /// beat repeat to start on the next step, length (fraction of a bar) and repeats
+    pending_stutter: Option<(f32, u32)>,
+    /// input to be evaluated on a later bar line (or beat), and the step it's evaluated on
+    queued: Option<(String, usize)>,
+    /// when evaluated input takes over while playing
+    eval_quantization: EvalQuant,
+    /// stop automatically, i.e. for unattended installations
+    run_limit: Option<RunLimit>,
+    /// takes to start or to stop, and the labels of the lines recorded as stems
+    takes: Vec<PlannedTake>,
+    stems: HashSet<String>,
+    /// what lines tagged for fills play in the bar before a queued switch
+    fill_style: Option<FillStyle>,
+    /// dropped duplicate events have been reported since the last evaluation
+    flood_warned: bool,
+    /// id of the next new sequence
+    next_sequence_id: u32,
+    /// what's been played since the start
+    stats: SessionStats,
+    /// takes the sound events and the messages
+    pub sink: S,
+    /// wakes the scheduler up
+    pub clock: C,
+    /// sees (and changes) the sound events of each step before they go out
+    tick_hook: Option<Box<dyn TickHook>>,
+    /// warm everything up on start, before the first downbeat
+    priming: bool,
+    /// stopped, but to go on from where it is
+    paused: bool,
+    /// the latest browser time the scheduler was told of (a wakeup or a clock
+    /// reading), the playhead is where the sound is at that time
+    playhead_time: f64,
+}
+
+impl<S: EventSink, C: Clock> SchedulerCore<S, C> {
+    pub fn new(sink: S, clock: C) -> Self {
+        SchedulerCore {
+            audio_start_time: 0.0,
+            browser_start_time: 0.0,
+            logical_clock: LogicalClock::new(0.0, 0.0),
+            audio_clock: None,
+            next_schedule_time: 0.0,
+            lookahead: DEFAULT_LOOKAHEAD,
+            schedule_interval: DEFAULT_SCHEDULE_INTERVAL,
+            running: false,
+            tempo: 128.0,
+            meter: Meter::default(),
+            event_sequences: Vec::new(),
+            sample_registry: None,
+            parsers: ParserRegistry::new(),
+            energy: 0.5,
+            groups: GroupState::new(),
+            swing: Swing::new(),
+            line_offsets: HashMap::new(),
+            event_order: EventOrder::new(),
+            step_count: 0,
+            rng: StdRng::from_entropy(),
+            scale: Scale::default(),
+            locked: false,
+            timecode_fps: None,
+            controllers: ControllerMap::new(),
+            mod_inputs: HashMap::new(),
+            voices: HashSet::new(),
+            streams: HashSet::new(),
+            insert_chains: Vec::new(),
+            followed: HashSet::new(),
+            pending_stutter: None,
+            queued: None,
+            eval_quantization: EvalQuant::Immediate,
+            run_limit: None,
+            takes: Vec::new(),
+            stems: HashSet::new(),
+            fill_style: None,
+            flood_warned: false,
+            next_sequence_id: 0,
+            stats: SessionStats::new(),
+            sink,
+            clock,
+            tick_hook: None,
+            priming: false,
+            paused: false,
+            playhead_time: 0.0,
+        }
+    }
+
+    /// Have the given hook filter, change or add to the sound events of each
+    /// step before they go out (see hook.rs), none to remove it.
+    pub fn set_tick_hook(&mut self, hook: Option<Box<dyn TickHook>>) {
+        self.tick_hook = hook;
+    }
+
+    /// Prime on start (off by default): the samples of the lines are warmed up,
+    /// the first bar is scheduled at once and its downbeat comes a bit later
+    /// for that, so the first bar doesn't come out rough.
+    pub fn set_priming(&mut self, enabled: bool) {
+        self.priming = enabled;
+    }
+
+    /// Let the sink know where the given step is, if it's on a beat.
+    fn notify_position(&mut self, step: usize, timestamp: f64) {
+        let steps_per_beat = self.meter.steps_per_beat();
+        let steps_per_bar = self.meter.steps_per_bar();
+        if step % steps_per_beat != 0 {
+            return;
+        }
+
+        let position = Position {
+            bar: (step / steps_per_bar) as u32,
+            beat: (step % steps_per_bar / steps_per_beat) as u32,
+            step: step as u32,
+            tick: self.logical_clock.tick(),
+        };
+        self.sink.post(Message::Beat { position, timestamp });
+        if step % steps_per_bar == 0 {
+            self.sink.post(Message::Bar { position, timestamp });
+        }
+    }
+
+    /// Set a modulation input (0..1) that event params can follow,
+    /// i.e. "lp-freq=mod(tilt_x, 200, 4000)".
+    pub fn set_mod_input(&mut self, name: String, value: f32) {
+        self.mod_inputs.insert(name, value.max(0.0).min(1.0));
+    }
+
+    /// Set the measured envelope of a bus, which is available to all lines
+    /// as the modulation input "env_<label>" of the line playing on it.
+    pub fn set_bus_level(&mut self, bus: usize, level: f32) {
+        let name = bus.checked_sub(1).and_then(|seq_idx| self.event_sequences.get(seq_idx)).and_then(|seq| seq.name.clone());
+        if let Some(name) = name {
+            self.set_mod_input(format!("env_{}", name), level);
+        }
+    }
+
+    /// Map a controller id to a target (see ControlTarget), min and max
+    /// being the range the controller covers (the target's default if not given).
+    pub fn map_controller(&mut self, id: String, target: String, min: Option<f32>, max: Option<f32>) {
+        match ControlTarget::from_name(&target) {
+            Some(control_target) => {
+                let range = match (min, max) {
+                    (Some(min), Some(max)) => Some((min, max)),
+                    _ => None,
+                };
+                self.controllers.map(&id, control_target, range);
+            },
+            None => self.warn(&format!("unknown controller target {}", target)),
+        }
+    }
+
+    /// Receive a controller value (0..1) and apply it to all mapped targets.
+    pub fn set_controller_value(&mut self, id: String, value: f32) {
+        for (target, scaled) in self.controllers.resolve(&id, value) {
+            match target {
+                ControlTarget::Energy => self.set_energy(scaled),
+                ControlTarget::Tempo if scaled > 0.0 => self.change_tempo(self.meter.step_duration(scaled as f64)),
+                ControlTarget::Tempo => {},
+                ControlTarget::Swing => self.swing.set(scaled as f64),
+                ControlTarget::Mute(tag) => self.groups.mute(&tag, value > 0.5),
+                ControlTarget::GroupGain(tag) => self.groups.set_gain(&tag, scaled),
+                ControlTarget::GroupParam(tag, param) => self.groups.set_param(&tag, &param, scaled),
+                ControlTarget::Master(param) => self.sink.post(Message::MasterParam { name: param, value: scaled }),
+            }
+        }
+    }
+
+    /// Send timecode messages with the given frame rate (i.e. 24, 25 or 30),
+    /// zero turns them off.
+    pub fn set_timecode(&mut self, fps: f64) {
+        self.timecode_fps = if fps > 0.0 { Some(fps) } else { None };
+    }
+
+    /// Send the timecode for the current step.
+    fn send_timecode(&mut self) {
+        if let Some(fps) = self.timecode_fps {
+            // the timecode refers to the time the events of this step are played at
+            let timestamp = self.logical_clock.audio_time() + self.lookahead;
+            let timecode = Timecode::from_seconds(self.logical_clock.audio_time() - self.audio_start_time, fps);
+            self.sink.post(Message::Timecode { timecode, timestamp });
+        }
+    }
+
+    /// Enter lockdown mode, where clearing all sequences, big tempo jumps and
+    /// stopping are blocked until unlocked (panic still works).
+    pub fn lock(&mut self) {
+        self.locked = true;
+    }
+
+    pub fn unlock(&mut self) {
+        self.locked = false;
+    }
+
+    /// Report something.
+    fn warn(&mut self, message: &str) {
+        self.sink.post(Message::Warnings(vec![message.to_string()]));
+    }
+
+    fn change_tempo(&mut self, tempo: f64) {
+        if self.locked && ((tempo - self.tempo) / self.tempo).abs() > MAX_LOCKED_TEMPO_CHANGE {
+            self.warn(&format!("locked: tempo change from {} to {} ms blocked", self.tempo, tempo));
+        } else {
+            self.tempo = tempo;
+        }
+    }
+
+    /// Apply a directive line to the session state.
+    fn apply_directive(&mut self, directive: &Directive) {
+        match directive {
+            Directive::Tempo(bpm) if *bpm > 0.0 => self.change_tempo(self.meter.step_duration(*bpm as f64)),
+            Directive::Tempo(bpm) => self.warn(&format!("invalid tempo {}", bpm)),
+            Directive::Swing(swing) => self.set_swing(*swing as f64),
+            Directive::Ppqn(ppqn) => self.meter.set_ppqn(*ppqn),
+            Directive::Seed(seed) => self.set_seed(*seed),
+            Directive::Scale(root, mode) => match Scale::new(root, mode) {
+                Some(scale) => self.scale = scale,
+                None => self.warn(&format!("unknown scale {} {}", root, mode)),
+            },
+            Directive::Map(id, target, range) => {
+                self.map_controller(id.to_string(), target.to_string(), range.map(|r| r.0), range.map(|r| r.1));
+            },
+            Directive::Follow(label) => {
+                self.followed.insert(label.to_string());
+            },
+            Directive::Fill("off") => self.fill_style = None,
+            Directive::Fill(style) => match FillStyle::from_name(style) {
+                Some(style) => self.fill_style = Some(style),
+                None => self.warn(&format!("unknown fill style {}", style)),
+            },
+            // the main thread fetches the sample and reports back once it's loaded
+            Directive::Load(name, url) => {
+                if self.sample_registry.get_or_insert_with(SampleRegistry::new).request_load(name, url) {
+                    self.sink.post(Message::LoadSample { sample_id: name.to_string(), url: url.to_string() });
+                }
+            },
+        }
+    }
+
+    /// Delay the events of every other step by a fraction (up to 0.5) of the step,
+    /// like @swing. The steps themselves stay where they are.
+    pub fn set_swing(&mut self, amount: f64) {
+        self.swing.set(amount);
+    }
+
+    /// Swing the line with the given label by its own amount, a negative
+    /// one has it follow the global swing again.
+    pub fn set_line_swing(&mut self, label: String, amount: f64) {
+        self.swing.set_line(&label, amount);
+    }
+
+    /// Play the events of the line with the given label earlier (negative) or later
+    /// by a number of milliseconds, on top of swing, i.e. for samples with a slow
+    /// attack. Events aren't moved further back than the lookahead allows.
+    pub fn set_line_offset(&mut self, label: String, ms: f64) {
+        if ms == 0.0 {
+            self.line_offsets.remove(&label);
+        } else {
+            self.line_offsets.insert(label, ms);
+        }
+    }
+
+    /// The ids of the lines, in the order of the input. A line keeps its id across
+    /// evaluations, as long as it keeps its label, or isn't changed beyond recognition.
+    pub fn sequence_ids(&self) -> Vec<u32> {
+        self.event_sequences.iter().map(|seq| seq.id).collect()
+    }
+
+    /// What's been played since the start.
+    pub fn session_stats(&self) -> &SessionStats {
+        &self.stats
+    }
+
+    /// The lines, in the order of the input.
+    pub fn sequences(&self) -> &[EventSequence] {
+        &self.event_sequences
+    }
+
+    /// Master steps between the playhead and the next step to be scheduled,
+    /// the events of a step are heard a lookahead after they're scheduled.
+    fn steps_ahead(&self) -> f64 {
+        let heard_tick = self.logical_clock.tick_at(self.playhead_time - self.lookahead * 1000.0);
+        (self.logical_clock.tick() as f64 - heard_tick) / self.meter.ticks_per_step() as f64
+    }
+
+    /// where the sound is, in master steps since the start
+    fn playhead(&self) -> f64 {
+        (self.step_count as f64 - self.steps_ahead()).max(0.0)
+    }
+
+    /// The bar that's heard (counted since the start), as of the last wakeup or clock
+    /// reading, unlike the position passed to on_bar, which is that of the bar scheduled.
+    pub fn current_bar(&self) -> u32 {
+        (self.playhead() as usize / self.meter.steps_per_bar()) as u32
+    }
+
+    /// The beat within the bar that's heard, like current_bar.
+    pub fn current_beat(&self) -> u32 {
+        (self.playhead() as usize % self.meter.steps_per_bar() / self.meter.steps_per_beat()) as u32
+    }
+
+    /// How far (0..1) into the master step that's heard the sound is, like current_bar.
+    pub fn phase_in_step(&self) -> f64 {
+        self.playhead().fract()
+    }
+
+    /// The step of a line (by index, as ordered in the input) that's heard, within
+    /// the line's cycle, i.e. to draw a playhead over it. Lines with a different
+    /// speed count their own steps. None if there's no such line or it's stopped.
+    pub fn current_step(&self, seq_idx: usize) -> Option<u32> {
+        let seq = self.event_sequences.get(seq_idx).filter(|seq| !seq.stopped)?;
+        let step = (seq.position - self.steps_ahead() * seq.speed).max(0.0) as usize;
+        Some(if seq.cycle.is_empty() { step } else { step % seq.cycle.len() } as u32)
+    }
+
+    /// Set how many of its steps a line (by index, as ordered in the input) plays per
+    /// step of the master clock, until the input is evaluated again.
+    pub fn set_sequence_speed(&mut self, idx: usize, speed: f64) {
+        if speed <= 0.0 {
+            self.warn(&format!("invalid speed {}", speed));
+            return;
+        }
+        if let Some(seq) = self.sequence_mut(idx) {
+            seq.speed = speed;
+        }
+    }
+
+    /// The line with the given index (as ordered in the input), if there's one.
+    fn sequence_mut(&mut self, idx: usize) -> Option<&mut EventSequence> {
+        if idx >= self.event_sequences.len() {
+            self.warn(&format!("no line {}", idx));
+            return None;
+        }
+        self.event_sequences.get_mut(idx)
+    }
+
+    /// Start a line (by index, as ordered in the input) from the top, like a clip,
+    /// on the next step or, quantized, on the next bar line (if it's playing).
+    pub fn start_sequence(&mut self, idx: usize, quantized: bool) {
+        let step = if quantized && self.running { self.meter.next_boundary(self.step_count, EvalQuant::Bar) } else { self.step_count };
+        if let Some(seq) = self.sequence_mut(idx) {
+            seq.start_at = Some(step);
+        }
+    }
+
+    /// Stop a line (by index, as ordered in the input) until it's started again.
+    /// Unlike a muted line, it doesn't keep running, the others do.
+    pub fn stop_sequence(&mut self, idx: usize) {
+        if let Some(seq) = self.sequence_mut(idx) {
+            seq.stopped = true;
+            seq.start_at = None;
+        }
+    }
+
+    /// Silence a line (by index, as ordered in the input). It keeps running,
+    /// so it comes back in phase when unmuted.
+    pub fn mute(&mut self, idx: usize) {
+        if let Some(seq) = self.sequence_mut(idx) {
+            seq.muted = true;
+        }
+    }
+
+    pub fn unmute(&mut self, idx: usize) {
+        if let Some(seq) = self.sequence_mut(idx) {
+            seq.muted = false;
+        }
+    }
+
+    /// Only play soloed lines (and no muted ones), as long as there are any.
+    pub fn solo(&mut self, idx: usize) {
+        if let Some(seq) = self.sequence_mut(idx) {
+            seq.soloed = true;
+        }
+    }
+
+    pub fn clear_solo(&mut self) {
+        for seq in self.event_sequences.iter_mut() {
+            seq.soloed = false;
+        }
+    }
+
+    /// Mute or unmute all lines tagged with the given tag.
+    pub fn mute_group(&mut self, tag: String, muted: bool) {
+        self.groups.mute(&tag, muted);
+    }
+
+    /// Scale the level of all lines tagged with the given tag.
+    pub fn set_group_gain(&mut self, tag: String, gain: f32) {
+        self.groups.set_gain(&tag, gain);
+    }
+
+    /// Override a parameter on all lines tagged with the given tag.
+    pub fn set_group_param(&mut self, tag: String, param: String, value: f32) {
+        self.groups.set_param(&tag, &param, value);
+    }
+
+    /// Save the current group gains and parameter overrides under a name.
+    pub fn save_snapshot(&mut self, name: String) {
+        self.groups.save_snapshot(&name);
+    }
+
+    /// Move all group gains and parameter overrides to a saved snapshot
+    /// over the given number of beats.
+    pub fn morph_to(&mut self, name: String, beats: f32) {
+        let steps = (beats.max(0.0) * self.meter.steps_per_beat() as f32).round() as usize;
+        if let Err(err) = self.groups.morph_to(&name, steps) {
+            self.warn(&err);
+        }
+    }
+
+    /// Repeat the last bit of the master output (the given fraction of a bar,
+    /// i.e. 0.125 for an eighth note) a number of times, starting on the next step.
+    pub fn stutter(&mut self, fraction: f32, repeats: u32) {
+        self.pending_stutter = Some((fraction, repeats));
+    }
+
+    /// Record the next bars of the master output, from the next bar line on, into
+    /// a sample of the given name, to be played like any other (a sample of the same
+    /// name is replaced). The main thread registers it once it's there.
+    pub fn resample_master(&mut self, name: String, bars: usize) {
+        if !self.running {
+            self.warn("resample_master: nothing's playing");
+            return;
+        }
+
+        let steps_per_bar = self.meter.steps_per_bar();
+        let next_bar = (self.step_count + steps_per_bar - 1) / steps_per_bar * steps_per_bar;
+        let ticks_to_bar = (next_bar - self.step_count) as u64 * self.meter.ticks_per_step() as u64;
+        let start = self.logical_clock.audio_time_at(self.logical_clock.tick() + ticks_to_bar) + self.lookahead;
+
+        self.sink.post(Message::ResampleMaster {
+            name,
+            timestamp: start,
+            duration: (bars * steps_per_bar) as f64 * self.tempo / 1000.0,
+        });
+    }
+
+    /// Record a take of the given name from one bar line to another (counted since the
+    /// start, like the position passed to on_bar), of the master or of a line (by
+    /// label) as a stem. The sampler keeps it until a take of the same name is
+    /// recorded, the page can get it as a WAV file.
+    pub fn record_take(&mut self, name: String, from_bar: usize, to_bar: usize, stem: Option<String>) {
+        let steps_per_bar = self.meter.steps_per_bar();
+        if to_bar <= from_bar {
+            self.warn(&format!("take '{}' ends before it starts", name));
+            return;
+        }
+        if self.running && from_bar * steps_per_bar < self.step_count {
+            self.warn(&format!("take '{}': bar {} has passed", name, from_bar));
+            return;
+        }
+
+        self.takes.retain(|take| take.name != name);
+        self.takes.push(PlannedTake { name, stem, from: from_bar * steps_per_bar, to: to_bar * steps_per_bar });
+    }
+
+    /// Start and stop the takes punching in or out on the given step. Stems get a
+    /// bus of their own for as long as they're recorded.
+    fn punch_takes(&mut self, step: usize, timestamp: f64) {
+        let stems = self.stems.clone();
+
+        for idx in (0..self.takes.len()).rev() {
+            if self.takes[idx].to == step {
+                let take = self.takes.remove(idx);
+                if let Some(label) = take.stem.as_ref() {
+                    if !self.takes.iter().any(|other| other.stem.as_ref() == Some(label) && other.from < step) {
+                        self.stems.remove(label);
+                    }
+                }
+                self.sink.post(Message::TakeStop { name: take.name, timestamp });
+            }
+        }
+
+        let mut missing = Vec::new();
+        for take in self.takes.iter().filter(|take| take.from == step) {
+            let bus = match take.stem.as_ref() {
+                None => Some(0),
+                Some(label) => self.event_sequences.iter().position(|seq| seq.name.as_ref() == Some(label)).map(|idx| idx as u32 + 1),
+            };
+            match bus {
+                Some(bus) => {
+                    if let Some(label) = take.stem.as_ref() {
+                        self.stems.insert(label.clone());
+                    }
+                    self.sink.post(Message::TakeStart { name: take.name.clone(), bus, timestamp });
+                },
+                None => missing.push(take.name.clone()),
+            }
+        }
+
+        for name in missing.iter() {
+            self.takes.retain(|take| &take.name != name);
+            self.warn(&format!("take '{}': no such line", name));
+        }
+        if self.stems != stems {
+            self.update_insert_chains();
+        }
+    }
+
+    /// Slice a loaded sample (with the given duration in seconds) into equal pieces
+    /// and send a line playing them in the given style ("straight", "shuffled" or
+    /// "2-step"), for the editor.
+    pub fn chop(&mut self, sample: String, slices: usize, style: String, duration: f32) {
+        match ChopStyle::from_name(&style) {
+            Some(style) => {
+                let line = chop::chop_line(&sample, slices, style, duration, (self.tempo / 1000.0) as f32, &mut self.rng);
+                self.sink.post(Message::Chop { line });
+            },
+            None => self.warn(&format!("unknown chop style {}", style)),
+        }
+    }
+
+    /// Read the regions of an SFZ file (fetched from the given url) and send
+    /// them as the key zones of an instrument, for the main thread to load.
+    pub fn load_instrument(&mut self, name: String, sfz: String, url: String) {
+        let regions = instruments::parse_sfz(&sfz);
+        if regions.is_empty() {
+            self.warn(&format!("no regions with samples in {}", url));
+            return;
+        }
+
+        // sample paths are relative to the SFZ file
+        let base = &url[..url.rfind('/').map_or(0, |idx| idx + 1)];
+        let zones = regions.into_iter().enumerate().map(|(idx, mut region)| {
+            if !region.sample.contains("://") && !region.sample.starts_with('/') {
+                region.sample = format!("{}{}", base, region.sample);
+            }
+            (format!("{}.{}", name, idx), region)
+        }).collect();
+
+        self.sink.post(Message::LoadInstrument { name, zones });
+    }
+
+    /// Read the presets of an SF2 file (fetched from the given url) and send
+    /// them, for the main thread to load each preset as an instrument named
+    /// after the font, i.e. "gm.acoustic_grand_piano".
+    pub fn load_soundfont(&mut self, name: String, url: String, data: Vec<u8>) {
+        let font = match soundfont::parse_sf2(&data) {
+            Ok(font) => Some(font),
+            Err(err) => {
+                self.warn(&format!("couldn't read soundfont {}: {}", url, err));
+                None
+            }
+        };
+        self.sink.post(Message::LoadSoundfont { name, font });
+    }
+
+    /// Latch (or release) the freeze effect on the line with the given label,
+    /// the line needs a freeze insert, i.e. "pads [freeze]: ...".
+    pub fn freeze(&mut self, label: String, frozen: bool) {
+        let bus = self.event_sequences.iter().position(|seq| {
+            seq.name.as_ref() == Some(&label) && seq.inserts.iter().any(|effect| effect == "freeze")
+        });
+
+        match bus {
+            Some(seq_idx) => self.sink.post(Message::InsertParam {
+                bus: (seq_idx + 1) as u32,
+                effect: "freeze".to_string(),
+                param: "hold".to_string(),
+                value: if frozen { 1.0 } else { 0.0 },
+            }),
+            None => self.warn(&format!("no line {} with a freeze effect", label)),
+        }
+    }
+
+    /// Seed the random choices of the scheduler (random steps, probabilities, ghost
+    /// notes ...), so a performance can be replayed. Lines evaluated after seeding make
+    /// the same choices in the same order, except for learned ("learn") sequences.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// Set the global energy level (0..1), lines with an energy condition
+    /// come in and out accordingly.
+    pub fn set_energy(&mut self, energy: f32) {
+        self.energy = energy.max(0.0).min(1.0);
+    }
+
+    /// Set the notation for lines that don't specify one, i.e. "seq" or "sexp".
+    pub fn set_syntax(&mut self, name: String) {
+        if let Err(err) = self.parsers.set_default(&name) {
+            self.warn(&err);
+        }
+    }
+
+    /// Make a custom voice (defined on the sampler side) known to the scheduler,
+    /// so events with its name are played with it.
+    pub fn register_voice(&mut self, name: String) {
+        self.voices.insert(name.clone());
+        self.sample_registry.get_or_insert_with(SampleRegistry::new).register(name);
+    }
+
+    /// Make a streamed sample known to the scheduler, so events with its name are streamed.
+    pub fn register_stream(&mut self, name: String) {
+        self.streams.insert(name.clone());
+        self.sample_registry.get_or_insert_with(SampleRegistry::new).register(name);
+    }
+
+    /// Make a loaded sample known to the scheduler, so the input can be checked against it.
+    pub fn register_sample(&mut self, name: String) {
+        self.sample_registry.get_or_insert_with(SampleRegistry::new).register(name);
+    }
+
+    /// Forget an unloaded sample, lines still playing it get a warning on the next evaluation.
+    pub fn unregister_sample(&mut self, name: String) {
+        if let Some(registry) = self.sample_registry.as_mut() {
+            registry.unregister(&name);
+        }
+    }
+
+    /// Evaluate an input string on the next bar line instead of right away.
+    /// With a fill style set, the switch waits for a full bar to play the fill in.
+    /// Returns the warnings (or the syntax errors) if it's evaluated right
+    /// away (i.e. nothing's playing), later ones are sent.
+    pub fn queue(&mut self, input: String) -> Result<Vec<String>, Vec<ParseError>> {
+        if !self.running || self.event_sequences.is_empty() {
+            return self.evaluate_now(Some(input));
+        }
+
+        let steps_per_bar = self.meter.steps_per_bar();
+        let next_bar = (self.step_count / steps_per_bar + 1) * steps_per_bar;
+        let at = if self.fill_style.is_some() && next_bar - self.step_count < steps_per_bar {
+            next_bar + steps_per_bar
+        } else {
+            next_bar
+        };
+        self.queued = Some((input, at));
+        Ok(Vec::new())
+    }
+
+    /// Set the order events sharing a timestamp are handed to the sink in, by class,
+    /// i.e. "cut automation note" (the default): voices in a cut group first, then
+    /// gates, stutters and light cues, then the notes.
+    pub fn set_event_order(&mut self, order: String) {
+        match EventOrder::from_names(&order) {
+            Some(order) => self.event_order = order,
+            None => self.warn(&format!("unknown event order {}", order)),
+        }
+    }
+
+    /// Set when evaluated input takes over while playing, "immediate" (the default),
+    /// or on the next "beat" or "bar" line. Until then, what's playing keeps playing,
+    /// then all lines switch at once.
+    pub fn set_eval_quantization(&mut self, quantization: String) {
+        match EvalQuant::from_name(&quantization) {
+            Some(quantization) => self.eval_quantization = quantization,
+            None => self.warn(&format!("unknown eval quantization {}", quantization)),
+        }
+    }
+
+    /// Evaluate an input string, right away or on the next beat or bar line (see
+    /// set_eval_quantization). Returns warnings about events that don't resolve to any
+    /// sample or synth, if it's evaluated right away, later ones are sent. The syntax
+    /// errors of lines that can't be parsed are returned after the other lines are
+    /// evaluated (the warnings are sent then).
+    pub fn evaluate(&mut self, input: Option<String>) -> Result<Vec<String>, Vec<ParseError>> {
+        let staged = self.running && !self.event_sequences.is_empty();
+        match (self.eval_quantization, input) {
+            // like queueing, with a fill if there's a fill style
+            (EvalQuant::Bar, Some(input)) => self.queue(input),
+            (EvalQuant::Beat, Some(input)) if staged => {
+                self.queued = Some((input, self.meter.next_boundary(self.step_count, EvalQuant::Beat)));
+                Ok(Vec::new())
+            },
+            (_, input) => self.evaluate_now(input),
+        }
+    }
+
+    /// Turn an input string into a series of event sequences. Lines that can't be
+    /// parsed keep playing what they played before, the others are evaluated anyway.
+    fn evaluate_now(&mut self, input: Option<String>) -> Result<Vec<String>, Vec<ParseError>> {
+        let mut warnings: Vec<String> = Vec::new();
+        let mut errors: Vec<ParseError> = Vec::new();
+        
+        match input {
+            Some(all_lines) => {                                               
+                self.followed.clear();
+                self.flood_warned = false;
+                let mut step_probabilities: HashMap<String, Vec<f32>> = HashMap::new();
+                let mut gates: HashMap<String, GatePattern> = HashMap::new();
+                let mut contours: HashMap<String, f32> = HashMap::new();
+                let mut ghosts: HashMap<String, f32> = HashMap::new();
+                let mut parsed_lines: Vec<ParsedLine> = Vec::new();
+                // lines that can't be parsed keep playing what they played before,
+                // by line number, label and pattern as written
+                let mut failed_lines: Vec<(usize, Option<&str>, &str)> = Vec::new();
+                
+                for (line_idx, line) in all_lines.lines().enumerate() {
+                    // comments don't count, neither whole lines nor trailing ones
+                    let trimmed_line = parser::strip_comment(line).trim();
+                    
+                    if !trimmed_line.is_empty() && !trimmed_line.starts_with("#") {
+                        // probability lines don't count as sequences, they're
+                        // assigned to their target once all lines are known
+                        if let Ok((_, (target, probs))) = parser::step_probabilities(trimmed_line) {
+                            step_probabilities.insert(target.to_string(), probs);
+                            continue;
+                        }
+
+                        // same for gates
+                        if let Ok((_, (target, gate))) = parser::gate_pattern(trimmed_line) {
+                            gates.insert(target.to_string(), gate);
+                            continue;
+                        }
+
+                        if let Ok((_, (target, amount))) = parser::velocity_contour(trimmed_line) {
+                            contours.insert(target.to_string(), amount);
+                            continue;
+                        }
+
+                        if let Ok((_, (target, density))) = parser::ghost_notes(trimmed_line) {
+                            ghosts.insert(target.to_string(), density);
+                            continue;
+                        }
+
+                        if let Ok((_, directive)) = parser::directive(trimmed_line) {
+                            self.apply_directive(&directive);
+                            continue;
+                        }
+                        
+                        if let Ok((_, command)) = parser::group_command(trimmed_line) {
+                            self.groups.apply_command(&command);
+                            continue;
+                        }
+
+                        let (untagged_line, tags) = parser::split_tags(trimmed_line);
+                        
+                        let (body, header) = match parser::line_header(untagged_line) {
+                            Ok(res) => res,
+                            Err(_) => (untagged_line, parser::LineHeader { light: false, label: None, inserts: Vec::new(), energy: None, notation: None, speed: None }),
+                        };
+                        
+                        match self.parsers.parse(header.notation, body) {
+                            Ok(pattern) => {
+                                parsed_lines.push(ParsedLine {
+                                    line_number: line_idx + 1,
+                                    offset: body.as_ptr() as usize - line.as_ptr() as usize,
+                                    label: header.label.map(|l| l.to_string()).or_else(|| pattern.label.clone()),
+                                    energy: header.energy,
+                                    tags: tags.iter().map(|t| t.to_string()).collect(),
+                                    light: header.light,
+                                    inserts: header.inserts.iter().map(|i| i.to_string()).collect(),
+                                    speed: header.speed,
+                                    source: body.trim().to_string(),
+                                    pattern,
+                                });
+                            },
+                            Err(err) => {
+                                errors.push(ParseError {
+                                    line: line_idx + 1,
+                                    column: body.as_ptr() as usize - line.as_ptr() as usize + err.position + 1,
+                                    message: err.message,
+                                });
+                                failed_lines.push((line_idx + 1, header.label, body.trim()));
+                            },
+                        };
+                    }
+                }
+
+                if self.locked && parsed_lines.is_empty() && failed_lines.is_empty() && !self.event_sequences.is_empty() {
+                    self.warn("locked: clearing all sequences blocked");
+                    return Ok(warnings);
+                }
+                
+                let labels: HashSet<String> = parsed_lines.iter().filter_map(|parsed| parsed.label.clone()).collect();
+                let mut line_warnings: Vec<EvaluationWarning> = Vec::new();
+                
+                // unknown events are turned into rests instead of being
+                // dispatched to the sampler (remote lines play on samples we don't know)
+                if let Some(registry) = &self.sample_registry {
+                    for parsed in parsed_lines.iter_mut().filter(|parsed| !parsed.light && !parsed.tags.iter().any(|tag| tag == REMOTE_TAG)) {
+                        let unresolved: Vec<PatternEvent> = parsed.pattern.events.iter().filter(|ev| !labels.contains(&ev.name)).cloned().collect();
+                        line_warnings.append(&mut registry.unresolved_events(parsed.line_number, parsed.offset, &unresolved));
+                        
+                        for ev in parsed.pattern.events.iter_mut().filter(|ev| !labels.contains(&ev.name) && !registry.resolves(&ev.name)) {
+                            ev.name = "~".to_string();
+                            ev.params.clear();
+                        }
+                    }
+                }
+
+                // same for lighting cues that don't refer to a valid channel
+                for parsed in parsed_lines.iter_mut().filter(|parsed| parsed.light) {
+                    for ev in parsed.pattern.events.iter_mut().filter(|ev| ev.name != "~" && light::channel_from_name(&ev.name).is_none()) {
+                        line_warnings.push(EvaluationWarning { line: parsed.line_number, column: parsed.offset + ev.position + 1, message: format!("invalid light channel '{}'", ev.name) });
+                        ev.name = "~".to_string();
+                        ev.params.clear();
+                    }
+                }
+
+                let patterns: HashMap<String, &Pattern> = parsed_lines.iter()
+                    .filter_map(|parsed| parsed.label.clone().map(|l| (l, &parsed.pattern)))
+                    .collect();
+                
+                // lines used as sub-patterns only play as part of other lines
+                let referenced: HashSet<String> = parsed_lines.iter()
+                    .flat_map(|parsed| parsed.pattern.events.iter())
+                    .filter(|ev| labels.contains(&ev.name))
+                    .map(|ev| ev.name.clone())
+                    .collect();
+                
+                let playing: Vec<&ParsedLine> = parsed_lines.iter()
+                    .filter(|parsed| !parsed.label.as_ref().map_or(false, |l| referenced.contains(l)))
+                    .collect();
+
+                // lines go on with the sequence they were before, wherever they moved,
+                // the ones that can't be parsed too, all in the order of the input
+                let mut lines: Vec<InputLine> = playing.iter().map(|parsed| (parsed.line_number, Ok(*parsed)))
+                    .chain(failed_lines.iter().map(|(line_number, label, source)| (*line_number, Err((*label, *source)))))
+                    .collect();
+                lines.sort_by_key(|(line_number, _)| *line_number);
+                let previous_keys: Vec<(Option<&str>, &str)> = self.event_sequences.iter().map(|seq| (seq.name.as_deref(), seq.source.as_str())).collect();
+                let keys: Vec<(Option<&str>, &str)> = lines.iter().map(|(_, line)| match line {
+                    Ok(parsed) => (parsed.label.as_deref(), parsed.source.as_str()),
+                    Err(key) => *key,
+                }).collect();
+                let mut matches = matching::match_lines(&previous_keys, &keys);
+
+                // unlabeled lines that can't be parsed might not read like anything
+                // before, they keep what was in their place
+                for (idx, (_, line)) in lines.iter().enumerate() {
+                    if let (Err((None, _)), None) = (line, matches[idx]) {
+                        if idx < previous_keys.len() && !matches.contains(&Some(idx)) {
+                            matches[idx] = Some(idx);
+                        }
+                    }
+                }
+                let mut previous: Vec<Option<EventSequence>> = self.event_sequences.drain(..).map(Some).collect();
+                
+                for ((_, line), matched) in lines.iter().zip(matches.into_iter()) {
+                    let parsed = match line {
+                        Ok(parsed) => parsed,
+                        Err(_) => {
+                            if let Some(seq) = matched.and_then(|prev_idx| previous[prev_idx].take()) {
+                                self.event_sequences.push(seq);
+                            }
+                            continue;
+                        },
+                    };
+
+                    let mut pattern = parsed.pattern.clone();
+                    let mut references: HashMap<String, Vec<PatternEvent>> = HashMap::new();
+                    
+                    for ev in pattern.events.iter_mut().filter(|ev| labels.contains(&ev.name)) {
+                        if references.contains_key(&ev.name) {
+                            continue;
+                        }
+                        
+                        match notation::flatten_reference(&ev.name, &patterns) {
+                            Ok(flat_events) => {
+                                references.insert(ev.name.clone(), flat_events);
+                            },
+                            Err(err) => {
+                                line_warnings.push(EvaluationWarning { line: parsed.line_number, column: parsed.offset + ev.position + 1, message: err });
+                                ev.name = "~".to_string();
+                                ev.params.clear();
+                            }
+                        }
+                    }
+                    
+                    let mut seq = match matched.and_then(|prev_idx| previous[prev_idx].take()) {
+                        Some(mut seq) => {
+                            seq.update_sequence(&pattern, &mut self.rng);
+                            seq
+                        },
+                        None => {
+                            let mut seq = EventSequence::from_pattern(&pattern, &mut self.rng);
+                            seq.id = self.next_sequence_id;
+                            self.next_sequence_id += 1;
+                            seq
+                        },
+                    };
+                    seq.set_references(&references);
+                    seq.name = parsed.label.clone();
+                    seq.source = parsed.source.clone();
+                    seq.energy_threshold = parsed.energy;
+                    seq.tags = parsed.tags.clone();
+                    seq.light = parsed.light;
+                    seq.inserts = parsed.inserts.clone();
+                    seq.speed = parsed.speed.map_or(1.0, |speed| speed as f64);
+                    self.event_sequences.push(seq);
+                }
+
+                warnings.extend(line_warnings.iter().map(|warning| warning.to_string()));
+
+                self.update_insert_chains();
+
+                for seq in self.event_sequences.iter_mut() {
+                    seq.step_probabilities = match &seq.name {
+                        Some(name) => step_probabilities.get(name).cloned().unwrap_or_default(),
+                        None => Vec::new(),
+                    };
+                    seq.gate = seq.name.as_ref().and_then(|name| gates.get(name).cloned());
+                    seq.ghost_density = seq.name.as_ref().and_then(|name| ghosts.get(name)).cloned().unwrap_or(0.0);
+
+                    // an unchanged contour keeps drifting from where it is
+                    let amount = seq.name.as_ref().and_then(|name| contours.get(name));
+                    if amount != seq.contour.as_ref().map(|contour| contour.amount()).as_ref() {
+                        seq.contour = amount.map(|amount| VelocityContour::new(*amount));
+                    }
+                }
+
+                for target in step_probabilities.keys() {
+                    if !self.event_sequences.iter().any(|seq| seq.name.as_ref() == Some(target)) {
+                        self.warn(&format!("no line labeled {} to assign probabilities to", target));
+                    }
+                }
+
+                for target in gates.keys() {
+                    if !self.event_sequences.iter().any(|seq| seq.name.as_ref() == Some(target)) {
+                        self.warn(&format!("no line labeled {} to gate", target));
+                    }
+                }
+
+                for target in contours.keys() {
+                    if !self.event_sequences.iter().any(|seq| seq.name.as_ref() == Some(target)) {
+                        self.warn(&format!("no line labeled {} to humanize", target));
+                    }
+                }
+
+                for target in ghosts.keys() {
+                    if !self.event_sequences.iter().any(|seq| seq.name.as_ref() == Some(target)) {
+                        self.warn(&format!("no line labeled {} to add ghost notes to", target));
+                    }
+                }
+            }
+            
+            None => return Err(vec![ParseError { line: 0, column: 0, message: "no input".to_string() }]),
+        }
+
+        if errors.is_empty() {
+            Ok(warnings)
+        } else {
+            // the errors are what comes back, the warnings go the other way
+            if !warnings.is_empty() {
+                self.sink.post(Message::Warnings(warnings));
+            }
+            Err(errors)
+        }
+    }    
+
+    /// Let the sampler know about changed insert effects. Bus 0 is the master,
+    /// every line with insert effects or a followed envelope plays on its own bus
+    /// (stems are followed, so their bus is there).
+    fn update_insert_chains(&mut self) {
+        let chains: Vec<(Vec<String>, bool)> = self.event_sequences.iter().map(|seq| {
+            (seq.inserts.clone(), seq.name.as_ref().map_or(false, |name| self.followed.contains(name) || self.stems.contains(name)))
+        }).collect();
+
+        for bus_idx in 0..chains.len().max(self.insert_chains.len()) {
+            let chain = chains.get(bus_idx).cloned().unwrap_or_default();
+            if self.insert_chains.get(bus_idx) == Some(&chain) {
+                continue;
+            }
+
+            self.sink.post(Message::InsertChain { bus: (bus_idx + 1) as u32, effects: chain.0, follow: chain.1 });
+        }
+
+        self.insert_chains = chains;
+    }
+
+    /// Fetch all events from the event sequences, hand them to the sink
+    fn generate_and_send_events(&mut self) {
+        // a queued switch happens on its bar line
+        if self.queued.as_ref().map_or(false, |(_, at)| self.step_count >= *at) {
+            if let Some((input, _)) = self.queued.take() {
+                match self.evaluate_now(Some(input)) {
+                    Ok(warnings) => if !warnings.is_empty() {
+                        self.sink.post(Message::Warnings(warnings));
+                    },
+                    Err(errors) => self.sink.post(Message::Errors(errors)),
+                }
+            }
+        }
+
+        if self.event_sequences.is_empty() {
+            self.pending_stutter = None;
+            return
+        }
+
+        // gates follow the straight grid, not the swung one
+        let step = self.step_count;
+        let step_time = self.logical_clock.audio_time() + self.lookahead;
+        // swing delays every other step, but only its events
+        let trigger_time = step_time + swing_offset(step, self.meter.quantize(self.swing.global()), self.tempo);
+        self.step_count += 1;
+        self.stats.count_step();
+        self.notify_position(step, step_time);
+        self.punch_takes(step, step_time);
+
+        // gates, stutters and light cues go out before or after the sound events,
+        // by the event order
+        let mut automation: Vec<(f64, Message)> = Vec::new();
+
+        if let Some((fraction, repeats)) = self.pending_stutter.take() {
+            automation.push((trigger_time, stutter_message(trigger_time, fraction, repeats, self.tempo, self.meter.steps_per_bar())));
+        }
+        self.groups.advance_morph();
+
+        // the last bar before a queued switch, how far into it this step is
+        let steps_per_bar = self.meter.steps_per_bar();
+        let fill = match (self.fill_style, &self.queued) {
+            (Some(style), Some((_, at))) if *at > step && *at - step <= steps_per_bar => {
+                Some((style, (steps_per_bar - (*at - step)) as f32 / steps_per_bar as f32))
+            },
+            _ => None,
+        };
+
+        // for params computed from counters, each line adds its own cycle
+        let counters = Counters { step, beat: step / self.meter.steps_per_beat(), bar: step / steps_per_bar, cycle: 0 };
+        
+        // identical events on the same step are played once
+        let mut flood_guard = FloodGuard::new();
+
+        let solo = self.event_sequences.iter().any(|seq| seq.soloed);
+        let mut sounds = Vec::new();
+        // lines that went around, with the cycle they're on and when it starts
+        let mut cycle_starts = Vec::new();
+
+        for (seq_idx, seq) in self.event_sequences.iter_mut().enumerate() {
+            // launched lines start over, stopped ones are skipped altogether
+            if seq.start_at.map_or(false, |at| step >= at) {
+                seq.restart(&mut self.rng);
+                seq.start_at = None;
+                seq.stopped = false;
+            }
+            if seq.stopped {
+                continue;
+            }
+            
+            // inactive sequences keep running, so they come back in sync,
+            // offsets are in master steps from here on
+            let mut step_events = Vec::new();
+            for due in seq.due_steps() {
+                let cycle = seq.current_cycle();
+                let mut events = seq.get_next_event(&mut self.rng, &self.mod_inputs, &counters);
+                if seq.step_count == 1 || seq.current_cycle() != cycle {
+                    cycle_starts.push((seq_idx, seq.id, seq.current_cycle(), step_time + due * self.tempo / 1000.0));
+                }
+
+                // empty steps might be filled with a ghost note
+                let mut ghost = false;
+                if events.is_empty() && !seq.light {
+                    if let Some((ghost_event, ghost_params)) = seq.ghost_note(&self.mod_inputs, &counters) {
+                        events.push((0.0, ghost_event, ghost_params, HashMap::new(), 1.0));
+                        ghost = true;
+                    }
+                }
+
+                let speed = seq.speed;
+                step_events.extend(events.into_iter().map(|(offset, ev, params, ramps, duration)| (due + offset / speed, ev, params, ramps, duration, ghost)));
+            }
+
+            // nudged lines, but not into the past
+            let line_offsets = &self.line_offsets;
+            let nudge = seq.name.as_ref().and_then(|name| line_offsets.get(name)).cloned().unwrap_or(0.0) / 1000.0;
+            let now = self.logical_clock.audio_time();
+            let nudged = |time: f64| (time + nudge).max(now);
+
+            // lines can swing on their own
+            let swing = self.swing.of_line(seq.name.as_ref());
+            let trigger_time = step_time + swing_offset(step, self.meter.quantize(swing), self.tempo);
+
+            let rng = &mut self.rng;
+            let contour_gain = seq.contour.as_mut().map(|contour| contour.next_gain(rng));
+            if !seq.is_active(self.energy) || self.groups.is_muted(&seq.tags) || seq.muted || (solo && !seq.soloed) {
+                continue;
+            }
+
+            if seq.light {
+                for (step_offset, next_event, next_params, _, _, _) in step_events.iter() {
+                    if let Some(cue) = LightCue::from_event(next_event, next_params) {
+                        let timestamp = nudged(trigger_time + self.meter.tick_time(self.meter.ticks(*step_offset), self.tempo));
+                        automation.push((timestamp, Message::LightCue { cue, timestamp }));
+                    }
+                }
+                continue;
+            }
+
+            // the gate keeps moving during rests, for the tails of earlier events
+            if let Some(gate) = &seq.gate {
+                if let Some(message) = gate_message(gate, step, step_time, self.tempo, self.meter.steps_per_beat(), seq_idx as u32 + 1) {
+                    automation.push((step_time, message));
+                }
+            }
+
+            // offsets (in steps) and levels of the hits of each event,
+            // and how far up a rising fill bends the line
+            let mut hits = vec![(0.0, 1.0)];
+            let mut rise = None;
+            if let Some((style, progress)) = fill.filter(|_| seq.tags.iter().any(|tag| tag == FILL_TAG)) {
+                match style {
+                    FillStyle::Roll => if let Some((sound, sound_params)) = seq.first_sound(&self.mod_inputs, &counters) {
+                        step_events = vec![(0.0, sound, sound_params, HashMap::new(), 1.0, false)];
+                        hits = fills::roll_hits(progress);
+                    },
+                    FillStyle::Rise => rise = Some(fills::rise_factor(progress)),
+                }
+            }
+
+            // subdivided steps have several events, each at its offset within the step
+            for (step_offset, next_event, mut next_params, mut ramp_targets, duration, ghost) in step_events.into_iter() {
+                // offsets within the step are placed on ticks
+                let event_time = nudged(trigger_time + self.meter.tick_time(self.meter.ticks(step_offset), self.tempo));
+
+                if next_event == STUTTER_EVENT {
+                    let fraction = next_params.get("len").cloned().unwrap_or(DEFAULT_STUTTER_LENGTH);
+                    let repeats = next_params.get("rep").map_or(DEFAULT_STUTTER_REPEATS, |rep| rep.max(1.0) as u32);
+                    automation.push((event_time, stutter_message(event_time, fraction, repeats, self.tempo, self.meter.steps_per_bar())));
+                    continue;
+                }
+                
+                let next_source_type = match next_event.as_str() {
+                    "sine" => "SineSynth",
+                    "saw" => "LFSawSynth",
+                    "sqr" => "LFSquareSynth",
+                    name if self.voices.contains(name) => "CustomVoice",
+                    name if self.streams.contains(name) => "Streamer",
+                    _ => "Sampler",
+                };
+                                                               
+                self.groups.transform(&seq.tags, next_source_type, &mut next_params);
+
+                // scale degrees are turned into frequencies here,
+                // so changing the scale affects all lines
+                if let Some(degree) = next_params.remove("deg") {
+                    next_params.insert("freq".to_string(), self.scale.degree_to_freq(degree));
+                }
+                if let Some(degree) = ramp_targets.remove("deg") {
+                    ramp_targets.insert("freq".to_string(), self.scale.degree_to_freq(degree));
+                }
+
+                // samples are played faster, synths only if they have a frequency
+                if let Some(factor) = rise {
+                    match next_source_type {
+                        "Sampler" | "Streamer" => {
+                            let rate = next_params.get("rate").cloned().unwrap_or(1.0);
+                            next_params.insert("rate".to_string(), rate * factor);
+                        },
+                        _ => if let Some(freq) = next_params.get_mut("freq") {
+                            *freq *= factor;
+                        },
+                    }
+                }
+                
+                // the sampler knows the pitch of the sample, it only needs the key
+                let fit_key = next_params.remove(FIT_KEY_FLAG).is_some() && next_source_type == "Sampler";
+
+                // tied notes are held for as long as they last, unless they say otherwise
+                let tied = duration > 1.0;
+                let duration = duration / seq.speed * self.tempo / 1000.0;
+                if tied && !next_params.contains_key("sus") {
+                    let release = next_params.get("rel").cloned().unwrap_or(0.0) as f64;
+                    next_params.insert("sus".to_string(), (duration - release).max(0.0) as f32);
+                }
+
+                for (offset, hit_level) in hits.iter() {
+                    let mut hit_params = next_params.clone();
+                    let gain = contour_gain.unwrap_or(1.0) * if ghost { GHOST_LEVEL } else { 1.0 } * hit_level;
+                    if gain != 1.0 {
+                        let level = hit_params.get("lvl").cloned().unwrap_or_else(|| default_level(next_source_type));
+                        hit_params.insert("lvl".to_string(), level * gain);
+                    }
+
+                    let timestamp = event_time + self.meter.tick_time(self.meter.ticks(*offset), self.tempo);
+                    let bus = if seq.has_own_bus(&self.followed, &self.stems) { seq_idx as u32 + 1 } else { 0 };
+                    if !flood_guard.admit(timestamp, &next_event, bus, seq.id, &hit_params) {
+                        continue;
+                    }
+
+                    // events that will be dispatched to sampler, all at once
+                    self.stats.count_event(seq.id, &next_event);
+                    sounds.push(SoundEvent {
+                        timestamp,
+                        source_type: next_source_type.to_string(),
+                        sample_id: next_event.clone(),
+                        bus,
+                        remote: seq.tags.iter().any(|tag| tag == REMOTE_TAG),
+                        expendable: seq.tags.iter().any(|tag| tag == EXPENDABLE_TAG),
+                        fit_key: if fit_key { Some(self.scale.root()) } else { None },
+                        params: hit_params,
+                        ramps: ramp_targets.clone(),
+                        duration,
+                    });
+                }
+            }
+        }
+
+        for (line, id, cycle, timestamp) in cycle_starts.into_iter() {
+            self.sink.post(Message::Cycle { line, id, cycle, timestamp });
+        }
+
+        // as the sound events are sent as one, automation is placed relative to the notes
+        automation.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let mut automation: Vec<Message> = automation.into_iter().map(|(_, message)| message).collect();
+        if self.event_order.rank(EventClass::Automation) < self.event_order.rank(EventClass::Note) {
+            for message in automation.drain(..) {
+                self.sink.post(message);
+            }
+        }
+
+        // the tick hook has the last word
+        if let Some(hook) = &self.tick_hook {
+            match hook::run_hook(hook.as_ref(), std::mem::take(&mut sounds), step) {
+                Ok(hooked) => sounds = hooked,
+                Err((kept, err)) => {
+                    // the step still plays, without the hook
+                    sounds = kept;
+                    self.tick_hook = None;
+                    self.warn(&format!("tick hook failed, it's removed: {}", err));
+                },
+            }
+        }
+
+        // the sink takes what it can, in the order they'd have in the batch,
+        // the rest goes out as one
+        let order = &self.event_order;
+        sounds.sort_by(|a, b| a.timestamp.total_cmp(&b.timestamp)
+                       .then(order.rank(EventClass::of_sound(&a.params)).cmp(&order.rank(EventClass::of_sound(&b.params)))));
+        let mut batch = EventBatch::new();
+        for sound in sounds.iter() {
+            let event = sound.as_batch_event();
+            if !self.sink.push(&event) {
+                batch.push(&event);
+            }
+        }
+        self.sink.flush();
+        batch.sort(&self.event_order);
+        if !batch.is_empty() {
+            self.sink.post(Message::Events(batch));
+        }
+
+        for message in automation.into_iter() {
+            self.sink.post(message);
+        }
+
+        // once per evaluation, it'd be the same every cycle
+        if flood_guard.dropped() > 0 && !self.flood_warned {
+            self.flood_warned = true;
+            self.warn(&format!("{} duplicate or excess events dropped, at most {} lines start a sample at once", flood_guard.dropped(), flood::MAX_VOICES_PER_TIMESTAMP));
+        }
+    }
+
+    /// The main scheduler recursion.
+    /// Schedule the steps due before the given (browser) time. Returns false if
+    /// the scheduler stopped because its run limit was reached.
+    fn schedule_until(&mut self, horizon: f64) -> bool {
+        while self.logical_clock.is_due(horizon) {
+            if self.run_limit_reached() {
+                self.running = false;
+                self.run_limit = None;
+                self.sink.post(Message::RunComplete {
+                    step: self.step_count,
+                    timestamp: self.logical_clock.audio_time() + self.lookahead,
+                });
+                return false
+            }
+
+            // Get current events and hand them to the sink.
+            self.generate_and_send_events();
+            self.send_timecode();
+
+            // The grid stays regular, swing and nudges only shift the events.
+            self.logical_clock.advance(self.tempo, self.meter.ticks_per_step());
+        }
+        true
+    }
+
+    /// Warm up before the first downbeat: the sampler reads through the samples
+    /// the lines play and triggers each once, silently (see the worklet), and the
+    /// whole first bar is scheduled right away instead of step by step.
+    fn prime(&mut self, browser_timestamp: f64) {
+        let mut samples: Vec<String> = self.event_sequences.iter()
+            .filter(|seq| !seq.light)
+            .flat_map(|seq| seq.sound_names())
+            .filter(|name| !samples::SYNTH_SOURCES.contains(&name.as_str()) && !self.voices.contains(name) && !self.streams.contains(name))
+            .collect();
+        samples.sort();
+        samples.dedup();
+
+        self.sink.post(Message::Prime { samples });
+
+        let first_bar = browser_timestamp + self.meter.steps_per_bar() as f64 * self.tempo;
+        self.schedule_until(first_bar);
+    }
+
+    pub fn scheduler_routine(&mut self, browser_timestamp: f64) {
+        if !self.running {
+            return
+        }
+        self.playhead_time = self.playhead_time.max(browser_timestamp);
+
+        // locked to the audio clock, in case it drifted, stalled or resumed
+        if let Some((audio_time, browser_time)) = self.audio_clock.take() {
+            self.logical_clock.sync(audio_time, browser_time);
+        }
+
+        // All steps due before the next wakeup, each with its own logical time,
+        // so the timestamps don't depend on when exactly the wakeup happened.
+        let horizon = browser_timestamp + self.schedule_interval;
+        if !self.schedule_until(horizon) {
+            return
+        }
+
+        self.next_schedule_time = self.logical_clock.next_wakeup(horizon, self.schedule_interval);
+        
+        // Time-recursive call to scheduler function.
+        self.clock.wake_up_in(self.next_schedule_time);
+    }
+
+    /// Report the audio clock (AudioContext.currentTime, in seconds) as read at the
+    /// given browser time (performance.now(), in the scheduler's time origin). The
+    /// timestamps of the events are rebased on it on the next wakeup, so they stay
+    /// locked to the audio clock. Best reported about as often as the scheduler wakes up.
+    pub fn sync_audio_clock(&mut self, audio_time: f64, browser_time: f64) {
+        self.audio_clock = Some((audio_time, browser_time));
+        self.playhead_time = self.playhead_time.max(browser_time);
+    }
+
+    /// Start this scheduler, from the top.
+    pub fn start(&mut self, audio_timestamp: f64, browser_timestamp: f64) {
+        self.paused = false;
+        // with priming, the first downbeat leaves some time to warm up in
+        let delay = if self.priming { PRIME_DELAY } else { 0.0 };
+        self.audio_start_time = audio_timestamp + delay;
+        self.browser_start_time = browser_timestamp + delay * 1000.0;
+        self.logical_clock = LogicalClock::new(self.audio_start_time, self.browser_start_time);
+        // readings from before the start might be from before a suspend
+        self.audio_clock = None;
+        self.playhead_time = browser_timestamp;
+        self.step_count = 0;
+        self.stats = SessionStats::new();
+        // a switch that was queued before stopping happens right away
+        if let Some(queued) = self.queued.as_mut() {
+            queued.1 = 0;
+        }
+        self.running = true;
+        if self.priming {
+            self.prime(browser_timestamp);
+        }
+        self.scheduler_routine(browser_timestamp);
+    }
+
+    /// Stop this scheduler.
+    pub fn stop(&mut self) {
+        if self.locked {
+            self.warn("locked: stop blocked, use panic to stop anyway");
+        } else {
+            self.running = false;
+            self.paused = false;
+        }
+    }
+
+    /// Hold everything where it is, to go on from there with resume. What's
+    /// scheduled already still plays.
+    pub fn pause(&mut self) {
+        if self.locked {
+            self.warn("locked: pause blocked");
+        } else if self.running {
+            self.running = false;
+            self.paused = true;
+        }
+    }
+
+    /// Go on after a pause, the next step comes at the given audio time
+    /// (AudioContext.currentTime) and browser time (performance.now()), with
+    /// the lines, the position and the tempo where they were.
+    pub fn resume(&mut self, audio_timestamp: f64, browser_timestamp: f64) {
+        if !self.paused {
+            self.warn("not paused");
+            return;
+        }
+        self.logical_clock.resume(audio_timestamp, browser_timestamp);
+        // readings from before the pause are off by the pause
+        self.audio_clock = None;
+        self.playhead_time = browser_timestamp;
+        self.paused = false;
+        self.running = true;
+        self.scheduler_routine(browser_timestamp);
+    }
+
+    /// Stop after the given number of bars, counted from the next bar line (or from
+    /// the start, if the scheduler isn't running yet). Sends a RunComplete message
+    /// when it's done, so an installation can move on to the next scene.
+    pub fn run_for(&mut self, bars: usize) {
+        let steps_per_bar = self.meter.steps_per_bar();
+        let next_bar = if self.running { (self.step_count + steps_per_bar - 1) / steps_per_bar * steps_per_bar } else { 0 };
+        self.run_limit = Some(RunLimit::Step(next_bar + bars * steps_per_bar));
+    }
+
+    /// Stop once the events of the next step would be played at or after the given
+    /// audio time (AudioContext.currentTime). Sends a RunComplete message, like run_for.
+    pub fn run_until(&mut self, audio_time: f64) {
+        self.run_limit = Some(RunLimit::Time(audio_time));
+    }
+
+    fn run_limit_reached(&self) -> bool {
+        match self.run_limit {
+            Some(RunLimit::Step(at)) => self.step_count >= at,
+            Some(RunLimit::Time(at)) => self.logical_clock.audio_time() + self.lookahead >= at,
+            None => false,
+        }
+    }
+
+    /// Stop this scheduler, even in lockdown mode.
+    pub fn panic(&mut self) {
+        self.running = false;
+        self.paused = false;
+    }
+
+    /// Set tick duration.
+    pub fn set_tempo(&mut self, tempo: f64) {
+        self.change_tempo(tempo);
+    }
+
+    /// Set the tempo in beats per minute, the tick duration follows from the subdivision.
+    pub fn set_bpm(&mut self, bpm: f64) {
+        if bpm > 0.0 {
+            self.change_tempo(self.meter.step_duration(bpm));
+        } else {
+            self.warn(&format!("invalid tempo {}", bpm));
+        }
+    }
+
+    /// The current tempo in beats per minute.
+    pub fn get_bpm(&self) -> f64 {
+        self.meter.bpm(self.tempo)
+    }
+
+    /// Set how many steps make a beat (4 for 16th notes), the tempo in bpm stays the same.
+    /// In lockdown mode, it's blocked along with the step duration, if that jumps too far.
+    pub fn set_subdivision(&mut self, subdivision: u32) {
+        let bpm = self.get_bpm();
+        let previous = self.meter;
+        self.meter.set_subdivision(subdivision);
+        let tempo = self.meter.step_duration(bpm);
+        self.change_tempo(tempo);
+        if self.tempo != tempo {
+            self.meter = previous;
+        }
+    }
+
+    /// Set the number of ticks per beat, the grid that events within a step
+    /// (subdivisions, rolls, swing) are placed on. 96 by default.
+    pub fn set_ppqn(&mut self, ppqn: u32) {
+        self.meter.set_ppqn(ppqn);
+    }
+
+    /// Set how many beats make a bar, for queued switches, fills and stutters.
+    pub fn set_beats_per_bar(&mut self, beats_per_bar: u32) {
+        self.meter.set_beats_per_bar(beats_per_bar);
+    }
+
+    /// Set how often (in milliseconds) the scheduler wakes up to schedule the
+    /// steps that are due until the next wakeup, 100 by default. Longer intervals
+    /// mean fewer timer calls, but changes take longer to be heard.
+    pub fn set_schedule_interval(&mut self, interval: f64) {
+        if interval > 0.0 {
+            self.schedule_interval = interval;
+        } else {
+            self.warn(&format!("invalid schedule interval {}", interval));
+        }
+    }
+
+    /// Set how far ahead (in milliseconds) events are scheduled, i.e. to
+    /// leave time for them to reach networked peers. Never below the default.
+    pub fn set_lookahead(&mut self, lookahead: f64) {
+        self.lookahead = (lookahead / 1000.0).max(DEFAULT_LOOKAHEAD);
+    }
+
+    /// How far ahead (in seconds) events are scheduled, the events of a step
+    /// started at some audio time are heard that much later.
+    pub fn get_lookahead(&self) -> f64 {
+        self.lookahead
+    }
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::batch::BatchEvent;
+
+    /// keeps the timestamps and sample ids of the events it takes, and the messages
+    #[derive(Default)]
+    struct TestSink {
+        events: Vec<(f64, String)>,
+        messages: Vec<Message>,
+        /// takes no events
+        full: bool,
+    }
+
+    impl EventSink for TestSink {
+        fn push(&mut self, event: &BatchEvent) -> bool {
+            if !self.full {
+                self.events.push((event.timestamp, event.sample_id.to_string()));
+            }
+            !self.full
+        }
+
+        fn post(&mut self, message: Message) {
+            self.messages.push(message);
+        }
+    }
+
+    /// only keeps the delays it's asked to wake up after
+    #[derive(Default)]
+    struct TestClock {
+        delays: Vec<f64>,
+    }
+
+    impl Clock for TestClock {
+        fn now(&self) -> f64 {
+            0.0
+        }
+
+        fn wake_up_in(&mut self, delay: f64) {
+            self.delays.push(delay);
+        }
+    }
+
+    type TestScheduler = SchedulerCore<TestSink, TestClock>;
+
+    fn scheduler(input: &str) -> TestScheduler {
+        let mut scheduler = SchedulerCore::new(TestSink::default(), TestClock::default());
+        scheduler.set_seed(1);
+        scheduler.evaluate(Some(input.to_string())).unwrap();
+        scheduler
+    }
+
+    /// the sample ids of the events of the next steps, step by step
+    fn play(scheduler: &mut TestScheduler, steps: usize) -> Vec<Vec<String>> {
+        (0..steps).map(|_| {
+            scheduler.generate_and_send_events();
+            scheduler.logical_clock.advance(scheduler.tempo, scheduler.meter.ticks_per_step());
+            scheduler.sink.events.drain(..).map(|(_, sample_id)| sample_id).collect()
+        }).collect()
+    }
+
+    #[test]
+    fn test_scheduler_runs_natively() {
+        let mut scheduler = scheduler("bd ~ sn hh");
+        scheduler.sink.messages.clear();
+        scheduler.start(0.0, 0.0);
+
+        // the first step is scheduled right away, a lookahead ahead, on a bar,
+        // and the line starts its first cycle
+        assert_eq!(scheduler.sink.events, vec![(0.1, "bd".to_string())]);
+        assert!(matches!(scheduler.sink.messages[..], [Message::Beat { .. }, Message::Bar { .. }, Message::Cycle { line: 0, cycle: 0, .. }]));
+        // and the clock is asked to wake the scheduler up again
+        assert_eq!(scheduler.clock.delays.len(), 1);
+
+        scheduler.sink.events.clear();
+        assert_eq!(play(&mut scheduler, 3), vec![vec![], vec!["sn"], vec!["hh"]]);
+    }
+
+    #[test]
+    fn test_start_and_stop_sequence() {
+        let mut scheduler = scheduler("bd sn hh\ncp ~");
+        assert_eq!(play(&mut scheduler, 2), vec![vec!["bd", "cp"], vec!["sn"]]);
+
+        // a stopped line is skipped, the others go on
+        scheduler.stop_sequence(0);
+        assert_eq!(play(&mut scheduler, 2), vec![vec!["cp"], vec![]]);
+        assert_eq!(scheduler.current_step(0), None);
+
+        // started again, it's from the top
+        scheduler.start_sequence(0, false);
+        assert_eq!(play(&mut scheduler, 2), vec![vec!["bd", "cp"], vec!["sn"]]);
+
+        // quantized, it waits for the next bar line, that's step 16
+        scheduler.running = true;
+        scheduler.stop_sequence(0);
+        scheduler.start_sequence(0, true);
+        let steps = play(&mut scheduler, 11);
+        assert!(steps[..10].iter().all(|events| !events.contains(&"bd".to_string())));
+        assert_eq!(steps[10], vec!["bd", "cp"]);
+
+        // there's no such line
+        scheduler.start_sequence(2, false);
+        assert!(matches!(scheduler.sink.messages.last(), Some(Message::Warnings(warnings)) if warnings[0] == "no line 2"));
+    }
+
+    #[test]
+    fn test_failed_lines_keep_their_place() {
+        // an unlabeled line that doesn't read like anything before keeps
+        // what was in its place
+        let mut unlabeled = scheduler("bd ~\nhh hh\ncp ~ ~");
+        let errors = unlabeled.evaluate(Some("bd ~\n(((\ncp ~ ~".to_string())).unwrap_err();
+        assert_eq!(errors[0].line, 2);
+        assert_eq!(unlabeled.sequence_ids(), vec![0, 1, 2]);
+        assert_eq!(play(&mut unlabeled, 1), vec![vec!["bd", "hh", "cp"]]);
+
+        // a labeled one keeps its sequence by label, where it is in the input
+        let mut labeled = scheduler("hh hh\ndrums: bd ~");
+        labeled.evaluate(Some("drums: bd (((\nhh hh".to_string())).unwrap_err();
+        assert_eq!(labeled.sequence_ids(), vec![1, 0]);
+        labeled.mute(0);
+        assert_eq!(play(&mut labeled, 1), vec![vec!["hh"]]);
+    }
+
+    #[test]
+    fn test_empty_patterns_are_errors() {
+        let mut scheduler = scheduler("bd ~ sn ~");
+        let errors = scheduler.evaluate(Some("bd ~ sn ~\ndrums: @lvl: cyc >> 0.5".to_string())).unwrap_err();
+        assert_eq!((errors[0].line, errors[0].column), (2, 8));
+        assert_eq!(errors.len(), 1);
+
+        let errors = scheduler.evaluate(Some("intro: bd ~\nverse: sn ~\nintro*0 >> verse*0".to_string())).unwrap_err();
+        assert_eq!((errors[0].line, errors[0].column), (3, 1));
+
+        let errors = scheduler.evaluate(Some("bd(0,0)".to_string())).unwrap_err();
+        assert_eq!((errors[0].line, errors[0].column), (1, 3));
+    }
+
+    #[test]
+    fn test_refused_events_go_out_as_batch() {
+        let mut scheduler = scheduler("[bd sn]");
+        scheduler.sink.full = true;
+        play(&mut scheduler, 1);
+
+        let batches: Vec<usize> = scheduler.sink.messages.iter().filter_map(|message| match message {
+            Message::Events(batch) => Some(batch.len()),
+            _ => None,
+        }).collect();
+        assert_eq!(batches, vec![2]);
+    }
+}
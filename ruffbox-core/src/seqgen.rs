@@ -1,3 +1,5 @@
+use rand::SeedableRng;
+use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
 use std::hash::Hash;
 use vom_rs::safe_pfa::Pfa;
@@ -14,20 +16,23 @@ pub trait SequenceGenerator<T, S> {
 ////////////
 
 pub struct RandomSequenceGenerator<T> {
-    items: Vec<T>
+    items: Vec<T>,
+    // seeded by the scheduler, so the choices can be replayed
+    rng: StdRng,
 }
 
 impl <T: Copy> RandomSequenceGenerator<T> {
-    pub fn from_seq(seq: &Vec<T>) -> Self {
+    pub fn from_seq(seq: &Vec<T>, seed: u64) -> Self {
         RandomSequenceGenerator {
             items: seq.to_vec(),
+            rng: StdRng::seed_from_u64(seed),
         }
     }
 }
 
 impl <T: Copy> SequenceGenerator<T, usize> for RandomSequenceGenerator<T> {    
     fn get_next(&mut self) -> Option<T> {
-        match self.items.choose(&mut rand::thread_rng()) {
+        match self.items.choose(&mut self.rng) {
             Some(thing) => Some(*thing),
             None => None                
         }
@@ -231,6 +236,16 @@ mod tests {
         println!("Result: {:?}", results);
     }
 
+    #[test]
+    fn test_random_gen_seeded() {
+        let items: Vec<usize> = (0..16).collect();
+        let mut rnd_gen = RandomSequenceGenerator::from_seq(&items, 42);
+        let mut same_gen = RandomSequenceGenerator::from_seq(&items, 42);
+        let results: Vec<Option<usize>> = (0..32).map(|_| rnd_gen.get_next()).collect();
+        let replayed: Vec<Option<usize>> = (0..32).map(|_| same_gen.get_next()).collect();
+        assert_eq!(results, replayed);
+    }
+
     #[test]
     fn test_pfa_gen() {
         let in_vec:Vec<N32> = vec![(20.0).into(), (200.0).into(), (10.0).into(), (20.0).into(), (200.0).into(), (10.0).into(),
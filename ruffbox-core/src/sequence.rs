@@ -0,0 +1,769 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use rand::Rng;
+use rand::rngs::StdRng;
+use decorum::N32;
+
+use crate::seqgen::*;
+use crate::humanize::VelocityContour;
+use crate::expr::{Condition, Counters, Expr};
+use crate::parser::{ChoiceMode, GatePattern, ParamValue, CHANCE_PARAM, EVERY_PARAM, TIE_PARAM};
+use crate::notation::{ParamPattern, Pattern, PatternEvent};
+
+pub type EventHash = u64;
+
+/// events with this name repeat the master output instead of playing something,
+/// i.e. "stutter;len=0.125;rep=4"
+pub const STUTTER_EVENT: &str = "stutter";
+
+fn calculate_hash<T: Hash>(t: &T) -> u64 {
+    let mut s = DefaultHasher::new();
+    t.hash(&mut s);
+    s.finish()
+}
+
+/// An event param, either fixed, following a modulation input,
+/// or moving over the duration of the event.
+#[derive(Clone, PartialEq, Hash)]
+pub enum EventParam {
+    Const(N32),
+    Mod(String, N32, N32),
+    Ramp(N32, N32),
+    Expr(Expr),
+}
+
+impl EventParam {
+    fn from_param_value(value: &ParamValue) -> Self {
+        match value {
+            ParamValue::Const(val) => EventParam::Const((*val).into()),
+            ParamValue::Mod(input, min, max) => EventParam::Mod(input.to_string(), (*min).into(), (*max).into()),
+            ParamValue::Ramp(from, to) => EventParam::Ramp((*from).into(), (*to).into()),
+            ParamValue::Expr(expr) => EventParam::Expr(expr.clone()),
+        }
+    }
+
+    /// the current value (where a ramp starts), unknown modulation inputs count as zero
+    fn resolve(&self, mod_inputs: &HashMap<String, f32>, counters: &Counters) -> f32 {
+        match self {
+            EventParam::Const(val) => (*val).into(),
+            EventParam::Expr(expr) => expr.eval(counters),
+            EventParam::Ramp(from, _) => (*from).into(),
+            EventParam::Mod(input, min, max) => {
+                let min: f32 = (*min).into();
+                let max: f32 = (*max).into();
+                min + mod_inputs.get(input).cloned().unwrap_or(0.0) * (max - min)
+            }
+        }
+    }
+}
+
+pub struct MainEvent {
+    name: String,
+    params: HashMap<String, EventParam>,
+}
+
+impl Hash for MainEvent {
+     fn hash<H: Hasher>(&self, state: &mut H) {
+         self.name.hash(state);
+         for (par, val) in self.params.iter() {
+             par.hash(state);
+             val.hash(state);
+         }
+     }    
+}
+
+impl MainEvent {
+    fn from_pattern_event(input: &PatternEvent) -> Self {
+        let mut param_map = HashMap::new();
+
+        for param_tuple in input.params.iter() {
+            param_map.insert(param_tuple.0.to_string(), EventParam::from_param_value(&param_tuple.1));
+        }
+        
+        MainEvent {
+            name: input.name.to_string(),
+            params: param_map,
+        }
+    }
+
+    /// whether the event plays this time, on the given cycle of its line,
+    /// if it's conditional ("bd?0.5", "sn!4")
+    fn is_due(&self, cycle: usize, rng: &mut StdRng) -> bool {
+        let condition = |name| match self.params.get(name) {
+            Some(EventParam::Const(val)) => Some(f32::from(*val)),
+            _ => None,
+        };
+        if condition(EVERY_PARAM).map_or(false, |every| every >= 1.0 && cycle % every as usize != 0) {
+            return false;
+        }
+        condition(CHANCE_PARAM).map_or(true, |chance| rng.gen::<f32>() < chance)
+    }
+
+    /// the steps the event is held for beyond its own ("saw _ _")
+    fn ties(&self) -> f64 {
+        match self.params.get(TIE_PARAM) {
+            Some(EventParam::Const(ties)) => f32::from(*ties) as f64,
+            _ => 0.0,
+        }
+    }
+
+    /// the params that go along with the event
+    fn sound_params(&self) -> impl Iterator<Item = (&String, &EventParam)> {
+        self.params.iter().filter(|(par, _)| par.as_str() != CHANCE_PARAM && par.as_str() != EVERY_PARAM && par.as_str() != TIE_PARAM)
+    }
+}
+
+impl PartialEq for MainEvent {    
+    fn eq(&self, other: &Self) -> bool {
+        for (param, value) in self.params.iter() {
+            if !other.params.contains_key(param) {
+                return false
+            } else if *value != other.params[param] {
+                return false
+            }
+        }
+        self.name == other.name        
+    }
+}
+
+/// A place in a line, with its offset, whether it's stacked on the one before,
+/// its position and, for choices, the mode and all events to pick from.
+type Slot = (f64, bool, usize, Option<ChoiceMode>, Vec<EventHash>);
+
+/// Store the events of a line, events that share a step (subdivisions) are
+/// grouped under a hash of their own, as are the events of a choice. The line
+/// they're written on is none for the line itself, the label of a sub-pattern
+/// otherwise. Returns the hashes of the steps.
+fn hash_steps(events: &[PatternEvent], line: Option<&str>, event_refs: &mut HashMap<EventHash, MainEvent>, subdivisions: &mut HashMap<EventHash, Vec<(f64, EventHash)>>, choices: &mut HashMap<EventHash, (ChoiceMode, Vec<EventHash>)>) -> Vec<EventHash> {
+    // the events of a choice count as one, with the offset and position of the first
+    let mut slots: Vec<Slot> = Vec::new();
+    
+    for parsed_event in events.iter() {
+        let main_event = MainEvent::from_pattern_event(parsed_event);
+        let main_event_hash = calculate_hash::<MainEvent>(&main_event);
+        event_refs.insert(main_event_hash, main_event);
+
+        match (slots.last_mut(), parsed_event.choice) {
+            (Some(slot), Some((_, idx))) if idx > 0 => slot.4.push(main_event_hash),
+            (_, choice) => slots.push((parsed_event.offset, parsed_event.stacked, parsed_event.position, choice.map(|(mode, _)| mode), vec![main_event_hash])),
+        }
+    }
+
+    let mut steps: Vec<Vec<(f64, EventHash)>> = Vec::new();
+
+    for (offset, stacked, position, mode, options) in slots.into_iter() {
+        let ev_hash = match mode {
+            Some(mode) => {
+                // with the line and the position in the hash, equal choices (even in
+                // a sub-pattern or the other part of a conditional line) take their turns separately
+                let choice_hash = calculate_hash(&(line, position, mode == ChoiceMode::Alternate, &options));
+                choices.insert(choice_hash, (mode, options));
+                choice_hash
+            },
+            None => options[0],
+        };
+
+        match steps.last_mut() {
+            Some(step) if offset > 0.0 || stacked => step.push((offset, ev_hash)),
+            _ => steps.push(vec![(offset, ev_hash)]),
+        }
+    }
+
+    steps.into_iter().map(|step| {
+        if step.len() == 1 {
+            return step[0].1;
+        }
+        let group: Vec<(u64, EventHash)> = step.iter().map(|(offset, ev_hash)| (offset.to_bits(), *ev_hash)).collect();
+        let group_hash = calculate_hash(&group);
+        subdivisions.insert(group_hash, step);
+        group_hash
+    }).collect()
+}
+
+/// A part of a conditional line, as steps, with its generator and param sequences.
+#[derive(Clone)]
+pub struct Branch {
+    steps: Vec<EventHash>,
+    generator: String,
+    params: Vec<ParamPattern>,
+}
+
+/// the condition and the two parts of a conditional line
+pub type Branches = (Condition, Branch, Branch);
+
+/// The steps of a pattern, for conditional lines those of the part it starts with
+/// (and both parts).
+fn hash_pattern(pattern: &Pattern, event_refs: &mut HashMap<EventHash, MainEvent>, subdivisions: &mut HashMap<EventHash, Vec<(f64, EventHash)>>, choices: &mut HashMap<EventHash, (ChoiceMode, Vec<EventHash>)>) -> (Vec<EventHash>, Option<Branches>) {
+    match &pattern.condition {
+        Some((condition, else_part)) => {
+            let then_branch = Branch {
+                steps: hash_steps(&pattern.events[..else_part.events], None, event_refs, subdivisions, choices),
+                generator: pattern.generator.clone(),
+                params: pattern.params[..else_part.params].to_vec(),
+            };
+            let else_branch = Branch {
+                steps: hash_steps(&pattern.events[else_part.events..], None, event_refs, subdivisions, choices),
+                generator: else_part.generator.clone(),
+                params: pattern.params[else_part.params..].to_vec(),
+            };
+            (then_branch.steps.clone(), Some((condition.clone(), then_branch, else_branch)))
+        },
+        None => (hash_steps(&pattern.events, None, event_refs, subdivisions, choices), None),
+    }
+}
+
+/// The generator of a line's steps, random ones are seeded from the given rng,
+/// cycles go on from the given index, if any.
+fn event_generator(generator: &str, steps: &[EventHash], rng: &mut StdRng, index: Option<usize>) -> Box<dyn SequenceGenerator<EventHash, usize>> {
+    let steps = steps.to_vec();
+    match (generator, index) {
+        ("rnd", _) => Box::new(RandomSequenceGenerator::from_seq(&steps, rng.gen())),
+        ("cyc", Some(index)) => Box::new(CycleSequenceGenerator::from_seq_with_index(&steps, index)),
+        ("learn", _) => Box::new(PfaSequenceGenerator::from_seq(&steps)),
+        _ => Box::new(CycleSequenceGenerator::from_seq(&steps)),
+    }
+}
+
+/// The generator of a param sequence, like event_generator.
+fn param_generator(param: &ParamPattern, rng: &mut StdRng, index: Option<usize>) -> Box<dyn SequenceGenerator<N32, usize>> {
+    let mut param_conv:Vec<N32> = Vec::new();
+    for raw_float in &param.values {
+        param_conv.push((*raw_float).into())
+    }
+
+    match (param.generator.as_str(), index) {
+        ("rnd", _) => Box::new(RandomSequenceGenerator::from_seq(&param_conv, rng.gen())),
+        ("cyc", Some(index)) => Box::new(CycleSequenceGenerator::from_seq_with_index(&param_conv, index)),
+        ("learn", _) => Box::new(PfaSequenceGenerator::from_seq(&param_conv)),
+        ("bounce", _) => Box::new(BounceSequenceGenerator::from_params(param_conv[0], param_conv[1], param_conv[2])),
+        ("ramp", _) => Box::new(RampSequenceGenerator::from_params(param_conv[0], param_conv[1], param_conv[2])),
+        //"brownian" => Box::new(BounceSequenceGenerator::from_params(param_conv[0], param_conv[1], param_conv[2])),
+        _ => Box::new(CycleSequenceGenerator::from_seq(&param_conv)),
+    }
+}
+
+/// A simple event sequence represented by a vector of strings and params
+pub struct EventSequence {
+    /// stays the same as long as the line can be told apart across evaluations
+    pub id: u32,
+    pub name: Option<String>,
+    /// the pattern as written, to find the line again after edits
+    pub source: String,
+    event_refs: HashMap<EventHash, MainEvent>,
+    /// the events of the steps that are split up, with their offsets
+    subdivisions: HashMap<EventHash, Vec<(f64, EventHash)>>,
+    /// the events of the choices, i.e. "<bd sn>"
+    choices: HashMap<EventHash, (ChoiceMode, Vec<EventHash>)>,
+    /// how often each alternating choice has been played
+    choice_turns: HashMap<EventHash, usize>,
+    events: Box<dyn SequenceGenerator<EventHash, usize>>,
+    param_generators: HashMap<String, Box<dyn SequenceGenerator<N32, usize>>>,
+    pub step_probabilities: Vec<f32>,
+    /// tempo-synced gain of the line's bus
+    pub gate: Option<GatePattern>,
+    /// slowly drifting level, across steps
+    pub contour: Option<VelocityContour>,
+    /// the line's events in order, ghost notes are picked from them
+    pub cycle: Vec<EventHash>,
+    /// chance of a ghost note on an empty step
+    pub ghost_density: f32,
+    /// the ghost notes of the current cycle, by step
+    ghost_steps: Vec<Option<EventHash>>,
+    pub step_count: usize,
+    /// conditional lines pick one of their parts at the start of every cycle
+    branches: Option<Branches>,
+    /// cycles a conditional line started, and the steps left of the current one
+    branch_cycles: usize,
+    branch_steps_left: usize,
+    /// labels of other lines, and the events those play
+    references: HashMap<String, Vec<EventHash>>,
+    /// the remaining events of the sub-pattern that's currently playing
+    pending: VecDeque<EventHash>,
+    /// only play above (true) or below (false) a certain energy level
+    pub energy_threshold: Option<(bool, f32)>,
+    pub tags: Vec<String>,
+    /// sends lighting cues instead of sound events
+    pub light: bool,
+    /// effects the sounds of this line run through
+    pub inserts: Vec<String>,
+    /// steps played per step of the master clock
+    pub speed: f64,
+    /// where the line is (in its own steps) at the start of the next tick
+    pub position: f64,
+    /// muted or soloed by hand, these keep across evaluations
+    pub muted: bool,
+    pub soloed: bool,
+    /// stopped lines don't move at all until they're started again (like clips),
+    /// from the top, on the given step
+    pub stopped: bool,
+    pub start_at: Option<usize>,
+    /// to start the line from the top, its generator and param sequences
+    generator: String,
+    params: Vec<ParamPattern>,
+}
+
+impl EventSequence {
+        
+    /// Create an event sequence from a parsed pattern, random choices are seeded from the given rng.
+    pub fn from_pattern(pattern: &Pattern, rng: &mut StdRng) -> Self {        
+        let mut main_events = HashMap::new();
+        let mut subdivisions = HashMap::new();
+        let mut choices = HashMap::new();
+        let (event_hashes, branches) = hash_pattern(pattern, &mut main_events, &mut subdivisions, &mut choices);
+
+        let mut param_row_map: HashMap<String, Box<dyn SequenceGenerator<N32, usize>>> = HashMap::new();
+        
+        for parsed_param_seq in pattern.params.iter() {
+            param_row_map.insert(parsed_param_seq.param.to_string(), param_generator(parsed_param_seq, rng, None));
+        }
+        
+                
+        EventSequence {
+            name: None,
+            event_refs: main_events,
+            subdivisions,
+            choices,
+            choice_turns: HashMap::new(),
+            events: event_generator(&pattern.generator, &event_hashes, rng, None),
+            cycle: event_hashes,
+            
+            param_generators: param_row_map,
+            step_probabilities: Vec::new(),
+            gate: None,
+            contour: None,
+            ghost_density: 0.0,
+            ghost_steps: Vec::new(),
+            step_count: 0,
+            branches,
+            branch_cycles: 0,
+            branch_steps_left: 0,
+            references: HashMap::new(),
+            pending: VecDeque::new(),
+            energy_threshold: None,
+            tags: Vec::new(),
+            light: false,
+            inserts: Vec::new(),
+            speed: 1.0,
+            position: 0.0,
+            id: 0,
+            source: String::new(),
+            muted: false,
+            soloed: false,
+            stopped: false,
+            start_at: None,
+            generator: pattern.generator.clone(),
+            params: pattern.params.clone(),
+        }
+    }
+
+    /// Update an existing sequence from a parsed pattern, random choices are seeded from the given rng.
+    pub fn update_sequence(&mut self, pattern: &Pattern, rng: &mut StdRng) {
+        self.event_refs.clear();
+        self.param_generators.clear();
+
+        let mut main_events = HashMap::new();
+        let mut subdivisions = HashMap::new();
+        let mut choices = HashMap::new();
+                
+        //let mut param_row_map: HashMap<String, Box<dyn SequenceGenerator<N32>>> = HashMap::new();
+        
+        for parsed_param_seq in pattern.params.iter() {
+            let key = parsed_param_seq.param.to_string();
+            let mut state = 0;
+            if self.param_generators.contains_key(&key) {
+                state = self.param_generators[&key].get_state();
+            }
+            
+            self.param_generators.insert(key, param_generator(parsed_param_seq, rng, Some(state)));
+        }
+
+        let (event_hashes, branches) = hash_pattern(pattern, &mut main_events, &mut subdivisions, &mut choices);
+        
+        self.event_refs = main_events;
+        // a conditional line picks its part anew, from the start of it
+        self.branches = branches;
+        self.branch_steps_left = 0;
+        self.subdivisions = subdivisions;
+        // unchanged alternations go on where they are
+        self.choice_turns.retain(|choice_hash, _| choices.contains_key(choice_hash));
+        self.choices = choices;
+        self.ghost_steps.clear();
+
+        let cycle_state = self.events.get_state();
+        self.generator = pattern.generator.clone();
+        self.params = pattern.params.clone();
+        self.events = event_generator(&pattern.generator, &event_hashes, rng, Some(cycle_state));
+        self.cycle = event_hashes;
+    }
+
+    /// Set the events to play for references to other lines (by label).
+    pub fn set_references(&mut self, references: &HashMap<String, Vec<PatternEvent>>) {
+        self.references.clear();
+        self.pending.clear();
+
+        for (label, flat_events) in references.iter() {
+            let flat_hashes = hash_steps(flat_events, Some(label), &mut self.event_refs, &mut self.subdivisions, &mut self.choices);
+            self.references.insert(label.to_string(), flat_hashes);
+        }
+    }
+
+    /// Go back to the top of the line, as if it was just evaluated, random
+    /// choices are seeded from the given rng.
+    pub fn restart(&mut self, rng: &mut StdRng) {
+        self.step_count = 0;
+        self.position = 0.0;
+        self.pending.clear();
+        self.choice_turns.clear();
+        self.ghost_steps.clear();
+        self.branch_cycles = 0;
+        self.branch_steps_left = 0;
+        self.events = event_generator(&self.generator, &self.cycle, rng, None);
+        self.param_generators = self.params.iter().map(|param| (param.param.to_string(), param_generator(param, rng, None))).collect();
+    }
+
+    /// lines with insert effects, a gate, a followed envelope or a stem being recorded
+    /// play on their own bus
+    pub fn has_own_bus(&self, followed: &HashSet<String>, stems: &HashSet<String>) -> bool {
+        !self.inserts.is_empty() || self.gate.is_some() || self.name.as_ref().map_or(false, |name| followed.contains(name) || stems.contains(name))
+    }
+
+    /// check whether this sequence plays at the given energy level
+    pub fn is_active(&self, energy: f32) -> bool {
+        match self.energy_threshold {
+            Some((true, threshold)) => energy > threshold,
+            Some((false, threshold)) => energy < threshold,
+            None => true,
+        }
+    }
+
+    /// Move on by a tick of the master clock, returns when the line's steps that fall
+    /// into it start (in master steps from the start of the tick). Faster lines play
+    /// several steps per tick, slower ones skip some ticks.
+    pub fn due_steps(&mut self) -> Vec<f64> {
+        let mut due = Vec::new();
+        let mut step = self.position.ceil();
+        while step < self.position + self.speed {
+            due.push((step - self.position) / self.speed);
+            step += 1.0;
+        }
+        self.position += self.speed;
+        due
+    }
+
+    /// the events on a step, with their offsets within it
+    fn step_events(&self, step_hash: &EventHash) -> Vec<(f64, EventHash)> {
+        self.subdivisions.get(step_hash).cloned().unwrap_or_else(|| vec![(0.0, *step_hash)])
+    }
+
+    /// the event a choice picks this time, other events are just themselves
+    fn choose(&mut self, ev_hash: EventHash, rng: &mut StdRng) -> EventHash {
+        match self.choices.get(&ev_hash) {
+            Some((ChoiceMode::Random, options)) => options[rng.gen_range(0, options.len())],
+            Some((ChoiceMode::Alternate, options)) => {
+                let turn = self.choice_turns.entry(ev_hash).or_insert(0);
+                let picked = options[*turn % options.len()];
+                *turn += 1;
+                picked
+            },
+            None => ev_hash,
+        }
+    }
+
+    /// the events of the line that play a sound, in order
+    fn sounds(&self) -> Vec<EventHash> {
+        self.cycle.iter().flat_map(|step_hash| self.step_events(step_hash)).flat_map(|(_, ev_hash)| {
+            self.choices.get(&ev_hash).map_or_else(|| vec![ev_hash], |(_, options)| options.clone())
+        }).filter(|ev_hash| {
+            let name = &self.event_refs[ev_hash].name;
+            name != "~" && name != STUTTER_EVENT && !self.references.contains_key(name)
+        }).collect()
+    }
+
+    /// the names of the line's sounds, in order, i.e. the samples it plays
+    pub fn sound_names(&self) -> Vec<String> {
+        self.sounds().iter().map(|ev_hash| self.event_refs[ev_hash].name.clone()).collect()
+    }
+
+    /// an event with its fixed params
+    fn resolve_event(&self, ev_hash: &EventHash, mod_inputs: &HashMap<String, f32>, counters: &Counters) -> Option<(String, HashMap<String, f32>)> {
+        let ev = self.event_refs.get(ev_hash)?;
+        Some((ev.name.clone(), ev.sound_params().map(|(par, val)| (par.to_string(), val.resolve(mod_inputs, counters))).collect()))
+    }
+
+    /// the first sound of the line, i.e. for fills
+    pub fn first_sound(&self, mod_inputs: &HashMap<String, f32>, counters: &Counters) -> Option<(String, HashMap<String, f32>)> {
+        self.resolve_event(self.sounds().first()?, mod_inputs, &self.line_counters(counters))
+    }
+
+    /// Pick the steps of the next cycle that get a ghost note, if they're empty.
+    fn place_ghost_notes(&mut self, rng: &mut StdRng) {
+        let sounds = self.sounds();
+        let density = self.ghost_density;
+        self.ghost_steps = (0..self.cycle.len()).map(|_| {
+            if !sounds.is_empty() && rng.gen::<f32>() < density {
+                Some(sounds[rng.gen_range(0, sounds.len())])
+            } else {
+                None
+            }
+        }).collect();
+    }
+
+    /// the ghost note (and its params) on the step that was just played, if there is one
+    pub fn ghost_note(&self, mod_inputs: &HashMap<String, f32>, counters: &Counters) -> Option<(String, HashMap<String, f32>)> {
+        if self.cycle.is_empty() || self.step_count == 0 {
+            return None;
+        }
+
+        let ev_hash = self.ghost_steps.get((self.step_count - 1) % self.cycle.len())?.as_ref()?;
+        self.resolve_event(ev_hash, mod_inputs, &self.line_counters(counters))
+    }
+
+    /// how often the line went around, up to the step that was just played
+    pub fn current_cycle(&self) -> usize {
+        if self.branches.is_some() {
+            self.branch_cycles.saturating_sub(1)
+        } else if self.cycle.is_empty() {
+            0
+        } else {
+            self.step_count.saturating_sub(1) / self.cycle.len()
+        }
+    }
+
+    /// the scheduler's counters, plus how often the line went around
+    fn line_counters(&self, counters: &Counters) -> Counters {
+        Counters { cycle: self.current_cycle(), ..*counters }
+    }
+
+    /// For conditional lines, check the condition at the start of every cycle
+    /// and play the part it picks, with its own generator and param sequences.
+    fn next_branch(&mut self, rng: &mut StdRng, counters: &Counters) {
+        let (condition, then_branch, else_branch) = match &self.branches {
+            Some(branches) => branches,
+            None => return,
+        };
+
+        if self.branch_steps_left == 0 {
+            let counters = Counters { cycle: self.branch_cycles, ..*counters };
+            let branch = if condition.holds(&counters) { then_branch } else { else_branch };
+            self.cycle = branch.steps.clone();
+            self.events = event_generator(&branch.generator, &branch.steps, rng, None);
+            self.param_generators = branch.params.iter().map(|param| (param.param.to_string(), param_generator(param, rng, None))).collect();
+            self.branch_steps_left = branch.steps.len();
+            self.branch_cycles += 1;
+        }
+        self.branch_steps_left = self.branch_steps_left.saturating_sub(1);
+    }
+
+    /// get the events of the next step in the sequence, with their offset within the
+    /// step, where their ramped params end up and how long they last (in steps),
+    /// none if it's a rest
+    pub fn get_next_event(&mut self, rng: &mut StdRng, mod_inputs: &HashMap<String, f32>, counters: &Counters) -> Vec<(f64, String, HashMap<String, f32>, HashMap<String, f32>, f64)> {
+        let step = self.step_count;
+        self.step_count += 1;
+
+        // sub-patterns are played to the end before a conditional line checks again
+        if self.pending.is_empty() {
+            self.next_branch(rng, counters);
+        }
+
+        // ghost notes are placed anew every cycle
+        if self.ghost_density > 0.0 && !self.cycle.is_empty() && step % self.cycle.len() == 0 {
+            self.place_ghost_notes(rng);
+        }
+        
+        // sub-patterns are played completely before the sequence moves on
+        let next_hash = match self.pending.pop_front() {
+            Some(ev_hash) => Some(ev_hash),
+            None => match self.events.get_next() {
+                Some(ev_hash) if self.event_refs.get(&ev_hash).map_or(false, |ev| self.references.contains_key(&ev.name)) => {
+                    self.pending.extend(self.references[&self.event_refs[&ev_hash].name].iter());
+                    self.pending.pop_front()
+                },
+                other => other,
+            }
+        };
+        
+        let mut step_events: Vec<(f64, EventHash)> = match next_hash {
+            Some(step_hash) => self.step_events(&step_hash),
+            None => Vec::new(),
+        };
+        // choices pick their event every time they come up
+        for (_, ev_hash) in step_events.iter_mut() {
+            *ev_hash = self.choose(*ev_hash, rng);
+        }
+        // events last until the next one of the step (rests included), or its end,
+        // plus the steps they're tied over, rests on steps of their own don't hold them
+        let offsets: Vec<f64> = step_events.iter().map(|(offset, _)| *offset).collect();
+        let duration = |offset: f64| offsets.iter().cloned().filter(|next| *next > offset).fold(1.0, f64::min) - offset;
+        // by position, the same event might come up more than once on a step
+        let durations: Vec<f64> = step_events.iter().map(|(offset, ev_hash)| duration(*offset) + self.event_refs[ev_hash].ties()).collect();
+        let mut step_events: Vec<(f64, EventHash, f64)> = step_events.into_iter().zip(durations)
+            .map(|((offset, ev_hash), duration)| (offset, ev_hash, duration))
+            .collect();
+        step_events.retain(|(_, ev_hash, _)| self.event_refs[ev_hash].name != "~");
+
+        // conditional events, by chance or on every so many cycles of the line
+        let cycle = self.current_cycle();
+        step_events.retain(|(_, ev_hash, _)| self.event_refs[ev_hash].is_due(cycle, rng));
+
+        if step_events.is_empty() {
+            return Vec::new();
+        }
+
+        // each step might have its own trigger probability
+        if !self.step_probabilities.is_empty() {
+            let prob = self.step_probabilities[step % self.step_probabilities.len()];
+            if rng.gen::<f32>() >= prob {
+                return Vec::new()
+            }
+        }
+
+        let counters = self.line_counters(counters);
+
+        // the param sequences move on once per step, all events on it get the same values
+        let mut generated: Vec<(String, f32)> = Vec::new();
+        for (par, gen) in self.param_generators.iter_mut() {
+            if let Some(val) = gen.get_next() {
+                generated.push((par.to_string(), val.into()));
+            }
+        }
+
+        step_events.iter().map(|(offset, ev_hash, duration)| {
+            let ev = &self.event_refs[ev_hash];
+            let mut final_param_map: HashMap<String, f32> = HashMap::new();
+            let mut ramp_targets: HashMap<String, f32> = HashMap::new();
+
+            // pref for dyn params, so insert fixed pars first (might be overwritten)
+            for (par, val) in ev.sound_params() {
+                final_param_map.insert(par.to_string(), val.resolve(mod_inputs, &counters));
+                if let EventParam::Ramp(_, to) = val {
+                    ramp_targets.insert(par.to_string(), (*to).into());
+                }
+            }
+
+            for (par, val) in generated.iter() {
+                final_param_map.insert(par.to_string(), *val);
+            }
+            
+            // a generated value replaces the ramp
+            ramp_targets.retain(|par, _| !self.param_generators.contains_key(par));
+
+            (*offset, ev.name.clone(), final_param_map, ramp_targets, *duration)
+        }).collect()
+    }
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use rand::SeedableRng;
+    use crate::notation::{PatternParser, SeqNotation};
+
+    fn sequence(input: &str, references: &[(&str, &str)]) -> EventSequence {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut seq = EventSequence::from_pattern(&SeqNotation.parse(input).unwrap(), &mut rng);
+        let references: HashMap<String, Vec<PatternEvent>> = references.iter()
+            .map(|(label, body)| (label.to_string(), SeqNotation.parse(body).unwrap().events))
+            .collect();
+        seq.set_references(&references);
+        seq
+    }
+
+    /// the names of the events on the next steps, "~" for empty steps
+    fn play(seq: &mut EventSequence, steps: usize) -> Vec<String> {
+        let mut rng = StdRng::seed_from_u64(1);
+        (0..steps).map(|step| {
+            let counters = Counters { step, ..Counters::default() };
+            let events = seq.get_next_event(&mut rng, &HashMap::new(), &counters);
+            if events.is_empty() { "~".to_string() } else { events.iter().map(|ev| ev.1.clone()).collect::<Vec<_>>().join(" ") }
+        }).collect()
+    }
+
+    #[test]
+    fn test_equal_choices_take_separate_turns() {
+        // in the line, and in a sub-pattern
+        let mut seq = sequence("<bd sn> <bd sn>", &[]);
+        assert_eq!(play(&mut seq, 4), vec!["bd", "bd", "sn", "sn"]);
+
+        let mut seq = sequence("<bd sn> fill", &[("fill", "<bd sn>")]);
+        assert_eq!(play(&mut seq, 4), vec!["bd", "bd", "sn", "sn"]);
+    }
+
+    #[test]
+    fn test_branches_keep_their_generators_and_params() {
+        let mut seq = sequence("if cycle%2==0: bd sn @rate: cyc >> 1 2 else: hh hh @lvl: cyc >> 0.5", &[]);
+        let mut rng = StdRng::seed_from_u64(1);
+        let steps: Vec<(String, Vec<String>)> = (0..4).map(|_| {
+            let events = seq.get_next_event(&mut rng, &HashMap::new(), &Counters::default());
+            let mut params: Vec<String> = events[0].2.iter().map(|(param, value)| format!("{}={}", param, value)).collect();
+            params.sort();
+            (events[0].1.clone(), params)
+        }).collect();
+        assert_eq!(steps, vec![
+            ("bd".to_string(), vec!["rate=1".to_string()]),
+            ("sn".to_string(), vec!["rate=2".to_string()]),
+            ("hh".to_string(), vec!["lvl=0.5".to_string()]),
+            ("hh".to_string(), vec!["lvl=0.5".to_string()]),
+        ]);
+
+        // a random part stays random
+        let mut seq = sequence("if cycle%2==0: bd else: rnd >> hh sn cp", &[]);
+        let played = play(&mut seq, 40);
+        let else_steps: Vec<&str> = played.iter().enumerate().filter(|(step, _)| step % 4 != 0).map(|(_, name)| name.as_str()).collect();
+        assert!(else_steps.iter().all(|name| ["hh", "sn", "cp"].contains(name)));
+        assert_ne!(else_steps.chunks(3).filter(|cycle| *cycle == ["hh", "sn", "cp"]).count(), 10);
+    }
+
+    #[test]
+    fn test_restart() {
+        // from the top, params included
+        let mut seq = sequence("bd sn hh @rate: cyc >> 1 2", &[]);
+        let mut rng = StdRng::seed_from_u64(1);
+        play(&mut seq, 2);
+        seq.restart(&mut rng);
+        let events = seq.get_next_event(&mut rng, &HashMap::new(), &Counters::default());
+        assert_eq!((events[0].1.as_str(), events[0].2["rate"]), ("bd", 1.0));
+        assert_eq!(seq.step_count, 1);
+
+        // a conditional line picks its part anew
+        let mut seq = sequence("if cycle==0: bd else: sn", &[]);
+        assert_eq!(play(&mut seq, 3), vec!["bd", "sn", "sn"]);
+        seq.restart(&mut rng);
+        assert_eq!(play(&mut seq, 2), vec!["bd", "sn"]);
+
+        // a random line stays random
+        let mut seq = sequence("rnd >> bd sn hh cp", &[]);
+        seq.restart(&mut rng);
+        let played = play(&mut seq, 40);
+        assert_ne!(played.chunks(4).filter(|cycle| *cycle == ["bd", "sn", "hh", "cp"]).count(), 10);
+    }
+
+    /// the durations (in steps) of the events on the next step
+    fn durations(seq: &mut EventSequence) -> Vec<f64> {
+        let mut rng = StdRng::seed_from_u64(1);
+        seq.get_next_event(&mut rng, &HashMap::new(), &Counters::default()).iter().map(|ev| ev.4).collect()
+    }
+
+    #[test]
+    fn test_durations() {
+        // a step, rests don't hold it
+        let mut seq = sequence("bd ~ ~ ~", &[]);
+        assert_eq!(durations(&mut seq), vec![1.0]);
+
+        // ties do
+        let mut seq = sequence("saw _ _ ~", &[]);
+        assert_eq!(durations(&mut seq), vec![3.0]);
+
+        // within a step, up to the next event, rests included
+        let mut seq = sequence("[bd sn] [hh ~]", &[]);
+        assert_eq!(durations(&mut seq), vec![0.5, 0.5]);
+        assert_eq!(durations(&mut seq), vec![0.5]);
+
+        // the same event, each where it is
+        let mut seq = sequence("[bd [bd bd]]", &[]);
+        assert_eq!(durations(&mut seq), vec![0.5, 0.25, 0.25]);
+    }
+}
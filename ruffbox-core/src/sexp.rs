@@ -0,0 +1,266 @@
+use nom::{
+    branch::alt,
+    number::complete::float,
+    bytes::complete::take_while1,
+    character::complete::{char, multispace0},
+    combinator::map,
+    multi::many0,
+    sequence::{preceded, delimited},
+    IResult,
+};
+
+use crate::parser;
+use crate::parser::ParamValue;
+use crate::notation::{Pattern, PatternEvent, ParamPattern, PatternParser, SyntaxError};
+
+// S-EXPRESSIONS
+// An alternative, Lisp-like notation, i.e.
+// "(sx 'beat (cyc (bd) (~) (sn :lvl 0.5) :rate (rnd 1.0 0.9)))"
+// (sx 'name ...) names the line, (nuc 'name (bd)) repeats a single event,
+// (cyc ...), (rnd ...) and (learn ...) work like their counterparts in
+// the default notation, event params can follow modulation inputs,
+// i.e. (saw :lp-freq (mod tilt_x 200 4000)).
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr<'a> {
+    Number(f32),
+    Symbol(&'a str),
+    Quoted(&'a str),
+    Keyword(&'a str),
+    List(Vec<Expr<'a>>),
+}
+
+fn symbol_name(input: &str) -> IResult<&str, &str> {
+    take_while1(|c: char| c.is_alphanumeric() || c == '_' || c == '-' || c == '.' || c == '~')(input)
+}
+
+fn atom(input: &str) -> IResult<&str, Expr<'_>> {
+    alt((map(float, Expr::Number),
+         map(preceded(char('\''), symbol_name), Expr::Quoted),
+         map(preceded(char(':'), symbol_name), Expr::Keyword),
+         map(symbol_name, Expr::Symbol)))(input)
+}
+
+fn list(input: &str) -> IResult<&str, Expr<'_>> {
+    map(delimited(char('('), many0(expr), preceded(multispace0, char(')'))), Expr::List)(input)
+}
+
+pub fn expr(input: &str) -> IResult<&str, Expr<'_>> {
+    preceded(multispace0, alt((list, atom)))(input)
+}
+
+/// Split a list into its head, an optional quoted name and the remaining arguments.
+fn head_and_args<'a, 'b>(exprs: &'b [Expr<'a>]) -> Result<(&'a str, Option<&'a str>, &'b [Expr<'a>]), String> {
+    match exprs {
+        [Expr::Symbol(head), Expr::Quoted(name), args @ ..] => Ok((head, Some(name), args)),
+        [Expr::Symbol(head), args @ ..] => Ok((head, None, args)),
+        _ => Err(format!("expected a function, got {:?}", exprs.first())),
+    }
+}
+
+/// Split arguments into positional ones and keyword pairs.
+fn split_keyword_args<'a, 'b>(args: &'b [Expr<'a>]) -> Result<(Vec<&'b Expr<'a>>, Vec<(&'a str, &'b Expr<'a>)>), String> {
+    let mut positional = Vec::new();
+    let mut keywords = Vec::new();
+
+    let mut arg_iter = args.iter();
+    while let Some(arg) = arg_iter.next() {
+        match arg {
+            Expr::Keyword(key) => match arg_iter.next() {
+                Some(val) => keywords.push((*key, val)),
+                None => return Err(format!("no value for :{}", key)),
+            },
+            _ => positional.push(arg),
+        }
+    }
+
+    Ok((positional, keywords))
+}
+
+fn check_param_name(name: &str) -> Result<(), String> {
+    match parser::param_name(name) {
+        Ok(("", _)) => Ok(()),
+        _ => Err(format!("unknown parameter :{}", name)),
+    }
+}
+
+fn number(expr: &Expr) -> Result<f32, String> {
+    match expr {
+        Expr::Number(num) => Ok(*num),
+        _ => Err(format!("expected a number, got {:?}", expr)),
+    }
+}
+
+/// 0.5, (mod tilt_x 200 4000) or (ramp -1 1)
+fn event_param_value(expr: &Expr) -> Result<ParamValue, String> {
+    match expr {
+        Expr::Number(num) => Ok(ParamValue::Const(*num)),
+        Expr::List(items) => match items.as_slice() {
+            [Expr::Symbol("mod"), Expr::Symbol(input), min, max] => Ok(ParamValue::Mod(input.to_string(), number(min)?, number(max)?)),
+            [Expr::Symbol("ramp"), from, to] => Ok(ParamValue::Ramp(number(from)?, number(to)?)),
+            _ => Err(format!("expected a modulation input or a ramp, got {:?}", expr)),
+        },
+        _ => Err(format!("expected a number, got {:?}", expr)),
+    }
+}
+
+/// bd, or (bd :lvl 0.5)
+fn compile_event(expr: &Expr, input: &str) -> Result<PatternEvent, String> {
+    let position = |name: &str| name.as_ptr() as usize - input.as_ptr() as usize;
+
+    match expr {
+        Expr::Symbol(name) => Ok(PatternEvent { name: name.to_string(), params: Vec::new(), position: position(name), offset: 0.0, choice: None, stacked: false }),
+        Expr::List(items) => match items.split_first() {
+            Some((Expr::Symbol(name), args)) => {
+                let (positional, keywords) = split_keyword_args(args)?;
+                if !positional.is_empty() {
+                    return Err(format!("unexpected arguments for event {}", name));
+                }
+
+                let mut params = Vec::new();
+                for (key, val) in keywords {
+                    check_param_name(key)?;
+                    params.push((key.to_string(), event_param_value(val)?));
+                }
+
+                Ok(PatternEvent { name: name.to_string(), params, position: position(name), offset: 0.0, choice: None, stacked: false })
+            },
+            _ => Err(format!("expected an event, got {:?}", expr)),
+        },
+        _ => Err(format!("expected an event, got {:?}", expr)),
+    }
+}
+
+/// 0.5, or (rnd 1.0 0.9)
+fn compile_param(param: &str, expr: &Expr) -> Result<ParamPattern, String> {
+    check_param_name(param)?;
+
+    match expr {
+        Expr::Number(num) => Ok(ParamPattern { param: param.to_string(), generator: "cyc".to_string(), values: vec![*num] }),
+        Expr::List(items) => {
+            let (head, _, args) = head_and_args(items)?;
+            match head {
+                "cyc" | "rnd" | "learn" | "bounce" | "ramp" => Ok(ParamPattern {
+                    param: param.to_string(),
+                    generator: head.to_string(),
+                    values: args.iter().map(number).collect::<Result<Vec<f32>, String>>()?,
+                }),
+                _ => Err(format!("unknown generator {}", head)),
+            }
+        },
+        _ => Err(format!("expected a parameter value, got {:?}", expr)),
+    }
+}
+
+fn compile(expr: &Expr, input: &str) -> Result<Pattern, String> {
+    let items = match expr {
+        Expr::List(items) => items,
+        _ => return Err(format!("expected a list, got {:?}", expr)),
+    };
+
+    let (head, name, args) = head_and_args(items)?;
+    let label = name.map(|name| name.to_string());
+
+    match head {
+        "sx" => match args {
+            [body] => {
+                let mut pattern = compile(body, input)?;
+                pattern.label = label.or(pattern.label);
+                Ok(pattern)
+            },
+            _ => Err("sx needs exactly one pattern".to_string()),
+        },
+        "nuc" | "cyc" | "rnd" | "learn" => {
+            let (positional, keywords) = split_keyword_args(args)?;
+
+            if head == "nuc" && positional.len() != 1 {
+                return Err("nuc needs exactly one event".to_string());
+            } else if positional.is_empty() {
+                return Err(format!("{} needs at least one event", head));
+            }
+
+            Ok(Pattern {
+                label,
+                generator: if head == "nuc" { "cyc".to_string() } else { head.to_string() },
+                events: positional.iter().map(|ev| compile_event(ev, input)).collect::<Result<Vec<PatternEvent>, String>>()?,
+                params: keywords.iter().map(|(param, val)| compile_param(param, val)).collect::<Result<Vec<ParamPattern>, String>>()?,
+                condition: None,
+            })
+        },
+        _ => Err(format!("unknown function {}", head)),
+    }
+}
+
+pub struct SexpNotation;
+
+impl PatternParser for SexpNotation {
+    fn parse(&self, input: &str) -> Result<Pattern, SyntaxError> {
+        match expr(input) {
+            Ok((rest, parsed)) if rest.trim().is_empty() => Ok(compile(&parsed, input)?),
+            Ok((rest, _)) => Err(SyntaxError::unexpected(input, rest)),
+            Err(err) => Err(SyntaxError::from_nom(input, err)),
+        }
+    }
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::notation::SeqNotation;
+
+    #[test]
+    fn test_expr() {
+        let res = expr("(nuc 'bd (bd :lvl 0.5))");
+        assert_eq!(res, Ok(("", Expr::List(vec![
+            Expr::Symbol("nuc"),
+            Expr::Quoted("bd"),
+            Expr::List(vec![Expr::Symbol("bd"), Expr::Keyword("lvl"), Expr::Number(0.5)])
+        ]))));
+    }
+
+    #[test]
+    fn test_same_as_seq_notation() {
+        let sexp = SexpNotation.parse("(sx 'beat (cyc bd ~ (sn :lvl 0.5) :rate (rnd 1.0 0.9)))").unwrap();
+        let seq = SeqNotation.parse("cyc >> bd ~ sn;lvl=0.5 @rate: rnd >> 1.0 0.9").unwrap();
+
+        assert_eq!(sexp.label, Some("beat".to_string()));
+        assert_eq!(sexp.generator, seq.generator);
+        assert_eq!(sexp.params, seq.params);
+
+        let events = |pattern: &Pattern| pattern.events.iter().map(|ev| (ev.name.clone(), ev.params.clone())).collect::<Vec<(String, Vec<(String, ParamValue)>)>>();
+        assert_eq!(events(&sexp), events(&seq));
+        assert_eq!(sexp.events[1].position, 18);
+    }
+
+    #[test]
+    fn test_nuc() {
+        let pattern = SexpNotation.parse("(nuc 'hats (hh :rate 2.0) :lvl 0.3)").unwrap();
+
+        assert_eq!(pattern.label, Some("hats".to_string()));
+        assert_eq!(pattern.events.len(), 1);
+        assert_eq!(pattern.params[0].values, vec![0.3]);
+
+        assert!(SexpNotation.parse("(nuc 'hats hh sn)").is_err());
+
+        let pattern = SexpNotation.parse("(nuc (saw :lp-freq (mod tilt_x 200 4000)))").unwrap();
+        assert_eq!(pattern.events[0].params[0].1, ParamValue::Mod("tilt_x".to_string(), 200.0, 4000.0));
+
+        let pattern = SexpNotation.parse("(nuc (sine :pos (ramp -1 1)))").unwrap();
+        assert_eq!(pattern.events[0].params[0].1, ParamValue::Ramp(-1.0, 1.0));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(SexpNotation.parse("(cyc bd sn").is_err());
+        assert!(SexpNotation.parse("(foo bd sn)").is_err());
+        assert!(SexpNotation.parse("(cyc (bd :nope 1.0))").is_err());
+        assert!(SexpNotation.parse("(cyc bd) sn").is_err());
+
+        // nothing to play
+        assert_eq!(SexpNotation.parse("(cyc)"), Err(SyntaxError { position: 0, message: "cyc needs at least one event".to_string() }));
+        assert!(SexpNotation.parse("(sx 'x (rnd))").is_err());
+        assert!(SexpNotation.parse("(learn :rate (cyc 1 2))").is_err());
+    }
+}
@@ -0,0 +1,442 @@
+use std::collections::HashSet;
+
+// SOUNDFONTS
+// A practical subset of SF2: the sample data, the key and velocity ranges of
+// the zones, root key and tuning, attenuation, and whether the sample loops.
+// Modulators, envelopes, filters and the other generators are left out,
+// the synth has its own for that. Each preset is flattened into a list of
+// zones, with the preset's generators applied to its instruments' ones.
+// Stereo samples are played from the left one, ROM samples not at all.
+// Fonts are read in the scheduler's worker, the sampler only gets the samples
+// and the zones, so a big file doesn't hold up the audio thread.
+
+// generators
+const GEN_KEY_RANGE: u16 = 43;
+const GEN_VEL_RANGE: u16 = 44;
+const GEN_INSTRUMENT: u16 = 41;
+const GEN_SAMPLE_ID: u16 = 53;
+const GEN_ROOT_KEY: u16 = 58;
+const GEN_COARSE_TUNE: u16 = 51;
+const GEN_FINE_TUNE: u16 = 52;
+const GEN_ATTENUATION: u16 = 48;
+const GEN_SAMPLE_MODES: u16 = 54;
+
+// sample types
+const SAMPLE_RIGHT: u16 = 2;
+const SAMPLE_ROM: u16 = 0x8000;
+
+/// A sample of the font, 16 bit mono.
+#[derive(Clone, Debug)]
+pub struct FontSample {
+    pub data: Vec<f32>,
+    pub samplerate: u32,
+    pub root: i32,
+    /// in cents
+    pub correction: i32,
+    /// in frames of the sample
+    pub loop_start: usize,
+    pub loop_end: usize,
+}
+
+/// A zone of a preset, with everything that applies to it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FontZone {
+    pub lokey: i32,
+    pub hikey: i32,
+    pub lovel: i32,
+    pub hivel: i32,
+    pub root: i32,
+    /// in cents, the sample's pitch correction included
+    pub tune: i32,
+    /// in dB
+    pub attenuation: f32,
+    pub looping: bool,
+    /// index into the samples of the font
+    pub sample: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct FontPreset {
+    /// usable as a source name, i.e. "acoustic_grand_piano", names that repeat
+    /// (in different banks) get bank and program added, "piano_1_0"
+    pub name: String,
+    pub zones: Vec<FontZone>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SoundFont {
+    pub presets: Vec<FontPreset>,
+    pub samples: Vec<FontSample>,
+}
+
+impl SoundFont {
+    /// indices of the samples the presets play, in order
+    pub fn used_samples(&self) -> Vec<usize> {
+        let used: HashSet<usize> = self.presets.iter().flat_map(|preset| preset.zones.iter().map(|zone| zone.sample)).collect();
+        let mut used: Vec<usize> = used.into_iter().collect();
+        used.sort_unstable();
+        used
+    }
+}
+
+fn u16_at(data: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([data[pos], data[pos + 1]])
+}
+
+fn u32_at(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+}
+
+/// a zero-terminated name of up to 20 characters
+fn name_at(data: &[u8], pos: usize) -> String {
+    let raw = &data[pos..pos + 20];
+    let end = raw.iter().position(|c| *c == 0).unwrap_or(20);
+    String::from_utf8_lossy(&raw[..end]).to_string()
+}
+
+/// lower case, anything but letters and numbers as underscores
+fn source_name(name: &str) -> String {
+    let mut source = String::new();
+    for c in name.trim().chars() {
+        if c.is_ascii_alphanumeric() {
+            source.push(c.to_ascii_lowercase());
+        } else if !source.is_empty() && !source.ends_with('_') {
+            source.push('_');
+        }
+    }
+    source.trim_end_matches('_').to_string()
+}
+
+/// The sub-chunks of a RIFF list (or the file), by id.
+fn chunks(data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut chunks = Vec::new();
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let id = [data[pos], data[pos + 1], data[pos + 2], data[pos + 3]];
+        let len = u32_at(data, pos + 4) as usize;
+        // a length beyond the address space (on 32 bit) ends the file
+        let end = match (pos + 8).checked_add(len) {
+            Some(end) => end.min(data.len()),
+            None => break,
+        };
+        chunks.push((id, &data[pos + 8..end]));
+        // chunks are padded to an even length
+        pos = end + (len & 1);
+    }
+    chunks
+}
+
+/// A LIST chunk of the given type.
+fn list<'a>(riff: &[([u8; 4], &'a [u8])], list_type: &[u8; 4]) -> Option<&'a [u8]> {
+    riff.iter()
+        .find(|(id, body)| id == b"LIST" && body.len() >= 4 && &body[0..4] == list_type)
+        .map(|(_, body)| &body[4..])
+}
+
+/// The records of a fixed size in a chunk of the pdta list.
+fn records<'a>(pdta: &[([u8; 4], &'a [u8])], id: &[u8; 4], size: usize) -> Result<Vec<&'a [u8]>, String> {
+    let (_, body) = pdta.iter().find(|(chunk_id, _)| chunk_id == id)
+        .ok_or_else(|| format!("no {} chunk", String::from_utf8_lossy(id)))?;
+    Ok(body.chunks_exact(size).collect())
+}
+
+/// A zone's generators, as (operator, amount).
+type Generators = Vec<(u16, [u8; 2])>;
+
+/// The generators of each zone, from the bags (start indices) and the list of generators.
+/// The last bag is the terminal one.
+fn zone_generators(bags: &[&[u8]], gens: &[&[u8]], first_bag: usize, end_bag: usize) -> Vec<Generators> {
+    (first_bag..end_bag.min(bags.len().saturating_sub(1)))
+        .map(|bag| {
+            let start = u16_at(bags[bag], 0) as usize;
+            let end = (u16_at(bags[bag + 1], 0) as usize).min(gens.len());
+            gens[start.min(end)..end].iter().map(|gen| (u16_at(gen, 0), [gen[2], gen[3]])).collect()
+        })
+        .collect()
+}
+
+fn amount(generators: &Generators, operator: u16) -> Option<[u8; 2]> {
+    generators.iter().rev().find(|(op, _)| *op == operator).map(|(_, amount)| *amount)
+}
+
+fn signed(generators: &Generators, operator: u16) -> Option<i32> {
+    amount(generators, operator).map(|amount| i16::from_le_bytes(amount) as i32)
+}
+
+fn range(generators: &Generators, operator: u16) -> Option<(i32, i32)> {
+    amount(generators, operator).map(|amount| (amount[0] as i32, amount[1] as i32))
+}
+
+/// Split the zones into the global one (if the first one doesn't end in the given
+/// generator, it is one) and the others, which are returned with the global
+/// generators in front of theirs, so theirs take precedence.
+fn with_global(zones: Vec<Generators>, terminal: u16) -> Vec<Generators> {
+    let global = match zones.first() {
+        Some(first) if amount(first, terminal).is_none() => first.clone(),
+        _ => Vec::new(),
+    };
+    zones.into_iter()
+        .filter(|zone| amount(zone, terminal).is_some())
+        .map(|zone| global.iter().cloned().chain(zone).collect())
+        .collect()
+}
+
+fn intersect(a: (i32, i32), b: (i32, i32)) -> Option<(i32, i32)> {
+    let range = (a.0.max(b.0), a.1.min(b.1));
+    if range.0 <= range.1 { Some(range) } else { None }
+}
+
+/// Read the presets and samples of an SF2 file.
+pub fn parse_sf2(data: &[u8]) -> Result<SoundFont, String> {
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"sfbk" {
+        return Err("not an SF2 file".to_string());
+    }
+
+    let riff = chunks(&data[12..]);
+    let sdta = chunks(list(&riff, b"sdta").ok_or("no sample data")?);
+    let smpl = sdta.iter().find(|(id, _)| id == b"smpl").map(|(_, body)| *body).ok_or("no sample data")?;
+    let pdta = chunks(list(&riff, b"pdta").ok_or("no preset data")?);
+
+    let phdr = records(&pdta, b"phdr", 38)?;
+    let pbag = records(&pdta, b"pbag", 4)?;
+    let pgen = records(&pdta, b"pgen", 4)?;
+    let inst = records(&pdta, b"inst", 22)?;
+    let ibag = records(&pdta, b"ibag", 4)?;
+    let igen = records(&pdta, b"igen", 4)?;
+    let shdr = records(&pdta, b"shdr", 46)?;
+
+    // all samples are read, only the ones that are used are loaded later on
+    let sample_count = shdr.len().saturating_sub(1);
+    let frames = smpl.len() / 2;
+    let samples: Vec<FontSample> = shdr[..sample_count].iter().map(|header| {
+        let start = (u32_at(header, 20) as usize).min(frames);
+        let end = (u32_at(header, 24) as usize).min(frames).max(start);
+        FontSample {
+            data: (start..end).map(|frame| i16::from_le_bytes([smpl[frame * 2], smpl[frame * 2 + 1]]) as f32 / 32768.0).collect(),
+            samplerate: u32_at(header, 36),
+            root: header[40] as i32,
+            correction: header[41] as i8 as i32,
+            loop_start: (u32_at(header, 28) as usize).saturating_sub(start).min(end - start),
+            loop_end: (u32_at(header, 32) as usize).saturating_sub(start).min(end - start),
+        }
+    }).collect();
+    let sample_types: Vec<u16> = shdr[..sample_count].iter().map(|header| u16_at(header, 44)).collect();
+
+    // the zones of the instruments, with their global zone applied
+    let instruments: Vec<Vec<Generators>> = (0..inst.len().saturating_sub(1)).map(|idx| {
+        let zones = zone_generators(&ibag, &igen, u16_at(inst[idx], 20) as usize, u16_at(inst[idx + 1], 20) as usize);
+        with_global(zones, GEN_SAMPLE_ID)
+    }).collect();
+
+    let mut presets = Vec::new();
+    let mut names = HashSet::new();
+    for idx in 0..phdr.len().saturating_sub(1) {
+        let preset_zones = zone_generators(&pbag, &pgen, u16_at(phdr[idx], 24) as usize, u16_at(phdr[idx + 1], 24) as usize);
+
+        let mut zones = Vec::new();
+        for preset_zone in with_global(preset_zones, GEN_INSTRUMENT) {
+            let instrument = match signed(&preset_zone, GEN_INSTRUMENT).and_then(|inst| instruments.get(inst as usize)) {
+                Some(instrument) => instrument,
+                None => continue,
+            };
+
+            for inst_zone in instrument {
+                let sample = match signed(inst_zone, GEN_SAMPLE_ID).map(|sample| sample as u16 as usize) {
+                    Some(sample) if sample < sample_count => sample,
+                    _ => continue,
+                };
+                if sample_types[sample] & SAMPLE_ROM != 0 || sample_types[sample] & SAMPLE_RIGHT != 0 && sample_types[sample] & 4 == 0 {
+                    continue;
+                }
+
+                // ranges are narrowed by the preset, the rest adds up
+                let full = (0, 127);
+                let keys = intersect(range(inst_zone, GEN_KEY_RANGE).unwrap_or(full), range(&preset_zone, GEN_KEY_RANGE).unwrap_or(full));
+                let vels = intersect(range(inst_zone, GEN_VEL_RANGE).unwrap_or(full), range(&preset_zone, GEN_VEL_RANGE).unwrap_or(full));
+                let (keys, vels) = match (keys, vels) {
+                    (Some(keys), Some(vels)) => (keys, vels),
+                    _ => continue,
+                };
+                let generator = |operator| signed(inst_zone, operator).unwrap_or(0) + signed(&preset_zone, operator).unwrap_or(0);
+
+                zones.push(FontZone {
+                    lokey: keys.0,
+                    hikey: keys.1,
+                    lovel: vels.0,
+                    hivel: vels.1,
+                    root: signed(inst_zone, GEN_ROOT_KEY).filter(|root| *root >= 0).unwrap_or(samples[sample].root),
+                    tune: generator(GEN_COARSE_TUNE) * 100 + generator(GEN_FINE_TUNE) + samples[sample].correction,
+                    // in centibels
+                    attenuation: generator(GEN_ATTENUATION).max(0) as f32 / 10.0,
+                    looping: signed(inst_zone, GEN_SAMPLE_MODES).unwrap_or(0) & 1 == 1,
+                    sample,
+                });
+            }
+        }
+
+        if zones.is_empty() {
+            continue;
+        }
+
+        // names can repeat in different banks
+        let (bank, program) = (u16_at(phdr[idx], 22), u16_at(phdr[idx], 20));
+        let mut name = source_name(&name_at(phdr[idx], 0));
+        if name.is_empty() || names.contains(&name) {
+            name = format!("{}{}_{}", if name.is_empty() { "preset_".to_string() } else { name + "_" }, bank, program);
+        }
+        names.insert(name.clone());
+
+        presets.push(FontPreset { name, zones });
+    }
+
+    Ok(SoundFont { presets, samples })
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    fn chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(body);
+        if body.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn list_chunk(list_type: &[u8; 4], chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut body = list_type.to_vec();
+        for sub in chunks {
+            body.extend_from_slice(sub);
+        }
+        chunk(b"LIST", &body)
+    }
+
+    fn name(name: &str) -> Vec<u8> {
+        let mut raw = name.as_bytes().to_vec();
+        raw.resize(20, 0);
+        raw
+    }
+
+    fn gen(operator: u16, amount: [u8; 2]) -> Vec<u8> {
+        let mut raw = operator.to_le_bytes().to_vec();
+        raw.extend_from_slice(&amount);
+        raw
+    }
+
+    fn bag(gen_idx: u16) -> Vec<u8> {
+        let mut raw = gen_idx.to_le_bytes().to_vec();
+        raw.extend_from_slice(&[0, 0]);
+        raw
+    }
+
+    fn preset_header(preset_name: &str, program: u16, bag_idx: u16) -> Vec<u8> {
+        let mut raw = name(preset_name);
+        raw.extend_from_slice(&program.to_le_bytes());
+        raw.extend_from_slice(&0u16.to_le_bytes());
+        raw.extend_from_slice(&bag_idx.to_le_bytes());
+        raw.extend_from_slice(&[0; 12]);
+        raw
+    }
+
+    fn sample_header(sample_name: &str, start: u32, end: u32, loop_points: (u32, u32), root: u8, sample_type: u16) -> Vec<u8> {
+        let mut raw = name(sample_name);
+        for value in [start, end, loop_points.0, loop_points.1, 22050].iter() {
+            raw.extend_from_slice(&value.to_le_bytes());
+        }
+        raw.extend_from_slice(&[root, 0]);
+        raw.extend_from_slice(&0u16.to_le_bytes());
+        raw.extend_from_slice(&sample_type.to_le_bytes());
+        raw
+    }
+
+    /// A small font: one preset ("Soft Pad") with a global zone of -3 dB, playing
+    /// an instrument with two zones, a looped low one and a high one for loud notes
+    /// only, tuned up a semitone. The second sample is the right half of a stereo
+    /// pair, and isn't used.
+    fn test_font() -> Vec<u8> {
+        let mut smpl = Vec::new();
+        for i in 0..200i16 {
+            smpl.extend_from_slice(&(i * 100).to_le_bytes());
+        }
+
+        let phdr = [preset_header("Soft Pad", 5, 0), preset_header("EOP", 0, 2)].concat();
+        let pbag = [bag(0), bag(1), bag(2)].concat();
+        let pgen = [gen(GEN_ATTENUATION, 30i16.to_le_bytes()), gen(GEN_INSTRUMENT, [0, 0]), gen(0, [0, 0])].concat();
+
+        let mut inst = name("pad");
+        inst.extend_from_slice(&0u16.to_le_bytes());
+        inst.extend_from_slice(&name("EOI"));
+        inst.extend_from_slice(&2u16.to_le_bytes());
+        let ibag = [bag(0), bag(3), bag(7)].concat();
+        let igen = [
+            gen(GEN_KEY_RANGE, [0, 59]), gen(GEN_SAMPLE_MODES, 1i16.to_le_bytes()), gen(GEN_SAMPLE_ID, [0, 0]),
+            gen(GEN_KEY_RANGE, [60, 127]), gen(GEN_VEL_RANGE, [100, 127]), gen(GEN_COARSE_TUNE, 1i16.to_le_bytes()), gen(GEN_SAMPLE_ID, [1, 0]),
+            gen(0, [0, 0]),
+        ].concat();
+        let shdr = [
+            sample_header("low", 0, 100, (20, 80), 48, 1),
+            sample_header("high", 100, 200, (0, 0), 72, 1),
+            sample_header("high R", 100, 200, (0, 0), 72, SAMPLE_RIGHT),
+            sample_header("EOS", 0, 0, (0, 0), 0, 0),
+        ].concat();
+
+        let sfbk = [
+            b"sfbk".to_vec(),
+            list_chunk(b"INFO", &[chunk(b"ifil", &[2, 0, 1, 0])]),
+            list_chunk(b"sdta", &[chunk(b"smpl", &smpl)]),
+            list_chunk(b"pdta", &[chunk(b"phdr", &phdr), chunk(b"pbag", &pbag), chunk(b"pmod", &[0; 10]), chunk(b"pgen", &pgen),
+                                  chunk(b"inst", &inst), chunk(b"ibag", &ibag), chunk(b"imod", &[0; 10]), chunk(b"igen", &igen),
+                                  chunk(b"shdr", &shdr)]),
+        ].concat();
+        chunk(b"RIFF", &sfbk)
+    }
+
+    #[test]
+    fn soundfont_test_parse() {
+        let font = parse_sf2(&test_font()).unwrap();
+        assert_eq!(font.samples.len(), 3);
+        assert_eq!(font.samples[0].data.len(), 100);
+        assert!((font.samples[1].data[0] - 10000.0 / 32768.0).abs() < 0.00001);
+        assert_eq!((font.samples[0].loop_start, font.samples[0].loop_end), (20, 80));
+        assert_eq!(font.samples[0].samplerate, 22050);
+
+        assert_eq!(font.presets.len(), 1);
+        let preset = &font.presets[0];
+        assert_eq!(preset.name, "soft_pad");
+        assert_eq!(preset.zones, vec![
+            FontZone { lokey: 0, hikey: 59, lovel: 0, hivel: 127, root: 48, tune: 0, attenuation: 3.0, looping: true, sample: 0 },
+            FontZone { lokey: 60, hikey: 127, lovel: 100, hivel: 127, root: 72, tune: 100, attenuation: 3.0, looping: false, sample: 1 },
+        ]);
+        // the third sample isn't played
+        assert_eq!(font.used_samples(), vec![0, 1]);
+    }
+
+    #[test]
+    fn soundfont_test_invalid() {
+        assert!(parse_sf2(b"RIFF\0\0\0\0WAVE").is_err());
+        // cut off in the middle, the preset data is missing
+        let font = test_font();
+        assert!(parse_sf2(&font[..500]).is_err());
+        assert_eq!(source_name("  Grand Piano (2) "), "grand_piano_2");
+    }
+
+    #[test]
+    fn soundfont_test_oversized_chunk() {
+        // a length that doesn't fit behind the position
+        let mut data = b"LIST".to_vec();
+        data.extend_from_slice(&[0xff; 4]);
+        data.extend_from_slice(b"pdta");
+        let chunks = chunks(&data);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].1, b"pdta");
+
+        let mut font = b"RIFF\0\0\0\0sfbk".to_vec();
+        font.extend_from_slice(&data);
+        assert!(parse_sf2(&font).is_err());
+    }
+}
@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+
+// SESSION STATISTICS
+// What's been played since the start, for a summary after a set, and to spot
+// lines that never trigger anything. The sound events are counted as the lines
+// play them, before the tick hook sees them.
+
+/// Counts of the steps and sound events since the scheduler was started.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SessionStats {
+    /// master steps scheduled
+    pub steps: usize,
+    pub events: usize,
+    /// by sample (or synth) name
+    pub per_sample: HashMap<String, usize>,
+    /// by line id
+    pub per_line: HashMap<u32, usize>,
+}
+
+impl SessionStats {
+    pub fn new() -> Self {
+        SessionStats::default()
+    }
+
+    pub fn count_step(&mut self) {
+        self.steps += 1;
+    }
+
+    /// a sound event of the line with the given id
+    pub fn count_event(&mut self, line: u32, sample: &str) {
+        self.events += 1;
+        *self.per_sample.entry(sample.to_string()).or_insert(0) += 1;
+        *self.per_line.entry(line).or_insert(0) += 1;
+    }
+
+    /// the events of a line, zero for lines that never played anything
+    pub fn line_events(&self, line: u32) -> usize {
+        self.per_line.get(&line).cloned().unwrap_or(0)
+    }
+
+    /// sound events per step, on average
+    pub fn density(&self) -> f64 {
+        if self.steps == 0 {
+            0.0
+        } else {
+            self.events as f64 / self.steps as f64
+        }
+    }
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_counts() {
+        let mut stats = SessionStats::new();
+        assert_eq!(stats.density(), 0.0);
+
+        for _ in 0..4 {
+            stats.count_step();
+        }
+        stats.count_event(1, "bd");
+        stats.count_event(1, "bd");
+        stats.count_event(2, "sn");
+
+        assert_eq!(stats.events, 3);
+        assert_eq!(stats.per_sample["bd"], 2);
+        assert_eq!(stats.per_sample["sn"], 1);
+        assert_eq!(stats.line_events(1), 2);
+        assert_eq!(stats.line_events(2), 1);
+        // never played
+        assert_eq!(stats.line_events(3), 0);
+        assert_eq!(stats.density(), 0.75);
+    }
+}
@@ -0,0 +1,49 @@
+use std::fmt;
+
+// TIMECODE
+// SMPTE-style timecode (hours:minutes:seconds:frames) derived from the
+// audio clock, so other software can chase the performance.
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Timecode {
+    pub hours: u32,
+    pub minutes: u32,
+    pub seconds: u32,
+    pub frames: u32,
+}
+
+impl Timecode {
+    /// Timecode for the time (in seconds) since the performance started.
+    pub fn from_seconds(time: f64, fps: f64) -> Self {
+        let total_frames = (time.max(0.0) * fps).floor() as u64;
+        let frames_per_second = fps.round().max(1.0) as u64;
+        let total_seconds = total_frames / frames_per_second;
+
+        Timecode {
+            hours: (total_seconds / 3600) as u32,
+            minutes: ((total_seconds / 60) % 60) as u32,
+            seconds: (total_seconds % 60) as u32,
+            frames: (total_frames % frames_per_second) as u32,
+        }
+    }
+}
+
+impl fmt::Display for Timecode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02}:{:02}:{:02}:{:02}", self.hours, self.minutes, self.seconds, self.frames)
+    }
+}
+
+// TEST TEST TEST 
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_timecode() {
+        assert_eq!(Timecode::from_seconds(0.0, 25.0), Timecode { hours: 0, minutes: 0, seconds: 0, frames: 0 });
+        assert_eq!(Timecode::from_seconds(3723.5, 25.0), Timecode { hours: 1, minutes: 2, seconds: 3, frames: 12 });
+        assert_eq!(Timecode::from_seconds(61.99, 30.0).to_string(), "00:01:01:29");
+    }
+}
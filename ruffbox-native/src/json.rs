@@ -0,0 +1,240 @@
+// JSON
+// Just enough JSON for the remote commands (see remote.rs): a command is a
+// single object, i.e. {"cmd": "set_bpm", "bpm": 90}, and the replies are
+// written by hand, with their strings quoted by `quote`.
+
+/// A parsed JSON value, objects keep their keys in order.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// the value of a key, if this is an object that has it
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(entries) => entries.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Number(number) => Some(*number),
+            _ => None,
+        }
+    }
+}
+
+struct Reader<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, String> {
+        Err(format!("{} at {}", message, self.pos))
+    }
+
+    /// the next char, which has to be the given one
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.rest().starts_with(expected) {
+            self.pos += expected.len_utf8();
+            Ok(())
+        } else {
+            self.error(&format!("expected '{}'", expected))
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        let rest = self.rest();
+        match rest.chars().next() {
+            Some('{') => self.object(),
+            Some('[') => self.array(),
+            Some('"') => self.string().map(Value::String),
+            Some('-') | Some('0'..='9') => self.number(),
+            _ => {
+                for (literal, value) in [("null", Value::Null), ("true", Value::Bool(true)), ("false", Value::Bool(false))] {
+                    if rest.starts_with(literal) {
+                        self.pos += literal.len();
+                        return Ok(value);
+                    }
+                }
+                self.error("expected a value")
+            },
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, String> {
+        self.expect('{')?;
+        let mut entries = Vec::new();
+        self.skip_whitespace();
+        if self.rest().starts_with('}') {
+            self.pos += 1;
+            return Ok(Value::Object(entries));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.expect(':')?;
+            entries.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.rest().chars().next() {
+                Some(',') => self.pos += 1,
+                Some('}') => {
+                    self.pos += 1;
+                    return Ok(Value::Object(entries));
+                },
+                _ => return self.error("expected ',' or '}'"),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.rest().starts_with(']') {
+            self.pos += 1;
+            return Ok(Value::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.rest().chars().next() {
+                Some(',') => self.pos += 1,
+                Some(']') => {
+                    self.pos += 1;
+                    return Ok(Value::Array(items));
+                },
+                _ => return self.error("expected ',' or ']'"),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, String> {
+        let rest = self.rest();
+        let len = rest.find(|c: char| !(c.is_ascii_digit() || "+-.eE".contains(c))).unwrap_or(rest.len());
+        match rest[..len].parse::<f64>() {
+            Ok(number) => {
+                self.pos += len;
+                Ok(Value::Number(number))
+            },
+            Err(_) => self.error("invalid number"),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut string = String::new();
+        let mut chars = self.rest().char_indices();
+        while let Some((idx, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += idx + 1;
+                    return Ok(string);
+                },
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => string.push('\n'),
+                    Some((_, 't')) => string.push('\t'),
+                    Some((_, 'r')) => string.push('\r'),
+                    Some((_, 'b')) => string.push('\u{8}'),
+                    Some((_, 'f')) => string.push('\u{c}'),
+                    Some((_, 'u')) => {
+                        let hex: String = (0..4).filter_map(|_| chars.next().map(|(_, c)| c)).collect();
+                        let code = u32::from_str_radix(&hex, 16).map_err(|_| format!("invalid escape at {}", self.pos + idx))?;
+                        // surrogate pairs aren't put back together, editors don't send them for pattern text
+                        string.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+                    },
+                    Some((_, escaped)) => string.push(escaped),
+                    None => break,
+                },
+                _ => string.push(c),
+            }
+        }
+        self.error("unterminated string")
+    }
+}
+
+/// Parse a JSON text, nothing but whitespace may follow the value.
+pub fn parse(input: &str) -> Result<Value, String> {
+    let mut reader = Reader { input, pos: 0 };
+    let value = reader.value()?;
+    reader.skip_whitespace();
+    if reader.pos < input.len() {
+        return reader.error("unexpected input");
+    }
+    Ok(value)
+}
+
+/// A string as a JSON string, quotes included.
+pub fn quote(string: &str) -> String {
+    let mut quoted = String::with_capacity(string.len() + 2);
+    quoted.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_command() {
+        let command = parse(r#" {"cmd": "evaluate_loop", "loop_data": "bd ~ \"sn\"\nhh", "bars": -2.5e1, "quantized": true, "stem": null, "ids": [1, 2]} "#).unwrap();
+        assert_eq!(command.get("cmd").and_then(Value::as_str), Some("evaluate_loop"));
+        assert_eq!(command.get("loop_data").and_then(Value::as_str), Some("bd ~ \"sn\"\nhh"));
+        assert_eq!(command.get("bars").and_then(Value::as_f64), Some(-25.0));
+        assert_eq!(command.get("quantized"), Some(&Value::Bool(true)));
+        assert_eq!(command.get("stem"), Some(&Value::Null));
+        assert_eq!(command.get("ids"), Some(&Value::Array(vec![Value::Number(1.0), Value::Number(2.0)])));
+        assert_eq!(command.get("nope"), None);
+
+        assert!(parse("{\"cmd\": \"stop\"").is_err());
+        assert!(parse("{\"cmd\": \"stop\"} x").is_err());
+        assert!(parse("{cmd: 'stop'}").is_err());
+    }
+
+    #[test]
+    fn test_quote() {
+        let text = "bd \"x\"\n\\ \u{1}";
+        assert_eq!(quote(text), r#""bd \"x\"\n\\ \u0001""#);
+        assert_eq!(parse(&quote(text)), Ok(Value::String(text.to_string())));
+    }
+}
@@ -0,0 +1,5 @@
+// The parts of the native build that other native hosts share (see
+// ruffbox-clap), the program itself is main.rs.
+
+pub mod player;
+pub mod wav;
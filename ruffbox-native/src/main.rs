@@ -0,0 +1,155 @@
+mod json;
+mod remote;
+
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
+use std::{env, fs, thread};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use parking_lot::Mutex;
+
+use ruffbox_core::Clock;
+use ruffbox_core::scheduler::SchedulerCore;
+use ruffbox_synth::Engine;
+use ruffbox_native::player::NativePlayer;
+
+// NATIVE
+// ruffbox as a desktop program, a pattern file plays on the default output,
+// with the WAV files of a directory as its samples:
+//
+//   ruffbox-native <pattern file> [bpm] [sample dir] [seed] [port]
+//
+// It's the same scheduler as in the browser (see scheduler.rs in ruffbox-core),
+// so labels, directives and sub-patterns work the same. It's the tale of two
+// clocks again (see the README), only both are the samples the audio callback
+// has rendered here, and the routine is woken up by a sleeping thread. With a
+// port, an editor can send it commands over a WebSocket (see remote.rs), they're
+// carried out while it waits for the next wakeup.
+
+/// so the same file plays the same, unless another seed is given
+const DEFAULT_SEED: u64 = 0;
+
+/// The time of the audio that's been rendered, in milliseconds.
+struct AudioClock {
+    frames: Arc<AtomicU64>,
+    samplerate: f64,
+    /// until the scheduler's routine is called again, in milliseconds
+    wakeup: f64,
+}
+
+impl Clock for AudioClock {
+    fn now(&self) -> f64 {
+        self.frames.load(Ordering::Relaxed) as f64 / self.samplerate * 1000.0
+    }
+
+    /// the main loop sleeps for the delay
+    fn wake_up_in(&mut self, delay: f64) {
+        self.wakeup = delay;
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        eprintln!("usage: ruffbox-native <pattern file> [bpm] [sample dir] [seed] [port]");
+        return;
+    }
+    let input = fs::read_to_string(&args[1]).expect("couldn't read the pattern file");
+    let bpm = args.get(2).and_then(|bpm| bpm.parse::<f64>().ok()).unwrap_or(120.0);
+    let seed = args.get(4).and_then(|seed| seed.parse::<u64>().ok()).unwrap_or(DEFAULT_SEED);
+    let port = args.get(5).and_then(|port| port.parse::<u16>().ok());
+
+    let device = cpal::default_host().default_output_device().expect("no output device");
+    let config: cpal::StreamConfig = device.default_output_config().expect("no output config").into();
+    let samplerate = config.sample_rate.0 as f32;
+    let channels = config.channels as usize;
+
+    let engine = Arc::new(Mutex::new(Engine::new(samplerate, samplerate)));
+    let frames = Arc::new(AtomicU64::new(0));
+
+    let stream = {
+        let engine = engine.clone();
+        let frames = frames.clone();
+        let mut left = Vec::new();
+        let mut right = Vec::new();
+        device.build_output_stream(&config, move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+            let length = data.len() / channels;
+            left.resize(length, 0.0);
+            right.resize(length, 0.0);
+            // the stream time is that of the first sample
+            let rendered = frames.fetch_add(length as u64, Ordering::Relaxed);
+            engine.lock().render(rendered as f64 / samplerate as f64, &mut left, &mut right);
+
+            for (frame, (l, r)) in data.chunks_mut(channels).zip(left.iter().zip(right.iter())) {
+                match frame {
+                    [mono] => *mono = (l + r) * 0.5,
+                    [first, second, rest @ ..] => {
+                        *first = *l;
+                        *second = *r;
+                        rest.iter_mut().for_each(|sample| *sample = 0.0);
+                    },
+                    [] => (),
+                }
+            }
+        }, |err| eprintln!("output failed: {}", err)).expect("couldn't open the output")
+    };
+    stream.play().expect("couldn't start the output");
+
+    let clock = AudioClock { frames, samplerate: samplerate as f64, wakeup: 0.0 };
+    let mut player = NativePlayer::new(engine, samplerate);
+    let samples = match args.get(3) {
+        Some(dir) => player.load_samples(Path::new(dir)).expect("couldn't read the sample directory"),
+        None => Vec::new(),
+    };
+
+    let mut scheduler = SchedulerCore::new(player, clock);
+    for name in samples {
+        scheduler.register_sample(name);
+    }
+    scheduler.set_seed(seed);
+    scheduler.set_bpm(bpm);
+    match scheduler.evaluate(Some(input)) {
+        Ok(warnings) => warnings.iter().for_each(|warning| eprintln!("{}", warning)),
+        Err(errors) => {
+            errors.iter().for_each(|err| eprintln!("{}", err));
+            // the lines can still be fixed remotely
+            if port.is_none() {
+                return;
+            }
+        },
+    }
+
+    // without a port, nothing ever comes in, and waiting for it is just sleeping
+    let (sender, commands) = mpsc::channel();
+    if let Some(port) = port {
+        // only for editors on the same machine
+        match remote::serve(("127.0.0.1", port), sender.clone()) {
+            Ok(addr) => eprintln!("listening on ws://{}", addr),
+            Err(err) => eprintln!("couldn't listen on port {}: {}", port, err),
+        }
+    }
+
+    let now = scheduler.clock.now();
+    scheduler.start(now / 1000.0, now);
+    let mut next_wakeup = now + scheduler.clock.wakeup;
+    loop {
+        let now = scheduler.clock.now();
+        if now >= next_wakeup {
+            scheduler.scheduler_routine(now);
+            next_wakeup = now + scheduler.clock.wakeup;
+            continue;
+        }
+        match commands.recv_timeout(Duration::from_secs_f64((next_wakeup - now) / 1000.0)) {
+            Ok(command) => {
+                let reply = remote::apply(&mut scheduler, &command.message);
+                command.reply(reply);
+                // a start or a resume has the routine run, and wake up anew
+                next_wakeup = next_wakeup.min(scheduler.clock.now() + scheduler.clock.wakeup);
+            },
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => thread::sleep(Duration::from_secs_f64((next_wakeup - now) / 1000.0)),
+        }
+    }
+}
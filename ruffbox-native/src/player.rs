@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use parking_lot::Mutex;
+
+use ruffbox_core::batch::BatchEvent;
+use ruffbox_core::scheduler::Message;
+use ruffbox_core::EventSink;
+use ruffbox_synth::{resample, Engine, SourceType, SynthParameter};
+
+use crate::wav::read_wav;
+
+// PLAYER
+// The sound events go straight to the synth, like the worklet does it in the
+// browser (see js/worklet.js). Samples are loaded from WAV files up front,
+// custom voices and streams aren't there natively (yet), their events are
+// dropped, as are those of samples that aren't loaded.
+
+/// Plays the sound events on the synth the audio callback renders.
+pub struct NativePlayer {
+    engine: Arc<Mutex<Engine>>,
+    samplerate: f32,
+    /// the buffer numbers of the loaded samples, by name
+    samples: HashMap<String, usize>,
+}
+
+impl NativePlayer {
+    pub fn new(engine: Arc<Mutex<Engine>>, samplerate: f32) -> Self {
+        NativePlayer { engine, samplerate, samples: HashMap::new() }
+    }
+
+    /// Load a WAV file as a sample, resampled to the output samplerate like
+    /// the browser does it. Loading a name again swaps the buffer.
+    pub fn load_sample(&mut self, name: &str, path: &Path) -> Result<(), String> {
+        let bytes = fs::read(path).map_err(|err| err.to_string())?;
+        let wav = read_wav(&bytes)?;
+        let samples = resample(&wav.samples, wav.samplerate, self.samplerate);
+
+        // first point and last two points are padding for interpolation
+        let mut padded = Vec::with_capacity(samples.len() + 3);
+        padded.push(0.0);
+        padded.extend_from_slice(&samples);
+        padded.extend_from_slice(&[0.0, 0.0]);
+
+        let mut engine = self.engine.lock();
+        let sample_buf = match self.samples.get(name) {
+            Some(sample_buf) => engine.replace_sample(*sample_buf, &padded),
+            None => engine.load_sample(&padded),
+        };
+        self.samples.insert(name.to_string(), sample_buf);
+        Ok(())
+    }
+
+    /// Load the WAV files of a directory, each named after its file (without
+    /// the extension). Returns the names of the loaded ones.
+    pub fn load_samples(&mut self, dir: &Path) -> Result<Vec<String>, String> {
+        let mut paths: Vec<_> = fs::read_dir(dir).map_err(|err| err.to_string())?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("wav")))
+            .collect();
+        paths.sort();
+
+        let mut names = Vec::new();
+        for path in paths.iter() {
+            let name = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(name) => name.to_string(),
+                None => continue,
+            };
+            match self.load_sample(&name, path) {
+                Ok(()) => names.push(name),
+                Err(err) => eprintln!("couldn't load {}: {}", path.display(), err),
+            }
+        }
+        Ok(names)
+    }
+}
+
+impl EventSink for NativePlayer {
+    fn push(&mut self, event: &BatchEvent) -> bool {
+        let (source_type, sample_buf) = match event.source_type {
+            "SineSynth" => (SourceType::SineSynth, 0),
+            "LFSawSynth" => (SourceType::LFSawSynth, 0),
+            "LFSquareSynth" => (SourceType::LFSquareSynth, 0),
+            "Sampler" => match self.samples.get(event.sample_id) {
+                Some(sample_buf) => (SourceType::Sampler, *sample_buf),
+                None => return true,
+            },
+            _ => return true,
+        };
+
+        let mut engine = self.engine.lock();
+        let instance_id = engine.prepare_instance(source_type, event.timestamp, sample_buf);
+        engine.set_instance_bus(instance_id, event.bus as usize);
+        for (name, value) in event.params.iter() {
+            if name == "cut" {
+                // starting this voice stops the others of its cut group
+                engine.set_instance_cut_group(instance_id, *value as usize);
+            } else if let Some(par) = SynthParameter::from_name(name) {
+                engine.set_instance_parameter(instance_id, par, *value);
+            }
+        }
+        for (name, to) in event.ramps.iter() {
+            if let (Some(par), Some(from)) = (SynthParameter::from_name(name), event.params.get(name)) {
+                engine.set_instance_ramp(instance_id, par, *from, *to);
+            }
+        }
+        if event.expendable {
+            engine.set_instance_expendable(instance_id);
+        }
+        engine.trigger(instance_id);
+        true
+    }
+
+    fn post(&mut self, message: Message) {
+        match message {
+            Message::Warnings(warnings) => warnings.iter().for_each(|warning| eprintln!("{}", warning)),
+            Message::Errors(errors) => errors.iter().for_each(|err| eprintln!("{}", err)),
+            _ => (),
+        }
+    }
+}
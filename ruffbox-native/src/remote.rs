@@ -0,0 +1,446 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+use ruffbox_core::{Clock, EventSink};
+use ruffbox_core::scheduler::SchedulerCore;
+
+use crate::json::{self, Value};
+
+// REMOTE
+// An editor (or anything else that speaks WebSocket) can drive the native build
+// with the scheduler commands of the browser version (see the README), sent as
+// JSON text messages, i.e. {"cmd": "evaluate_loop", "loop_data": "bd ~ sn ~"}.
+// Each connection is served on a thread of its own, the commands are carried out
+// on the main thread in between wakeups (see main.rs), and what they have to
+// say goes back to the connection they came from, the way the worker posts it.
+
+/// appended to the client's key for the handshake, see RFC 6455
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// pattern files are small, anything bigger than this is a mistake
+const MAX_MESSAGE_SIZE: usize = 1 << 20;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+/// A command that came in on a connection, and the way back to it.
+pub struct Command {
+    pub message: Value,
+    reply: Sender<Option<String>>,
+}
+
+impl Command {
+    /// Send the reply (a JSON text, if there is one) back, the connection
+    /// waits for it before it takes the next command.
+    pub fn reply(self, reply: Option<String>) {
+        // the connection might be gone by now
+        let _ = self.reply.send(reply);
+    }
+}
+
+/// Listen for WebSocket connections on the given address, the commands that come
+/// in go to the given sender. Returns the address it listens on.
+pub fn serve<A: ToSocketAddrs>(addr: A, commands: Sender<Command>) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let commands = commands.clone();
+            thread::spawn(move || {
+                if let Err(err) = connection(stream, commands) {
+                    eprintln!("remote connection failed: {}", err);
+                }
+            });
+        }
+    });
+    Ok(local_addr)
+}
+
+/// Carry out a command on the scheduler, returns the reply, if there is one.
+/// Timestamps aren't needed natively, starting and resuming happens right away.
+pub fn apply<S: EventSink, C: Clock>(scheduler: &mut SchedulerCore<S, C>, message: &Value) -> Option<String> {
+    let text = |key: &str| message.get(key).and_then(Value::as_str).unwrap_or("").to_string();
+    let number = |key: &str| message.get(key).and_then(Value::as_f64);
+
+    let evaluated = match message.get("cmd").and_then(Value::as_str) {
+        Some("evaluate_loop") => scheduler.evaluate(Some(text("loop_data"))),
+        Some("queue_loop") => scheduler.queue(text("loop_data")),
+        Some("start") => {
+            let now = scheduler.clock.now();
+            scheduler.start(now / 1000.0, now);
+            return None;
+        },
+        Some("resume") => {
+            let now = scheduler.clock.now();
+            scheduler.resume(now / 1000.0, now);
+            return None;
+        },
+        Some("stop") => {
+            scheduler.stop();
+            return None;
+        },
+        Some("pause") => {
+            scheduler.pause();
+            return None;
+        },
+        Some("panic") => {
+            scheduler.panic();
+            return None;
+        },
+        Some("set_tempo") => match number("tempo") {
+            Some(tempo) => {
+                scheduler.set_tempo(tempo);
+                return None;
+            },
+            None => Ok(vec!["set_tempo needs a tempo".to_string()]),
+        },
+        Some("set_bpm") => match number("bpm") {
+            Some(bpm) => {
+                scheduler.set_bpm(bpm);
+                return None;
+            },
+            None => Ok(vec!["set_bpm needs a bpm".to_string()]),
+        },
+        Some("get_bpm") => return Some(format!("{{\"type\": \"bpm\", \"bpm\": {}}}", scheduler.get_bpm())),
+        Some(cmd) => Ok(vec![format!("unknown command '{}'", cmd)]),
+        None => Ok(vec!["no command".to_string()]),
+    };
+
+    match evaluated {
+        Ok(warnings) if warnings.is_empty() => None,
+        Ok(warnings) => {
+            let warnings: Vec<String> = warnings.iter().map(|warning| json::quote(warning)).collect();
+            Some(format!("{{\"type\": \"warnings\", \"warnings\": [{}]}}", warnings.join(", ")))
+        },
+        Err(errors) => {
+            let errors: Vec<String> = errors.iter()
+                .map(|err| format!("{{\"line\": {}, \"column\": {}, \"message\": {}}}", err.line, err.column, json::quote(&err.message)))
+                .collect();
+            Some(format!("{{\"type\": \"errors\", \"errors\": [{}]}}", errors.join(", ")))
+        },
+    }
+}
+
+/// Serve a single connection, until it's closed.
+fn connection(stream: TcpStream, commands: Sender<Command>) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    handshake(&mut reader, &mut writer)?;
+
+    let (reply_sender, replies) = mpsc::channel();
+    while let Some(text) = read_message(&mut reader, &mut writer)? {
+        let message = match json::parse(&text) {
+            Ok(message) => message,
+            Err(err) => {
+                let warning = json::quote(&format!("invalid command: {}", err));
+                write_frame(&mut writer, OP_TEXT, format!("{{\"type\": \"warnings\", \"warnings\": [{}]}}", warning).as_bytes())?;
+                continue;
+            },
+        };
+        if commands.send(Command { message, reply: reply_sender.clone() }).is_err() {
+            // nobody's there to carry it out
+            break;
+        }
+        match replies.recv() {
+            Ok(Some(reply)) => write_frame(&mut writer, OP_TEXT, reply.as_bytes())?,
+            Ok(None) => (),
+            Err(_) => break,
+        }
+    }
+    Ok(())
+}
+
+/// The HTTP upgrade to a WebSocket, only the key is looked at.
+fn handshake<R: BufRead, W: Write>(reader: &mut R, writer: &mut W) -> io::Result<()> {
+    let mut key = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed during the handshake"));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("sec-websocket-key") {
+                key = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    match key {
+        Some(key) => write!(writer, "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n", accept_key(&key)),
+        None => {
+            write!(writer, "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")?;
+            Err(io::Error::new(io::ErrorKind::InvalidData, "not a WebSocket handshake"))
+        },
+    }
+}
+
+/// The accept key for the key a client sent.
+fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key, HANDSHAKE_GUID).as_bytes()))
+}
+
+/// The next text message, put together from its frames, None once the
+/// connection is closed. Pings are answered on the way.
+fn read_message<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> io::Result<Option<String>> {
+    let mut message = Vec::new();
+    loop {
+        let mut head = [0u8; 2];
+        match reader.read_exact(&mut head) {
+            Ok(()) => (),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0F;
+        let masked = head[1] & 0x80 != 0;
+        let len = match head[1] & 0x7F {
+            126 => {
+                let mut len = [0u8; 2];
+                reader.read_exact(&mut len)?;
+                u16::from_be_bytes(len) as u64
+            },
+            127 => {
+                let mut len = [0u8; 8];
+                reader.read_exact(&mut len)?;
+                u64::from_be_bytes(len)
+            },
+            len => len as u64,
+        };
+        if len as usize + message.len() > MAX_MESSAGE_SIZE {
+            write_frame(writer, OP_CLOSE, &1009u16.to_be_bytes())?;
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "message too big"));
+        }
+
+        let mut mask = [0u8; 4];
+        if masked {
+            reader.read_exact(&mut mask)?;
+        }
+        let mut payload = vec![0u8; len as usize];
+        reader.read_exact(&mut payload)?;
+        if masked {
+            payload.iter_mut().enumerate().for_each(|(idx, byte)| *byte ^= mask[idx % 4]);
+        }
+
+        match opcode {
+            OP_TEXT | OP_CONTINUATION => {
+                message.extend_from_slice(&payload);
+                if fin {
+                    return String::from_utf8(message).map(Some)
+                        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "message isn't UTF-8"));
+                }
+            },
+            OP_PING => write_frame(writer, OP_PONG, &payload)?,
+            OP_CLOSE => {
+                write_frame(writer, OP_CLOSE, &payload)?;
+                return Ok(None);
+            },
+            // binary messages and pongs aren't taken
+            _ => (),
+        }
+    }
+}
+
+/// A single, unmasked frame, as servers send them.
+fn write_frame<W: Write>(writer: &mut W, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        },
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        },
+    }
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame)?;
+    writer.flush()
+}
+
+/// SHA-1, only for the handshake.
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    // padded to a multiple of 64 bytes, with the length in bits at the end
+    let mut padded = data.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in padded.chunks(64) {
+        let mut words = [0u32; 80];
+        for (idx, word) in block.chunks(4).enumerate() {
+            words[idx] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for idx in 16..80 {
+            words[idx] = (words[idx - 3] ^ words[idx - 8] ^ words[idx - 14] ^ words[idx - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (idx, word) in words.iter().enumerate() {
+            let (f, k) = match idx {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (word, add) in state.iter_mut().zip([a, b, c, d, e]) {
+            *word = word.wrapping_add(add);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (bytes, word) in digest.chunks_mut(4).zip(state.iter()) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+/// Standard base64, padded.
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (idx, byte)| bits | (*byte as u32) << (16 - 8 * idx));
+        for idx in 0..4 {
+            if idx <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * idx) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use std::time::Duration;
+    use ruffbox_core::batch::BatchEvent;
+    use ruffbox_core::scheduler::Message;
+
+    #[derive(Default)]
+    struct TestSink {
+        events: usize,
+    }
+
+    impl EventSink for TestSink {
+        fn push(&mut self, _: &BatchEvent) -> bool {
+            self.events += 1;
+            true
+        }
+
+        fn post(&mut self, _: Message) {}
+    }
+
+    struct TestClock;
+
+    impl Clock for TestClock {
+        fn now(&self) -> f64 {
+            0.0
+        }
+
+        fn wake_up_in(&mut self, _: f64) {}
+    }
+
+    /// a masked text frame, as clients send them
+    fn send(stream: &mut TcpStream, text: &str) {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![0x80 | OP_TEXT, 0x80 | 126];
+        frame.extend_from_slice(&(text.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&mask);
+        frame.extend(text.bytes().enumerate().map(|(idx, byte)| byte ^ mask[idx % 4]));
+        stream.write_all(&frame).unwrap();
+    }
+
+    /// an unmasked text frame, as the server sends them
+    fn receive(stream: &mut TcpStream) -> String {
+        let mut head = [0u8; 2];
+        stream.read_exact(&mut head).unwrap();
+        assert_eq!(head[0], 0x80 | OP_TEXT);
+        let mut payload = vec![0u8; head[1] as usize];
+        stream.read_exact(&mut payload).unwrap();
+        String::from_utf8(payload).unwrap()
+    }
+
+    /// what the main loop does in between wakeups
+    fn carry_out(scheduler: &mut SchedulerCore<TestSink, TestClock>, commands: &mpsc::Receiver<Command>, stream: &mut TcpStream, text: &str) {
+        send(stream, text);
+        let command = commands.recv_timeout(Duration::from_secs(5)).unwrap();
+        let reply = apply(scheduler, &command.message);
+        command.reply(reply);
+    }
+
+    #[test]
+    fn test_handshake() {
+        // the example from RFC 6455
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(base64(b"ruffbox"), "cnVmZmJveA==");
+        assert_eq!(base64(b"ruff"), "cnVmZg==");
+        assert_eq!(base64(b"ruf"), "cnVm");
+    }
+
+    #[test]
+    fn test_remote_commands() {
+        let (sender, commands) = mpsc::channel();
+        let addr = serve("127.0.0.1:0", sender).unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        write!(stream, "GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8];
+            stream.read_exact(&mut byte).unwrap();
+            response.push(byte[0]);
+        }
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101"));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+        let mut scheduler = SchedulerCore::new(TestSink::default(), TestClock);
+
+        carry_out(&mut scheduler, &commands, &mut stream, r#"{"cmd": "evaluate_loop", "loop_data": "bd ~ sn ~\nhh hh"}"#);
+        carry_out(&mut scheduler, &commands, &mut stream, r#"{"cmd": "set_bpm", "bpm": 90}"#);
+        carry_out(&mut scheduler, &commands, &mut stream, r#"{"cmd": "start"}"#);
+        assert_eq!(scheduler.sequence_ids(), vec![0, 1]);
+        assert_eq!(scheduler.get_bpm(), 90.0);
+        // the first step is scheduled on the start
+        assert_eq!(scheduler.sink.events, 2);
+
+        // syntax errors go back, like the worker posts them
+        carry_out(&mut scheduler, &commands, &mut stream, r#"{"cmd": "evaluate_loop", "loop_data": "bd ~\nsn )"}"#);
+        assert_eq!(receive(&mut stream), r#"{"type": "errors", "errors": [{"line": 2, "column": 4, "message": "unexpected ')'"}]}"#);
+        carry_out(&mut scheduler, &commands, &mut stream, r#"{"cmd": "get_bpm"}"#);
+        assert_eq!(receive(&mut stream), r#"{"type": "bpm", "bpm": 90}"#);
+
+        send(&mut stream, "{\"cmd\": ");
+        assert!(receive(&mut stream).starts_with(r#"{"type": "warnings", "warnings": ["invalid command"#));
+        carry_out(&mut scheduler, &commands, &mut stream, r#"{"cmd": "stop"}"#);
+        carry_out(&mut scheduler, &commands, &mut stream, r#"{"cmd": "set_bpm", "bpm": 120}"#);
+        assert_eq!(scheduler.get_bpm(), 120.0);
+    }
+}
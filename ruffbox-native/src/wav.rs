@@ -0,0 +1,142 @@
+// WAV
+// Just enough of a WAV reader to load samples from disk: PCM in 8, 16, 24 or
+// 32 bit, or 32 bit float, in any number of channels. Like in the browser,
+// only the first channel is kept.
+
+/// the first channel of a WAV file, and its samplerate
+pub struct Wav {
+    pub samples: Vec<f32>,
+    pub samplerate: f32,
+}
+
+const PCM: u16 = 1;
+const FLOAT: u16 = 3;
+const EXTENSIBLE: u16 = 0xFFFE;
+
+fn u16_at(bytes: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_le_bytes([*bytes.get(pos)?, *bytes.get(pos + 1)?]))
+}
+
+fn u32_at(bytes: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_le_bytes([*bytes.get(pos)?, *bytes.get(pos + 1)?, *bytes.get(pos + 2)?, *bytes.get(pos + 3)?]))
+}
+
+/// one sample, in -1..1
+fn decode(bytes: &[u8], format: u16) -> f32 {
+    match (format, bytes) {
+        // 8 bit is unsigned
+        (PCM, [a]) => (*a as f32 - 128.0) / 128.0,
+        (PCM, [a, b]) => i16::from_le_bytes([*a, *b]) as f32 / 32768.0,
+        (PCM, [a, b, c]) => (i32::from_le_bytes([0, *a, *b, *c]) >> 8) as f32 / 8388608.0,
+        (PCM, [a, b, c, d]) => i32::from_le_bytes([*a, *b, *c, *d]) as f32 / 2147483648.0,
+        (FLOAT, [a, b, c, d]) => f32::from_le_bytes([*a, *b, *c, *d]),
+        _ => 0.0,
+    }
+}
+
+pub fn read_wav(bytes: &[u8]) -> Result<Wav, String> {
+    if bytes.get(0..4) != Some(b"RIFF") || bytes.get(8..12) != Some(b"WAVE") {
+        return Err("not a WAV file".to_string());
+    }
+
+    // (format, channels, samplerate, bits)
+    let mut fmt = None;
+    let mut data = None;
+    let mut pos = 12;
+    while let (Some(id), Some(size)) = (bytes.get(pos..pos + 4), u32_at(bytes, pos + 4)) {
+        let start = pos + 8;
+        let end = (start + size as usize).min(bytes.len());
+        match id {
+            b"fmt " => {
+                let mut format = u16_at(bytes, start).ok_or("broken format chunk")?;
+                if format == EXTENSIBLE {
+                    // the actual format is at the start of the sub format guid
+                    format = u16_at(bytes, start + 24).ok_or("broken format chunk")?;
+                }
+                let channels = u16_at(bytes, start + 2).ok_or("broken format chunk")?;
+                let samplerate = u32_at(bytes, start + 4).ok_or("broken format chunk")?;
+                let bits = u16_at(bytes, start + 14).ok_or("broken format chunk")?;
+                fmt = Some((format, channels, samplerate, bits));
+            },
+            b"data" => data = Some(&bytes[start..end]),
+            _ => (),
+        }
+        // chunks are padded to an even size
+        pos = start + size as usize + (size as usize & 1);
+    }
+
+    let (format, channels, samplerate, bits) = fmt.ok_or("no format chunk")?;
+    let data = data.ok_or("no data chunk")?;
+    match (format, bits) {
+        (PCM, 8) | (PCM, 16) | (PCM, 24) | (PCM, 32) | (FLOAT, 32) => (),
+        _ => return Err(format!("unsupported format {} in {} bit", format, bits)),
+    }
+    if channels == 0 {
+        return Err("no channels".to_string());
+    }
+
+    let width = bits as usize / 8;
+    let frame = width * channels as usize;
+    Ok(Wav {
+        samples: data.chunks_exact(frame).map(|frame| decode(&frame[..width], format)).collect(),
+        samplerate: samplerate as f32,
+    })
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    fn wav(format: u16, channels: u16, bits: u16, data: &[u8]) -> Vec<u8> {
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+        wav.extend_from_slice(b"WAVE");
+        wav.extend_from_slice(b"fmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&format.to_le_bytes());
+        wav.extend_from_slice(&channels.to_le_bytes());
+        wav.extend_from_slice(&44100u32.to_le_bytes());
+        wav.extend_from_slice(&(44100 * (bits / 8 * channels) as u32).to_le_bytes());
+        wav.extend_from_slice(&(bits / 8 * channels).to_le_bytes());
+        wav.extend_from_slice(&bits.to_le_bytes());
+        // something to skip
+        wav.extend_from_slice(b"LIST");
+        wav.extend_from_slice(&3u32.to_le_bytes());
+        wav.extend_from_slice(&[1, 2, 3, 0]);
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        wav.extend_from_slice(data);
+        wav
+    }
+
+    #[test]
+    fn test_read_pcm_first_channel() {
+        let mut data = Vec::new();
+        for (left, right) in [(16384i16, 1i16), (-32768, 2)].iter() {
+            data.extend_from_slice(&left.to_le_bytes());
+            data.extend_from_slice(&right.to_le_bytes());
+        }
+
+        let read = read_wav(&wav(PCM, 2, 16, &data)).unwrap();
+        assert_eq!(read.samplerate, 44100.0);
+        assert_eq!(read.samples, vec![0.5, -1.0]);
+    }
+
+    #[test]
+    fn test_read_24_bit_and_float() {
+        let read = read_wav(&wav(PCM, 1, 24, &[0x00, 0x00, 0xC0, 0x00, 0x00, 0x40])).unwrap();
+        assert_eq!(read.samples, vec![-0.5, 0.5]);
+
+        let read = read_wav(&wav(FLOAT, 1, 32, &0.25f32.to_le_bytes())).unwrap();
+        assert_eq!(read.samples, vec![0.25]);
+    }
+
+    #[test]
+    fn test_reject_other_files() {
+        assert!(read_wav(b"OggS and so on").is_err());
+        assert!(read_wav(&wav(2, 1, 4, &[0, 0])).is_err());
+    }
+}
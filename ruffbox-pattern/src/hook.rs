@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+
+pub use ruffbox_core::hook::{SoundEvent, TickHook, run_hook};
+
+use crate::batch::BatchEvent;
+use crate::js_object;
+
+// TICK HOOKS, IN JS
+// The tick hook (see hook.rs in ruffbox-core) as a function set from JS. It
+// gets the sound events as plain objects, { timestamp, source_type, sample_id,
+// bus, remote, expendable, fit_key, params: {...}, ramps: {...}, duration }, and
+// returns the ones to play. Returned events without a timestamp or sample id
+// are dropped, everything else has defaults. The event callbacks (see on_event)
+// get the events the same way.
+
+fn map_to_js(map: &HashMap<String, f32>) -> JsValue {
+    let entries: Vec<(&str, JsValue)> = map.iter().map(|(name, value)| (name.as_str(), (*value).into())).collect();
+    js_object(&entries)
+}
+
+/// the numbers of an object, anything else is left out
+fn map_from_js(value: &JsValue) -> HashMap<String, f32> {
+    let object = match value.dyn_ref::<js_sys::Object>() {
+        Some(object) => object,
+        None => return HashMap::new(),
+    };
+    js_sys::Object::entries(object).iter().filter_map(|entry| {
+        let entry: js_sys::Array = entry.dyn_into().ok()?;
+        Some((entry.get(0).as_string()?, entry.get(1).as_f64()? as f32))
+    }).collect()
+}
+
+fn field(value: &JsValue, name: &str) -> JsValue {
+    js_sys::Reflect::get(value, &JsValue::from(name)).unwrap_or(JsValue::UNDEFINED)
+}
+
+pub fn event_to_js(event: &BatchEvent) -> JsValue {
+    js_object(&[
+        ("timestamp", event.timestamp.into()),
+        ("source_type", event.source_type.into()),
+        ("sample_id", event.sample_id.into()),
+        ("bus", event.bus.into()),
+        ("remote", event.remote.into()),
+        ("expendable", event.expendable.into()),
+        ("fit_key", event.fit_key.map_or(JsValue::UNDEFINED, JsValue::from)),
+        ("params", map_to_js(event.params)),
+        ("ramps", map_to_js(event.ramps)),
+        ("duration", event.duration.into()),
+    ])
+}
+
+pub fn event_from_js(value: &JsValue) -> Option<SoundEvent> {
+    Some(SoundEvent {
+        // without a proper timestamp, there's no telling when it'd be played
+        timestamp: field(value, "timestamp").as_f64().filter(|timestamp| timestamp.is_finite())?,
+        source_type: field(value, "source_type").as_string().unwrap_or_else(|| "Sampler".to_string()),
+        sample_id: field(value, "sample_id").as_string()?,
+        bus: field(value, "bus").as_f64().map_or(0, |bus| bus.max(0.0) as u32),
+        remote: field(value, "remote").as_bool().unwrap_or(false),
+        expendable: field(value, "expendable").as_bool().unwrap_or(false),
+        fit_key: field(value, "fit_key").as_f64().map(|key| key as i32),
+        params: map_from_js(&field(value, "params")),
+        ramps: map_from_js(&field(value, "ramps")),
+        duration: field(value, "duration").as_f64().unwrap_or(0.0),
+    })
+}
+
+/// A tick hook set from JS.
+pub struct JsHook(pub js_sys::Function);
+
+impl TickHook for JsHook {
+    fn call(&self, events: &[SoundEvent], step: usize) -> Result<Option<Vec<SoundEvent>>, String> {
+        let array = js_sys::Array::new();
+        for event in events.iter() {
+            array.push(&event_to_js(&event.as_batch_event()));
+        }
+
+        let result = self.0.call2(&JsValue::NULL, &array, &(step as u32).into())
+            .map_err(|err| err.as_string().unwrap_or_else(|| format!("{:?}", err)))?;
+        if result.is_undefined() {
+            return Ok(None);
+        }
+
+        let returned: js_sys::Array = result.dyn_into().map_err(|_| "the hook didn't return an array".to_string())?;
+        Ok(Some(returned.iter().filter_map(|event| event_from_js(&event)).collect()))
+    }
+}
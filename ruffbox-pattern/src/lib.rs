@@ -1,19 +1,14 @@
-#[macro_use]
-extern crate stdweb;
 extern crate web_sys;
 
 //use js_sys::Math;
-pub mod seqgen;
-pub mod parser;
+// the patterns and the scheduler are in ruffbox-core, this runs them in the browser
+pub use ruffbox_core::{seqgen, parser, samples, notation, sexp, groups, scale, timecode, light, controllers,
+                       humanize, fills, chop, meter, instruments, soundfont, flood, expr, groove, matching, batch, sequence, stats,
+                       scheduler};
+pub use ruffbox_core::scheduler::ParseError;
 
-use std::collections::hash_map::DefaultHasher;
 use wasm_bindgen::prelude::*;
-use std::hash::{Hash, Hasher};
-use std::collections::HashMap;
-
-use crate::seqgen::*;
-
-use decorum::N32;
+use wasm_bindgen::JsCast;
 
 // A macro to provide `println!(..)`-style syntax for `console.log` logging.
 macro_rules! log {
@@ -22,344 +17,45 @@ macro_rules! log {
     }
 }
 
-type EventHash = u64;
-
-fn calculate_hash<T: Hash>(t: &T) -> u64 {
-    let mut s = DefaultHasher::new();
-    t.hash(&mut s);
-    s.finish()
-}
-
-struct MainEvent {
-    name: String,
-    params: HashMap<String, N32>,
-}
-
-impl Hash for MainEvent {
-     fn hash<H: Hasher>(&self, state: &mut H) {
-         self.name.hash(state);
-         for (par, val) in self.params.iter() {
-             par.hash(state);
-             (*val).hash(state);
-         }
-     }    
-}
-
-impl MainEvent {
-    fn from_parsed_input(input_name: String, input_params: &Vec<(&str, f32)>) -> Self {
-        let mut param_map = HashMap::new();
-
-        for param_tuple in input_params {
-            param_map.insert(param_tuple.0.to_string(), param_tuple.1.into());
-        }
-        
-        MainEvent {
-            name: input_name.to_string(),
-            params: param_map,
-        }
-    }
-}
+pub mod hook;
+pub mod ring;
+pub mod worker;
 
-impl PartialEq for MainEvent {    
-    fn eq(&self, other: &Self) -> bool {
-        for (param, value) in self.params.iter() {
-            if !other.params.contains_key(param) {
-                return false
-            } else if *value != other.params[param] {
-                return false
-            }
-        }
-        self.name == other.name        
+/// Build a plain object from key/value pairs, to be sent as a message.
+fn js_object(entries: &[(&str, JsValue)]) -> JsValue {
+    let object = js_sys::Object::new();
+    for (key, value) in entries {
+        js_sys::Reflect::set(&object, &JsValue::from(*key), value).ok();
     }
+    object.into()
 }
 
-/// A simple event sequence represented by a vector of strings and params
-struct EventSequence {
-    event_refs: HashMap<EventHash, MainEvent>,
-    events: Box<dyn SequenceGenerator<EventHash, usize>>,
-    param_generators: HashMap<String, Box<dyn SequenceGenerator<N32, usize>>>
-}
-
-impl EventSequence {
-        
-    /// Create an event sequence from a string.    
-    pub fn from_parsed_line_ast(input_line: ((&str, Vec<(&str, Vec<(&str, f32)>)>), Vec<((&str, &str), Vec<f32>)>)) -> Self {        
-        let pattern_ast = input_line.0;
-        let param_asts = input_line.1;
-        
-        let mut main_events = HashMap::new();
-        let mut event_hashes = Vec::new();
-        
-        for parsed_event in pattern_ast.1.iter() {
-            let main_event = MainEvent::from_parsed_input(parsed_event.0.to_string(), &parsed_event.1);
-            let main_event_hash = calculate_hash::<MainEvent>(&main_event);
-            main_events.insert(main_event_hash, main_event);
-            event_hashes.push(main_event_hash);
-        }
-
-        let mut param_row_map: HashMap<String, Box<dyn SequenceGenerator<N32, usize>>> = HashMap::new();
-        
-        for parsed_param_seq in param_asts.iter() {
-            let mut param_conv:Vec<N32> = Vec::new();
-            for raw_float in &parsed_param_seq.1 {
-                param_conv.push((*raw_float).into())
-            }
-
-            param_row_map.insert(
-                (parsed_param_seq.0).0.to_string(),
-                match (parsed_param_seq.0).1 {
-                    "rnd" => Box::new(RandomSequenceGenerator::from_seq(&param_conv)),
-                    "cyc" => Box::new(CycleSequenceGenerator::from_seq(&param_conv)),
-                    "learn" => Box::new(PfaSequenceGenerator::from_seq(&param_conv)),
-                    "bounce" => Box::new(BounceSequenceGenerator::from_params(param_conv[0], param_conv[1], param_conv[2])),
-                    "ramp" => Box::new(RampSequenceGenerator::from_params(param_conv[0], param_conv[1], param_conv[2])),
-                    //"brownian" => Box::new(BounceSequenceGenerator::from_params(param_conv[0], param_conv[1], param_conv[2])),
-                    _ => Box::new(CycleSequenceGenerator::from_seq(&param_conv)),
-                });            
-        }
-        
-                
-        EventSequence {
-            event_refs: main_events,
-            events: match pattern_ast.0 {
-                "rnd" => Box::new(RandomSequenceGenerator::from_seq(&event_hashes)),
-                "cyc" => Box::new(CycleSequenceGenerator::from_seq(&event_hashes)),
-                "learn" => Box::new(PfaSequenceGenerator::from_seq(&event_hashes)),
-                _ => Box::new(CycleSequenceGenerator::from_seq(&event_hashes))
-            },
-            
-            param_generators: param_row_map,
-        }
-    }
-
-    /// Update an existing sequence from a string.
-    pub fn update_sequence(&mut self, input_line: ((&str, Vec<(&str, Vec<(&str, f32)>)>), Vec<((&str, &str), Vec<f32>)>)) {
-        self.event_refs.clear();
-        self.param_generators.clear();
-
-        let pattern_ast = input_line.0;
-        let param_asts = input_line.1;
-        
-        let mut main_events = HashMap::new();
-        let mut event_hashes = Vec::new();
-                
-        //let mut param_row_map: HashMap<String, Box<dyn SequenceGenerator<N32>>> = HashMap::new();
-        
-        for parsed_param_seq in param_asts.iter() {
-            let mut param_conv:Vec<N32> = Vec::new();
-            for raw_float in &parsed_param_seq.1 {
-                param_conv.push((*raw_float).into())
-            }
-
-            let key = (parsed_param_seq.0).0.to_string();
-            let mut state = 0;
-            if self.param_generators.contains_key(&key) {
-                state = self.param_generators[&key].get_state();
-            }
-            
-            self.param_generators.insert(
-                key,
-                match (parsed_param_seq.0).1 {
-                    "rnd" => Box::new(RandomSequenceGenerator::from_seq(&param_conv)),
-                    "cyc" => Box::new(CycleSequenceGenerator::from_seq_with_index(&param_conv, state)),
-                    "learn" => Box::new(PfaSequenceGenerator::from_seq(&param_conv)),
-                    "bounce" => Box::new(BounceSequenceGenerator::from_params(param_conv[0], param_conv[1], param_conv[2])),
-                    "ramp" => Box::new(RampSequenceGenerator::from_params(param_conv[0], param_conv[1], param_conv[2])),
-                    //"brownian" => Box::new(BounceSequenceGenerator::from_params(param_conv[0], param_conv[1], param_conv[2])),
-                    _ => Box::new(CycleSequenceGenerator::from_seq(&param_conv)),
-                });            
-        }
-
-        for parsed_event in pattern_ast.1.iter() {
-            let main_event = MainEvent::from_parsed_input(parsed_event.0.to_string(), &parsed_event.1);
-            let main_event_hash = calculate_hash::<MainEvent>(&main_event);
-            main_events.insert(main_event_hash, main_event);
-            event_hashes.push(main_event_hash);
+/// Hand a message to the sink, or post it to the main thread if there's none.
+fn post(sink: &Option<js_sys::Function>, message: &JsValue) {
+    let result = match sink {
+        Some(sink) => sink.call1(&JsValue::NULL, message),
+        None => {
+            let global: JsValue = js_sys::global().into();
+            js_sys::Reflect::get(&global, &JsValue::from("postMessage"))
+                .and_then(|post_message| post_message.dyn_into::<js_sys::Function>())
+                .and_then(|post_message| post_message.call1(&global, message))
         }
-        
-        self.event_refs = main_events;
+    };
 
-        let cycle_state = self.events.get_state();
-                
-        self.events = match pattern_ast.0 {
-            "rnd" => Box::new(RandomSequenceGenerator::from_seq(&event_hashes)),
-            "cyc" => Box::new(CycleSequenceGenerator::from_seq_with_index(&event_hashes, cycle_state)),
-            "learn" => Box::new(PfaSequenceGenerator::from_seq(&event_hashes)),
-            _ => Box::new(CycleSequenceGenerator::from_seq(&event_hashes))
-        };        
-    }
-
-    /// get the next event in the sequence
-    pub fn get_next_event(&mut self) -> (String, HashMap<String, f32>) {
-        let mut final_param_map: HashMap<String, f32> = HashMap::new();
-        match self.events.get_next() {
-            Some(ev_hash) => {
-                let ev = &self.event_refs[&ev_hash];
-                if ev.name == "~" {
-                    return ("~".to_string(), final_param_map)
-                }
-                // pref for dyn params, so insert fixed pars first (might be overwritten)
-                for (par, val) in ev.params.iter() {
-                    final_param_map.insert(par.to_string(), (*val).into());
-                }
-
-                // pref for dyn params, so insert fixed pars first (might be overwritten)
-                for (par, gen) in self.param_generators.iter_mut() {
-                    match gen.get_next() {
-                        Some(val) => final_param_map.insert(par.to_string(), val.into()),
-                        None => None
-                    };
-                }
-                
-                (ev.name.clone(), final_param_map)
-            },
-            None => ("~".to_string(), final_param_map)
-        }                                               
+    if result.is_err() {
+        log!("couldn't send message!");
     }
 }
 
-/// A simple time-recursion event scheduler running at a fixed time interval.
-#[wasm_bindgen]
-pub struct Scheduler {
-    /// time this scheduler was started (AudioContext.currentTime)
-    audio_start_time: f64,
-    /// time this scheduler was started (performance.now())
-    browser_start_time: f64,    
-    audio_logical_time: f64,
-    browser_logical_time: f64,
-    next_schedule_time: f64,
-    lookahead: f64, // in seconds
-    running: bool,
-    tempo: f64, // currently just the duration of a 16th note ...
-    event_sequences: Vec<EventSequence>,
-}
-
-#[wasm_bindgen]
-impl Scheduler {
-    pub fn new() -> Self {
-        Scheduler{
-            audio_start_time: 0.0,
-            browser_start_time: 0.0,
-            audio_logical_time: 0.0,
-            browser_logical_time: 0.0,
-            next_schedule_time: 0.0,
-            lookahead: 0.100,
-            running: false,
-            tempo: 128.0,
-            event_sequences: Vec::new(),
-        }
-    }
-
-    /// Evaluate an input string, turn it into a series of event sequences.
-    pub fn evaluate(&mut self, input: Option<String>) {        
-        match input {
-            Some(all_lines) => {                                               
-                let mut seq_idx = 0;
-
-                for line in all_lines.lines() {
-                    let trimmed_line = line.trim();
-                    
-                    if !trimmed_line.is_empty() && !trimmed_line.starts_with("#") {
-                        match parser::pattern_line(trimmed_line) {
-                            Ok(ast) => {
-                                if self.event_sequences.len() > seq_idx {
-                                    self.event_sequences[seq_idx].update_sequence(ast.1);
-                                } else {
-                                    self.event_sequences.push(EventSequence::from_parsed_line_ast(ast.1));
-                                }
-                            },
-                            Err(err) => log!("invalid line! {:?}, {}", err, trimmed_line) // ??
-                        };
-                        
-                        seq_idx += 1;                        
-                    }
-                }
-
-                // check if we need to remove some sequnces because the number of lines got reduced ...
-                if seq_idx < self.event_sequences.len() {
-                    self.event_sequences.truncate(seq_idx);
-                }
-            }
-            
-            None => log!("no input!")
-        }
-    }    
-
-    /// Fetch all events from the event sequences, post them to main thread
-    fn generate_and_send_events(&mut self) {
-        if self.event_sequences.is_empty() {
-            return
-        }
-
-        let trigger_time = self.audio_logical_time + self.lookahead;
-        
-        for seq in self.event_sequences.iter_mut() {
-            
-            let (next_event, next_params) = seq.get_next_event();
-            
-            let next_source_type = match next_event.as_str() {
-                "sine" => "SineSynth",
-                "saw" => "LFSawSynth",
-                "sqr" => "LFSquareSynth",
-                _ => "Sampler",
-            };
-                                                           
-            if next_event != "~" {
-                // post events that will be dispatched to sampler
-                js! {                
-                    postMessage( { source_type: @{ next_source_type }, timestamp: @{ trigger_time }, sample_id: @{ next_event }, params: @{ next_params }} );
-                }
-            }
-        }
-    }
-
-    /// The main scheduler recursion.
-    pub fn scheduler_routine(&mut self, browser_timestamp: f64) {
-        if !self.running {
-            return
-        }
-
-        // Get current events and post them to main thread.
-        self.generate_and_send_events();
-
-        // Calculate drift, correct timing.
-        // The time at which this is called is most likely later, but never earlier,
-        // than the time it SHOULD have been called at (self.browser_logical_time).
-        // To compensate for the delay, we schedule the next call a bit earlier
-        // than the actual interval.
-        self.next_schedule_time = self.tempo - (browser_timestamp - self.browser_logical_time);
-
-        // Advance timestamps!
-        // audio time in seconds
-        self.audio_logical_time += self.tempo / 1000.0;
-
-        // browser time in milliseconds
-        self.browser_logical_time += self.tempo;
-        
-        // Time-recursive call to scheduler function.
-        // i'm looking forward to the day I can do that in pure rust ... 
-        js! {            
-            self.sleep( @{ self.next_schedule_time } ).then( () => self.scheduler.scheduler_routine( performance.now()));
-        };                
-    }
-
-    /// Start this scheduler.
-    pub fn start(&mut self, audio_timestamp: f64, browser_timestamp: f64) {
-        self.audio_start_time = audio_timestamp;
-        self.browser_start_time = browser_timestamp;
-        self.audio_logical_time = self.audio_start_time;
-        self.browser_logical_time = self.browser_start_time;
-        self.running = true;
-        self.scheduler_routine(browser_timestamp);
-    }
-
-    /// Stop this scheduler.
-    pub fn stop(&mut self) {
-        self.running = false;
-    }
-
-    /// Set tick duration.
-    pub fn set_tempo(&mut self, tempo: f64) {
-        self.tempo = tempo;
+/// the errors of an evaluation, as [{ line, column, message }, ...]
+fn parse_errors_to_js(errors: &[ParseError]) -> JsValue {
+    let array = js_sys::Array::new();
+    for err in errors.iter() {
+        array.push(&js_object(&[
+            ("line", (err.line as u32).into()),
+            ("column", (err.column as u32).into()),
+            ("message", err.message.as_str().into()),
+        ]));
     }
+    array.into()
 }
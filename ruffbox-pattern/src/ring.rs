@@ -0,0 +1,265 @@
+use wasm_bindgen::JsValue;
+
+use ruffbox_core::EventSink;
+
+use crate::batch::{BatchEvent, FLAG_EXPENDABLE, FLAG_FIT_KEY, FLAG_REMOTE};
+
+// EVENT RING
+// Instead of going through the main thread as messages, the sound events can
+// be written into a SharedArrayBuffer the sampler reads from directly, on every
+// block it renders. The scheduler is the only one writing, the sampler the only
+// one reading. The buffer starts with two Int32 (where the scheduler has written
+// up to, and where the sampler has read up to, both in slots of the data), the
+// data is Float64 from byte 8 on. Each event is a record of its own:
+//
+//   length (in slots, itself included), timestamp, bus, flags, fit key, duration,
+//   source type, sample id, P, P times (param name, value), R, R times (param name, ramp target)
+//
+// with the flags and the duration as in a batch, and the strings inline, as their
+// length followed by their UTF-16 code units. A record never runs over the end of
+// the data, if it doesn't fit there anymore the rest is marked (WRAP) and it goes
+// to the start. One slot always stays free, so the ring is empty when both
+// positions are the same. Events that don't fit are left to the usual messages.
+
+pub const HEADER_BYTES: u32 = 8;
+pub const WRITE_POSITION: u32 = 0;
+pub const READ_POSITION: u32 = 1;
+/// the rest of the data is skipped, the next record is at the start
+pub const WRAP: f64 = -1.0;
+
+/// Where the records go.
+pub trait RingStorage {
+    /// in slots
+    fn capacity(&self) -> usize;
+    fn write(&mut self, position: usize, values: &[f64]);
+    /// where the reader is
+    fn read_position(&self) -> usize;
+    /// let the reader see what's been written, up to the given position
+    fn publish(&mut self, write_position: usize);
+}
+
+/// A SharedArrayBuffer, laid out as above.
+pub struct SharedStorage {
+    positions: js_sys::Int32Array,
+    data: js_sys::Float64Array,
+}
+
+impl SharedStorage {
+    pub fn new(buffer: &js_sys::SharedArrayBuffer) -> Self {
+        SharedStorage {
+            positions: js_sys::Int32Array::new_with_byte_offset_and_length(buffer, 0, 2),
+            data: js_sys::Float64Array::new_with_byte_offset(buffer, HEADER_BYTES),
+        }
+    }
+}
+
+impl RingStorage for SharedStorage {
+    fn capacity(&self) -> usize {
+        self.data.length() as usize
+    }
+
+    fn write(&mut self, position: usize, values: &[f64]) {
+        self.data.subarray(position as u32, (position + values.len()) as u32).copy_from(values);
+    }
+
+    fn read_position(&self) -> usize {
+        let positions: &JsValue = &self.positions;
+        js_sys::Atomics::load(positions, READ_POSITION).unwrap_or(0).max(0) as usize
+    }
+
+    fn publish(&mut self, write_position: usize) {
+        let positions: &JsValue = &self.positions;
+        js_sys::Atomics::store(positions, WRITE_POSITION, write_position as i32).ok();
+    }
+}
+
+fn push_string(record: &mut Vec<f64>, string: &str) {
+    let start = record.len();
+    record.push(0.0);
+    record.extend(string.encode_utf16().map(|unit| unit as f64));
+    record[start] = (record.len() - start - 1) as f64;
+}
+
+/// An event as a record of the ring.
+pub fn encode(event: &BatchEvent) -> Vec<f64> {
+    let mut flags = 0;
+    if event.remote {
+        flags |= FLAG_REMOTE;
+    }
+    if event.expendable {
+        flags |= FLAG_EXPENDABLE;
+    }
+    if event.fit_key.is_some() {
+        flags |= FLAG_FIT_KEY;
+    }
+
+    let mut record = vec![
+        0.0,
+        event.timestamp,
+        event.bus as f64,
+        flags as f64,
+        event.fit_key.unwrap_or(0) as f64,
+        event.duration,
+    ];
+    push_string(&mut record, event.source_type);
+    push_string(&mut record, event.sample_id);
+
+    for values in [event.params, event.ramps].iter() {
+        let mut entries: Vec<(&String, &f32)> = values.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        record.push(entries.len() as f64);
+        for (name, value) in entries {
+            push_string(&mut record, name);
+            record.push(*value as f64);
+        }
+    }
+
+    record[0] = record.len() as f64;
+    record
+}
+
+/// The writing end of the ring.
+pub struct EventRing<S: RingStorage> {
+    storage: S,
+    /// where the next record goes, the reader only knows once it's published
+    position: usize,
+}
+
+impl<S: RingStorage> EventRing<S> {
+    /// a fresh ring, nothing's written yet and nothing's read
+    pub fn new(mut storage: S) -> Self {
+        storage.publish(0);
+        EventRing { storage, position: 0 }
+    }
+
+    /// let the reader see the events written so far
+    pub fn publish(&mut self) {
+        self.storage.publish(self.position);
+    }
+}
+
+impl<S: RingStorage> EventSink for EventRing<S> {
+    fn push(&mut self, event: &BatchEvent) -> bool {
+        let record = encode(event);
+        let capacity = self.storage.capacity();
+        let read = self.storage.read_position();
+
+        // where the record goes, if there's room for it
+        let start = if read <= self.position {
+            if self.position + record.len() < capacity {
+                Some(self.position)
+            } else if record.len() < read {
+                Some(0)
+            } else {
+                None
+            }
+        } else if self.position + record.len() < read {
+            Some(self.position)
+        } else {
+            None
+        };
+
+        match start {
+            Some(start) => {
+                if start != self.position {
+                    self.storage.write(self.position, &[WRAP]);
+                }
+                self.storage.write(start, &record);
+                self.position = start + record.len();
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use std::collections::HashMap;
+
+    struct VecStorage {
+        data: Vec<f64>,
+        read: usize,
+        published: usize,
+    }
+
+    impl RingStorage for VecStorage {
+        fn capacity(&self) -> usize {
+            self.data.len()
+        }
+
+        fn write(&mut self, position: usize, values: &[f64]) {
+            self.data[position..position + values.len()].copy_from_slice(values);
+        }
+
+        fn read_position(&self) -> usize {
+            self.read
+        }
+
+        fn publish(&mut self, write_position: usize) {
+            self.published = write_position;
+        }
+    }
+
+    fn event<'a>(sample_id: &'a str, params: &'a HashMap<String, f32>, ramps: &'a HashMap<String, f32>) -> BatchEvent<'a> {
+        BatchEvent {
+            timestamp: 1.5,
+            source_type: "Sampler",
+            sample_id,
+            bus: 2,
+            remote: false,
+            expendable: true,
+            fit_key: None,
+            params,
+            ramps,
+            duration: 0.25,
+        }
+    }
+
+    #[test]
+    fn test_record_layout() {
+        let params: HashMap<String, f32> = [("rate".to_string(), 2.0), ("lvl".to_string(), 0.5)].iter().cloned().collect();
+        let ramps = HashMap::new();
+        let record = encode(&event("bd", &params, &ramps));
+
+        assert_eq!(&record[..6], &[record.len() as f64, 1.5, 2.0, 2.0, 0.0, 0.25]);
+        let mut rest = vec![7.0];
+        rest.extend("Sampler".encode_utf16().map(|unit| unit as f64));
+        rest.extend_from_slice(&[2.0, 'b' as u32 as f64, 'd' as u32 as f64, 2.0]);
+        rest.extend_from_slice(&[3.0, 'l' as u32 as f64, 'v' as u32 as f64, 'l' as u32 as f64, 0.5]);
+        rest.extend_from_slice(&[4.0, 'r' as u32 as f64, 'a' as u32 as f64, 't' as u32 as f64, 'e' as u32 as f64, 2.0]);
+        rest.push(0.0);
+        assert_eq!(&record[6..], &rest[..]);
+    }
+
+    #[test]
+    fn test_ring_wraps() {
+        let params = HashMap::new();
+        let ramps = HashMap::new();
+        let len = encode(&event("bd", &params, &ramps)).len();
+        let mut ring = EventRing::new(VecStorage { data: vec![0.0; len * 3], read: 0, published: 7 });
+        assert_eq!(ring.storage.published, 0);
+
+        // two fit, the slot kept free leaves no room for a third
+        assert!(ring.push(&event("bd", &params, &ramps)));
+        assert!(ring.push(&event("sn", &params, &ramps)));
+        assert!(!ring.push(&event("hh", &params, &ramps)));
+        ring.publish();
+        assert_eq!(ring.storage.published, len * 2);
+
+        // once the first is read, the next goes to the start, the end is skipped
+        ring.storage.read = len;
+        assert!(!ring.push(&event("hh", &params, &ramps)));
+        ring.storage.read = len + 1;
+        assert!(ring.push(&event("hh", &params, &ramps)));
+        assert_eq!(ring.storage.data[len * 2], WRAP);
+        assert_eq!(ring.storage.data[0], len as f64);
+        assert_eq!(ring.position, len);
+
+        // and it doesn't catch up with the reader
+        assert!(!ring.push(&event("bd", &params, &ramps)));
+    }
+}
@@ -0,0 +1,651 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::DedicatedWorkerGlobalScope;
+use ruffbox_core::{Clock, EventSink};
+
+use crate::batch::BatchEvent;
+use crate::hook::{JsHook, TickHook, event_to_js};
+use crate::ring::{EventRing, SharedStorage};
+use crate::scheduler::{Message, Position, SchedulerCore};
+use crate::{js_object, post, parse_errors_to_js, ParseError};
+
+// WORKER
+// The scheduler (see scheduler.rs in ruffbox-core) as JS sees it. The state is
+// shared with the closure the worker's timer calls, so the scheduler wakes itself
+// up (see scheduler_routine) and the worker script doesn't need to provide anything
+// for that. The closure only holds on to the state weakly, a freed scheduler
+// doesn't wake up anymore.
+//
+// What the scheduler has to tell is posted to the main thread as { type, ... }
+// objects, the sound events as a batch (see batch.rs), unless they're written to
+// the event ring or handed to the event callbacks.
+//
+// Posting and the callbacks wait until the scheduler is let go of (see
+// with_core), so they can call it again, i.e. evaluate another pattern on a
+// bar. The tick hook and the clock function run while it's busy, calling it
+// from there throws an error.
+//
+// Each method that isn't about JS is passed on to the SchedulerCore, that's where
+// they're documented.
+
+type Core = SchedulerCore<JsSink, WorkerClock>;
+
+/// A simple time-recursion event scheduler.
+#[wasm_bindgen]
+pub struct Scheduler {
+    core: Rc<RefCell<Core>>,
+}
+
+#[wasm_bindgen]
+impl Scheduler {
+    pub fn new() -> Self {
+        let core = Rc::new(RefCell::new(Core::new(JsSink::new(), WorkerClock::new())));
+
+        let weak_core = Rc::downgrade(&core);
+        let wakeup = Closure::wrap(Box::new(move || {
+            if let Some(core) = weak_core.upgrade() {
+                with_core(&core, wake_up);
+            }
+        }) as Box<dyn FnMut()>);
+        core.borrow_mut().clock.wakeup = Some(wakeup);
+
+        Scheduler { core }
+    }
+
+    /// Send all messages (events, warnings, cues ...) to the given callback
+    /// instead of posting them, i.e. when running outside of a web worker.
+    pub fn set_sink(&self, sink: js_sys::Function) {
+        with_core(&self.core, |core| core.sink.function = Some(sink));
+    }
+
+    /// Let the given callback drive the scheduler instead of the worker's sleep.
+    /// It's called with the delay (in milliseconds) until the next scheduler_routine
+    /// call is due, which is up to the caller (asynchronously, as the scheduler is
+    /// still busy when the callback runs). That way, patterns can be run on any
+    /// clock, including a virtual one to render faster than realtime.
+    pub fn set_clock(&self, clock: js_sys::Function) {
+        with_core(&self.core, |core| core.clock.function = Some(clock));
+    }
+
+    /// Call the given function on every beat, with the position ({ bar, beat, step, tick },
+    /// all but the beat counted since the start, beat within the bar) and the audio
+    /// time the beat is played at, i.e. to pulse a display in time.
+    pub fn on_beat(&self, callback: js_sys::Function) {
+        with_core(&self.core, |core| core.sink.beat_callbacks.push(callback));
+    }
+
+    /// Call the given function on every bar, like on_beat.
+    pub fn on_bar(&self, callback: js_sys::Function) {
+        with_core(&self.core, |core| core.sink.bar_callbacks.push(callback));
+    }
+
+    /// Call the given function whenever a line starts another cycle (and on its first
+    /// step), with { line, id, cycle } (the line by index, as ordered in the input, and
+    /// by id, see sequence_ids) and the audio time the cycle starts at.
+    pub fn on_cycle(&self, callback: js_sys::Function) {
+        with_core(&self.core, |core| core.sink.cycle_callbacks.push(callback));
+    }
+
+    /// Hand the sound events of each step to the given function, one by one as plain
+    /// objects (as the tick hook gets them, see hook.rs), i.e. to play them on MIDI or
+    /// WebAudio nodes of your own. Once there's such a function, the events aren't
+    /// posted anymore (everything else still is).
+    pub fn on_event(&self, callback: js_sys::Function) {
+        with_core(&self.core, |core| core.sink.event_callbacks.push(callback));
+    }
+
+    /// Have the given function filter, change or add to the sound events of each
+    /// step before they go out (see hook.rs), none to remove it. It's called with
+    /// the events as plain objects and the step, and returns the events to play.
+    pub fn set_tick_hook(&self, hook: Option<js_sys::Function>) {
+        with_core(&self.core, |core| core.set_tick_hook(hook.map(|hook| Box::new(JsHook(hook)) as Box<dyn TickHook>)));
+    }
+
+    /// Write the sound events to the given SharedArrayBuffer (see ring.rs), for the
+    /// sampler to read them from there, instead of posting them. None goes back to
+    /// posting. Remote events, and the ones that don't fit, are still posted.
+    pub fn set_event_ring(&self, buffer: Option<js_sys::SharedArrayBuffer>) {
+        with_core(&self.core, |core| core.sink.event_ring = buffer.map(|buffer| EventRing::new(SharedStorage::new(&buffer))));
+    }
+
+    /// What's been played since the start: { events, steps, density, samples, lines },
+    /// the sound events and master steps in total, the events per step on average,
+    /// the events by sample (or synth) name, and by line, in input order, as
+    /// { id, label, events }. Lines that never played anything have zero events.
+    pub fn get_session_stats(&self) -> JsValue {
+        with_core(&self.core, |core| {
+            let stats = core.session_stats();
+            let samples: Vec<(&str, JsValue)> = stats.per_sample.iter().map(|(name, count)| (name.as_str(), (*count as u32).into())).collect();
+            let lines = js_sys::Array::new();
+            for seq in core.sequences().iter() {
+                lines.push(&js_object(&[
+                    ("id", seq.id.into()),
+                    ("label", seq.name.clone().map_or(JsValue::NULL, JsValue::from)),
+                    ("events", (stats.line_events(seq.id) as u32).into()),
+                ]));
+            }
+            js_object(&[
+                ("events", (stats.events as u32).into()),
+                ("steps", (stats.steps as u32).into()),
+                ("density", stats.density().into()),
+                ("samples", js_object(&samples)),
+                ("lines", lines.into()),
+            ])
+        })
+    }
+
+    /// Like the SchedulerCore's, the syntax errors are thrown as an array of
+    /// { line, column, message } objects.
+    pub fn queue(&self, input: String) -> Result<js_sys::Array, JsValue> {
+        evaluated(with_core(&self.core, |core| core.queue(input)))
+    }
+
+    /// Like the SchedulerCore's, the syntax errors are thrown as an array of
+    /// { line, column, message } objects.
+    pub fn evaluate(&self, input: Option<String>) -> Result<js_sys::Array, JsValue> {
+        evaluated(with_core(&self.core, |core| core.evaluate(input)))
+    }
+}
+
+/// Borrow the scheduler, and once it's let go of, hand JS what it had to tell.
+/// Calling in while it's busy (from the tick hook or the clock function) throws.
+fn with_core<T>(core: &RefCell<Core>, f: impl FnOnce(&mut Core) -> T) -> T {
+    let (result, pending) = match core.try_borrow_mut() {
+        Ok(mut core) => {
+            let result = f(&mut core);
+            (result, std::mem::take(&mut core.sink.pending))
+        },
+        Err(_) => wasm_bindgen::throw_str("the scheduler is busy, it can't be called from the tick hook or the clock function"),
+    };
+    for notify in pending {
+        notify();
+    }
+    result
+}
+
+/// The warnings of an evaluation, or its errors to be thrown, logged either way.
+fn evaluated(result: Result<Vec<String>, Vec<ParseError>>) -> Result<js_sys::Array, JsValue> {
+    match result {
+        Ok(warnings) => {
+            let array = js_sys::Array::new();
+            for warning in warnings.iter() {
+                log!("{}", warning);
+                array.push(&JsValue::from(warning));
+            }
+            Ok(array)
+        },
+        Err(errors) => {
+            for err in errors.iter() {
+                log!("{}", err);
+            }
+            Err(parse_errors_to_js(&errors))
+        },
+    }
+}
+
+/// The worker's own clock, the one its timer goes by.
+pub struct WorkerClock {
+    /// schedules the next scheduler_routine call instead of the worker's timer, if set
+    function: Option<js_sys::Function>,
+    /// wakes the scheduler up when the timer goes off, and the timer that's set, if any
+    wakeup: Option<Closure<dyn FnMut()>>,
+    timeout: Option<i32>,
+}
+
+impl WorkerClock {
+    fn new() -> Self {
+        WorkerClock { function: None, wakeup: None, timeout: None }
+    }
+}
+
+impl Clock for WorkerClock {
+    fn now(&self) -> f64 {
+        let scope: DedicatedWorkerGlobalScope = js_sys::global().unchecked_into();
+        scope.performance().map_or(0.0, |performance| performance.now())
+    }
+
+    fn wake_up_in(&mut self, delay: f64) {
+        if let Some(function) = &self.function {
+            if function.call1(&JsValue::NULL, &delay.into()).is_err() {
+                log!("clock failed!");
+            }
+            return;
+        }
+
+        // one timer at a time, a restart mustn't leave a second one running
+        let scope: DedicatedWorkerGlobalScope = js_sys::global().unchecked_into();
+        if let Some(timeout) = self.timeout.take() {
+            scope.clear_timeout_with_handle(timeout);
+        }
+        if let Some(wakeup) = &self.wakeup {
+            // timers are late anyway, but never early
+            match scope.set_timeout_with_callback_and_timeout_and_arguments_0(wakeup.as_ref().unchecked_ref(), delay.ceil() as i32) {
+                Ok(timeout) => self.timeout = Some(timeout),
+                Err(_) => log!("couldn't set the timer!"),
+            }
+        }
+    }
+}
+
+/// The worker's timer went off, it's time for the next scheduler_routine.
+fn wake_up(core: &mut Core) {
+    core.clock.timeout = None;
+    let now = core.clock.now();
+    core.scheduler_routine(now);
+}
+
+/// Where the scheduler's sound events and messages go in the browser.
+pub struct JsSink {
+    /// receives all messages instead of postMessage, if set
+    function: Option<js_sys::Function>,
+    /// called on every beat and every bar, with the position and its time
+    beat_callbacks: Vec<js_sys::Function>,
+    bar_callbacks: Vec<js_sys::Function>,
+    /// called whenever a line starts another cycle
+    cycle_callbacks: Vec<js_sys::Function>,
+    /// get the sound events instead of them being posted, if any
+    event_callbacks: Vec<js_sys::Function>,
+    /// the sound events are written to the sampler directly, if set (see ring.rs)
+    event_ring: Option<EventRing<SharedStorage>>,
+    /// posts and callbacks, made once the scheduler is let go of (see with_core)
+    pending: Vec<Box<dyn FnOnce()>>,
+}
+
+impl JsSink {
+    fn new() -> Self {
+        JsSink {
+            function: None,
+            beat_callbacks: Vec::new(),
+            bar_callbacks: Vec::new(),
+            cycle_callbacks: Vec::new(),
+            event_callbacks: Vec::new(),
+            event_ring: None,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Post a message, or hand it to the sink function, once the scheduler is let go of.
+    fn post_later(&mut self, message: JsValue) {
+        let function = self.function.clone();
+        self.pending.push(Box::new(move || post(&function, &message)));
+    }
+
+    /// Let the beat or bar callbacks know where a step is.
+    fn notify_position(&mut self, bar: bool, position: Position, timestamp: f64) {
+        let value = js_object(&[
+            ("bar", position.bar.into()),
+            ("beat", position.beat.into()),
+            ("step", position.step.into()),
+            ("tick", (position.tick as f64).into()),
+        ]);
+
+        let callbacks = if bar { self.bar_callbacks.clone() } else { self.beat_callbacks.clone() };
+        let function = self.function.clone();
+        self.pending.push(Box::new(move || {
+            let failed = callbacks.iter().any(|callback| callback.call2(&JsValue::NULL, &value, &timestamp.into()).is_err());
+            if failed {
+                post(&function, &message_to_js(Message::Warnings(vec!["position callback failed".to_string()])));
+            }
+        }));
+    }
+}
+
+impl EventSink for JsSink {
+    fn push(&mut self, event: &BatchEvent) -> bool {
+        // subscribers take the events instead of the sampler
+        if !self.event_callbacks.is_empty() {
+            let value = event_to_js(event);
+            let callbacks = self.event_callbacks.clone();
+            self.pending.push(Box::new(move || {
+                for callback in callbacks.iter() {
+                    if callback.call1(&JsValue::NULL, &value).is_err() {
+                        log!("event callback failed!");
+                    }
+                }
+            }));
+            return true;
+        }
+
+        // straight to the sampler, remote events go by the main thread
+        match &mut self.event_ring {
+            Some(ring) => !event.remote && ring.push(event),
+            None => false,
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Some(ring) = &mut self.event_ring {
+            ring.publish();
+        }
+    }
+
+    fn post(&mut self, message: Message) {
+        match message {
+            Message::Beat { position, timestamp } => self.notify_position(false, position, timestamp),
+            Message::Bar { position, timestamp } => self.notify_position(true, position, timestamp),
+            Message::Cycle { line, id, cycle, timestamp } => {
+                let value = js_object(&[
+                    ("line", (line as u32).into()),
+                    ("id", id.into()),
+                    ("cycle", (cycle as u32).into()),
+                ]);
+                let callbacks = self.cycle_callbacks.clone();
+                self.pending.push(Box::new(move || {
+                    for callback in callbacks.iter() {
+                        if callback.call2(&JsValue::NULL, &value, &timestamp.into()).is_err() {
+                            log!("cycle callback failed!");
+                        }
+                    }
+                }));
+            },
+            message => self.post_later(message_to_js(message)),
+        }
+    }
+}
+
+/// A message of the scheduler, as it's posted.
+fn message_to_js(message: Message) -> JsValue {
+    match message {
+        Message::Events(batch) => {
+            let strings = js_sys::Array::new();
+            for string in batch.strings() {
+                strings.push(&JsValue::from(string));
+            }
+            js_object(&[
+                ("type", "events".into()),
+                ("count", (batch.len() as u32).into()),
+                ("strings", strings.into()),
+                ("data", js_sys::Float64Array::from(batch.data()).into()),
+            ])
+        },
+        Message::Warnings(warnings) => {
+            let array = js_sys::Array::new();
+            for warning in warnings.iter() {
+                log!("{}", warning);
+                array.push(&JsValue::from(warning));
+            }
+            js_object(&[
+                ("type", "warnings".into()),
+                ("warnings", array.into()),
+            ])
+        },
+        Message::Errors(errors) => {
+            for err in errors.iter() {
+                log!("{}", err);
+            }
+            js_object(&[
+                ("type", "errors".into()),
+                ("errors", parse_errors_to_js(&errors)),
+            ])
+        },
+        Message::Timecode { timecode, timestamp } => js_object(&[
+            ("type", "timecode".into()),
+            ("timecode", timecode.to_string().into()),
+            ("hours", timecode.hours.into()),
+            ("minutes", timecode.minutes.into()),
+            ("seconds", timecode.seconds.into()),
+            ("frames", timecode.frames.into()),
+            ("timestamp", timestamp.into()),
+        ]),
+        Message::MasterParam { name, value } => js_object(&[
+            ("type", "master_param".into()),
+            ("name", name.into()),
+            ("value", value.into()),
+        ]),
+        Message::LoadSample { sample_id, url } => js_object(&[
+            ("type", "load_sample".into()),
+            ("sample_id", sample_id.into()),
+            ("url", url.into()),
+        ]),
+        Message::ResampleMaster { name, timestamp, duration } => js_object(&[
+            ("type", "resample_master".into()),
+            ("name", name.into()),
+            ("timestamp", timestamp.into()),
+            ("duration", duration.into()),
+        ]),
+        Message::TakeStart { name, bus, timestamp } => js_object(&[
+            ("type", "take_start".into()),
+            ("name", name.into()),
+            ("bus", bus.into()),
+            ("timestamp", timestamp.into()),
+        ]),
+        Message::TakeStop { name, timestamp } => js_object(&[
+            ("type", "take_stop".into()),
+            ("name", name.into()),
+            ("timestamp", timestamp.into()),
+        ]),
+        Message::Chop { line } => js_object(&[
+            ("type", "chop".into()),
+            ("line", line.into()),
+        ]),
+        Message::LoadInstrument { name, zones } => {
+            let array = js_sys::Array::new();
+            for (sample_id, region) in zones.into_iter() {
+                array.push(&js_object(&[
+                    ("sample_id", sample_id.into()),
+                    ("url", region.sample.into()),
+                    ("lokey", region.lokey.into()),
+                    ("hikey", region.hikey.into()),
+                    ("root", region.root.into()),
+                ]));
+            }
+            js_object(&[
+                ("type", "load_instrument".into()),
+                ("name", name.into()),
+                ("zones", array.into()),
+            ])
+        },
+        // the samples the presets play, and their zones, the presets are null
+        // if the file can't be read
+        Message::LoadSoundfont { name, font: None } => js_object(&[
+            ("type", "load_soundfont".into()),
+            ("name", name.into()),
+            ("presets", JsValue::NULL),
+        ]),
+        Message::LoadSoundfont { name, font: Some(font) } => {
+            let sample_id = |sample: usize| format!("{}.sample{}", name, sample);
+            let samples = js_sys::Array::new();
+            for idx in font.used_samples() {
+                let sample = &font.samples[idx];
+                samples.push(&js_object(&[
+                    ("sample_id", sample_id(idx).into()),
+                    ("data", js_sys::Float32Array::from(&sample.data[..]).into()),
+                    ("samplerate", sample.samplerate.into()),
+                ]));
+            }
+
+            let presets = js_sys::Array::new();
+            for preset in font.presets.iter() {
+                let zones = js_sys::Array::new();
+                for zone in preset.zones.iter() {
+                    let sample = &font.samples[zone.sample];
+                    // in frames of the sample, played with the loop flag
+                    let (loop_start, loop_end) = if zone.looping { (sample.loop_start, sample.loop_end) } else { (0, 0) };
+                    zones.push(&js_object(&[
+                        ("sample_id", sample_id(zone.sample).into()),
+                        ("lokey", zone.lokey.into()),
+                        ("hikey", zone.hikey.into()),
+                        ("lovel", zone.lovel.into()),
+                        ("hivel", zone.hivel.into()),
+                        ("root", zone.root.into()),
+                        ("tune", zone.tune.into()),
+                        ("samplerate", sample.samplerate.into()),
+                        ("attenuation", zone.attenuation.into()),
+                        ("loop_start", (loop_start as u32).into()),
+                        ("loop_end", (loop_end as u32).into()),
+                    ]));
+                }
+                presets.push(&js_object(&[
+                    ("name", format!("{}.{}", name, preset.name).into()),
+                    ("zones", zones.into()),
+                ]));
+            }
+
+            js_object(&[
+                ("type", "load_soundfont".into()),
+                ("name", name.as_str().into()),
+                ("samples", samples.into()),
+                ("presets", presets.into()),
+            ])
+        },
+        Message::InsertParam { bus, effect, param, value } => js_object(&[
+            ("type", "insert_param".into()),
+            ("bus", bus.into()),
+            ("effect", effect.into()),
+            ("param", param.into()),
+            ("value", value.into()),
+        ]),
+        Message::InsertChain { bus, effects, follow } => {
+            let array = js_sys::Array::new();
+            for effect in effects.iter() {
+                array.push(&JsValue::from(effect));
+            }
+            js_object(&[
+                ("type", "insert_chain".into()),
+                ("bus", bus.into()),
+                ("effects", array.into()),
+                ("follow", follow.into()),
+            ])
+        },
+        Message::Stutter { timestamp, length, repeats } => js_object(&[
+            ("type", "stutter".into()),
+            ("timestamp", timestamp.into()),
+            ("length", length.into()),
+            ("repeats", repeats.into()),
+        ]),
+        Message::Gate { bus, timestamp, length, level, shape } => js_object(&[
+            ("type", "gate".into()),
+            ("bus", bus.into()),
+            ("timestamp", timestamp.into()),
+            ("length", length.into()),
+            ("level", level.into()),
+            ("shape", shape.into()),
+        ]),
+        Message::LightCue { cue, timestamp } => js_object(&[
+            ("type", "light_cue".into()),
+            ("channel", cue.channel.into()),
+            ("value", cue.value.into()),
+            ("fade", cue.fade.into()),
+            ("timestamp", timestamp.into()),
+        ]),
+        Message::RunComplete { step, timestamp } => js_object(&[
+            ("type", "run_complete".into()),
+            ("step", (step as u32).into()),
+            ("timestamp", timestamp.into()),
+        ]),
+        Message::Prime { samples } => {
+            let array = js_sys::Array::new();
+            for sample in samples.iter() {
+                array.push(&sample.as_str().into());
+            }
+            js_object(&[
+                ("type", "prime".into()),
+                ("samples", array.into()),
+            ])
+        },
+        // the callbacks take these
+        Message::Beat { .. } | Message::Bar { .. } | Message::Cycle { .. } => JsValue::UNDEFINED,
+    }
+}
+
+macro_rules! pass_on {
+    ( $( fn $name:ident ( $( $arg:ident : $arg_type:ty ),* ) $( -> $ret:ty )? ; )* ) => {
+        #[wasm_bindgen]
+        impl Scheduler {
+            $(
+                pub fn $name(&self, $( $arg: $arg_type ),*) $( -> $ret )? {
+                    with_core(&self.core, |core| core.$name($( $arg ),*))
+                }
+            )*
+        }
+    }
+}
+
+pass_on! {
+    fn set_priming(enabled: bool);
+    fn set_mod_input(name: String, value: f32);
+    fn set_bus_level(bus: usize, level: f32);
+    fn map_controller(id: String, target: String, min: Option<f32>, max: Option<f32>);
+    fn set_controller_value(id: String, value: f32);
+    fn set_timecode(fps: f64);
+    fn lock();
+    fn unlock();
+    fn set_swing(amount: f64);
+    fn set_line_swing(label: String, amount: f64);
+    fn set_line_offset(label: String, ms: f64);
+    fn sequence_ids() -> Vec<u32>;
+    fn current_bar() -> u32;
+    fn current_beat() -> u32;
+    fn phase_in_step() -> f64;
+    fn current_step(seq_idx: usize) -> Option<u32>;
+    fn set_sequence_speed(idx: usize, speed: f64);
+    fn start_sequence(idx: usize, quantized: bool);
+    fn stop_sequence(idx: usize);
+    fn mute(idx: usize);
+    fn unmute(idx: usize);
+    fn solo(idx: usize);
+    fn clear_solo();
+    fn mute_group(tag: String, muted: bool);
+    fn set_group_gain(tag: String, gain: f32);
+    fn set_group_param(tag: String, param: String, value: f32);
+    fn save_snapshot(name: String);
+    fn morph_to(name: String, beats: f32);
+    fn stutter(fraction: f32, repeats: u32);
+    fn resample_master(name: String, bars: usize);
+    fn record_take(name: String, from_bar: usize, to_bar: usize, stem: Option<String>);
+    fn chop(sample: String, slices: usize, style: String, duration: f32);
+    fn load_instrument(name: String, sfz: String, url: String);
+    fn load_soundfont(name: String, url: String, data: Vec<u8>);
+    fn freeze(label: String, frozen: bool);
+    fn set_seed(seed: u64);
+    fn set_energy(energy: f32);
+    fn set_syntax(name: String);
+    fn register_voice(name: String);
+    fn register_stream(name: String);
+    fn register_sample(name: String);
+    fn unregister_sample(name: String);
+    fn set_event_order(order: String);
+    fn set_eval_quantization(quantization: String);
+    fn scheduler_routine(browser_timestamp: f64);
+    fn sync_audio_clock(audio_time: f64, browser_time: f64);
+    fn start(audio_timestamp: f64, browser_timestamp: f64);
+    fn stop();
+    fn pause();
+    fn resume(audio_timestamp: f64, browser_timestamp: f64);
+    fn run_for(bars: usize);
+    fn run_until(audio_time: f64);
+    fn panic();
+    fn set_tempo(tempo: f64);
+    fn set_bpm(bpm: f64);
+    fn get_bpm() -> f64;
+    fn set_subdivision(subdivision: u32);
+    fn set_ppqn(ppqn: u32);
+    fn set_beats_per_bar(beats_per_bar: u32);
+    fn set_schedule_interval(interval: f64);
+    fn set_lookahead(lookahead: f64);
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_callbacks_can_call_the_scheduler() {
+        let core = Rc::new(RefCell::new(Core::new(JsSink::new(), WorkerClock::new())));
+        let called = Rc::new(Cell::new(false));
+
+        let (callback_core, callback_called) = (core.clone(), called.clone());
+        with_core(&core, |core| core.sink.pending.push(Box::new(move || {
+            with_core(&callback_core, |core| core.set_bpm(90.0));
+            callback_called.set(true);
+        })));
+        assert!(called.get());
+        assert!(core.borrow().sink.pending.is_empty());
+        assert_eq!(with_core(&core, |core| core.get_bpm()), 90.0);
+    }
+}
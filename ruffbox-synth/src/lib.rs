@@ -13,10 +13,24 @@ pub extern "C" fn alloc(size: usize) -> *mut f32 {
     Box::into_raw(vec.into_boxed_slice()) as *mut f32
 }
 
+/// free memory from alloc, once the data has been handed over (i.e. loaded samples are copied)
+///
+/// # Safety
+/// the pointer and size have to be those of an alloc that isn't freed yet
+#[no_mangle]
+pub unsafe extern "C" fn dealloc(ptr: *mut f32, size: usize) {
+    drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(ptr, size)));
+}
+
 mod ruffbox;
 
+// for native hosts (see ruffbox-native), the wasm module uses the functions below
+pub use ruffbox::engine::Engine;
+pub use ruffbox::synth::{SourceType, SynthParameter};
+pub use ruffbox::export::resample;
+
 lazy_static! {
-    static ref RUFF: Mutex<ruffbox::Ruffbox> = Mutex::new(ruffbox::Ruffbox::new());
+    static ref RUFF: Mutex<ruffbox::engine::Engine> = Mutex::new(ruffbox::engine::Engine::new(44100.0, 44100.0));
 }
 
 #[no_mangle]
@@ -26,12 +40,171 @@ pub extern "C" fn process(out_ptr_l: *mut f32, out_ptr_r: *mut f32, size: usize,
     let out_buf_l: &mut [f32] = unsafe { std::slice::from_raw_parts_mut(out_ptr_l, size)};
     let out_buf_r: &mut [f32] = unsafe { std::slice::from_raw_parts_mut(out_ptr_r, size)};
 
-    // mono for now ... 
-    let out = ruff.process(stream_time);
-    for i in 0..128 {
-        out_buf_l[i] = out[0][i];
-        out_buf_r[i] = out[1][i];
-    }    
+    // any block size works, it's processed in blocks of 128 internally
+    ruff.render(stream_time, out_buf_l, out_buf_r);
+}
+
+/// the four channels of the ambisonic mode, AmbiX order (W Y Z X)
+///
+/// # Safety
+/// each pointer has to point to size floats (i.e. from alloc)
+#[no_mangle]
+pub unsafe extern "C" fn process_bformat(out_ptr_w: *mut f32, out_ptr_y: *mut f32, out_ptr_z: *mut f32, out_ptr_x: *mut f32, size: usize, stream_time: f64) {
+    let mut ruff = RUFF.lock();
+
+    let mut out_bufs: [&mut [f32]; 4] = [
+        std::slice::from_raw_parts_mut(out_ptr_w, size),
+        std::slice::from_raw_parts_mut(out_ptr_y, size),
+        std::slice::from_raw_parts_mut(out_ptr_z, size),
+        std::slice::from_raw_parts_mut(out_ptr_x, size),
+    ];
+
+    ruff.render_bformat(stream_time, &mut out_bufs);
+}
+
+/// beat repeat on the master, the last length seconds are looped repeats times
+#[no_mangle]
+pub extern "C" fn stutter(timestamp: f64, length: f32, repeats: usize) {
+    let mut ruff = RUFF.lock();
+    ruff.stutter(timestamp, length, repeats);
+}
+
+/// tempo-synced gain of a bus, one segment (a step or a beat) at a time
+#[no_mangle]
+pub extern "C" fn gate(bus: usize, timestamp: f64, length: f32, level: f32, shape: ruffbox::synth::gate::GateShape) {
+    let mut ruff = RUFF.lock();
+    ruff.gate(bus, timestamp, length, level, shape);
+}
+
+/// the time it took the host to call process, for load measurement,
+/// returns true if the engine has been overloaded for a while
+#[no_mangle]
+pub extern "C" fn report_dsp_time(seconds: f64) -> bool {
+    let mut ruff = RUFF.lock();
+    ruff.report_dsp_time(seconds)
+}
+
+/// capture the master output from the given time on, for the given duration,
+/// returns an id to store it as a sample with, once it's done
+#[no_mangle]
+pub extern "C" fn start_capture(start_time: f64, duration: f32) -> usize {
+    let mut ruff = RUFF.lock();
+    ruff.start_capture(start_time, duration)
+}
+
+/// id of a capture that's done, -1 if there's none
+#[no_mangle]
+pub extern "C" fn get_finished_capture() -> i32 {
+    let ruff = RUFF.lock();
+    ruff.finished_capture().map_or(-1, |id| id as i32)
+}
+
+/// store a finished capture as a new sample (sample_buf -1) or in place of
+/// an existing one, returns the buffer number, -1 if the capture isn't done
+#[no_mangle]
+pub extern "C" fn store_capture(id: usize, sample_buf: i32) -> i32 {
+    let mut ruff = RUFF.lock();
+    let replace = if sample_buf < 0 { None } else { Some(sample_buf as usize) };
+    ruff.store_capture(id, replace).map_or(-1, |buf| buf as i32)
+}
+
+/// record a take of the master (bus -1) or of a bus from the given time on,
+/// until it's stopped, returns its id
+#[no_mangle]
+pub extern "C" fn start_take(bus: i32, start_time: f64) -> usize {
+    let mut ruff = RUFF.lock();
+    let bus = if bus < 0 { None } else { Some(bus as usize) };
+    ruff.start_take(bus, start_time)
+}
+
+#[no_mangle]
+pub extern "C" fn stop_take(id: usize, end_time: f64) {
+    let mut ruff = RUFF.lock();
+    ruff.stop_take(id, end_time);
+}
+
+#[no_mangle]
+pub extern "C" fn discard_take(id: usize) {
+    let mut ruff = RUFF.lock();
+    ruff.discard_take(id);
+}
+
+/// write a take as a WAV file, like stop_recording, 0 if there's no such take,
+/// the file itself is at get_recording_ptr
+#[no_mangle]
+pub extern "C" fn export_take(id: usize, samplerate: u32, depth: ruffbox::export::BitDepth) -> usize {
+    let mut ruff = RUFF.lock();
+    ruff.export_take(id, samplerate, depth).map_or(0, |wav| wav.len())
+}
+
+/// loudness of the master over the last 3 seconds, in LUFS
+#[no_mangle]
+pub extern "C" fn get_loudness_short_term() -> f32 {
+    let ruff = RUFF.lock();
+    ruff.loudness().short_term()
+}
+
+/// gated loudness of the master since the last reset, in LUFS
+#[no_mangle]
+pub extern "C" fn get_loudness_integrated() -> f32 {
+    let ruff = RUFF.lock();
+    ruff.loudness().integrated()
+}
+
+/// highest true peak of the master since the last reset, in dBTP
+#[no_mangle]
+pub extern "C" fn get_true_peak() -> f32 {
+    let ruff = RUFF.lock();
+    ruff.loudness().true_peak()
+}
+
+#[no_mangle]
+pub extern "C" fn reset_loudness() {
+    let mut ruff = RUFF.lock();
+    ruff.reset_loudness();
+}
+
+/// record the stereo output, until stop_recording is called
+#[no_mangle]
+pub extern "C" fn start_recording() {
+    let mut ruff = RUFF.lock();
+    ruff.start_recording();
+}
+
+/// write the recording as a WAV file (samplerate 0 keeps the output samplerate),
+/// returns its size in bytes, 0 if nothing was recorded, the file itself is at get_recording_ptr
+#[no_mangle]
+pub extern "C" fn stop_recording(samplerate: u32, depth: ruffbox::export::BitDepth) -> usize {
+    let mut ruff = RUFF.lock();
+    ruff.stop_recording(samplerate, depth).map_or(0, |wav| wav.len())
+}
+
+/// the last recording, valid until the next one is stopped
+#[no_mangle]
+pub extern "C" fn get_recording_ptr() -> *const u8 {
+    let ruff = RUFF.lock();
+    ruff.recording_ptr()
+}
+
+/// stop the least important voices on sustained overload
+#[no_mangle]
+pub extern "C" fn set_voice_thinning(enabled: bool) {
+    let mut ruff = RUFF.lock();
+    ruff.set_voice_thinning(enabled);
+}
+
+/// the share of the available time spent processing
+#[no_mangle]
+pub extern "C" fn get_load() -> f32 {
+    let ruff = RUFF.lock();
+    ruff.load()
+}
+
+/// internal and output samplerate, the samples loaded are expected to be at the output samplerate
+#[no_mangle]
+pub extern "C" fn configure_engine(internal_sr: f32, output_sr: f32) {
+    let mut ruff = RUFF.lock();
+    ruff.configure(internal_sr, output_sr);
 }
 
 #[no_mangle]
@@ -46,12 +219,169 @@ pub extern "C" fn set_instance_parameter(instance_id: usize, par: ruffbox::synth
     ruff.set_instance_parameter(instance_id, par, val);
 }
 
+#[no_mangle]
+pub extern "C" fn set_instance_bus(instance_id: usize, bus: usize) {
+    let mut ruff = RUFF.lock();
+    ruff.set_instance_bus(instance_id, bus);
+}
+
+/// sweep a parameter over the duration of the voice
+#[no_mangle]
+pub extern "C" fn set_instance_ramp(instance_id: usize, par: ruffbox::synth::SynthParameter, from: f32, to: f32) {
+    let mut ruff = RUFF.lock();
+    ruff.set_instance_ramp(instance_id, par, from, to);
+}
+
+#[no_mangle]
+pub extern "C" fn set_instance_expendable(instance_id: usize) {
+    let mut ruff = RUFF.lock();
+    ruff.set_instance_expendable(instance_id);
+}
+
+#[no_mangle]
+pub extern "C" fn set_instance_cut_group(instance_id: usize, group: usize) {
+    let mut ruff = RUFF.lock();
+    ruff.set_instance_cut_group(instance_id, group);
+}
+
+#[no_mangle]
+pub extern "C" fn set_cut_fade(seconds: f32) {
+    let mut ruff = RUFF.lock();
+    ruff.set_cut_fade(seconds);
+}
+
+#[no_mangle]
+pub extern "C" fn set_cut_group_fade(group: usize, seconds: f32) {
+    let mut ruff = RUFF.lock();
+    ruff.set_cut_group_fade(group, seconds);
+}
+
+#[no_mangle]
+pub extern "C" fn set_instance_onset_slice(instance_id: usize, sample_buf: usize, onset: usize) {
+    let mut ruff = RUFF.lock();
+    ruff.set_instance_onset_slice(instance_id, sample_buf, onset);
+}
+
+#[no_mangle]
+pub extern "C" fn clear_insert_chain(bus: usize) {
+    let mut ruff = RUFF.lock();
+    ruff.clear_insert_chain(bus);
+}
+
+#[no_mangle]
+pub extern "C" fn follow_bus(bus: usize) {
+    let mut ruff = RUFF.lock();
+    ruff.follow_bus(bus);
+}
+
+#[no_mangle]
+pub extern "C" fn get_bus_level(bus: usize) -> f32 {
+    let ruff = RUFF.lock();
+    ruff.bus_level(bus)
+}
+
+#[no_mangle]
+pub extern "C" fn add_insert_effect(bus: usize, insert_type: ruffbox::synth::inserts::InsertType) {
+    let mut ruff = RUFF.lock();
+    ruff.add_insert_effect(bus, insert_type);
+}
+
+#[no_mangle]
+pub extern "C" fn set_insert_parameter(bus: usize, idx: usize, par: ruffbox::synth::SynthParameter, val: f32) {
+    let mut ruff = RUFF.lock();
+    ruff.set_insert_parameter(bus, idx, par, val);
+}
+
+#[no_mangle]
+pub extern "C" fn define_voice() -> usize {
+    let mut ruff = RUFF.lock();
+    ruff.define_voice()
+}
+
+/// inputs are node numbers, negative for no input,
+/// returns the node number, negative if the node couldn't be added
+#[no_mangle]
+pub extern "C" fn add_voice_node(voice: usize, node_type: ruffbox::synth::graph::NodeType, input_a: i32, input_b: i32, value: f32) -> i32 {
+    let mut ruff = RUFF.lock();
+    let input = |idx: i32| if idx < 0 { None } else { Some(idx as usize) };
+    ruff.add_voice_node(voice, node_type, input(input_a), input(input_b), value).map_or(-1, |idx| idx as i32)
+}
+
+/// returns the instrument number
+#[no_mangle]
+pub extern "C" fn define_instrument() -> usize {
+    let mut ruff = RUFF.lock();
+    ruff.define_instrument()
+}
+
+/// keys are midi notes
+#[no_mangle]
+pub extern "C" fn add_key_zone(instrument: usize, lokey: i32, hikey: i32, root: i32, sample_buf: usize) -> bool {
+    let mut ruff = RUFF.lock();
+    ruff.add_key_zone(instrument, lokey, hikey, root, sample_buf)
+}
+
+/// the buffer an instrument plays a frequency (in Hz) from, at a velocity (0 - 127),
+/// negative if it has no zones
+#[no_mangle]
+pub extern "C" fn get_key_zone_sample(instrument: usize, freq: f32, velocity: i32) -> i32 {
+    let ruff = RUFF.lock();
+    ruff.instrument_zone(instrument, freq, velocity).map_or(-1, |(sample_buf, _, _)| sample_buf as i32)
+}
+
+/// the playback rate an instrument plays a frequency (in Hz) with
+#[no_mangle]
+pub extern "C" fn get_key_zone_rate(instrument: usize, freq: f32, velocity: i32) -> f32 {
+    let ruff = RUFF.lock();
+    ruff.instrument_zone(instrument, freq, velocity).map_or(1.0, |(_, rate, _)| rate)
+}
+
+/// the gain of the zone an instrument plays a frequency (in Hz) with
+#[no_mangle]
+pub extern "C" fn get_key_zone_gain(instrument: usize, freq: f32, velocity: i32) -> f32 {
+    let ruff = RUFF.lock();
+    ruff.instrument_zone(instrument, freq, velocity).map_or(1.0, |(_, _, gain)| gain)
+}
+
+/// velocity range (0 - 127), tuning (cents, samplerate of the sample, 0 for the output samplerate)
+/// and attenuation (dB) of the zone added last to an instrument, false if it has none
+#[no_mangle]
+pub extern "C" fn tune_key_zone(instrument: usize, lovel: i32, hivel: i32, cents: i32, samplerate: f32, attenuation: f32) -> bool {
+    let mut ruff = RUFF.lock();
+    ruff.tune_key_zone(instrument, lovel, hivel, cents, samplerate, attenuation)
+}
+
 #[no_mangle]
 pub extern "C" fn set_master_parameter(par: ruffbox::synth::SynthParameter, val: f32) {
     let mut ruff = RUFF.lock();
     ruff.set_master_parameter(par, val);
 }
 
+#[no_mangle]
+pub extern "C" fn set_normalization(mode: ruffbox::NormalizationMode) {
+    let mut ruff = RUFF.lock();
+    ruff.set_normalization(mode);
+}
+
+#[no_mangle]
+pub extern "C" fn set_interpolation(interpolation: ruffbox::synth::sampler::Interpolation) {
+    let mut ruff = RUFF.lock();
+    ruff.set_interpolation(interpolation);
+}
+
+/// stereo, binaural or ambisonic, for the voices triggered from now on
+#[no_mangle]
+pub extern "C" fn set_spatial_mode(mode: ruffbox::SpatialMode) {
+    let mut ruff = RUFF.lock();
+    ruff.set_spatial_mode(mode);
+}
+
+#[no_mangle]
+pub extern "C" fn set_dc_blocker(enabled: bool) {
+    let mut ruff = RUFF.lock();
+    ruff.set_dc_blocker(enabled);
+}
+
 #[no_mangle]
 pub extern "C" fn trigger(instance_id: usize) {
     let mut ruff = RUFF.lock();
@@ -65,3 +395,138 @@ pub extern "C" fn load(sample_ptr: *mut f32, size: usize) -> usize {
     ruff.load_sample(in_buf)
 }
 
+/// new data for a loaded sample, voices already playing it keep the old data
+///
+/// # Safety
+/// the pointer has to point to size floats (i.e. from alloc)
+#[no_mangle]
+pub unsafe extern "C" fn replace(sample_buf: usize, sample_ptr: *mut f32, size: usize) -> usize {
+    let mut ruff = RUFF.lock();
+    let in_buf: &mut [f32] = std::slice::from_raw_parts_mut(sample_ptr, size);
+    ruff.replace_sample(sample_buf, in_buf)
+}
+
+
+/// where a sample loops and how long the crossfade is (in seconds),
+/// for events with looping on (PlaybackLoop)
+#[no_mangle]
+pub extern "C" fn set_sample_loop(sample_buf: usize, start: f32, end: f32, crossfade: f32) -> bool {
+    let mut ruff = RUFF.lock();
+    ruff.set_sample_loop(sample_buf, start, end, crossfade)
+}
+
+/// free a loaded sample, voices still playing it finish first
+#[no_mangle]
+pub extern "C" fn unload(sample_buf: usize) -> bool {
+    let mut ruff = RUFF.lock();
+    ruff.unload_sample(sample_buf)
+}
+
+/// add a long sample (length in frames) to be streamed in chunks, returns the stream number
+#[no_mangle]
+pub extern "C" fn create_stream(length: usize) -> usize {
+    let mut ruff = RUFF.lock();
+    ruff.create_stream(length)
+}
+
+/// the chunk of a stream that's needed the soonest, -1 if none is missing
+#[no_mangle]
+pub extern "C" fn get_stream_request(stream: usize) -> i32 {
+    let ruff = RUFF.lock();
+    ruff.next_stream_request(stream).map_or(-1, |chunk| chunk as i32)
+}
+
+/// hand over a chunk of a stream (the data is copied)
+///
+/// # Safety
+/// the pointer has to point to size floats (i.e. from alloc)
+#[no_mangle]
+pub unsafe extern "C" fn load_stream_chunk(stream: usize, chunk: usize, sample_ptr: *mut f32, size: usize) -> bool {
+    let mut ruff = RUFF.lock();
+    let in_buf: &mut [f32] = std::slice::from_raw_parts_mut(sample_ptr, size);
+    ruff.load_stream_chunk(stream, chunk, in_buf)
+}
+
+/// bytes of stream chunks in memory
+#[no_mangle]
+pub extern "C" fn get_stream_memory() -> usize {
+    let ruff = RUFF.lock();
+    ruff.stream_memory()
+}
+
+/// frames of all streams that were played as silence because they weren't there in time
+#[no_mangle]
+pub extern "C" fn get_stream_underruns() -> usize {
+    let ruff = RUFF.lock();
+    ruff.stream_underruns()
+}
+
+/// bytes of loaded sample data
+#[no_mangle]
+pub extern "C" fn get_sample_memory() -> usize {
+    let ruff = RUFF.lock();
+    ruff.sample_memory()
+}
+
+/// bytes of unloaded or replaced sample data that's still playing
+#[no_mangle]
+pub extern "C" fn get_voice_memory() -> usize {
+    let mut ruff = RUFF.lock();
+    ruff.voice_memory()
+}
+
+/// warm up a sample before it's first played, i.e. before the first downbeat
+#[no_mangle]
+pub extern "C" fn prime_sample(sample_buf: usize) -> bool {
+    let ruff = RUFF.lock();
+    ruff.prime_sample(sample_buf)
+}
+
+// sample info queries, these return zero if the sample doesn't exist
+
+#[no_mangle]
+pub extern "C" fn get_sample_duration(sample_buf: usize) -> f64 {
+    let ruff = RUFF.lock();
+    ruff.get_sample_info(sample_buf).map_or(0.0, |info| info.duration)
+}
+
+#[no_mangle]
+pub extern "C" fn get_sample_peak(sample_buf: usize) -> f32 {
+    let ruff = RUFF.lock();
+    ruff.get_sample_info(sample_buf).map_or(0.0, |info| info.peak)
+}
+
+#[no_mangle]
+pub extern "C" fn get_sample_rms(sample_buf: usize) -> f32 {
+    let ruff = RUFF.lock();
+    ruff.get_sample_info(sample_buf).map_or(0.0, |info| info.rms)
+}
+
+#[no_mangle]
+pub extern "C" fn get_sample_onset_count(sample_buf: usize) -> usize {
+    let ruff = RUFF.lock();
+    ruff.get_sample_info(sample_buf).map_or(0, |info| info.onsets.len())
+}
+
+/// fundamental frequency in Hz, zero if the sample has no clear pitch
+#[no_mangle]
+pub extern "C" fn get_sample_pitch(sample_buf: usize) -> f32 {
+    let ruff = RUFF.lock();
+    ruff.get_sample_info(sample_buf).and_then(|info| info.pitch).unwrap_or(0.0)
+}
+
+/// the playback rate that puts a sample into the key with the given root (midi note)
+#[no_mangle]
+pub extern "C" fn get_sample_key_fit_rate(sample_buf: usize, root: i32) -> f32 {
+    let ruff = RUFF.lock();
+    ruff.get_sample_info(sample_buf).map_or(1.0, |info| info.key_fit_rate(root))
+}
+
+/// onset position in seconds
+#[no_mangle]
+pub extern "C" fn get_sample_onset(sample_buf: usize, onset_idx: usize) -> f64 {
+    let ruff = RUFF.lock();
+    ruff.get_sample_info(sample_buf)
+        .and_then(|info| info.onset_time(onset_idx))
+        .unwrap_or(0.0)
+}
@@ -1,4 +1,10 @@
 pub mod synth;
+pub mod analysis;
+pub mod engine;
+pub mod instruments;
+pub mod export;
+pub mod loudness;
+pub mod streams;
 
 // crossbeam for the event queue
 use crossbeam::channel::Sender;
@@ -8,28 +14,96 @@ use crossbeam::atomic::AtomicCell;
 use std::collections::HashMap;
 
 use std::cmp::Ordering;
-use std::sync::Arc;
+use std::f32::consts::{FRAC_1_SQRT_2, SQRT_2};
+use std::sync::{Arc, Weak};
+
+use parking_lot::Mutex;
 
 use crate::ruffbox::synth::StereoSynth;
+use crate::ruffbox::synth::Effect;
 use crate::ruffbox::synth::SynthParameter;
 use crate::ruffbox::synth::SourceType;
 use crate::ruffbox::synth::freeverb::StereoFreeverb;
 use crate::ruffbox::synth::delay::StereoDelay;
+use crate::ruffbox::synth::stutter::StereoStutter;
+use crate::ruffbox::synth::gate::{GateShape, TempoGate};
+use crate::ruffbox::synth::filters::DcBlocker;
+use crate::ruffbox::synth::inserts::{InsertChain, InsertType};
+use crate::ruffbox::synth::graph::{GraphVoice, NodeType, VoiceDefinition};
 use crate::ruffbox::synth::synths::*;
+use crate::ruffbox::synth::sampler::{Interpolation, LoopPoints};
+use crate::ruffbox::synth::binaural::{BinauralPanner, HrirTable};
+use crate::ruffbox::synth::ambisonics::{self, AmbisonicEncoder};
+use crate::ruffbox::analysis::SampleInfo;
+use crate::ruffbox::streams::SampleStream;
+use crate::ruffbox::instruments::Instrument;
+use crate::ruffbox::loudness::LoudnessMeter;
 
 /// timed event, to be created in the trigger method, then 
 /// sent to the event queue to be either dispatched directly
 /// or pushed to the pending queue ...
 struct ScheduledEvent {
     timestamp: f64,
+    /// the instance id, voices starting at the same time start in the order they were prepared in
+    order: usize,
     source: Box<dyn StereoSynth + Send>,
+    /// the insert chain the output goes through, if there's one for it
+    bus: usize,
+    /// dropped first when voices have to be thinned out
+    expendable: bool,
+    /// the peak of the last block, to find the quietest voices
+    peak: f32,
+    /// takes over the panning in binaural mode
+    binaural: Option<BinauralPanner>,
+    /// takes over the panning in ambisonic mode, unless the voice has inserts
+    ambisonic: Option<AmbisonicEncoder>,
+    ramps: Vec<ParamRamp>,
+    /// seconds since the voice started, for the ramps
+    ramp_time: f32,
+    /// the dur parameter, if set
+    duration: Option<f32>,
+    /// attack, sustain and release as set
+    envelope_times: [f32; 3],
+    /// a new voice of the same cut group stops this one, i.e. an open hi-hat by a closed one
+    cut_group: Option<usize>,
+    /// fading out after being cut
+    cut_fade: Option<CutFade>,
+}
+
+/// the fade of a voice that's been cut, instead of stopping it dead (and clicking)
+#[derive(Clone, Copy)]
+struct CutFade {
+    /// sample of the current block the fade starts at
+    start: usize,
+    gain: f32,
+    /// gain lost per sample
+    step: f32,
+}
+
+/// a parameter moving from one value to another over the length of the voice,
+/// i.e. a sweep across the stereo field
+#[derive(Clone, Copy)]
+struct ParamRamp {
+    par: SynthParameter,
+    from: f32,
+    to: f32,
+}
+
+/// the sources' own panning is centered when they're spatialized,
+/// so both channels are the source at -3dB
+fn mono(block: &[[f32; 128]; 2]) -> [f32; 128] {
+    let mut mono = [0.0; 128];
+    for (s, (left, right)) in mono.iter_mut().zip(block[0].iter().zip(block[1].iter())) {
+        *s = (left + right) * 0.5 * SQRT_2;
+    }
+    mono
 }
 
 impl Ord for ScheduledEvent {
     /// ScheduledEvent implements Ord so the pending events queue
     /// can be ordered by the timestamps ...
     fn cmp(&self, other: &Self) -> Ordering {
-        self.timestamp.partial_cmp(&other.timestamp).unwrap()
+        self.timestamp.total_cmp(&other.timestamp).then(self.order.cmp(&other.order))
     }
 }
 
@@ -45,7 +119,7 @@ impl PartialEq for ScheduledEvent {
     /// ScheduledEvent implements PartialEq so the pending events queue
     /// can be ordered by the timestamps ...
     fn eq(&self, other: &Self) -> bool {
-        self.timestamp == other.timestamp
+        self.timestamp == other.timestamp && self.order == other.order
     }
 }
 
@@ -56,20 +130,181 @@ impl ScheduledEvent {
     pub fn new(ts: f64, src: Box<dyn StereoSynth + Send>) -> Self {
         ScheduledEvent {
             timestamp: ts,
+            order: 0,
             source: src,
+            bus: 0,
+            expendable: false,
+            peak: 0.0,
+            binaural: None,
+            ambisonic: None,
+            ramps: Vec::new(),
+            ramp_time: 0.0,
+            duration: None,
+            envelope_times: [0.0; 3],
+            cut_group: None,
+            cut_fade: None,
+        }
+    }
+
+    /// fade out over the given number of samples, from the given sample of the next block on
+    fn cut(&mut self, start: usize, fade_samples: usize) {
+        if self.cut_fade.is_none() {
+            self.cut_fade = Some(CutFade { start, gain: 1.0, step: 1.0 / fade_samples.max(1) as f32 });
         }
     }
 
+    /// whether the voice is done, by itself or by having been cut
+    fn is_finished(&self) -> bool {
+        self.source.is_finished() || self.cut_fade.is_some_and(|fade| fade.gain <= 0.0)
+    }
+
     pub fn set_parameter(&mut self, par: SynthParameter, value: f32) {
-        self.source.set_parameter(par, value);
+        // the length of the ramps
+        match par {
+            SynthParameter::Duration => self.duration = Some(value),
+            SynthParameter::Attack => self.envelope_times[0] = value,
+            SynthParameter::Sustain => self.envelope_times[1] = value,
+            SynthParameter::Release => self.envelope_times[2] = value,
+            _ => (),
+        };
+
+        match par {
+            // the source stays centered, the position is the spatializer's
+            SynthParameter::StereoPosition | SynthParameter::Azimuth | SynthParameter::Elevation
+                if self.binaural.is_some() || self.ambisonic.is_some() => {
+                if let Some(panner) = self.binaural.as_mut() {
+                    panner.set_parameter(par, value);
+                }
+                if let Some(encoder) = self.ambisonic.as_mut() {
+                    encoder.set_parameter(par, value);
+                }
+            },
+            _ => self.source.set_parameter(par, value),
+        }
+    }
+
+    /// insert chains (followed buses included) are stereo, so voices going
+    /// through one are panned by the source after all
+    fn leave_sound_field(&mut self) {
+        if let Some(encoder) = self.ambisonic.take() {
+            self.source.set_parameter(SynthParameter::StereoPosition, encoder.stereo_position());
+        }
+    }
+
+    /// start a ramp, the parameter is set to where it starts right away
+    fn add_ramp(&mut self, par: SynthParameter, from: f32, to: f32) {
+        self.set_parameter(par, from);
+        self.ramps.push(ParamRamp { par, from, to });
+    }
+
+    /// the dur parameter, or the envelope as far as it's set, or a second
+    fn ramp_length(&self) -> f32 {
+        let envelope: f32 = self.envelope_times.iter().sum();
+        match self.duration {
+            Some(duration) if duration > 0.0 => duration,
+            _ if envelope > 0.0 => envelope,
+            _ => 1.0,
+        }
+    }
+
+    /// ramped parameters are updated once per block
+    fn advance_ramps(&mut self, seconds: f32) {
+        let progress = (self.ramp_time / self.ramp_length()).min(1.0);
+        let ramps = std::mem::take(&mut self.ramps);
+        for ramp in ramps.iter() {
+            self.set_parameter(ramp.par, ramp.from + (ramp.to - ramp.from) * progress);
+        }
+        self.ramps = ramps;
+        self.ramp_time += seconds;
+    }
+
+    /// the next block of the source, rendered binaurally if needed
+    fn next_block(&mut self, start_sample: usize, sec_per_sample: f64) -> [[f32; 128]; 2] {
+        if !self.ramps.is_empty() {
+            self.advance_ramps(((128 - start_sample) as f64 * sec_per_sample) as f32);
+        }
+
+        let mut block = self.source.get_next_block(start_sample);
+        if let Some(fade) = self.cut_fade.as_mut() {
+            let [left, right] = &mut block;
+            for (l, r) in left[fade.start..].iter_mut().zip(right[fade.start..].iter_mut()) {
+                fade.gain = (fade.gain - fade.step).max(0.0);
+                *l *= fade.gain;
+                *r *= fade.gain;
+            }
+            fade.start = 0;
+        }
+
+        match self.binaural.as_mut() {
+            // a centered voice is at -3dB on both ears, like in stereo
+            Some(panner) => panner.process_block(mono(&block).map(|s| s * FRAC_1_SQRT_2)),
+            None => block,
+        }
+    }
+
+    fn update_peak(&mut self, block: &[[f32; 128]; 2]) {
+        self.peak = block[0].iter().chain(block[1].iter()).fold(0.0, |peak, s| peak.max(s.abs()));
+    }
+}
+
+/// how sample levels are evened out when a sample is triggered
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub enum NormalizationMode {
+    Off,
+    Peak,
+    Rms,
+}
+
+impl NormalizationMode {
+    const PEAK_TARGET: f32 = 1.0;
+    const RMS_TARGET: f32 = 0.2;
+    const MAX_TRIM: f32 = 16.0; // don't blow up (nearly) silent samples ...
+
+    /// the gain needed to bring a sample to the target level
+    pub fn trim_gain(&self, info: &SampleInfo) -> f32 {
+        let gain = match self {
+            NormalizationMode::Off => return 1.0,
+            NormalizationMode::Peak => NormalizationMode::PEAK_TARGET / info.peak,
+            // rms-normalized samples still shouldn't clip
+            NormalizationMode::Rms => (NormalizationMode::RMS_TARGET / info.rms).min(NormalizationMode::PEAK_TARGET / info.peak),
+        };
+
+        if gain.is_finite() {
+            gain.min(NormalizationMode::MAX_TRIM)
+        } else {
+            1.0
+        }
     }
 }
 
+/// how the voices are placed in the output
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum SpatialMode {
+    Stereo,
+    /// for headphones, voices are rendered through head-related impulse responses
+    Binaural,
+    /// first-order B-format, to be decoded to any speaker layout downstream
+    Ambisonic,
+}
+
 /// the main synth instance
 pub struct Ruffbox {
-    running_instances: Vec<Box<dyn StereoSynth + Send>>,
+    running_instances: Vec<ScheduledEvent>,
     pending_events: Vec<ScheduledEvent>,
     buffers: Vec<Arc<Vec<f32>>>,
+    buffer_info: Vec<SampleInfo>,
+    /// numbers of unloaded buffers, the next loads reuse them
+    free_buffers: Vec<usize>,
+    /// replaced or unloaded sample data, alive as long as voices still play it
+    retired_buffers: Vec<Weak<Vec<f32>>>,
+    /// where samples loop, if they've been given loop points
+    loop_points: HashMap<usize, LoopPoints>,
+    /// long samples, streamed in chunks
+    streams: Vec<Arc<Mutex<SampleStream>>>,
+    normalization: NormalizationMode,
+    interpolation: Interpolation,
     prepared_instance_map: HashMap<usize, ScheduledEvent>,
     instance_counter: AtomicCell<usize>,
     new_instances_q_send: crossbeam::channel::Sender<ScheduledEvent>,
@@ -79,10 +314,40 @@ pub struct Ruffbox {
     now: f64,
     master_reverb: StereoFreeverb,
     master_delay: StereoDelay,
+    master_stutter: StereoStutter,
+    /// start time, length in seconds and repeats of the next stutter
+    pending_stutter: Option<(f64, f32, usize)>,
+    insert_chains: HashMap<usize, InsertChain>,
+    /// tempo-synced gain of a bus, after its insert chain
+    gates: HashMap<usize, TempoGate>,
+    /// the last block each bus put out, for stems
+    bus_outputs: HashMap<usize, [[f32; 128]; 2]>,
+    voices: Vec<VoiceDefinition>,
+    instruments: Vec<Instrument>,
+    master_dc_blocker: [DcBlocker; 2],
+    dc_blocker_enabled: bool,
+    samplerate: f32,
+    buffer_samplerate: f32,
+    spatial_mode: SpatialMode,
+    hrirs: Option<Arc<HrirTable>>,
+    bformat: [[f32; 128]; 4],
+    /// how long (in seconds) a voice takes to fade out when it's cut,
+    /// unless its cut group has a fade time of its own
+    cut_fade_time: f32,
+    cut_group_fade_times: HashMap<usize, f32>,
+    /// of the master output
+    loudness: LoudnessMeter,
 }
 
 impl Ruffbox {
+    // the engine decides on the samplerate, this is the default it uses
     pub fn new() -> Ruffbox {
+        Ruffbox::with_samplerate(44100.0)
+    }
+
+    /// the samplerate everything is processed at, loaded samples are assumed
+    /// to be at the output samplerate (see set_buffer_samplerate)
+    pub fn with_samplerate(sr: f32) -> Ruffbox {
         let (tx, rx): (Sender<ScheduledEvent>, Receiver<ScheduledEvent>) = crossbeam::channel::bounded(1000);
 
         // tweak some reverb values ... 
@@ -91,22 +356,46 @@ impl Ruffbox {
         rev.set_damp(0.43);
         rev.set_wet(1.0);
 
-        let del = StereoDelay::with_max_capacity_sec(2.0, 44100.0);
+        let del = StereoDelay::with_max_capacity_sec(2.0, sr);
         
         Ruffbox {            
             running_instances: Vec::with_capacity(600),
             pending_events: Vec::with_capacity(600),
             buffers: Vec::with_capacity(20),
+            buffer_info: Vec::with_capacity(20),
+            free_buffers: Vec::new(),
+            retired_buffers: Vec::new(),
+            loop_points: HashMap::new(),
+            streams: Vec::new(),
+            normalization: NormalizationMode::Off,
+            interpolation: Interpolation::Cubic,
             prepared_instance_map: HashMap::with_capacity(600),
             instance_counter: AtomicCell::new(0),
             new_instances_q_send: tx,
             new_instances_q_rec: rx,
             // timing stuff
-            block_duration: 128.0 / 44100.0,
-            sec_per_sample: 1.0 / 44100.0,
+            block_duration: 128.0 / sr as f64,
+            sec_per_sample: 1.0 / sr as f64,
             now: 0.0,
             master_reverb: rev,
             master_delay: del,
+            master_stutter: StereoStutter::with_max_capacity_sec(4.0, sr),
+            pending_stutter: None,
+            insert_chains: HashMap::new(),
+            gates: HashMap::new(),
+            bus_outputs: HashMap::new(),
+            voices: Vec::new(),
+            instruments: Vec::new(),
+            master_dc_blocker: [DcBlocker::new(sr), DcBlocker::new(sr)],
+            samplerate: sr,
+            buffer_samplerate: sr,
+            dc_blocker_enabled: true,
+            spatial_mode: SpatialMode::Stereo,
+            hrirs: None,
+            bformat: [[0.0; 128]; 4],
+            cut_fade_time: 0.005,
+            cut_group_fade_times: HashMap::new(),
+            loudness: LoudnessMeter::new(sr),
         }
     }
            
@@ -117,16 +406,24 @@ impl Ruffbox {
         let mut master_reverb_in: [f32; 128] = [0.0; 128];
 
         self.now = stream_time;
+        self.bformat = [[0.0; 128]; 4];
+
+        for stream in self.streams.iter() {
+            stream.lock().set_time(stream_time);
+        }
         
         // remove finished instances ...
-        self.running_instances.retain( |instance| !&instance.is_finished());
+        self.running_instances.retain( |instance| !instance.is_finished());
 
         // add new instances
-        for new_event in self.new_instances_q_rec.try_iter() {
+        let new_events: Vec<ScheduledEvent> = self.new_instances_q_rec.try_iter().collect();
+        for new_event in new_events {
             if new_event.timestamp == 0.0 || new_event.timestamp == self.now {
-                self.running_instances.push(new_event.source);                
+                self.cut_voices(new_event.cut_group, 0);
+                self.running_instances.push(new_event);                
             } else if new_event.timestamp < self.now { // late events 
-                self.running_instances.push(new_event.source);
+                self.cut_voices(new_event.cut_group, 0);
+                self.running_instances.push(new_event);
                 // how to send out a late message ??
                 // some lock-free message queue to a printer thread or something .... 
                 // println!("late");
@@ -135,22 +432,45 @@ impl Ruffbox {
             }            
         }
 
+        // voices starting in this block cut the running ones of their group
+        let block_end = stream_time + self.block_duration;
+        let cuts: Vec<(Option<usize>, usize)> = self.pending_events.iter()
+            .filter(|event| event.cut_group.is_some() && event.timestamp < block_end)
+            .map(|event| (event.cut_group, ((event.timestamp - stream_time) / self.sec_per_sample).max(0.0).round() as usize))
+            .collect();
+        for (cut_group, offset) in cuts {
+            self.cut_voices(cut_group, offset);
+        }
+
         // handle already running instances
         for running_inst in self.running_instances.iter_mut() {
-            let block = running_inst.get_next_block(0);
-            for s in 0..128 {
-                out_buf[0][s] += block[0][s];
-                out_buf[1][s] += block[1][s];
+            let block = running_inst.next_block(0, self.sec_per_sample);
+            running_inst.update_peak(&block);
+
+            // sends are taken before the insert chain
+            let dry_out = match self.insert_chains.get_mut(&running_inst.bus) {
+                Some(chain) => &mut chain.input,
+                None => &mut out_buf,
+            };
 
-                master_reverb_in[s] += (block[0][s] + block[1][s]) * running_inst.reverb_level();
-                master_delay_in[0][s] += block[0][s] * running_inst.delay_level();
-                master_delay_in[1][s] += block[1][s] * running_inst.delay_level();
+            if let Some(encoder) = running_inst.ambisonic.as_mut() {
+                encoder.process_block(mono(&block), &mut self.bformat);
+            }
+            
+            for s in 0..128 {
+                if running_inst.ambisonic.is_none() {
+                    dry_out[0][s] += block[0][s];
+                    dry_out[1][s] += block[1][s];
+                }
+
+                master_reverb_in[s] += (block[0][s] + block[1][s]) * running_inst.source.reverb_level();
+                master_delay_in[0][s] += block[0][s] * running_inst.source.delay_level();
+                master_delay_in[1][s] += block[1][s] * running_inst.source.delay_level();
             }
         }
         
-        // sort new events by timestamp, order of already sorted elements doesn't matter
+        // sort new events by timestamp (and the order they were prepared in, for the same timestamp)
         self.pending_events.sort_unstable_by(|a, b| b.cmp(a));
-        let block_end = stream_time + self.block_duration;
         
         // fetch event if it belongs to this block, if any ...
         while !self.pending_events.is_empty() && self.pending_events.last().unwrap().timestamp < block_end {
@@ -160,11 +480,23 @@ impl Ruffbox {
             // calculate precise timing
             let sample_offset = (current_event.timestamp - stream_time) / self.sec_per_sample;           
 
-            let block = current_event.source.get_next_block(sample_offset.round() as usize);
+            let block = current_event.next_block(sample_offset.round() as usize, self.sec_per_sample);
+            current_event.update_peak(&block);
 
+            let dry_out = match self.insert_chains.get_mut(&current_event.bus) {
+                Some(chain) => &mut chain.input,
+                None => &mut out_buf,
+            };
+
+            if let Some(encoder) = current_event.ambisonic.as_mut() {
+                encoder.process_block(mono(&block), &mut self.bformat);
+            }
+            
             for s in 0..128 {
-                out_buf[0][s] += block[0][s];
-                out_buf[1][s] += block[1][s];
+                if current_event.ambisonic.is_none() {
+                    dry_out[0][s] += block[0][s];
+                    dry_out[1][s] += block[1][s];
+                }
                 
                 master_reverb_in[s] += (block[0][s] + block[1][s]) * current_event.source.reverb_level();
                 master_delay_in[0][s] += block[0][s] * current_event.source.delay_level();
@@ -173,8 +505,20 @@ impl Ruffbox {
             
             // if length of sample event is longer than the rest of the block,
             // add to running instances
-            if !current_event.source.is_finished() {
-                self.running_instances.push(current_event.source);
+            if !current_event.is_finished() {
+                self.running_instances.push(current_event);
+            }
+        }
+
+        for (bus, chain) in self.insert_chains.iter_mut() {
+            let mut chain_out = chain.process();
+            if let Some(gate) = self.gates.get_mut(bus) {
+                chain_out = gate.process(chain_out, stream_time, self.sec_per_sample);
+            }
+            self.bus_outputs.insert(*bus, chain_out);
+            for s in 0..128 {
+                out_buf[0][s] += chain_out[0][s];
+                out_buf[1][s] += chain_out[1][s];
             }
         }
 
@@ -185,6 +529,30 @@ impl Ruffbox {
             out_buf[0][s] += reverb_out[0][s] + delay_out[0][s];
             out_buf[1][s] += reverb_out[1][s] + delay_out[1][s];
         }
+
+        // the stereo parts join the voices in the sound field, and the
+        // stereo output becomes a version to listen to
+        if self.spatial_mode == SpatialMode::Ambisonic {
+            ambisonics::encode_stereo(&out_buf, &mut self.bformat);
+            out_buf = ambisonics::decode_stereo(&self.bformat);
+        }
+
+        // fetch the stutter if it starts in this block
+        if let Some((timestamp, length, repeats)) = self.pending_stutter {
+            if timestamp < block_end {
+                let offset = ((timestamp - stream_time) / self.sec_per_sample).max(0.0).round() as usize;
+                self.master_stutter.trigger(offset, (length * self.samplerate) as usize, repeats);
+                self.pending_stutter = None;
+            }
+        }
+        out_buf = self.master_stutter.process(out_buf);
+
+        if self.dc_blocker_enabled {
+            out_buf[0] = self.master_dc_blocker[0].process_block(out_buf[0], 0);
+            out_buf[1] = self.master_dc_blocker[1].process_block(out_buf[1], 0);
+        }
+
+        self.loudness.process(&out_buf);
                               
         out_buf
     }
@@ -193,21 +561,204 @@ impl Ruffbox {
     pub fn prepare_instance(&mut self, src_type: SourceType, timestamp: f64, sample_buf: usize) -> usize {
         let instance_id = self.instance_counter.fetch_add(1);
 
-        let scheduled_event = match src_type {
-            SourceType::SineOsc => ScheduledEvent::new(timestamp, Box::new(SineSynth::new(44100.0))),
-            SourceType::SineSynth => ScheduledEvent::new(timestamp, Box::new(SineSynth::new(44100.0))),
-            SourceType::Sampler => ScheduledEvent::new(timestamp, Box::new(StereoSampler::with_buffer_ref(&self.buffers[sample_buf], 44100.0))),
-            SourceType::LFSawSynth => ScheduledEvent::new(timestamp, Box::new(LFSawSynth::new(44100.0))),
-            SourceType::LFSquareSynth => ScheduledEvent::new(timestamp, Box::new(LFSquareSynth::new(44100.0))),
+        let mut scheduled_event = match src_type {
+            SourceType::SineOsc => ScheduledEvent::new(timestamp, Box::new(SineSynth::new(self.samplerate))),
+            SourceType::SineSynth => ScheduledEvent::new(timestamp, Box::new(SineSynth::new(self.samplerate))),
+            SourceType::Sampler => {
+                let mut sampler = StereoSampler::with_buffer_ref(&self.buffers[sample_buf], self.samplerate);
+                if self.buffer_samplerate != self.samplerate {
+                    sampler.set_buffer_samplerate(self.buffer_samplerate);
+                }
+                sampler.set_trim(self.normalization.trim_gain(&self.buffer_info[sample_buf]));
+                // can still be overridden per event
+                sampler.set_interpolation(self.interpolation);
+                if let Some(points) = self.loop_points.get(&sample_buf) {
+                    sampler.set_loop_points(*points);
+                }
+                ScheduledEvent::new(timestamp, Box::new(sampler))
+            },
+            SourceType::LFSawSynth => ScheduledEvent::new(timestamp, Box::new(LFSawSynth::new(self.samplerate))),
+            SourceType::LFSquareSynth => ScheduledEvent::new(timestamp, Box::new(LFSquareSynth::new(self.samplerate))),
+            // for custom voices, the buffer number is the voice number
+            SourceType::CustomVoice => match self.voices.get(sample_buf) {
+                Some(voice) => ScheduledEvent::new(timestamp, Box::new(GraphVoice::new(voice, self.samplerate))),
+                // nothing to play, the instance is never prepared
+                None => return instance_id,
+            },
+            // for streamers, it's the stream number
+            SourceType::Streamer => {
+                let mut streamer = match self.streams.get(sample_buf) {
+                    Some(stream) => StereoStreamer::with_stream(stream, self.samplerate),
+                    None => return instance_id,
+                };
+                if self.buffer_samplerate != self.samplerate {
+                    streamer.set_buffer_samplerate(self.buffer_samplerate);
+                }
+                ScheduledEvent::new(timestamp, Box::new(streamer))
+            },
         };
 
+        scheduled_event.order = instance_id;
+        if let Some(hrirs) = self.hrirs.as_ref() {
+            scheduled_event.binaural = Some(BinauralPanner::new(hrirs));
+        }
+        if self.spatial_mode == SpatialMode::Ambisonic {
+            scheduled_event.ambisonic = Some(AmbisonicEncoder::new());
+        }
+
         self.prepared_instance_map.insert(instance_id, scheduled_event);
         
         instance_id
     }
 
     pub fn set_instance_parameter(&mut self, instance_id: usize, par: SynthParameter, val: f32) {
-        self.prepared_instance_map.get_mut(&instance_id).unwrap().set_parameter(par, val);
+        if let Some(event) = self.prepared_instance_map.get_mut(&instance_id) {
+            event.set_parameter(par, val);
+        }
+    }
+
+    /// route a prepared instance through the insert chain of the given bus
+    pub fn set_instance_bus(&mut self, instance_id: usize, bus: usize) {
+        if let Some(event) = self.prepared_instance_map.get_mut(&instance_id) {
+            event.bus = bus;
+            if self.insert_chains.contains_key(&bus) {
+                event.leave_sound_field();
+            }
+        }
+    }
+
+    /// move a parameter of a prepared instance from one value to another over the
+    /// duration of the voice (the dur parameter, or its envelope)
+    pub fn set_instance_ramp(&mut self, instance_id: usize, par: SynthParameter, from: f32, to: f32) {
+        if let Some(event) = self.prepared_instance_map.get_mut(&instance_id) {
+            event.add_ramp(par, from, to);
+        }
+    }
+
+    /// put a prepared instance into a cut group, once it starts, it stops
+    /// (fades out) the other voices of the group
+    pub fn set_instance_cut_group(&mut self, instance_id: usize, group: usize) {
+        if let Some(event) = self.prepared_instance_map.get_mut(&instance_id) {
+            event.cut_group = Some(group);
+        }
+    }
+
+    /// how long voices take to fade out when they're cut, in seconds
+    pub fn set_cut_fade(&mut self, seconds: f32) {
+        self.cut_fade_time = seconds.max(0.0);
+    }
+
+    /// the fade time of a cut group, instead of the global one
+    pub fn set_cut_group_fade(&mut self, group: usize, seconds: f32) {
+        self.cut_group_fade_times.insert(group, seconds.max(0.0));
+    }
+
+    /// fade out the running voices of a cut group, from the given sample of the block on
+    fn cut_voices(&mut self, cut_group: Option<usize>, offset: usize) {
+        let group = match cut_group {
+            Some(group) => group,
+            None => return,
+        };
+
+        let fade_time = self.cut_group_fade_times.get(&group).cloned().unwrap_or(self.cut_fade_time);
+        let fade_samples = (fade_time * self.samplerate).round() as usize;
+        for instance in self.running_instances.iter_mut().filter(|instance| instance.cut_group == Some(group)) {
+            instance.cut(offset, fade_samples);
+        }
+    }
+
+    /// mark a prepared instance as the first to go when voices are thinned out
+    pub fn set_instance_expendable(&mut self, instance_id: usize) {
+        if let Some(event) = self.prepared_instance_map.get_mut(&instance_id) {
+            event.expendable = true;
+        }
+    }
+
+    /// have a prepared sampler instance play the slice of its sample between the given
+    /// onset and the next one (or the end), parameters set later still override this
+    pub fn set_instance_onset_slice(&mut self, instance_id: usize, sample_buf: usize, onset: usize) {
+        let info = match self.buffer_info.get(sample_buf) {
+            Some(info) if onset < info.onsets.len() => info,
+            _ => return,
+        };
+
+        let start = info.onsets[onset];
+        let end = info.onsets.get(onset + 1).cloned().unwrap_or(info.length);
+        let event = match self.prepared_instance_map.get_mut(&instance_id) {
+            Some(event) => event,
+            None => return,
+        };
+        event.set_parameter(SynthParameter::PlaybackStart, start as f32 / info.length as f32);
+        event.set_parameter(SynthParameter::Sustain, (end - start) as f32 / self.buffer_samplerate);
+    }
+
+    /// the number of voices currently playing
+    pub fn running_voices(&self) -> usize {
+        self.running_instances.len()
+    }
+
+    /// Stop up to the given number of running voices, expendable ones first,
+    /// then the quietest, then the oldest. Returns how many were stopped.
+    pub fn thin_voices(&mut self, count: usize) -> usize {
+        let count = count.min(self.running_instances.len());
+
+        self.running_instances.sort_by(|a, b| {
+            b.expendable.cmp(&a.expendable)
+                .then(a.peak.partial_cmp(&b.peak).unwrap_or(Ordering::Equal))
+                .then(a.timestamp.partial_cmp(&b.timestamp).unwrap_or(Ordering::Equal))
+        });
+        self.running_instances.drain(..count);
+
+        count
+    }
+
+    /// remove all effects from a bus, instances on it play directly to the master again
+    pub fn clear_insert_chain(&mut self, bus: usize) {
+        self.insert_chains.remove(&bus);
+        self.bus_outputs.remove(&bus);
+    }
+
+    /// append an effect to the insert chain of a bus
+    pub fn add_insert_effect(&mut self, bus: usize, insert_type: InsertType) {
+        let sr = self.samplerate;
+        self.insert_chains.entry(bus).or_insert_with(|| InsertChain::new(sr)).add(insert_type);
+    }
+
+    /// make sure a bus exists even without effects, so its level can be followed
+    pub fn follow_bus(&mut self, bus: usize) {
+        let sr = self.samplerate;
+        self.insert_chains.entry(bus).or_insert_with(|| InsertChain::new(sr));
+    }
+
+    /// open or close a bus for a while (or duck it, depending on the shape),
+    /// the bus exists from now on, even without effects
+    pub fn gate(&mut self, bus: usize, timestamp: f64, length: f32, level: f32, shape: GateShape) {
+        let sr = self.samplerate;
+        self.insert_chains.entry(bus).or_insert_with(|| InsertChain::new(sr));
+        self.gates.entry(bus).or_insert_with(|| TempoGate::new(sr)).schedule(timestamp, length as f64, level, shape);
+    }
+
+    /// the last block a bus put out, after its insert chain and gate,
+    /// none if there's no such bus
+    pub fn bus_output(&self, bus: usize) -> Option<&[[f32; 128]; 2]> {
+        self.bus_outputs.get(&bus)
+    }
+
+    /// the envelope of a bus' output, 0 if there's no such bus
+    pub fn bus_level(&self, bus: usize) -> f32 {
+        self.insert_chains.get(&bus).map_or(0.0, |chain| chain.level())
+    }
+
+    /// set a parameter of the effect at the given position of a bus' insert chain
+    pub fn set_insert_parameter(&mut self, bus: usize, idx: usize, par: SynthParameter, val: f32) {
+        if let Some(chain) = self.insert_chains.get_mut(&bus) {
+            chain.set_parameter(idx, par, val);
+        }
+    }
+
+    /// loop the last length seconds of the output, repeats times, from the given time on
+    pub fn stutter(&mut self, timestamp: f64, length: f32, repeats: usize) {
+        self.pending_stutter = Some((timestamp, length, repeats));
     }
 
     pub fn set_master_parameter(&mut self, par: SynthParameter, val: f32) {
@@ -217,16 +768,241 @@ impl Ruffbox {
     
     /// triggers a synth for buffer reference or a synth
     pub fn trigger(&mut self, instance_id: usize) {
-        // add check if it actually exists !
-        let scheduled_event = self.prepared_instance_map.remove(&instance_id).unwrap();        
-        self.new_instances_q_send.send(scheduled_event).unwrap();
+        // instances that couldn't be prepared are ignored
+        if let Some(mut scheduled_event) = self.prepared_instance_map.remove(&instance_id) {
+            scheduled_event.source.prefetch(scheduled_event.timestamp);
+            self.new_instances_q_send.send(scheduled_event).unwrap();
+        }
+    }
+
+    /// start a new custom voice, returns the voice number
+    pub fn define_voice(&mut self) -> usize {
+        self.voices.push(VoiceDefinition::new());
+        self.voices.len() - 1
+    }
+
+    /// add a node to a custom voice, returns the node number
+    pub fn add_voice_node(&mut self, voice: usize, node_type: NodeType, input_a: Option<usize>, input_b: Option<usize>, value: f32) -> Result<usize, String> {
+        match self.voices.get_mut(voice) {
+            Some(definition) => definition.add_node(node_type, input_a, input_b, value),
+            None => Err(format!("voice {} doesn't exist", voice)),
+        }
+    }
+
+    /// start a new multi-sampled instrument, returns the instrument number
+    pub fn define_instrument(&mut self) -> usize {
+        self.instruments.push(Instrument::new());
+        self.instruments.len() - 1
+    }
+
+    /// map a range of keys (midi notes) to a loaded sample recorded at the root key,
+    /// returns false if there's no such instrument or sample
+    pub fn add_key_zone(&mut self, instrument: usize, lokey: i32, hikey: i32, root: i32, sample_buf: usize) -> bool {
+        if sample_buf >= self.buffers.len() {
+            return false;
+        }
+        match self.instruments.get_mut(instrument) {
+            Some(instrument) => {
+                instrument.add_zone(lokey, hikey, root, sample_buf);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// the buffer number, playback rate and gain an instrument plays a frequency
+    /// with, at a velocity (0 - 127)
+    pub fn instrument_zone(&self, instrument: usize, freq: f32, velocity: i32) -> Option<(usize, f32, f32)> {
+        let (zone, rate) = self.instruments.get(instrument)?.zone(freq, velocity)?;
+        Some((zone.sample_buf, rate, zone.gain))
+    }
+
+    /// Velocity range, tuning (in cents) and attenuation (in dB) of the zone added
+    /// last to an instrument, i.e. for SF2 zones. Samples recorded at another
+    /// samplerate (0 for the one of the loaded samples) are played at their pitch.
+    pub fn tune_key_zone(&mut self, instrument: usize, lovel: i32, hivel: i32, cents: i32, samplerate: f32, attenuation: f32) -> bool {
+        let buffer_samplerate = self.buffer_samplerate;
+        match self.instruments.get_mut(instrument).and_then(|instrument| instrument.last_zone_mut()) {
+            Some(zone) => {
+                zone.lovel = lovel.min(hivel);
+                zone.hivel = lovel.max(hivel);
+                zone.rate = 2.0_f32.powf(cents as f32 / 1200.0);
+                if samplerate > 0.0 {
+                    zone.rate *= samplerate / buffer_samplerate;
+                }
+                zone.gain = 10.0_f32.powf(-attenuation.max(0.0) / 20.0);
+                true
+            },
+            None => false,
+        }
     }
 
     /// loads a sample and returns the assigned buffer number
     pub fn load_sample(&mut self, samples:&[f32]) -> usize {
+        if let Some(sample_buf) = self.free_buffers.pop() {
+            return self.replace_sample(sample_buf, samples);
+        }
+        
+        // first point and last two points are padding for interpolation
+        let content = if samples.len() > 3 { &samples[1..samples.len() - 2] } else { &[] };
+        self.buffer_info.push(SampleInfo::from_samples(content, self.buffer_samplerate));
         self.buffers.push(Arc::new(samples.to_vec()));
         self.buffers.len() - 1
-    }     
+    }
+
+    /// Put new sample data behind an existing buffer number, voices that are already
+    /// playing keep the old data until they end. Loads it as a new sample if the buffer
+    /// doesn't exist. Returns the buffer number.
+    pub fn replace_sample(&mut self, sample_buf: usize, samples:&[f32]) -> usize {
+        if sample_buf >= self.buffers.len() {
+            return self.load_sample(samples);
+        }
+
+        let content = if samples.len() > 3 { &samples[1..samples.len() - 2] } else { &[] };
+        self.buffer_info[sample_buf] = SampleInfo::from_samples(content, self.buffer_samplerate);
+        // they were for the old sample
+        self.loop_points.remove(&sample_buf);
+        // the running samplers hold on to the old buffer, it's dropped with the last of them
+        let old = std::mem::replace(&mut self.buffers[sample_buf], Arc::new(samples.to_vec()));
+        self.retired_buffers.push(Arc::downgrade(&old));
+        sample_buf
+    }
+
+    /// Free a loaded sample, voices that are still playing it finish first.
+    /// The buffer number stays valid (it plays silence) until it's reused by
+    /// the next load. Returns false if there's no such sample.
+    pub fn unload_sample(&mut self, sample_buf: usize) -> bool {
+        if sample_buf >= self.buffers.len() || self.free_buffers.contains(&sample_buf) {
+            return false;
+        }
+
+        // just the padding for interpolation, plus one sample of silence
+        self.replace_sample(sample_buf, &[0.0; 4]);
+        self.free_buffers.push(sample_buf);
+        true
+    }
+
+    /// Set where a sample loops (in seconds), for events that play it with looping on,
+    /// and how long the crossfade from the loop end into what's before the loop start
+    /// is. Returns false if there's no such sample.
+    pub fn set_sample_loop(&mut self, sample_buf: usize, start: f32, end: f32, crossfade: f32) -> bool {
+        let length = match self.buffer_info.get(sample_buf) {
+            Some(info) => info.length,
+            None => return false,
+        };
+
+        let frames = |seconds: f32| ((seconds.max(0.0) * self.buffer_samplerate) as usize).min(length);
+        self.loop_points.insert(sample_buf, LoopPoints::new(frames(start), frames(end), frames(crossfade)));
+        true
+    }
+
+    /// Add a long sample (of the given length in frames) to be streamed in chunks,
+    /// returns the stream number. The chunks it needs are asked for with
+    /// next_stream_request and handed over with load_stream_chunk.
+    pub fn create_stream(&mut self, length: usize) -> usize {
+        self.streams.push(Arc::new(Mutex::new(SampleStream::new(length))));
+        self.streams.len() - 1
+    }
+
+    /// the chunk of a stream that's needed the soonest, if there's one missing
+    pub fn next_stream_request(&self, stream: usize) -> Option<usize> {
+        self.streams.get(stream)?.lock().next_request()
+    }
+
+    /// hand over a chunk of a stream, returns false if there's no such chunk
+    pub fn load_stream_chunk(&mut self, stream: usize, chunk: usize, samples: &[f32]) -> bool {
+        match self.streams.get(stream) {
+            Some(stream) => stream.lock().load_chunk(chunk, samples),
+            None => false,
+        }
+    }
+
+    /// bytes of stream chunks in memory
+    pub fn stream_memory(&self) -> usize {
+        self.streams.iter().map(|stream| stream.lock().memory()).sum()
+    }
+
+    /// frames of all streams that were played as silence
+    pub fn stream_underruns(&self) -> usize {
+        self.streams.iter().map(|stream| stream.lock().underruns()).sum()
+    }
+
+    /// bytes of sample data that's loaded
+    pub fn sample_memory(&self) -> usize {
+        self.buffers.iter().map(|buf| buf.len() * std::mem::size_of::<f32>()).sum()
+    }
+
+    /// bytes of replaced or unloaded sample data that voices are still playing
+    pub fn voice_memory(&mut self) -> usize {
+        self.retired_buffers.retain(|buf| buf.strong_count() > 0);
+        self.retired_buffers.iter()
+            .filter_map(|buf| buf.upgrade())
+            .map(|buf| buf.len() * std::mem::size_of::<f32>())
+            .sum()
+    }
+
+    /// information about a loaded sample, if it exists
+    pub fn get_sample_info(&self, sample_buf: usize) -> Option<&SampleInfo> {
+        self.buffer_info.get(sample_buf)
+    }
+
+    /// Read through a sample once, so its memory is warm before it's first played.
+    /// Returns false if there's no such sample.
+    pub fn prime_sample(&self, sample_buf: usize) -> bool {
+        match self.buffers.get(sample_buf) {
+            Some(buf) => {
+                // a read per cache line is enough
+                std::hint::black_box(buf.iter().step_by(16).sum::<f32>());
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// set how sample levels are trimmed at trigger time
+    pub fn set_normalization(&mut self, mode: NormalizationMode) {
+        self.normalization = mode;
+    }
+
+    /// the default interpolation of repitched samples
+    pub fn set_interpolation(&mut self, interpolation: Interpolation) {
+        self.interpolation = interpolation;
+    }
+
+    /// the samplerate of loaded samples, if it isn't the processing samplerate,
+    /// they're played back at the rate needed to keep their pitch
+    pub fn set_buffer_samplerate(&mut self, sr: f32) {
+        self.buffer_samplerate = sr;
+    }
+
+    /// stereo by default, applies to the voices triggered from now on
+    pub fn set_spatial_mode(&mut self, mode: SpatialMode) {
+        self.spatial_mode = mode;
+        self.hrirs = match mode {
+            SpatialMode::Binaural => Some(self.hrirs.take().unwrap_or_else(|| Arc::new(HrirTable::new(self.samplerate)))),
+            _ => None,
+        };
+    }
+
+    /// the first-order B-format (AmbiX, W Y Z X) of the last processed block,
+    /// silent unless in ambisonic mode
+    pub fn bformat(&self) -> &[[f32; 128]; 4] {
+        &self.bformat
+    }
+
+    /// loudness and true peak of the master output
+    pub fn loudness(&self) -> &LoudnessMeter {
+        &self.loudness
+    }
+
+    pub fn reset_loudness(&mut self) {
+        self.loudness.reset();
+    }
+
+    /// the master output is DC-blocked by default
+    pub fn set_dc_blocker(&mut self, enabled: bool) {
+        self.dc_blocker_enabled = enabled;
+    }
 }
 
 
@@ -236,10 +1012,12 @@ mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
     use std::f32::consts::PI;
+    use crate::ruffbox::streams::CHUNK_FRAMES;
     
     #[test]
     fn test_sine_synth_at_block_start() {
         let mut ruff = Ruffbox::new();
+        ruff.set_dc_blocker(false);
 
         let inst = ruff.prepare_instance(SourceType::SineSynth, 0.0, 0);
         ruff.set_instance_parameter(inst, SynthParameter::PitchFrequency, 440.0);
@@ -268,6 +1046,7 @@ mod tests {
     fn test_basic_playback() {
         
         let mut ruff = Ruffbox::new();
+        ruff.set_dc_blocker(false);
 
         // first point and last two points are for eventual interpolation
         let sample1 = [0.0, 0.0, 0.1, 0.2, 0.3, 0.4, 0.3, 0.2, 0.1, 0.0, 0.0, 0.0];
@@ -300,6 +1079,7 @@ mod tests {
     fn reverb_smoke_test() {
         
         let mut ruff = Ruffbox::new();
+        ruff.set_dc_blocker(false);
 
         // first point and last two points are for eventual interpolation
         let sample1 = [0.0, 0.0, 0.1, 0.2, 0.3, 0.4, 0.3, 0.2, 0.1, 0.0, 0.0, 0.0];
@@ -324,9 +1104,36 @@ mod tests {
         }        
     }
 
+    #[test]
+    fn test_sample_normalization() {
+        let mut ruff = Ruffbox::new();
+
+        let sample1 = [0.0, 0.0, 0.1, 0.2, 0.3, 0.4, 0.3, 0.2, 0.1, 0.0, 0.0, 0.0];
+        let sample2 = [0.0, 0.0, 0.01, 0.02, 0.03, 0.04, 0.03, 0.02, 0.01, 0.0, 0.0, 0.0];
+
+        let bnum1 = ruff.load_sample(&sample1);
+        let bnum2 = ruff.load_sample(&sample2);
+
+        let trim = |ruff: &Ruffbox, bnum: usize| ruff.normalization.trim_gain(&ruff.buffer_info[bnum]);
+        
+        // untouched by default
+        assert_approx_eq::assert_approx_eq!(trim(&ruff, bnum1), 1.0, 0.00001);
+        assert_approx_eq::assert_approx_eq!(trim(&ruff, bnum2), 1.0, 0.00001);
+
+        ruff.set_normalization(NormalizationMode::Peak);
+        assert_approx_eq::assert_approx_eq!(trim(&ruff, bnum1) * 0.4, 1.0, 0.00001);
+        // quiet sample would need 25x gain, which is capped
+        assert_approx_eq::assert_approx_eq!(trim(&ruff, bnum2), 16.0, 0.00001);
+
+        // both samples have the same shape, so they should end up at the same level
+        ruff.set_normalization(NormalizationMode::Rms);
+        assert_approx_eq::assert_approx_eq!(trim(&ruff, bnum1) * 0.1, trim(&ruff, bnum2) * 0.01, 0.00001);
+    }
+
     #[test]
     fn test_scheduled_playback() {
         let mut ruff = Ruffbox::new();
+        ruff.set_dc_blocker(false);
 
         // block duration in seconds
         let block_duration = 0.00290249433;
@@ -365,6 +1172,7 @@ mod tests {
     #[test]
     fn test_overlap_playback() {
         let mut ruff = Ruffbox::new();
+        ruff.set_dc_blocker(false);
 
         // block duration in seconds
         let block_duration = 0.00290249433;
@@ -413,6 +1221,7 @@ mod tests {
     #[test]
     fn test_disjunct_playback() {
         let mut ruff = Ruffbox::new();
+        ruff.set_dc_blocker(false);
 
         // block duration in seconds
         let block_duration = 0.00290249433;
@@ -467,10 +1276,74 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_insert_chain_playback() {
+        let mut ruff = Ruffbox::new();
+        ruff.set_dc_blocker(false);
+
+        ruff.add_insert_effect(1, InsertType::BitCrusher);
+        ruff.set_insert_parameter(1, 0, SynthParameter::BitDepth, 2.0);
+
+        let inst = ruff.prepare_instance(SourceType::SineSynth, 0.0, 0);
+        ruff.set_instance_parameter(inst, SynthParameter::PitchFrequency, 440.0);
+        ruff.set_instance_parameter(inst, SynthParameter::StereoPosition, -1.0);
+        ruff.set_instance_parameter(inst, SynthParameter::Level, 1.0);
+        ruff.set_instance_parameter(inst, SynthParameter::Attack, 0.0);
+        ruff.set_instance_parameter(inst, SynthParameter::Sustain, 1.0);
+        ruff.set_instance_parameter(inst, SynthParameter::Release, 0.0);
+        ruff.set_instance_bus(inst, 1);
+        ruff.trigger(inst);
+
+        let out_1 = ruff.process(0.0);
+
+        // two bits only leave -1, -0.5, 0, 0.5 and 1
+        for (i, out) in out_1[0].iter().enumerate() {
+            let comp = (2.0 * PI * 440.0 * (i as f32 * (1.0 / 44100.0))).sin();
+            assert_approx_eq::assert_approx_eq!(*out, (comp * 2.0).round() / 2.0, 0.00001);
+        }
+
+        ruff.clear_insert_chain(1);
+        assert!(ruff.insert_chains.is_empty());
+    }
+
+    #[test]
+    fn test_custom_voice_playback() {
+        let mut ruff = Ruffbox::new();
+        ruff.set_dc_blocker(false);
+
+        let voice = ruff.define_voice();
+        let osc = ruff.add_voice_node(voice, NodeType::Sine, None, None, 1.0).unwrap();
+        assert!(ruff.add_voice_node(voice, NodeType::Lowpass, Some(osc + 1), None, 1000.0).is_err());
+        assert!(ruff.add_voice_node(voice + 1, NodeType::Sine, None, None, 1.0).is_err());
+
+        let inst = ruff.prepare_instance(SourceType::CustomVoice, 0.0, voice);
+        ruff.set_instance_parameter(inst, SynthParameter::PitchFrequency, 440.0);
+        ruff.set_instance_parameter(inst, SynthParameter::StereoPosition, -1.0);
+        ruff.set_instance_parameter(inst, SynthParameter::Level, 1.0);
+        ruff.set_instance_parameter(inst, SynthParameter::Attack, 0.0);
+        ruff.set_instance_parameter(inst, SynthParameter::Sustain, 1.0);
+        ruff.set_instance_parameter(inst, SynthParameter::Release, 0.0);
+        ruff.trigger(inst);
+
+        let out_1 = ruff.process(0.0);
+
+        for (i, out) in out_1[0].iter().enumerate() {
+            let comp = (2.0 * PI * 440.0 * (i as f32 * (1.0 / 44100.0))).sin();
+            assert_approx_eq::assert_approx_eq!(*out, comp, 0.00001);
+        }
+
+        // unknown voices don't play
+        let inst = ruff.prepare_instance(SourceType::CustomVoice, 0.0, voice + 1);
+        ruff.set_instance_parameter(inst, SynthParameter::Level, 1.0);
+        ruff.trigger(inst);
+        assert_eq!(ruff.running_voices(), 1);
+    }
+
     #[test]
     fn test_late_playback() {
         
         let mut ruff = Ruffbox::new();
+        ruff.set_dc_blocker(false);
 
         let sample1 = [0.0, 0.0, 0.1, 0.2, 0.3, 0.4, 0.3, 0.2, 0.1, 0.0, 0.0, 0.0];
                 
@@ -492,4 +1365,373 @@ mod tests {
             assert_approx_eq::assert_approx_eq!(out_buf[0][i], sample1[i + 1], 0.00001);
         }        
     }
+
+    #[test]
+    fn test_thin_voices() {
+        let mut ruff = Ruffbox::new();
+        ruff.set_dc_blocker(false);
+
+        let mut play = |freq: f32, level: f32, expendable: bool| {
+            let inst = ruff.prepare_instance(SourceType::SineSynth, 0.0, 0);
+            ruff.set_instance_parameter(inst, SynthParameter::PitchFrequency, freq);
+            ruff.set_instance_parameter(inst, SynthParameter::StereoPosition, -1.0);
+            ruff.set_instance_parameter(inst, SynthParameter::Level, level);
+            ruff.set_instance_parameter(inst, SynthParameter::Attack, 0.0);
+            ruff.set_instance_parameter(inst, SynthParameter::Sustain, 1.0);
+            ruff.set_instance_parameter(inst, SynthParameter::Release, 0.0);
+            if expendable {
+                ruff.set_instance_expendable(inst);
+            }
+            ruff.trigger(inst);
+        };
+
+        play(440.0, 1.0, false);
+        play(550.0, 0.1, false);
+        play(660.0, 1.0, true);
+
+        ruff.process(0.0);
+        assert_eq!(ruff.running_voices(), 3);
+
+        // the expendable one goes first, loud as it is, then the quiet one
+        assert_eq!(ruff.thin_voices(2), 2);
+        assert_eq!(ruff.running_voices(), 1);
+
+        let out = ruff.process(128.0 / 44100.0);
+        let count = 128.0;
+        for (i, sample) in out[0].iter().enumerate() {
+            let comp = (2.0 * PI * 440.0 * ((i as f32 + count) / 44100.0)).sin();
+            assert_approx_eq::assert_approx_eq!(*sample, comp, 0.0001);
+        }
+
+        assert_eq!(ruff.thin_voices(5), 1);
+        assert_eq!(ruff.running_voices(), 0);
+    }
+
+    #[test]
+    fn test_cut_group_fade() {
+        let mut ruff = Ruffbox::new();
+        ruff.set_dc_blocker(false);
+
+        fn play(ruff: &mut Ruffbox, timestamp: f64, level: f32, group: usize) {
+            let inst = ruff.prepare_instance(SourceType::SineSynth, timestamp, 0);
+            ruff.set_instance_parameter(inst, SynthParameter::PitchFrequency, 440.0);
+            ruff.set_instance_parameter(inst, SynthParameter::StereoPosition, -1.0);
+            ruff.set_instance_parameter(inst, SynthParameter::Level, level);
+            ruff.set_instance_parameter(inst, SynthParameter::Attack, 0.0);
+            ruff.set_instance_parameter(inst, SynthParameter::Sustain, 1.0);
+            ruff.set_instance_parameter(inst, SynthParameter::Release, 0.0);
+            ruff.set_instance_cut_group(inst, group);
+            ruff.trigger(inst);
+        }
+
+        // the second (silent) voice cuts the first halfway through the second block
+        play(&mut ruff, 0.0, 1.0, 1);
+        play(&mut ruff, 192.0 / 44100.0, 0.0, 1);
+        ruff.process(0.0);
+
+        // 5ms by default
+        let fade_samples = (0.005_f32 * 44100.0).round();
+        let out = ruff.process(128.0 / 44100.0);
+        for (i, sample) in out[0].iter().enumerate() {
+            let comp = (2.0 * PI * 440.0 * ((i as f32 + 128.0) / 44100.0)).sin();
+            let gain = if i < 64 { 1.0 } else { 1.0 - (i - 63) as f32 / fade_samples };
+            assert_approx_eq::assert_approx_eq!(*sample, comp * gain, 0.0001);
+        }
+
+        // faded out after a couple of blocks, and gone
+        ruff.process(256.0 / 44100.0);
+        ruff.process(384.0 / 44100.0);
+        ruff.process(512.0 / 44100.0);
+        assert_eq!(ruff.running_voices(), 1);
+
+        // groups can cut right away, without a fade
+        ruff.set_cut_group_fade(2, 0.0);
+        play(&mut ruff, 640.0 / 44100.0, 1.0, 2);
+        play(&mut ruff, 832.0 / 44100.0, 0.0, 2);
+        ruff.process(640.0 / 44100.0);
+        let out = ruff.process(768.0 / 44100.0);
+        assert!(out[0][64..].iter().all(|sample| *sample == 0.0));
+        assert!(out[0][..64].iter().any(|sample| *sample != 0.0));
+    }
+
+    #[test]
+    fn test_binaural_playback() {
+        let mut ruff = Ruffbox::new();
+        ruff.set_dc_blocker(false);
+        ruff.set_spatial_mode(SpatialMode::Binaural);
+
+        let inst = ruff.prepare_instance(SourceType::SineSynth, 0.0, 0);
+        ruff.set_instance_parameter(inst, SynthParameter::PitchFrequency, 440.0);
+        ruff.set_instance_parameter(inst, SynthParameter::StereoPosition, 1.0);
+        ruff.set_instance_parameter(inst, SynthParameter::Level, 1.0);
+        ruff.set_instance_parameter(inst, SynthParameter::Attack, 0.0);
+        ruff.set_instance_parameter(inst, SynthParameter::Sustain, 1.0);
+        ruff.set_instance_parameter(inst, SynthParameter::Release, 0.0);
+        ruff.trigger(inst);
+
+        ruff.process(0.0);
+        let out = ruff.process(128.0 / 44100.0);
+
+        // the left ear still hears it, but less
+        let energy = |channel: &[f32; 128]| channel.iter().map(|s| s * s).sum::<f32>();
+        assert!(energy(&out[0]) > 0.0);
+        assert!(energy(&out[1]) > energy(&out[0]));
+    }
+
+    #[test]
+    fn test_position_ramp() {
+        let mut ruff = Ruffbox::new();
+        ruff.set_dc_blocker(false);
+
+        let inst = ruff.prepare_instance(SourceType::SineSynth, 0.0, 0);
+        ruff.set_instance_parameter(inst, SynthParameter::PitchFrequency, 440.0);
+        ruff.set_instance_parameter(inst, SynthParameter::Level, 1.0);
+        ruff.set_instance_parameter(inst, SynthParameter::Attack, 0.0);
+        ruff.set_instance_parameter(inst, SynthParameter::Sustain, 0.1);
+        ruff.set_instance_parameter(inst, SynthParameter::Release, 0.0);
+        ruff.set_instance_parameter(inst, SynthParameter::Duration, 128.0 * 10.0 / 44100.0);
+        ruff.set_instance_ramp(inst, SynthParameter::StereoPosition, -1.0, 1.0);
+        ruff.trigger(inst);
+
+        let balance = |out: [[f32; 128]; 2]| {
+            let left: f32 = out[0].iter().map(|s| s.abs()).sum();
+            let right: f32 = out[1].iter().map(|s| s.abs()).sum();
+            (right - left) / (right + left)
+        };
+
+        // from the left over ten blocks to the right, and staying there
+        let mut balances = Vec::new();
+        for b in 0..12 {
+            balances.push(balance(ruff.process(b as f64 * 128.0 / 44100.0)));
+        }
+        assert!(balances[0] < -0.99);
+        assert!(balances[5].abs() < 0.01);
+        assert!(balances[10] > 0.99);
+        assert!(balances[11] > 0.99);
+        assert!(balances.windows(2).all(|w| w[1] >= w[0]));
+    }
+
+    #[test]
+    fn test_onset_slice() {
+        let mut ruff = Ruffbox::new();
+        ruff.set_dc_blocker(false);
+
+        // two bursts, at 0.1 and 0.5 seconds, plus padding
+        let mut sample = vec![0.0; 44100 + 3];
+        for start in [4410, 22050].iter() {
+            for i in 0..4000 {
+                sample[start + i + 1] = (i as f32 * 0.3).sin() * (1.0 - i as f32 / 4000.0);
+            }
+        }
+        let bnum = ruff.load_sample(&sample);
+        assert_eq!(ruff.get_sample_info(bnum).unwrap().onsets.len(), 2);
+
+        let inst = ruff.prepare_instance(SourceType::Sampler, 0.0, bnum);
+        ruff.set_instance_onset_slice(inst, bnum, 0);
+        ruff.set_instance_parameter(inst, SynthParameter::Attack, 0.0);
+        ruff.set_instance_parameter(inst, SynthParameter::Release, 0.0);
+        ruff.trigger(inst);
+
+        // the first burst right away, instead of the silence before it
+        let out = ruff.process(0.0);
+        assert!(out[0].iter().any(|s| s.abs() > 0.1));
+
+        // and it stops where the second burst starts
+        for b in 1..140 {
+            ruff.process(b as f64 * 128.0 / 44100.0);
+        }
+        assert_eq!(ruff.running_voices(), 0);
+    }
+
+    #[test]
+    fn test_replace_sample() {
+        let mut ruff = Ruffbox::new();
+        ruff.set_dc_blocker(false);
+
+        let bnum = ruff.load_sample(&[0.5; 1003]);
+
+        let play = |ruff: &mut Ruffbox, timestamp: f64, pos: f32| {
+            let inst = ruff.prepare_instance(SourceType::Sampler, timestamp, bnum);
+            ruff.set_instance_parameter(inst, SynthParameter::StereoPosition, pos);
+            ruff.set_instance_parameter(inst, SynthParameter::Attack, 0.0);
+            ruff.set_instance_parameter(inst, SynthParameter::Sustain, 1.0);
+            ruff.set_instance_parameter(inst, SynthParameter::Release, 0.0);
+            ruff.trigger(inst);
+        };
+
+        // the old sample on the left, still playing when it's replaced ...
+        play(&mut ruff, 0.0, -1.0);
+        ruff.process(0.0);
+        assert_eq!(ruff.replace_sample(bnum, &[0.25; 1003]), bnum);
+
+        // ... the new one on the right
+        play(&mut ruff, 128.0 / 44100.0, 1.0);
+        let out = ruff.process(128.0 / 44100.0);
+        // (the sampler's filters leave a little ripple)
+        assert_approx_eq::assert_approx_eq!(out[0][64], 0.5, 0.05);
+        assert_approx_eq::assert_approx_eq!(out[1][64], 0.25, 0.05);
+    }
+
+    #[test]
+    fn test_prime_sample() {
+        let mut ruff = Ruffbox::new();
+        let bnum = ruff.load_sample(&[0.5; 1003]);
+        assert!(ruff.prime_sample(bnum));
+        assert!(!ruff.prime_sample(bnum + 1));
+    }
+
+    #[test]
+    fn test_unload_sample() {
+        let mut ruff = Ruffbox::new();
+        ruff.set_dc_blocker(false);
+
+        let bnum1 = ruff.load_sample(&[0.5; 1003]);
+        let bnum2 = ruff.load_sample(&[0.5; 503]);
+        assert_eq!(ruff.sample_memory(), (1003 + 503) * 4);
+
+        let inst = ruff.prepare_instance(SourceType::Sampler, 0.0, bnum1);
+        ruff.trigger(inst);
+        ruff.process(0.0);
+
+        // the voice keeps the data until it's done
+        assert!(ruff.unload_sample(bnum1));
+        assert!(!ruff.unload_sample(bnum1));
+        assert_eq!(ruff.sample_memory(), (4 + 503) * 4);
+        assert_eq!(ruff.voice_memory(), 1003 * 4);
+
+        for b in 1..10 {
+            ruff.process(b as f64 * 128.0 / 44100.0);
+        }
+        assert_eq!(ruff.running_voices(), 0);
+        assert_eq!(ruff.voice_memory(), 0);
+
+        // the next sample takes the free buffer
+        assert_eq!(ruff.load_sample(&[0.25; 103]), bnum1);
+        assert_eq!(ruff.load_sample(&[0.25; 103]), bnum2 + 1);
+    }
+
+    #[test]
+    fn test_stream_prefetch() {
+        let mut ruff = Ruffbox::new();
+        ruff.set_dc_blocker(false);
+
+        let stream = ruff.create_stream(CHUNK_FRAMES * 3);
+        assert_eq!(ruff.next_stream_request(stream), None);
+
+        // triggering asks for the chunk the voice starts in, before it's played
+        let inst = ruff.prepare_instance(SourceType::Streamer, 0.5, stream);
+        ruff.set_instance_parameter(inst, SynthParameter::PlaybackStart, 0.5);
+        ruff.trigger(inst);
+        assert_eq!(ruff.next_stream_request(stream), Some(1));
+        assert!(ruff.load_stream_chunk(stream, 1, &vec![0.5; CHUNK_FRAMES]));
+        assert_eq!(ruff.stream_memory(), CHUNK_FRAMES * 4);
+
+        // playing, it asks for the next one
+        let start = (0.5 * 44100.0 / 128.0) as usize;
+        let mut out = [[0.0; 128]; 2];
+        for b in start..start + 10 {
+            out = ruff.process(b as f64 * 128.0 / 44100.0);
+        }
+        assert_approx_eq::assert_approx_eq!(out[0][64], 0.5 * FRAC_1_SQRT_2, 0.05);
+        assert_eq!(ruff.next_stream_request(stream), Some(2));
+        assert_eq!(ruff.stream_underruns(), 0);
+
+        // unknown streams don't play
+        let inst = ruff.prepare_instance(SourceType::Streamer, 1.0, stream + 1);
+        ruff.trigger(inst);
+        assert_eq!(ruff.next_stream_request(stream + 1), None);
+    }
+
+    #[test]
+    fn test_simultaneous_order() {
+        let mut ruff = Ruffbox::new();
+        let first = ruff.prepare_instance(SourceType::SineSynth, 1.0, 0);
+        let second = ruff.prepare_instance(SourceType::SineSynth, 1.0, 0);
+        let earlier = ruff.prepare_instance(SourceType::SineSynth, 0.5, 0);
+        for instance_id in [second, earlier, first].iter() {
+            ruff.trigger(*instance_id);
+        }
+        ruff.process(0.0);
+
+        // the last one starts first
+        let order: Vec<usize> = ruff.pending_events.iter().map(|event| event.order).collect();
+        assert_eq!(order, vec![second, first, earlier]);
+    }
+
+    #[test]
+    fn test_sample_loop() {
+        let mut ruff = Ruffbox::new();
+        ruff.set_dc_blocker(false);
+
+        // a short sample, held for longer than it is
+        let bnum = ruff.load_sample(&[0.5; 1003]);
+        assert!(ruff.set_sample_loop(bnum, 200.0 / 44100.0, 800.0 / 44100.0, 100.0 / 44100.0));
+        assert!(!ruff.set_sample_loop(bnum + 1, 0.0, 0.1, 0.01));
+
+        let inst = ruff.prepare_instance(SourceType::Sampler, 0.0, bnum);
+        ruff.set_instance_parameter(inst, SynthParameter::PlaybackLoop, 1.0);
+        ruff.set_instance_parameter(inst, SynthParameter::Attack, 0.0);
+        ruff.set_instance_parameter(inst, SynthParameter::Sustain, 0.1);
+        ruff.set_instance_parameter(inst, SynthParameter::Release, 0.0);
+        ruff.set_instance_parameter(inst, SynthParameter::StereoPosition, -1.0);
+        ruff.trigger(inst);
+
+        // still playing after three times its length
+        let mut out = [[0.0; 128]; 2];
+        for b in 0..24 {
+            out = ruff.process(b as f64 * 128.0 / 44100.0);
+        }
+        assert_approx_eq::assert_approx_eq!(out[0][64], 0.5, 0.05);
+
+        // loading it again, the loop points are gone
+        ruff.replace_sample(bnum, &[0.5; 1003]);
+        assert!(ruff.loop_points.is_empty());
+    }
+
+    #[test]
+    fn test_instrument_zones() {
+        let mut ruff = Ruffbox::new();
+        let low = ruff.load_sample(&[0.5; 103]);
+        let high = ruff.load_sample(&[0.5; 103]);
+
+        let inst = ruff.define_instrument();
+        assert!(ruff.add_key_zone(inst, 0, 59, 48, low));
+        assert!(ruff.add_key_zone(inst, 60, 127, 72, high));
+        // no such sample, no such instrument
+        assert!(!ruff.add_key_zone(inst, 0, 127, 60, 5));
+        assert!(!ruff.add_key_zone(inst + 1, 0, 127, 60, low));
+
+        let (sample_buf, rate, gain) = ruff.instrument_zone(inst, 440.0, 100).unwrap();
+        assert_eq!(sample_buf, high);
+        assert_approx_eq::assert_approx_eq!(rate, 2.0_f32.powf(-3.0 / 12.0), 0.0001);
+        assert_eq!(gain, 1.0);
+        assert!(ruff.instrument_zone(inst + 1, 440.0, 100).is_none());
+    }
+
+    #[test]
+    fn test_tuned_key_zones() {
+        let mut ruff = Ruffbox::new();
+        let low = ruff.load_sample(&[0.5; 103]);
+        let high = ruff.load_sample(&[0.5; 103]);
+
+        let inst = ruff.define_instrument();
+        assert!(!ruff.tune_key_zone(inst, 0, 127, 0, 0.0, 0.0));
+        assert!(ruff.add_key_zone(inst, 0, 59, 48, low));
+        assert!(ruff.tune_key_zone(inst, 0, 127, 0, 22050.0, 3.0));
+        assert!(ruff.add_key_zone(inst, 60, 127, 72, high));
+        assert!(ruff.tune_key_zone(inst, 100, 127, 100, 22050.0, 3.0));
+        assert!(!ruff.tune_key_zone(inst + 1, 0, 127, 0, 0.0, 0.0));
+
+        // the low zone at its root, the samples are at 22050Hz ...
+        let (sample_buf, rate, gain) = ruff.instrument_zone(inst, 130.81, 64).unwrap();
+        assert_eq!(sample_buf, low);
+        assert_approx_eq::assert_approx_eq!(rate, 22050.0 / ruff.buffer_samplerate, 0.001);
+        assert_approx_eq::assert_approx_eq!(gain, 10.0_f32.powf(-3.0 / 20.0), 0.0001);
+
+        // ... the high one only for loud notes, a semitone up
+        let (sample_buf, rate, _) = ruff.instrument_zone(inst, 523.25, 110).unwrap();
+        assert_eq!(sample_buf, high);
+        assert_approx_eq::assert_approx_eq!(rate, 2.0_f32.powf(1.0 / 12.0) * 22050.0 / ruff.buffer_samplerate, 0.001);
+        assert_eq!(ruff.instrument_zone(inst, 523.25, 60).unwrap().0, low);
+    }
 }
@@ -0,0 +1,312 @@
+use crate::ruffbox::synth::spectral::fft;
+
+use std::f32::consts::PI;
+
+/// Some information about a sample, gathered once when it's loaded.
+#[derive(Clone, Debug)]
+pub struct SampleInfo {
+    pub length: usize,
+    pub duration: f64,
+    /// the samplerate the sample was analyzed at
+    pub samplerate: f32,
+    pub peak: f32,
+    pub rms: f32,
+    pub onsets: Vec<usize>,
+    /// fundamental frequency in Hz, for samples that have a clear one
+    pub pitch: Option<f32>,
+}
+
+/// hop size of the onset detection, onsets are found per frame of this size
+const ONSET_FRAME_SIZE: usize = 256;
+/// the spectra are taken over two frames
+const ONSET_WINDOW_SIZE: usize = 2 * ONSET_FRAME_SIZE;
+/// frames this far around are taken into account for the threshold
+const ONSET_THRESHOLD_FRAMES: usize = 8;
+/// the flux has to be this much above the local average to count as onset ...
+const ONSET_THRESHOLD_RATIO: f32 = 1.5;
+/// ... and above this share of the biggest flux in the sample
+const ONSET_THRESHOLD_FLOOR: f32 = 0.1;
+/// frames below this rms level are considered silent
+const ONSET_ENERGY_FLOOR: f32 = 0.01;
+/// minimum distance between two onsets, in seconds
+const ONSET_MIN_DISTANCE: f32 = 0.05;
+/// onsets are moved back a little, so slices starting there keep the attack
+const ONSET_PRE_ROLL: f32 = 0.001;
+
+fn rms(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    
+    let square_sum: f64 = samples.iter().map(|s| (*s as f64) * (*s as f64)).sum();
+    (square_sum / samples.len() as f64).sqrt() as f32
+}
+
+/// Spectral flux per frame, how much the (log) magnitude spectrum grows
+/// from the previous frame, summed over all bins.
+fn spectral_flux(samples: &[f32]) -> Vec<f32> {
+    // only whole frames, a cut off frame at the end would look like an onset
+    let frames = samples.len() / ONSET_FRAME_SIZE;
+    let window: Vec<f32> = (0..ONSET_WINDOW_SIZE)
+        .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / ONSET_WINDOW_SIZE as f32).cos())
+        .collect();
+
+    let mut flux = Vec::with_capacity(frames);
+    let mut last_magnitudes = vec![0.0; ONSET_WINDOW_SIZE / 2];
+    let mut re = vec![0.0; ONSET_WINDOW_SIZE];
+    let mut im = vec![0.0; ONSET_WINDOW_SIZE];
+
+    for frame_idx in 0..frames {
+        // the window ends with the frame
+        let end = (frame_idx + 1) * ONSET_FRAME_SIZE;
+        for (i, (r, w)) in re.iter_mut().zip(window.iter()).enumerate() {
+            let pos = (end + i).checked_sub(ONSET_WINDOW_SIZE);
+            *r = pos.and_then(|pos| samples.get(pos)).map_or(0.0, |s| s * w);
+        }
+        im.iter_mut().for_each(|i| *i = 0.0);
+        fft(&mut re, &mut im, false);
+
+        let mut frame_flux = 0.0;
+        for (bin, last) in last_magnitudes.iter_mut().enumerate() {
+            let magnitude = (1.0 + 100.0 * (re[bin] * re[bin] + im[bin] * im[bin]).sqrt()).ln();
+            frame_flux += (magnitude - *last).max(0.0);
+            *last = magnitude;
+        }
+        flux.push(frame_flux);
+    }
+
+    flux
+}
+
+/// Where within the given range the sound starts, the first
+/// sample that gets near the peak of the range.
+fn refine_onset(samples: &[f32], start: usize, end: usize) -> usize {
+    let range = &samples[start..end.min(samples.len())];
+    let peak = range.iter().fold(0.0, |peak: f32, sample| peak.max(sample.abs()));
+    start + range.iter().position(|sample| sample.abs() >= 0.1 * peak).unwrap_or(0)
+}
+
+/// Spectral flux onset detection, so changes in timbre count as well as changes
+/// in level. Returns the onset positions in samples.
+pub fn detect_onsets(samples: &[f32], sr: f32) -> Vec<usize> {
+    let min_distance = (ONSET_MIN_DISTANCE * sr) as usize;
+    let pre_roll = (ONSET_PRE_ROLL * sr) as usize;
+
+    let flux = spectral_flux(samples);
+    let floor = flux.iter().fold(0.0, |max: f32, f| max.max(*f)) * ONSET_THRESHOLD_FLOOR;
+
+    let mut onsets: Vec<usize> = Vec::new();
+
+    for (frame_idx, frame_flux) in flux.iter().enumerate() {
+        // local maxima, well above their surroundings
+        let around = &flux[frame_idx.saturating_sub(ONSET_THRESHOLD_FRAMES)..(frame_idx + ONSET_THRESHOLD_FRAMES + 1).min(flux.len())];
+        let average = around.iter().sum::<f32>() / around.len() as f32;
+        let previous = if frame_idx > 0 { flux[frame_idx - 1] } else { 0.0 };
+        let next = flux.get(frame_idx + 1).cloned().unwrap_or(0.0);
+
+        if *frame_flux <= floor || *frame_flux < previous || *frame_flux <= next || *frame_flux < average * ONSET_THRESHOLD_RATIO {
+            continue;
+        }
+
+        let end = (frame_idx + 1) * ONSET_FRAME_SIZE;
+        let start = end.saturating_sub(ONSET_WINDOW_SIZE);
+        if rms(&samples[start..end.min(samples.len())]) < ONSET_ENERGY_FLOOR {
+            continue;
+        }
+
+        let position = refine_onset(samples, start, end).saturating_sub(pre_roll);
+        match onsets.last() {
+            Some(last_onset) if position < last_onset + min_distance => {},
+            _ => onsets.push(position),
+        }
+    }
+
+    onsets
+}
+
+/// window size for the pitch detection
+const PITCH_WINDOW_SIZE: usize = 2048;
+/// the range of pitches looked for, in Hz
+const PITCH_MIN: f32 = 40.0;
+const PITCH_MAX: f32 = 2000.0;
+/// how periodic the sample has to be to count as pitched (lower is stricter)
+const PITCH_THRESHOLD: f32 = 0.15;
+
+/// Estimate the fundamental of a sample (YIN, on its loudest part).
+/// Returns none for noisy or percussive samples without a clear pitch.
+pub fn detect_pitch(samples: &[f32], sr: f32) -> Option<f32> {
+    let min_lag = (sr / PITCH_MAX) as usize;
+    let max_lag = (sr / PITCH_MIN) as usize;
+
+    if samples.len() < PITCH_WINDOW_SIZE + max_lag {
+        return None;
+    }
+
+    // the loudest window, where the note is most likely to sound clearly
+    let hop = PITCH_WINDOW_SIZE / 2;
+    let start = (0..=(samples.len() - PITCH_WINDOW_SIZE - max_lag) / hop)
+        .map(|i| i * hop)
+        .max_by(|a, b| rms(&samples[*a..*a + PITCH_WINDOW_SIZE]).total_cmp(&rms(&samples[*b..*b + PITCH_WINDOW_SIZE])))?;
+
+    if rms(&samples[start..start + PITCH_WINDOW_SIZE]) < ONSET_ENERGY_FLOOR {
+        return None;
+    }
+
+    let window = &samples[start..start + PITCH_WINDOW_SIZE + max_lag];
+
+    // cumulative mean normalized difference
+    let mut cmnd = vec![1.0; max_lag + 1];
+    let mut running_sum = 0.0;
+    for lag in 1..=max_lag {
+        let difference: f32 = (0..PITCH_WINDOW_SIZE).map(|i| {
+            let delta = window[i] - window[i + lag];
+            delta * delta
+        }).sum();
+        running_sum += difference;
+        cmnd[lag] = if running_sum > 0.0 { difference * lag as f32 / running_sum } else { 1.0 };
+    }
+
+    // the first dip below the threshold, down to its bottom
+    let mut lag = (min_lag.max(2)..max_lag).find(|lag| cmnd[*lag] < PITCH_THRESHOLD)?;
+    while lag + 1 < max_lag && cmnd[lag + 1] < cmnd[lag] {
+        lag += 1;
+    }
+
+    // parabolic interpolation between the lags around the dip
+    let (prev, cur, next) = (cmnd[lag - 1], cmnd[lag], cmnd[lag + 1]);
+    let curvature = prev + next - 2.0 * cur;
+    let offset = if curvature > 0.0 { 0.5 * (prev - next) / curvature } else { 0.0 };
+
+    Some(sr / (lag as f32 + offset))
+}
+
+impl SampleInfo {
+    /// Analyze the (unpadded) sample data.
+    pub fn from_samples(samples: &[f32], sr: f32) -> Self {
+        let peak = samples.iter().fold(0.0, |peak: f32, sample| peak.max(sample.abs()));
+        
+        SampleInfo {
+            length: samples.len(),
+            duration: samples.len() as f64 / sr as f64,
+            samplerate: sr,
+            peak,
+            rms: rms(samples),
+            onsets: detect_onsets(samples, sr),
+            pitch: detect_pitch(samples, sr),
+        }
+    }
+
+    /// position of an onset in seconds
+    pub fn onset_time(&self, onset: usize) -> Option<f64> {
+        self.onsets.get(onset).map(|pos| *pos as f64 / self.samplerate as f64)
+    }
+
+    /// The playback rate that moves the sample's pitch onto the nearest octave of
+    /// the given root (as midi note, or pitch class), so a melodic sample fits the key.
+    /// Samples without a pitch stay as they are.
+    pub fn key_fit_rate(&self, root: i32) -> f32 {
+        match self.pitch {
+            Some(pitch) => {
+                let note = 69.0 + 12.0 * (pitch / 440.0).log2();
+                // never more than a tritone up or down
+                let shift = (root as f32 - note).rem_euclid(12.0);
+                let shift = if shift > 6.0 { shift - 12.0 } else { shift };
+                2.0_f32.powf(shift / 12.0)
+            },
+            None => 1.0,
+        }
+    }
+}
+
+// TEST TEST TEST 
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_peak_and_rms() {
+        let info = SampleInfo::from_samples(&[0.5, -0.5, 0.5, -0.5], 44100.0);
+        assert_approx_eq::assert_approx_eq!(info.peak, 0.5, 0.00001);
+        assert_approx_eq::assert_approx_eq!(info.rms, 0.5, 0.00001);
+
+        let info = SampleInfo::from_samples(&[0.0, -0.8, 0.0, 0.0], 44100.0);
+        assert_approx_eq::assert_approx_eq!(info.peak, 0.8, 0.00001);
+        assert_approx_eq::assert_approx_eq!(info.rms, 0.4, 0.00001);
+    }
+
+    #[test]
+    fn test_empty_sample() {
+        let info = SampleInfo::from_samples(&[], 44100.0);
+        assert_approx_eq::assert_approx_eq!(info.peak, 0.0, 0.00001);
+        assert_approx_eq::assert_approx_eq!(info.rms, 0.0, 0.00001);
+        assert!(info.onsets.is_empty());
+    }
+
+    #[test]
+    fn test_onsets() {
+        let mut samples = vec![0.0; 44100];
+        
+        // two decaying bursts, at 0.1 and 0.5 seconds
+        for start in [4410, 22050].iter() {
+            for i in 0..4000 {
+                samples[start + i] = (i as f32 * 0.3).sin() * (1.0 - i as f32 / 4000.0);
+            }
+        }
+
+        let info = SampleInfo::from_samples(&samples, 44100.0);
+        
+        assert_eq!(info.onsets.len(), 2);
+        assert!(4410 - info.onsets[0] < ONSET_FRAME_SIZE);
+        assert!(22050 - info.onsets[1] < ONSET_FRAME_SIZE);
+        assert_approx_eq::assert_approx_eq!(info.duration, 1.0, 0.00001);
+        assert_approx_eq::assert_approx_eq!(info.onset_time(1).unwrap(), 0.5, ONSET_FRAME_SIZE as f64 / 44100.0);
+        assert!(info.onset_time(2).is_none());
+    }
+
+    #[test]
+    fn test_onsets_timbre_change() {
+        // same level all the way, but the pitch jumps after half a second
+        let samples: Vec<f32> = (0..44100).map(|i| {
+            let freq = if i < 22050 { 220.0 } else { 1760.0 };
+            0.5 * (2.0 * PI * freq * i as f32 / 44100.0).sin()
+        }).collect();
+
+        let info = SampleInfo::from_samples(&samples, 44100.0);
+
+        assert_eq!(info.onsets.len(), 2);
+        assert_eq!(info.onsets[0], 0);
+        assert!((info.onsets[1] as i64 - 22050).abs() < ONSET_WINDOW_SIZE as i64);
+    }
+
+    #[test]
+    fn test_pitch() {
+        // an a below middle c, with a few overtones
+        let samples: Vec<f32> = (0..22050).map(|i| {
+            let phase = 2.0 * PI * 220.0 * i as f32 / 44100.0;
+            0.5 * phase.sin() + 0.2 * (2.0 * phase).sin() + 0.1 * (3.0 * phase).sin()
+        }).collect();
+
+        let info = SampleInfo::from_samples(&samples, 44100.0);
+        assert_approx_eq::assert_approx_eq!(info.pitch.unwrap(), 220.0, 0.5);
+
+        // up three semitones to c, rather than down nine
+        assert_approx_eq::assert_approx_eq!(info.key_fit_rate(0), 2.0_f32.powf(3.0 / 12.0), 0.005);
+        // already in a
+        assert_approx_eq::assert_approx_eq!(info.key_fit_rate(57), 1.0, 0.005);
+    }
+
+    #[test]
+    fn test_no_pitch() {
+        // noise, from a simple lcg
+        let mut state: u32 = 12345;
+        let samples: Vec<f32> = (0..22050).map(|_| {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            (state >> 8) as f32 / (1 << 24) as f32 - 0.5
+        }).collect();
+
+        let info = SampleInfo::from_samples(&samples, 44100.0);
+        assert!(info.pitch.is_none());
+        assert_eq!(info.key_fit_rate(0), 1.0);
+    }
+}
@@ -0,0 +1,697 @@
+use std::ops::{Deref, DerefMut};
+
+use crate::ruffbox::Ruffbox;
+use crate::ruffbox::export::{self, BitDepth};
+
+/**
+ * Keeps track of the time spent processing compared to the time
+ * the processed audio lasts. The host measures the time, as there's
+ * no clock to rely on inside the wasm module.
+ */
+pub struct LoadMeter {
+    load: f32,
+    blocks_over: usize,
+    overloaded: bool,
+}
+
+impl Default for LoadMeter {
+    fn default() -> Self {
+        LoadMeter::new()
+    }
+}
+
+impl LoadMeter {
+    const SMOOTHING: f32 = 0.95;
+    /// above this, the next glitch isn't far
+    const THRESHOLD: f32 = 0.8;
+    /// about a third of a second at 128 samples
+    const SUSTAIN_BLOCKS: usize = 100;
+
+    pub fn new() -> Self {
+        LoadMeter {
+            load: 0.0,
+            blocks_over: 0,
+            overloaded: false,
+        }
+    }
+
+    /// returns true when the load has just been over the threshold for long enough
+    pub fn update(&mut self, dsp_time: f64, budget: f64) -> bool {
+        if budget <= 0.0 {
+            return false;
+        }
+
+        let block_load = (dsp_time / budget) as f32;
+        self.load = LoadMeter::SMOOTHING * self.load + (1.0 - LoadMeter::SMOOTHING) * block_load;
+
+        if self.load > LoadMeter::THRESHOLD {
+            self.blocks_over += 1;
+        } else {
+            self.blocks_over = 0;
+            self.overloaded = false;
+        }
+
+        if self.blocks_over >= LoadMeter::SUSTAIN_BLOCKS && !self.overloaded {
+            self.overloaded = true;
+            return true;
+        }
+        false
+    }
+
+    /// the smoothed load, 1.0 being all of the time available
+    pub fn load(&self) -> f32 {
+        self.load
+    }
+
+    /// whether the load is over the threshold, and has been for long enough
+    pub fn is_overloaded(&self) -> bool {
+        self.overloaded
+    }
+}
+
+/// The master output from a given time on, for a given number of samples,
+/// to become a sample of its own (mixed down to mono, like loaded samples).
+struct Capture {
+    id: usize,
+    start: f64,
+    length: usize,
+    samples: Vec<f32>,
+}
+
+/// Part of the output between two times (the end is only known once it's there),
+/// of the master or of a bus, for stems. Recorded at the internal samplerate.
+struct Take {
+    id: usize,
+    bus: Option<usize>,
+    start: f64,
+    end: f64,
+    samples: [Vec<f32>; 2],
+}
+
+impl Take {
+    /// keep what's between the start and the end of a block starting at the given time
+    fn record(&mut self, block_time: f64, sec_per_sample: f64, block: &[[f32; 128]; 2]) {
+        for (s, (left, right)) in block[0].iter().zip(block[1].iter()).enumerate() {
+            let time = block_time + s as f64 * sec_per_sample;
+            if time >= self.start && time < self.end {
+                self.samples[0].push(*left);
+                self.samples[1].push(*right);
+            }
+        }
+    }
+}
+
+/**
+ * Wraps the synth so it can be driven with any block size, and run at
+ * an internal samplerate different from the output samplerate (lower,
+ * to save cpu on weak devices). Internally, everything is still processed
+ * in blocks of 128 samples, the output is taken from a fifo and resampled.
+ */
+pub struct Engine {
+    ruffbox: Ruffbox,
+    internal_samplerate: f32,
+    output_samplerate: f32,
+    // processed samples (at the internal samplerate) that haven't been output yet,
+    // stereo first, then the four B-format channels
+    fifo: [Vec<f32>; 6],
+    // the fractional read position in the fifo
+    read_pos: f64,
+    load_meter: LoadMeter,
+    // the duration of the last rendered block, in seconds
+    last_budget: f64,
+    voice_thinning: bool,
+    // reports until the next voices are dropped, while overloaded
+    thinning_countdown: usize,
+    // the stereo output since recording was started, if it is
+    recording: Option<[Vec<f32>; 2]>,
+    // the last recording, as a WAV file
+    export: Vec<u8>,
+    // master output to be resampled, finished ones stay until they're stored
+    captures: Vec<Capture>,
+    next_capture_id: usize,
+    // named parts of the output, they stay until they're discarded
+    takes: Vec<Take>,
+    next_take_id: usize,
+}
+
+impl Deref for Engine {
+    type Target = Ruffbox;
+
+    fn deref(&self) -> &Ruffbox {
+        &self.ruffbox
+    }
+}
+
+impl DerefMut for Engine {
+    fn deref_mut(&mut self) -> &mut Ruffbox {
+        &mut self.ruffbox
+    }
+}
+
+impl Engine {
+    /// about 70ms at 128 samples
+    const THINNING_INTERVAL: usize = 25;
+
+    pub fn new(internal_sr: f32, output_sr: f32) -> Self {
+        let mut ruffbox = Ruffbox::with_samplerate(internal_sr);
+        ruffbox.set_buffer_samplerate(output_sr);
+
+        Engine {
+            ruffbox,
+            internal_samplerate: internal_sr,
+            output_samplerate: output_sr,
+            fifo: Default::default(),
+            read_pos: 0.0,
+            load_meter: LoadMeter::new(),
+            last_budget: 0.0,
+            voice_thinning: false,
+            thinning_countdown: 0,
+            recording: None,
+            export: Vec::new(),
+            captures: Vec::new(),
+            next_capture_id: 0,
+            takes: Vec::new(),
+            next_take_id: 0,
+        }
+    }
+
+    /// Change the samplerates. Loaded samples, custom voices and global
+    /// settings are kept, everything that's playing is dropped, and the insert
+    /// chains have to be set up again, so it's meant to be done at startup.
+    pub fn configure(&mut self, internal_sr: f32, output_sr: f32) {
+        let mut fresh = Ruffbox::with_samplerate(internal_sr);
+        fresh.set_buffer_samplerate(output_sr);
+
+        let old = &mut self.ruffbox;
+        fresh.buffers = std::mem::take(&mut old.buffers);
+        fresh.buffer_info = std::mem::take(&mut old.buffer_info);
+        fresh.voices = std::mem::take(&mut old.voices);
+        fresh.normalization = old.normalization;
+        fresh.interpolation = old.interpolation;
+        fresh.dc_blocker_enabled = old.dc_blocker_enabled;
+        // the responses are made for the new samplerate
+        fresh.set_spatial_mode(old.spatial_mode);
+        let voice_thinning = self.voice_thinning;
+
+        *self = Engine {
+            ruffbox: fresh,
+            internal_samplerate: internal_sr,
+            output_samplerate: output_sr,
+            fifo: Default::default(),
+            read_pos: 0.0,
+            load_meter: LoadMeter::new(),
+            last_budget: 0.0,
+            voice_thinning,
+            thinning_countdown: 0,
+            recording: None,
+            export: Vec::new(),
+            captures: Vec::new(),
+            next_capture_id: 0,
+            takes: Vec::new(),
+            next_take_id: 0,
+        };
+    }
+
+    /// Fill the output buffers (of any, but the same, length), stream time
+    /// being the time of the first sample.
+    pub fn render(&mut self, stream_time: f64, out_l: &mut [f32], out_r: &mut [f32]) {
+        self.render_channels(stream_time, 0, &mut [out_l, out_r]);
+
+        if let Some([rec_l, rec_r]) = self.recording.as_mut() {
+            rec_l.extend_from_slice(out_l);
+            rec_r.extend_from_slice(out_r);
+        }
+
+        for capture in self.captures.iter_mut() {
+            // from the sample closest to the start on, if it's in this block
+            let first = ((capture.start - stream_time) * self.output_samplerate as f64).round().max(0.0) as usize;
+            for (left, right) in out_l.iter().zip(out_r.iter()).skip(first) {
+                if capture.samples.len() == capture.length {
+                    break;
+                }
+                capture.samples.push((left + right) * 0.5);
+            }
+        }
+    }
+
+    /// Capture the master output from the given time on, for the given duration
+    /// (in seconds), to be stored as a sample once it's done. Returns its id.
+    pub fn start_capture(&mut self, start_time: f64, duration: f32) -> usize {
+        let length = (duration * self.output_samplerate).round() as usize;
+        let id = self.next_capture_id;
+        self.next_capture_id += 1;
+        self.captures.push(Capture { id, start: start_time, length, samples: Vec::with_capacity(length) });
+        id
+    }
+
+    /// The id of a capture that's done, if there is one.
+    pub fn finished_capture(&self) -> Option<usize> {
+        self.captures.iter().find(|capture| capture.samples.len() == capture.length).map(|capture| capture.id)
+    }
+
+    /// Store a finished capture as a new sample, or in place of the given one.
+    /// Returns the buffer number, or None if there's no such capture (yet).
+    pub fn store_capture(&mut self, id: usize, sample_buf: Option<usize>) -> Option<usize> {
+        let idx = self.captures.iter().position(|capture| capture.id == id && capture.samples.len() == capture.length)?;
+        let capture = self.captures.remove(idx);
+
+        // padding for interpolation, like loaded samples
+        let mut samples = Vec::with_capacity(capture.length + 3);
+        samples.push(0.0);
+        samples.extend_from_slice(&capture.samples);
+        samples.extend_from_slice(&[0.0, 0.0]);
+
+        Some(match sample_buf {
+            Some(sample_buf) => self.ruffbox.replace_sample(sample_buf, &samples),
+            None => self.ruffbox.load_sample(&samples),
+        })
+    }
+
+    /// Record the stereo output from now on, dropping what's been recorded so far.
+    pub fn start_recording(&mut self) {
+        self.recording = Some([Vec::new(), Vec::new()]);
+    }
+
+    /// Stop recording and write what's been recorded as a WAV file, at the
+    /// given samplerate (0 for the output samplerate) and bit depth.
+    /// The file is kept until the next recording is stopped.
+    /// None if nothing was recorded.
+    pub fn stop_recording(&mut self, samplerate: u32, depth: BitDepth) -> Option<&[u8]> {
+        let [rec_l, rec_r] = self.recording.take()?;
+        if rec_l.is_empty() {
+            return None;
+        }
+        let samplerate = if samplerate == 0 { self.output_samplerate as u32 } else { samplerate };
+
+        let left = export::resample(&rec_l, self.output_samplerate, samplerate as f32);
+        let right = export::resample(&rec_r, self.output_samplerate, samplerate as f32);
+        self.export = export::encode_wav(&[&left, &right], samplerate, depth);
+        Some(&self.export)
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    /// where the last recording is, for the host to copy it out
+    pub fn recording_ptr(&self) -> *const u8 {
+        self.export.as_ptr()
+    }
+
+    /// Record a take of the master (or of a bus, for a stem) from the given time on,
+    /// until it's stopped. The bus exists from now on, even without effects.
+    /// Returns its id.
+    pub fn start_take(&mut self, bus: Option<usize>, start_time: f64) -> usize {
+        if let Some(bus) = bus {
+            self.ruffbox.follow_bus(bus);
+        }
+        let id = self.next_take_id;
+        self.next_take_id += 1;
+        self.takes.push(Take { id, bus, start: start_time, end: f64::INFINITY, samples: [Vec::new(), Vec::new()] });
+        id
+    }
+
+    /// End a take at the given time, what's been recorded past it is dropped.
+    pub fn stop_take(&mut self, id: usize, end_time: f64) {
+        if let Some(take) = self.takes.iter_mut().find(|take| take.id == id) {
+            take.end = end_time;
+            let keep = ((end_time - take.start).max(0.0) * self.internal_samplerate as f64).ceil() as usize;
+            take.samples[0].truncate(keep);
+            take.samples[1].truncate(keep);
+        }
+    }
+
+    pub fn discard_take(&mut self, id: usize) {
+        self.takes.retain(|take| take.id != id);
+    }
+
+    /// Write a take (as far as it's recorded) as a WAV file, like stop_recording,
+    /// the file is where the last recording is. None if there's no such take.
+    pub fn export_take(&mut self, id: usize, samplerate: u32, depth: BitDepth) -> Option<&[u8]> {
+        let take = self.takes.iter().find(|take| take.id == id)?;
+        let samplerate = if samplerate == 0 { self.output_samplerate as u32 } else { samplerate };
+
+        let left = export::resample(&take.samples[0], self.internal_samplerate, samplerate as f32);
+        let right = export::resample(&take.samples[1], self.internal_samplerate, samplerate as f32);
+        self.export = export::encode_wav(&[&left, &right], samplerate, depth);
+        Some(&self.export)
+    }
+
+    /// Like render, but the output is the first-order B-format (AmbiX, W Y Z X)
+    /// of the ambisonic mode.
+    pub fn render_bformat(&mut self, stream_time: f64, out: &mut [&mut [f32]; 4]) {
+        self.render_channels(stream_time, 2, out);
+    }
+
+    /// output the fifo channels from the given one on
+    fn render_channels(&mut self, stream_time: f64, first_channel: usize, outs: &mut [&mut [f32]]) {
+        let len = outs.iter().map(|out| out.len()).min().unwrap_or(0);
+        if len == 0 {
+            return;
+        }
+
+        self.last_budget = len as f64 / self.output_samplerate as f64;
+
+        // internal samples per output sample
+        let ratio = self.internal_samplerate as f64 / self.output_samplerate as f64;
+
+        // the last sample to be read, and the one after if it's in between
+        let last_pos = self.read_pos + ratio * (len - 1) as f64;
+        let needed = last_pos.floor() as usize + if last_pos.fract() > 0.0 { 2 } else { 1 };
+
+        while self.fifo[0].len() < needed {
+            // the time of the next sample to be processed
+            let buffered = (self.fifo[0].len() as f64 - self.read_pos) / self.internal_samplerate as f64;
+            let block = self.ruffbox.process(stream_time + buffered);
+            let sec_per_sample = 1.0 / self.internal_samplerate as f64;
+            for take in self.takes.iter_mut() {
+                let output = match take.bus {
+                    Some(bus) => self.ruffbox.bus_output(bus),
+                    None => Some(&block),
+                };
+                if let Some(output) = output {
+                    take.record(stream_time + buffered, sec_per_sample, output);
+                }
+            }
+            let bformat = self.ruffbox.bformat();
+            for (fifo, channel) in self.fifo.iter_mut().zip(block.iter().chain(bformat.iter())) {
+                fifo.extend_from_slice(channel);
+            }
+        }
+
+        for i in 0..len {
+            let idx = self.read_pos.floor() as usize;
+            let frac = self.read_pos.fract() as f32;
+
+            for (out, fifo) in outs.iter_mut().zip(self.fifo.iter().skip(first_channel)) {
+                out[i] = if frac > 0.0 {
+                    fifo[idx] * (1.0 - frac) + fifo[idx + 1] * frac
+                } else {
+                    fifo[idx]
+                };
+            }
+
+            self.read_pos += ratio;
+        }
+
+        let consumed = self.read_pos.floor() as usize;
+        for fifo in self.fifo.iter_mut() {
+            fifo.drain(..consumed);
+        }
+        self.read_pos -= consumed as f64;
+    }
+
+    /// The time the host measured for the last render call, returns true
+    /// if that made for a sustained overload.
+    pub fn report_dsp_time(&mut self, seconds: f64) -> bool {
+        let overload = self.load_meter.update(seconds, self.last_budget);
+
+        // drop a quarter of the voices at a time, until the load is back
+        // below the threshold, giving each step some time to show
+        if self.voice_thinning && self.load_meter.is_overloaded() {
+            if self.thinning_countdown == 0 {
+                let count = (self.ruffbox.running_voices() / 4).max(1);
+                self.ruffbox.thin_voices(count);
+                self.thinning_countdown = Engine::THINNING_INTERVAL;
+            } else {
+                self.thinning_countdown -= 1;
+            }
+        } else {
+            self.thinning_countdown = 0;
+        }
+
+        overload
+    }
+
+    /// Protective mode, on sustained overload the least important
+    /// voices are stopped instead of letting the whole output crackle.
+    pub fn set_voice_thinning(&mut self, enabled: bool) {
+        self.voice_thinning = enabled;
+    }
+
+    pub fn load(&self) -> f32 {
+        self.load_meter.load()
+    }
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::ruffbox::synth::{SourceType, SynthParameter};
+    use std::f32::consts::PI;
+
+    fn play_sine(ruff: &mut Ruffbox, freq: f32) {
+        let inst = ruff.prepare_instance(SourceType::SineSynth, 0.0, 0);
+        ruff.set_instance_parameter(inst, SynthParameter::PitchFrequency, freq);
+        ruff.set_instance_parameter(inst, SynthParameter::StereoPosition, -1.0);
+        ruff.set_instance_parameter(inst, SynthParameter::Level, 1.0);
+        ruff.set_instance_parameter(inst, SynthParameter::Attack, 0.0);
+        ruff.set_instance_parameter(inst, SynthParameter::Sustain, 1.0);
+        ruff.set_instance_parameter(inst, SynthParameter::Release, 0.0);
+        ruff.trigger(inst);
+    }
+
+    #[test]
+    fn load_meter_test_sustained_overload() {
+        let mut meter = LoadMeter::new();
+        let budget = 128.0 / 44100.0;
+
+        for _ in 0..200 {
+            assert!(!meter.update(budget * 0.5, budget));
+        }
+        assert_approx_eq::assert_approx_eq!(meter.load(), 0.5, 0.001);
+
+        // a short spike isn't an overload
+        for _ in 0..20 {
+            assert!(!meter.update(budget * 2.0, budget));
+        }
+        for _ in 0..100 {
+            meter.update(budget * 0.5, budget);
+        }
+
+        // a sustained one is, but is only reported once
+        let reported = (0..300).filter(|_| meter.update(budget * 0.95, budget)).count();
+        assert_eq!(reported, 1);
+        assert!(meter.load() > 0.8);
+    }
+
+    #[test]
+    fn engine_test_voice_thinning() {
+        let mut engine = Engine::new(44100.0, 44100.0);
+        engine.set_voice_thinning(true);
+        for i in 0..8 {
+            play_sine(&mut engine, 100.0 * (i + 1) as f32);
+        }
+
+        let mut out_l = [0.0; 128];
+        let mut out_r = [0.0; 128];
+        engine.render(0.0, &mut out_l, &mut out_r);
+        assert_eq!(engine.running_voices(), 8);
+
+        // fine for a while
+        let budget = 128.0 / 44100.0;
+        for _ in 0..200 {
+            engine.report_dsp_time(budget * 0.5);
+        }
+        assert_eq!(engine.running_voices(), 8);
+
+        // then overloaded, voices are dropped bit by bit
+        let mut counts = Vec::new();
+        for _ in 0..300 {
+            engine.report_dsp_time(budget * 1.5);
+            counts.push(engine.running_voices());
+        }
+        assert!(counts.windows(2).all(|w| w[1] <= w[0]));
+        assert!(counts.contains(&6));
+        assert_eq!(engine.running_voices(), 0);
+    }
+
+    #[test]
+    fn engine_test_bformat() {
+        let mut engine = Engine::new(44100.0, 44100.0);
+        engine.set_spatial_mode(crate::ruffbox::SpatialMode::Ambisonic);
+        engine.set_dc_blocker(false);
+        // hard left, so it's in W and Y only
+        play_sine(&mut engine, 440.0);
+
+        let mut w = vec![0.0; 300];
+        let mut y = vec![0.0; 300];
+        let mut z = vec![0.0; 300];
+        let mut x = vec![0.0; 300];
+        engine.render_bformat(0.0, &mut [&mut w, &mut y, &mut z, &mut x]);
+
+        for i in 0..300 {
+            let comp = (2.0 * PI * 440.0 * (i as f32 / 44100.0)).sin();
+            assert_approx_eq::assert_approx_eq!(w[i], comp, 0.0001);
+            assert_approx_eq::assert_approx_eq!(z[i], 0.0, 0.0001);
+            // past the ramp from the front
+            if i >= 128 {
+                assert_approx_eq::assert_approx_eq!(y[i], comp, 0.0001);
+                assert_approx_eq::assert_approx_eq!(x[i], 0.0, 0.0001);
+            }
+        }
+    }
+
+    #[test]
+    fn engine_test_recording() {
+        let mut engine = Engine::new(44100.0, 44100.0);
+        play_sine(&mut engine, 440.0);
+
+        let mut out_l = [0.0; 300];
+        let mut out_r = [0.0; 300];
+        engine.render(0.0, &mut out_l, &mut out_r);
+        assert!(!engine.is_recording());
+        assert!(engine.stop_recording(0, BitDepth::Float32).is_none());
+
+        // nothing rendered, nothing recorded
+        engine.start_recording();
+        assert!(engine.stop_recording(0, BitDepth::Float32).is_none());
+
+        // only what's rendered while recording ends up in the file
+        engine.start_recording();
+        for _ in 0..10 {
+            engine.render(0.0, &mut out_l, &mut out_r);
+        }
+        let wav = engine.stop_recording(0, BitDepth::Float32).unwrap().to_vec();
+        assert!(!engine.is_recording());
+        assert_eq!(wav.len(), 44 + 3000 * 2 * 4);
+        assert_eq!(&wav[24..28], &44100u32.to_le_bytes());
+
+        // the last block, left channel
+        let last = 44 + 2999 * 2 * 4;
+        assert_eq!(f32::from_le_bytes([wav[last], wav[last + 1], wav[last + 2], wav[last + 3]]), out_l[299]);
+
+        // resampled, about as long at the new samplerate
+        engine.start_recording();
+        for _ in 0..10 {
+            engine.render(0.0, &mut out_l, &mut out_r);
+        }
+        let wav = engine.stop_recording(48000, BitDepth::Int16).unwrap();
+        assert_eq!(wav.len(), 44 + 3265 * 2 * 2);
+        assert_eq!(&wav[24..28], &48000u32.to_le_bytes());
+    }
+
+    #[test]
+    fn engine_test_capture() {
+        let mut engine = Engine::new(44100.0, 44100.0);
+        engine.set_dc_blocker(false);
+        play_sine(&mut engine, 440.0);
+
+        // starting in the middle of the second block, for 200 samples
+        let id = engine.start_capture(400.0 / 44100.0, 200.0 / 44100.0);
+        let mut out_l = [0.0; 300];
+        let mut out_r = [0.0; 300];
+        let mut rendered = Vec::new();
+        for block in 0..3 {
+            engine.render(block as f64 * 300.0 / 44100.0, &mut out_l, &mut out_r);
+            rendered.extend(out_l.iter().zip(out_r.iter()).map(|(l, r)| (l + r) * 0.5));
+            if block < 1 {
+                assert_eq!(engine.finished_capture(), None);
+            }
+        }
+        assert_eq!(engine.finished_capture(), Some(id));
+
+        let sample_buf = engine.store_capture(id, None).unwrap();
+        assert_eq!(engine.finished_capture(), None);
+        assert!(engine.store_capture(id, None).is_none());
+
+        // what was played, from the start time on, padded
+        let buffer = &engine.buffers[sample_buf];
+        assert_eq!(buffer.len(), 203);
+        assert_eq!(&buffer[1..201], &rendered[400..600]);
+        assert_eq!(engine.buffer_info[sample_buf].length, 200);
+    }
+
+    #[test]
+    fn engine_test_takes() {
+        let mut engine = Engine::new(44100.0, 44100.0);
+        engine.set_dc_blocker(false);
+        play_sine(&mut engine, 440.0);
+
+        // the master from the middle of the fourth block on, and a stem of a bus nothing plays on
+        let master = engine.start_take(None, 400.0 / 44100.0);
+        let stem = engine.start_take(Some(3), 0.0);
+        let mut out_l = [0.0; 128];
+        let mut out_r = [0.0; 128];
+        let mut rendered = Vec::new();
+        for block in 0..8 {
+            engine.render(block as f64 * 128.0 / 44100.0, &mut out_l, &mut out_r);
+            rendered.extend_from_slice(&out_l);
+            if block == 5 {
+                engine.stop_take(master, 600.0 / 44100.0);
+            }
+        }
+
+        // what was played, between the punch-in and the punch-out
+        let wav = engine.export_take(master, 0, BitDepth::Float32).unwrap().to_vec();
+        assert_eq!(wav.len(), 44 + 200 * 2 * 4);
+        for (i, sample) in rendered[400..600].iter().enumerate() {
+            let pos = 44 + i * 2 * 4;
+            assert_eq!(f32::from_le_bytes([wav[pos], wav[pos + 1], wav[pos + 2], wav[pos + 3]]), *sample);
+        }
+
+        // still going, silent
+        let wav = engine.export_take(stem, 0, BitDepth::Float32).unwrap().to_vec();
+        assert_eq!(wav.len(), 44 + 1024 * 2 * 4);
+        assert!(wav[44..].iter().all(|byte| *byte == 0));
+
+        engine.discard_take(master);
+        assert!(engine.export_take(master, 0, BitDepth::Float32).is_none());
+    }
+
+    #[test]
+    fn engine_test_same_as_ruffbox() {
+        let mut engine = Engine::new(44100.0, 44100.0);
+        let mut ruff = Ruffbox::new();
+        play_sine(&mut engine, 440.0);
+        play_sine(&mut ruff, 440.0);
+
+        let mut out_l = [0.0; 128];
+        let mut out_r = [0.0; 128];
+        engine.render(0.0, &mut out_l, &mut out_r);
+        let out = ruff.process(0.0);
+
+        for i in 0..128 {
+            assert_approx_eq::assert_approx_eq!(out_l[i], out[0][i], 0.00001);
+        }
+    }
+
+    #[test]
+    fn engine_test_block_sizes() {
+        let mut engine_small = Engine::new(44100.0, 44100.0);
+        let mut engine_large = Engine::new(44100.0, 44100.0);
+        play_sine(&mut engine_small, 440.0);
+        play_sine(&mut engine_large, 440.0);
+
+        let mut small_l = vec![0.0; 1000];
+        let mut small_r = vec![0.0; 1000];
+        for i in 0..10 {
+            let range = i * 100..(i + 1) * 100;
+            engine_small.render(i as f64 * 100.0 / 44100.0, &mut small_l[range.clone()], &mut small_r[range]);
+        }
+
+        let mut large_l = vec![0.0; 1000];
+        let mut large_r = vec![0.0; 1000];
+        engine_large.render(0.0, &mut large_l, &mut large_r);
+
+        for i in 0..1000 {
+            assert_approx_eq::assert_approx_eq!(small_l[i], large_l[i], 0.00001);
+        }
+    }
+
+    #[test]
+    fn engine_test_lower_internal_samplerate() {
+        let mut engine = Engine::new(22050.0, 44100.0);
+        play_sine(&mut engine, 441.0);
+
+        let mut out_l = vec![0.0; 44100];
+        let mut out_r = vec![0.0; 44100];
+        engine.render(0.0, &mut out_l, &mut out_r);
+
+        // still 441Hz at the output
+        let crossings = out_l.windows(2).filter(|w| (w[0] > 0.0) != (w[1] > 0.0)).count();
+        assert!((crossings as i32 - 882).abs() < 5);
+    }
+}
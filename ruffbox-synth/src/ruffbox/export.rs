@@ -0,0 +1,219 @@
+use crate::ruffbox::synth::noise::Noise;
+
+use std::f32::consts::PI;
+
+// EXPORT
+// Recordings are written as WAV files, in 16 bit (dithered), 24 bit or
+// 32 bit float, at the samplerate they were recorded at or resampled to
+// another one, so a bounce is ready to be released as it is.
+
+/// sample format of an exported file
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BitDepth {
+    /// with TPDF dither
+    Int16,
+    Int24,
+    Float32,
+}
+
+impl BitDepth {
+    fn bytes(&self) -> usize {
+        match self {
+            BitDepth::Int16 => 2,
+            BitDepth::Int24 => 3,
+            BitDepth::Float32 => 4,
+        }
+    }
+}
+
+/// half the width of the resampling filter, in samples of the lower samplerate
+const RESAMPLE_HALF_WIDTH: f32 = 32.0;
+
+/// Resample with a windowed sinc, with the cutoff lowered to the new nyquist
+/// frequency when going down, so nothing folds back.
+pub fn resample(samples: &[f32], from_sr: f32, to_sr: f32) -> Vec<f32> {
+    if from_sr == to_sr || samples.is_empty() {
+        return samples.to_vec();
+    }
+
+    let step = from_sr as f64 / to_sr as f64;
+    let cutoff = (to_sr / from_sr).min(1.0);
+    let half_width = RESAMPLE_HALF_WIDTH / cutoff;
+    let taps = half_width.ceil() as isize;
+    let len = (samples.len() as f64 / step).round() as usize;
+
+    (0..len)
+        .map(|i| {
+            let pos = i as f64 * step;
+            let idx = pos.floor() as isize;
+            let frac = pos.fract() as f32;
+
+            let mut out = 0.0;
+            for k in (1 - taps)..=taps {
+                let x = k as f32 - frac;
+                if x.abs() >= half_width {
+                    continue;
+                }
+                // nothing before the start or after the end
+                let sample = if idx + k < 0 { 0.0 } else { samples.get((idx + k) as usize).cloned().unwrap_or(0.0) };
+                let sinc = if x == 0.0 { 1.0 } else { (PI * cutoff * x).sin() / (PI * cutoff * x) };
+                let window = 0.5 + 0.5 * (PI * x / half_width).cos();
+                out += sample * cutoff * sinc * window;
+            }
+            out
+        })
+        .collect()
+}
+
+/// Triangular (TPDF) dither, the sum of two uniform noises of one step each,
+/// so the rounding error doesn't follow the signal.
+struct Dither {
+    noise: Noise,
+}
+
+impl Dither {
+    fn new() -> Self {
+        Dither { noise: Noise::new() }
+    }
+
+    /// -1..1 (in steps of the target format)
+    fn next(&mut self) -> f32 {
+        self.noise.uniform() - self.noise.uniform()
+    }
+}
+
+/// Interleave the channels (of the same length) and write them as a WAV file.
+pub fn encode_wav(channels: &[&[f32]], samplerate: u32, depth: BitDepth) -> Vec<u8> {
+    let frames = channels.iter().map(|channel| channel.len()).min().unwrap_or(0);
+    let block_align = channels.len() * depth.bytes();
+    let data_len = frames * block_align;
+
+    let mut wav = Vec::with_capacity(44 + data_len);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len as u32).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    // 1 = integer pcm, 3 = float
+    let format: u16 = if depth == BitDepth::Float32 { 3 } else { 1 };
+    wav.extend_from_slice(&format.to_le_bytes());
+    wav.extend_from_slice(&(channels.len() as u16).to_le_bytes());
+    wav.extend_from_slice(&samplerate.to_le_bytes());
+    wav.extend_from_slice(&(samplerate * block_align as u32).to_le_bytes());
+    wav.extend_from_slice(&(block_align as u16).to_le_bytes());
+    wav.extend_from_slice(&(depth.bytes() as u16 * 8).to_le_bytes());
+
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&(data_len as u32).to_le_bytes());
+
+    let mut dither = Dither::new();
+    for frame in 0..frames {
+        for channel in channels.iter() {
+            let sample = channel[frame];
+            match depth {
+                BitDepth::Int16 => {
+                    let value = (sample * 32767.0 + dither.next()).round().clamp(-32768.0, 32767.0) as i16;
+                    wav.extend_from_slice(&value.to_le_bytes());
+                }
+                BitDepth::Int24 => {
+                    let value = (sample as f64 * 8_388_607.0).round().clamp(-8_388_608.0, 8_388_607.0) as i32;
+                    wav.extend_from_slice(&value.to_le_bytes()[..3]);
+                }
+                BitDepth::Float32 => {
+                    wav.extend_from_slice(&sample.to_le_bytes());
+                }
+            }
+        }
+    }
+
+    wav
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    fn sine(freq: f32, sr: f32, len: usize) -> Vec<f32> {
+        (0..len).map(|i| (2.0 * PI * freq * i as f32 / sr).sin() * 0.5).collect()
+    }
+
+    fn u32_at(wav: &[u8], pos: usize) -> u32 {
+        u32::from_le_bytes([wav[pos], wav[pos + 1], wav[pos + 2], wav[pos + 3]])
+    }
+
+    fn u16_at(wav: &[u8], pos: usize) -> u16 {
+        u16::from_le_bytes([wav[pos], wav[pos + 1]])
+    }
+
+    #[test]
+    fn export_test_header() {
+        let left = vec![0.0; 100];
+        let right = vec![0.0; 100];
+        for (depth, format, bits) in [(BitDepth::Int16, 1, 16), (BitDepth::Int24, 1, 24), (BitDepth::Float32, 3, 32)].iter() {
+            let wav = encode_wav(&[&left, &right], 48000, *depth);
+            let data_len = 100 * 2 * *bits as usize / 8;
+            assert_eq!(wav.len(), 44 + data_len);
+            assert_eq!(&wav[0..4], b"RIFF");
+            assert_eq!(u32_at(&wav, 4) as usize, 36 + data_len);
+            assert_eq!(u16_at(&wav, 20), *format);
+            assert_eq!(u16_at(&wav, 22), 2);
+            assert_eq!(u32_at(&wav, 24), 48000);
+            assert_eq!(u32_at(&wav, 28), 48000 * 2 * *bits / 8);
+            assert_eq!(u16_at(&wav, 34), *bits as u16);
+            assert_eq!(&wav[36..40], b"data");
+            assert_eq!(u32_at(&wav, 40) as usize, data_len);
+        }
+    }
+
+    #[test]
+    fn export_test_sample_values() {
+        let samples = [0.5, -1.0, 1.0];
+
+        let wav = encode_wav(&[&samples], 44100, BitDepth::Float32);
+        assert_eq!(f32::from_le_bytes([wav[48], wav[49], wav[50], wav[51]]), -1.0);
+
+        let wav = encode_wav(&[&samples], 44100, BitDepth::Int24);
+        assert_eq!(&wav[44..47], &[0x00, 0x00, 0x40]);
+        assert_eq!(&wav[47..50], &[0x01, 0x00, 0x80]);
+        assert_eq!(&wav[50..53], &[0xff, 0xff, 0x7f]);
+    }
+
+    #[test]
+    fn export_test_dither() {
+        // silence stays within a step of zero, but isn't all zero ...
+        let silence = vec![0.0; 1000];
+        let wav = encode_wav(&[&silence], 44100, BitDepth::Int16);
+        let values: Vec<i16> = wav[44..].chunks(2).map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]])).collect();
+        assert!(values.iter().all(|value| value.abs() <= 1));
+        assert!(values.iter().any(|value| *value != 0));
+
+        // ... and a level in between two steps comes out right on average
+        let level = vec![0.25 / 32767.0; 10000];
+        let wav = encode_wav(&[&level], 44100, BitDepth::Int16);
+        let mean = wav[44..].chunks(2).map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]) as f32).sum::<f32>() / 10000.0;
+        assert!((mean - 0.25).abs() < 0.05);
+    }
+
+    #[test]
+    fn export_test_resample() {
+        let input = sine(1000.0, 44100.0, 44100);
+        let output = resample(&input, 44100.0, 48000.0);
+        assert_eq!(output.len(), 48000);
+
+        // away from the edges, it's the same sine at the new samplerate
+        let expected = sine(1000.0, 48000.0, 48000);
+        for i in 1000..47000 {
+            assert!((output[i] - expected[i]).abs() < 0.001);
+        }
+
+        // going down, what's above the new nyquist frequency is gone
+        let high = sine(20000.0, 44100.0, 44100);
+        let output = resample(&high, 44100.0, 22050.0);
+        assert_eq!(output.len(), 22050);
+        assert!(output[1000..21000].iter().all(|sample| sample.abs() < 0.01));
+    }
+}
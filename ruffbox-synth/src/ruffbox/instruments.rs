@@ -0,0 +1,150 @@
+/// A range of keys (midi notes) played by one sample, recorded at the root key.
+/// Zones can be limited to a range of velocities, too.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyZone {
+    pub lokey: i32,
+    pub hikey: i32,
+    pub lovel: i32,
+    pub hivel: i32,
+    pub root: i32,
+    pub sample_buf: usize,
+    /// on top of the repitching, for tuning and samples at another samplerate
+    pub rate: f32,
+    pub gain: f32,
+}
+
+impl KeyZone {
+    /// all velocities, played as recorded
+    pub fn new(lokey: i32, hikey: i32, root: i32, sample_buf: usize) -> Self {
+        KeyZone {
+            lokey: lokey.min(hikey),
+            hikey: lokey.max(hikey),
+            lovel: 0,
+            hivel: 127,
+            root,
+            sample_buf,
+            rate: 1.0,
+            gain: 1.0,
+        }
+    }
+
+    fn has_velocity(&self, velocity: i32) -> bool {
+        velocity >= self.lovel && velocity <= self.hivel
+    }
+
+    /// how many keys the note lies outside of the zone, 0 if it's inside
+    fn distance(&self, note: i32) -> i32 {
+        (self.lokey - note).max(note - self.hikey).max(0)
+    }
+}
+
+/**
+ * A multi-sampled instrument, each note is played by the sample of the zone
+ * it falls into, repitched from the zone's root. Notes outside of all zones
+ * are played by the closest one, stretched further.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Instrument {
+    zones: Vec<KeyZone>,
+}
+
+impl Instrument {
+    pub fn new() -> Self {
+        Instrument { zones: Vec::new() }
+    }
+
+    pub fn add_zone(&mut self, lokey: i32, hikey: i32, root: i32, sample_buf: usize) {
+        self.zones.push(KeyZone::new(lokey, hikey, root, sample_buf));
+    }
+
+    /// the zone added last, to set its velocities, tuning and gain
+    pub fn last_zone_mut(&mut self) -> Option<&mut KeyZone> {
+        self.zones.last_mut()
+    }
+
+    /// The zone playing a frequency (in Hz) at a velocity (0 - 127), with the
+    /// playback rate that gets it there from the zone's root. Overlapping zones
+    /// go by the order they were added in. If no zone has the velocity, all are
+    /// considered.
+    pub fn zone(&self, freq: f32, velocity: i32) -> Option<(&KeyZone, f32)> {
+        let note = 69.0 + 12.0 * (freq / 440.0).log2();
+        let key = note.round() as i32;
+        let layered = self.zones.iter().any(|zone| zone.has_velocity(velocity));
+        let zone = self.zones.iter()
+            .filter(|zone| !layered || zone.has_velocity(velocity))
+            .min_by_key(|zone| zone.distance(key))?;
+        Some((zone, zone.rate * 2.0_f32.powf((note - zone.root as f32) / 12.0)))
+    }
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    fn midi_to_freq(note: f32) -> f32 {
+        440.0 * 2.0_f32.powf((note - 69.0) / 12.0)
+    }
+
+    #[test]
+    fn test_key_zones() {
+        let mut piano = Instrument::new();
+        piano.add_zone(0, 59, 48, 3);
+        piano.add_zone(60, 71, 64, 4);
+        piano.add_zone(72, 127, 76, 5);
+
+        // the root plays as recorded
+        let (zone, rate) = piano.zone(midi_to_freq(64.0), 127).unwrap();
+        assert_eq!(zone.sample_buf, 4);
+        assert_approx_eq::assert_approx_eq!(rate, 1.0, 0.0001);
+
+        // an octave down from the root of the lowest zone
+        let (zone, rate) = piano.zone(midi_to_freq(36.0), 127).unwrap();
+        assert_eq!(zone.sample_buf, 3);
+        assert_approx_eq::assert_approx_eq!(rate, 0.5, 0.0001);
+
+        // the top of a zone is still in it
+        assert_eq!(piano.zone(midi_to_freq(71.0), 127).unwrap().0.sample_buf, 4);
+        assert_eq!(piano.zone(midi_to_freq(72.0), 127).unwrap().0.sample_buf, 5);
+    }
+
+    #[test]
+    fn test_closest_zone() {
+        let mut strings = Instrument::new();
+        assert!(strings.zone(440.0, 127).is_none());
+
+        strings.add_zone(55, 60, 57, 0);
+        strings.add_zone(64, 67, 65, 1);
+
+        // in the gap and beyond the range, the nearest zone stretches
+        assert_eq!(strings.zone(midi_to_freq(62.0), 127).unwrap().0.sample_buf, 0);
+        let (zone, rate) = strings.zone(midi_to_freq(77.0), 127).unwrap();
+        assert_eq!(zone.sample_buf, 1);
+        assert_approx_eq::assert_approx_eq!(rate, 2.0, 0.0001);
+    }
+
+    #[test]
+    fn test_velocity_layers() {
+        let mut drum = Instrument::new();
+        assert!(drum.last_zone_mut().is_none());
+        drum.add_zone(0, 127, 60, 0);
+        drum.last_zone_mut().unwrap().hivel = 63;
+        drum.add_zone(0, 127, 60, 1);
+        let hard = drum.last_zone_mut().unwrap();
+        hard.lovel = 64;
+        // recorded a fifth down at a lower samplerate
+        hard.rate = 1.5;
+
+        assert_eq!(drum.zone(midi_to_freq(60.0), 20).unwrap().0.sample_buf, 0);
+        let (zone, rate) = drum.zone(midi_to_freq(60.0), 100).unwrap();
+        assert_eq!(zone.sample_buf, 1);
+        assert_approx_eq::assert_approx_eq!(rate, 1.5, 0.0001);
+
+        // without velocities, any zone goes
+        let mut keys = Instrument::new();
+        keys.add_zone(0, 127, 60, 1);
+        keys.last_zone_mut().unwrap().lovel = 64;
+        assert_eq!(keys.zone(midi_to_freq(60.0), 20).unwrap().0.sample_buf, 1);
+    }
+}
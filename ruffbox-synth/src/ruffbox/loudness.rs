@@ -0,0 +1,263 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+use crate::ruffbox::synth::oversampling::{Oversampler, MAX_FACTOR};
+
+// LOUDNESS
+// Loudness of the master output as in ITU-R BS.1770 / EBU R 128: the signal
+// is K-weighted (a high shelf for the head, a highpass for the low end), its
+// energy is taken per 100ms block, short-term loudness is the last 3 seconds,
+// integrated loudness everything since the last reset, gated so silence and
+// quiet passages don't pull it down. The true peak is taken at 4x the samplerate,
+// to catch the peaks between samples a DAC (or a lossy encoder) would make.
+
+/// short-term loudness, in 100ms blocks
+const SHORT_TERM_BLOCKS: usize = 30;
+/// the blocks gated for integrated loudness are 400ms, overlapping by 75%
+const GATING_BLOCKS: usize = 4;
+/// blocks quieter than this don't count at all ...
+const ABSOLUTE_GATE: f64 = -70.0;
+/// ... neither do blocks this much below the loudness of the ones that do
+const RELATIVE_GATE: f64 = -10.0;
+/// gating blocks are kept in a histogram, of this resolution (in LU) ...
+const HISTOGRAM_STEP: f64 = 0.1;
+/// ... from the absolute gate up to +30 LUFS
+const HISTOGRAM_BINS: usize = 1000;
+
+/// mean square (summed over channels) to LUFS
+fn loudness(energy: f64) -> f32 {
+    if energy <= 0.0 {
+        f32::NEG_INFINITY
+    } else {
+        (-0.691 + 10.0 * energy.log10()) as f32
+    }
+}
+
+/// second order filter, direct form I
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Biquad { b, a, x: [0.0; 2], y: [0.0; 2] }
+    }
+
+    fn process_sample(&mut self, sample: f64) -> f64 {
+        let out = self.b[0] * sample + self.b[1] * self.x[0] + self.b[2] * self.x[1] - self.a[0] * self.y[0] - self.a[1] * self.y[1];
+        self.x = [sample, self.x[0]];
+        self.y = [out, self.y[0]];
+        out
+    }
+}
+
+/// the K-weighting pre-filter of BS.1770, for any samplerate
+/// (the coefficients of the standard are for 48kHz only)
+struct KWeighting {
+    shelf: Biquad,
+    highpass: Biquad,
+}
+
+impl KWeighting {
+    fn new(sr: f32) -> Self {
+        let sr = sr as f64;
+
+        let (freq, gain, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+        let k = (PI * freq / sr).tan();
+        let vh = 10.0_f64.powf(gain / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad::new(
+            [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        let (freq, q) = (38.13547087602444, 0.5003270373238773);
+        let k = (PI * freq / sr).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let highpass = Biquad::new([1.0, -2.0, 1.0], [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0]);
+
+        KWeighting { shelf, highpass }
+    }
+
+    fn process_sample(&mut self, sample: f32) -> f64 {
+        self.highpass.process_sample(self.shelf.process_sample(sample as f64))
+    }
+}
+
+/**
+ * Measures short-term and integrated loudness (in LUFS) and true peak
+ * (in dBTP) of a stereo signal, block by block.
+ */
+pub struct LoudnessMeter {
+    weighting: [KWeighting; 2],
+    oversamplers: [Oversampler; 2],
+    samplerate: f32,
+    /// samples in a 100ms block
+    block_size: usize,
+    /// weighted energy and samples of the current block so far
+    block_energy: f64,
+    block_fill: usize,
+    /// mean square of the last 100ms blocks, the most recent last
+    blocks: VecDeque<f64>,
+    /// count and summed energy of the gating blocks, per loudness
+    histogram: Vec<(usize, f64)>,
+    true_peak: f32,
+}
+
+impl LoudnessMeter {
+    pub fn new(sr: f32) -> Self {
+        let mut oversamplers = [Oversampler::new(), Oversampler::new()];
+        for oversampler in oversamplers.iter_mut() {
+            oversampler.set_factor(4.0);
+        }
+
+        LoudnessMeter {
+            weighting: [KWeighting::new(sr), KWeighting::new(sr)],
+            oversamplers,
+            samplerate: sr,
+            block_size: (sr / 10.0).round() as usize,
+            block_energy: 0.0,
+            block_fill: 0,
+            blocks: VecDeque::with_capacity(SHORT_TERM_BLOCKS),
+            histogram: vec![(0, 0.0); HISTOGRAM_BINS],
+            true_peak: 0.0,
+        }
+    }
+
+    /// start measuring anew
+    pub fn reset(&mut self) {
+        *self = LoudnessMeter::new(self.samplerate);
+    }
+
+    pub fn process(&mut self, block: &[[f32; 128]; 2]) {
+        let mut upsampled = [0.0; 128 * MAX_FACTOR];
+        for (channel, oversampler) in block.iter().zip(self.oversamplers.iter_mut()) {
+            oversampler.upsample(channel, &mut upsampled);
+            for sample in upsampled.iter() {
+                self.true_peak = self.true_peak.max(sample.abs());
+            }
+        }
+
+        for (left, right) in block[0].iter().zip(block[1].iter()) {
+            self.block_energy += self.weighting[0].process_sample(*left).powi(2)
+                + self.weighting[1].process_sample(*right).powi(2);
+            self.block_fill += 1;
+
+            if self.block_fill == self.block_size {
+                self.finish_block();
+            }
+        }
+    }
+
+    fn finish_block(&mut self) {
+        if self.blocks.len() == SHORT_TERM_BLOCKS {
+            self.blocks.pop_front();
+        }
+        self.blocks.push_back(self.block_energy / self.block_size as f64);
+        self.block_energy = 0.0;
+        self.block_fill = 0;
+
+        // a new gating block every 100ms, once there's 400ms
+        if self.blocks.len() >= GATING_BLOCKS {
+            let energy = self.blocks.iter().rev().take(GATING_BLOCKS).sum::<f64>() / GATING_BLOCKS as f64;
+            let bin = ((loudness(energy) as f64 - ABSOLUTE_GATE) / HISTOGRAM_STEP).floor();
+            if bin >= 0.0 {
+                let (count, sum) = &mut self.histogram[(bin as usize).min(HISTOGRAM_BINS - 1)];
+                *count += 1;
+                *sum += energy;
+            }
+        }
+    }
+
+    /// over the last 3 seconds (or what there is of them so far)
+    pub fn short_term(&self) -> f32 {
+        if self.blocks.is_empty() {
+            return f32::NEG_INFINITY;
+        }
+        loudness(self.blocks.iter().sum::<f64>() / self.blocks.len() as f64)
+    }
+
+    /// since the last reset
+    pub fn integrated(&self) -> f32 {
+        let mean = |bins: &[(usize, f64)]| {
+            let (count, sum) = bins.iter().fold((0, 0.0), |(count, sum), bin| (count + bin.0, sum + bin.1));
+            if count == 0 { 0.0 } else { sum / count as f64 }
+        };
+
+        let gate = loudness(mean(&self.histogram)) as f64 + RELATIVE_GATE;
+        if gate.is_infinite() {
+            return f32::NEG_INFINITY;
+        }
+        let first_bin = (((gate - ABSOLUTE_GATE) / HISTOGRAM_STEP).ceil().max(0.0) as usize).min(HISTOGRAM_BINS);
+        loudness(mean(&self.histogram[first_bin..]))
+    }
+
+    /// highest true peak since the last reset, in dBTP
+    pub fn true_peak(&self) -> f32 {
+        20.0 * self.true_peak.log10()
+    }
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    /// feed the meter a sine on both channels, at the given amplitude and phase
+    fn feed_sine(meter: &mut LoudnessMeter, freq: f32, amp: f32, phase: f32, seconds: f32) {
+        let sr = 48000.0;
+        let blocks = (seconds * sr / 128.0) as usize;
+        let mut count = 0;
+        for _ in 0..blocks {
+            let mut block = [[0.0; 128]; 2];
+            for sample in block[0].iter_mut() {
+                *sample = amp * (2.0 * std::f32::consts::PI * freq * count as f32 / sr + phase).sin();
+                count += 1;
+            }
+            block[1] = block[0];
+            meter.process(&block);
+        }
+    }
+
+    #[test]
+    fn loudness_test_reference_level() {
+        // a 1kHz sine at -18 dBFS on both channels reads -18 LUFS (EBU Tech 3341)
+        let mut meter = LoudnessMeter::new(48000.0);
+        feed_sine(&mut meter, 1000.0, 10.0_f32.powf(-18.0 / 20.0), 0.0, 3.0);
+        assert_approx_eq::assert_approx_eq!(meter.short_term(), -18.0, 0.1);
+        assert_approx_eq::assert_approx_eq!(meter.integrated(), -18.0, 0.1);
+    }
+
+    #[test]
+    fn loudness_test_gating() {
+        // quiet passages don't pull the integrated loudness down ...
+        let mut meter = LoudnessMeter::new(48000.0);
+        feed_sine(&mut meter, 1000.0, 10.0_f32.powf(-20.0 / 20.0), 0.0, 8.0);
+        feed_sine(&mut meter, 1000.0, 10.0_f32.powf(-40.0 / 20.0), 0.0, 4.0);
+        assert_approx_eq::assert_approx_eq!(meter.integrated(), -20.0, 0.1);
+        // ... but the short-term loudness follows them
+        assert_approx_eq::assert_approx_eq!(meter.short_term(), -40.0, 0.1);
+
+        // nothing but silence is no loudness at all
+        let mut meter = LoudnessMeter::new(48000.0);
+        feed_sine(&mut meter, 1000.0, 0.0, 0.0, 2.0);
+        assert_eq!(meter.integrated(), f32::NEG_INFINITY);
+
+        meter.reset();
+        assert_eq!(meter.short_term(), f32::NEG_INFINITY);
+    }
+
+    #[test]
+    fn loudness_test_true_peak() {
+        // a quarter of the samplerate, sampled at 45 degrees off its peaks,
+        // the samples stay 3dB below the peak in between
+        let mut meter = LoudnessMeter::new(48000.0);
+        feed_sine(&mut meter, 12000.0, 1.0, std::f32::consts::PI / 4.0, 0.1);
+        assert_approx_eq::assert_approx_eq!(meter.true_peak(), 0.0, 0.3);
+    }
+}
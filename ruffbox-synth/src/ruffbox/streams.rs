@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+
+// STREAMS
+// Long samples (DJ tracks, field recordings ...) aren't loaded into memory as
+// a whole, but streamed in chunks. The host keeps the file and hands chunks
+// over as they're asked for: the one a voice starts in as soon as the voice is
+// triggered (that is, ahead of time, by the lookahead of the scheduler), then
+// always the one after the chunk a voice is playing, before it gets there.
+// Chunks nobody needed for a while are dropped again.
+
+/// frames per chunk, about 1.5 seconds at 44.1kHz
+pub const CHUNK_FRAMES: usize = 65536;
+/// how long (in seconds) a chunk is kept after it was last needed
+pub const KEEP_TIME: f64 = 2.0;
+
+struct Chunk {
+    samples: Vec<f32>,
+    /// stream time (in seconds) the chunk can be dropped at
+    keep_until: f64,
+}
+
+/**
+ * A long sample, of which only the chunks that are played (or are about to be)
+ * are in memory.
+ */
+pub struct SampleStream {
+    /// in frames, of the whole sample
+    length: usize,
+    chunks: HashMap<usize, Chunk>,
+    /// chunks asked for that aren't there yet, and when they're needed
+    requests: HashMap<usize, f64>,
+    now: f64,
+    /// frames played while their chunk wasn't there
+    underruns: usize,
+}
+
+impl SampleStream {
+    pub fn new(length: usize) -> Self {
+        SampleStream {
+            length,
+            chunks: HashMap::new(),
+            requests: HashMap::new(),
+            now: 0.0,
+            underruns: 0,
+        }
+    }
+
+    /// length in frames
+    pub fn frames(&self) -> usize {
+        self.length
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        (self.length + CHUNK_FRAMES - 1) / CHUNK_FRAMES
+    }
+
+    /// the current stream time, in seconds
+    pub fn now(&self) -> f64 {
+        self.now
+    }
+
+    /// Ask for the chunk holding the given frame, to be there at the given time.
+    pub fn request(&mut self, frame: usize, at: f64) {
+        let chunk = frame / CHUNK_FRAMES;
+        if chunk >= self.chunk_count() {
+            return;
+        }
+
+        match self.chunks.get_mut(&chunk) {
+            Some(resident) => resident.keep_until = resident.keep_until.max(at + KEEP_TIME),
+            None => {
+                let needed_at = self.requests.entry(chunk).or_insert(at);
+                *needed_at = needed_at.min(at);
+            },
+        }
+    }
+
+    /// the chunk that's needed the soonest of those that aren't there yet
+    pub fn next_request(&self) -> Option<usize> {
+        self.requests.iter()
+            .min_by(|a, b| a.1.partial_cmp(b.1).unwrap().then(a.0.cmp(b.0)))
+            .map(|(chunk, _)| *chunk)
+    }
+
+    /// Hand over the samples of a chunk (CHUNK_FRAMES of them, the last one
+    /// can be shorter). Returns false if there's no such chunk.
+    pub fn load_chunk(&mut self, chunk: usize, samples: &[f32]) -> bool {
+        if chunk >= self.chunk_count() {
+            return false;
+        }
+
+        let needed_at = self.requests.remove(&chunk).unwrap_or(self.now).max(self.now);
+        self.chunks.insert(chunk, Chunk { samples: samples.to_vec(), keep_until: needed_at + KEEP_TIME });
+        true
+    }
+
+    /// The sample at a frame, None if its chunk isn't there. Silence after the end.
+    pub fn frame(&self, frame: usize) -> Option<f32> {
+        if frame >= self.length {
+            return Some(0.0);
+        }
+        self.chunks.get(&(frame / CHUNK_FRAMES))
+            .map(|chunk| chunk.samples.get(frame % CHUNK_FRAMES).cloned().unwrap_or(0.0))
+    }
+
+    pub fn underrun(&mut self) {
+        self.underruns += 1;
+    }
+
+    /// frames that were played as silence because they weren't there in time
+    pub fn underruns(&self) -> usize {
+        self.underruns
+    }
+
+    /// Move on to the given stream time, dropping the chunks (and requests)
+    /// that aren't needed anymore.
+    pub fn set_time(&mut self, now: f64) {
+        self.now = now;
+        self.chunks.retain(|_, chunk| chunk.keep_until >= now);
+        self.requests.retain(|_, needed_at| *needed_at + KEEP_TIME >= now);
+    }
+
+    /// bytes of sample data that's in memory
+    pub fn memory(&self) -> usize {
+        self.chunks.values().map(|chunk| chunk.samples.len() * std::mem::size_of::<f32>()).sum()
+    }
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn streams_test_requests() {
+        let mut stream = SampleStream::new(CHUNK_FRAMES * 3 + 100);
+        assert_eq!(stream.chunk_count(), 4);
+        assert_eq!(stream.next_request(), None);
+
+        // the soonest needed first, past the end there's nothing to ask for
+        stream.request(CHUNK_FRAMES * 2 + 5, 3.0);
+        stream.request(10, 1.0);
+        stream.request(CHUNK_FRAMES * 5, 0.5);
+        assert_eq!(stream.next_request(), Some(0));
+
+        assert!(stream.load_chunk(0, &vec![0.5; CHUNK_FRAMES]));
+        assert!(!stream.load_chunk(4, &[0.5]));
+        assert_eq!(stream.next_request(), Some(2));
+        assert_eq!(stream.frame(10), Some(0.5));
+        assert_eq!(stream.frame(CHUNK_FRAMES * 2 + 5), None);
+        assert_eq!(stream.frame(CHUNK_FRAMES * 4), Some(0.0));
+    }
+
+    #[test]
+    fn streams_test_eviction() {
+        let mut stream = SampleStream::new(CHUNK_FRAMES * 2);
+        stream.request(0, 1.0);
+        stream.load_chunk(0, &vec![0.5; CHUNK_FRAMES]);
+        assert_eq!(stream.memory(), CHUNK_FRAMES * 4);
+
+        // kept while it's needed, and a while after
+        stream.set_time(2.0);
+        stream.request(0, 2.0);
+        stream.set_time(2.0 + KEEP_TIME);
+        assert_eq!(stream.frame(0), Some(0.5));
+        stream.set_time(2.1 + KEEP_TIME);
+        assert_eq!(stream.frame(0), None);
+        assert_eq!(stream.memory(), 0);
+
+        // requests nobody came back for go, too
+        stream.request(CHUNK_FRAMES, 5.0);
+        stream.set_time(5.1 + KEEP_TIME);
+        assert_eq!(stream.next_request(), None);
+    }
+}
@@ -1,4 +1,5 @@
 pub mod sampler;
+pub mod streamer;
 pub mod envelopes;
 pub mod routing;
 pub mod oscillators;
@@ -6,7 +7,22 @@ pub mod synths;
 pub mod filters;
 pub mod freeverb;
 pub mod delay;
+pub mod inserts;
+pub mod graph;
+pub mod spectral;
+pub mod noise;
+pub mod oversampling;
+pub mod binaural;
+pub mod ambisonics;
+pub mod stutter;
+pub mod gate;
 
+/// flush tiny values to zero, as recursive filters and feedback
+/// loops decaying into the denormal range can cause cpu spikes
+#[inline(always)]
+pub fn flush_denormal(value: f32) -> f32 {
+    if value.abs() < 1.0e-15 { 0.0 } else { value }
+}
 
 pub enum SynthState { 
     Fresh,
@@ -15,6 +31,7 @@ pub enum SynthState {
 
 /// a collection of common parameters
 #[allow(dead_code)]
+#[repr(C)]
 #[derive(Clone,Copy)]
 pub enum SynthParameter {
     Attack,                     // 0
@@ -47,6 +64,68 @@ pub enum SynthParameter {
     Samplerate,                 // 27 
     StereoPosition,             // 28
     Sustain,                    // 29
+    BitDepth,                   // 30
+    CompressorThreshold,        // 31
+    CompressorRatio,            // 32
+    Age,                        // 33
+    Freeze,                     // 34
+    Smear,                      // 35
+    PitchShift,                 // 36
+    Oversampling,               // 37
+    Interpolation,              // 38
+    Azimuth,                    // 39
+    Elevation,                  // 40
+}
+
+impl SynthParameter {
+    /// the parameter a short name (as in the patterns) stands for, the same
+    /// table as sourceParameterShortName in js/setup.js
+    pub fn from_name(name: &str) -> Option<SynthParameter> {
+        Some(match name {
+            "atk" => SynthParameter::Attack,
+            "dec" => SynthParameter::Decay,
+            "del-damp" => SynthParameter::DelayDampeningFrequency,
+            "del-fb" => SynthParameter::DelayFeedback,
+            "del" => SynthParameter::DelayMix,
+            "del-time" => SynthParameter::DelayTime,
+            "dur" => SynthParameter::Duration,
+            "freq" => SynthParameter::PitchFrequency,
+            "note" => SynthParameter::PitchNote,
+            "hp-freq" => SynthParameter::HighpassCutoffFrequency,
+            "hp-q" => SynthParameter::HighpassQFactor,
+            "lvl" => SynthParameter::Level,
+            "lp-freq" => SynthParameter::LowpassCutoffFrequency,
+            "lp-q" => SynthParameter::LowpassQFactor,
+            "lp-dist" => SynthParameter::LowpassFilterDistortion,
+            "pf-freq" => SynthParameter::PeakFrequency,
+            "pf-gain" => SynthParameter::PeakGain,
+            "pf-q" => SynthParameter::PeakQFactor,
+            "pw" => SynthParameter::Pulsewidth,
+            "rate" => SynthParameter::PlaybackRate,
+            "start" => SynthParameter::PlaybackStart,
+            "loop" => SynthParameter::PlaybackLoop,
+            "rel" => SynthParameter::Release,
+            "rev-damp" => SynthParameter::ReverbDampening,
+            "rev" => SynthParameter::ReverbMix,
+            "rev-rs" => SynthParameter::ReverbRoomsize,
+            "buf" => SynthParameter::SampleBufferNumber,
+            "sr" => SynthParameter::Samplerate,
+            "pos" => SynthParameter::StereoPosition,
+            "sus" => SynthParameter::Sustain,
+            "bits" => SynthParameter::BitDepth,
+            "thresh" => SynthParameter::CompressorThreshold,
+            "ratio" => SynthParameter::CompressorRatio,
+            "age" => SynthParameter::Age,
+            "hold" => SynthParameter::Freeze,
+            "smear" => SynthParameter::Smear,
+            "shift" => SynthParameter::PitchShift,
+            "os" => SynthParameter::Oversampling,
+            "interp" => SynthParameter::Interpolation,
+            "azi" => SynthParameter::Azimuth,
+            "elev" => SynthParameter::Elevation,
+            _ => return None,
+        })
+    }
 }
 
 pub enum SourceType {
@@ -55,6 +134,8 @@ pub enum SourceType {
     SineSynth,
     LFSawSynth,
     LFSquareSynth,
+    CustomVoice,
+    Streamer,
 }
 
 pub trait Source {
@@ -78,4 +159,34 @@ pub trait StereoSynth {
     fn get_next_block(&mut self, start_sample: usize) -> [[f32; 128]; 2];
     fn reverb_level(&self) -> f32;
     fn delay_level(&self) -> f32;
+    /// called on triggering, once the parameters are set, with the time the
+    /// voice is going to start at, i.e. to fetch what it needs ahead of time
+    fn prefetch(&mut self, _timestamp: f64) {}
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_parameter_names_match_js() {
+        // the browser sends the parameter numbers from its own table, so they can't drift apart
+        let setup = include_str!("../../../js/setup.js");
+        let table = setup.split("const sourceParameterShortName = Object.freeze({").nth(1).unwrap();
+        let table = table.split("});").next().unwrap();
+
+        let mut count = 0;
+        for entry in table.lines().filter(|line| line.contains(':')) {
+            let mut parts = entry.split(':');
+            let name = parts.next().unwrap().trim().trim_matches('"');
+            let idx: usize = parts.next().unwrap().trim().trim_end_matches(',').parse().unwrap();
+            let par = SynthParameter::from_name(name).unwrap_or_else(|| panic!("{} is missing", name));
+            assert_eq!(par as usize, idx, "{}", name);
+            count += 1;
+        }
+        assert_eq!(count, 41);
+        assert!(SynthParameter::from_name("cut").is_none());
+    }
 }
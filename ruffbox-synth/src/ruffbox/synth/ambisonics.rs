@@ -0,0 +1,162 @@
+use crate::ruffbox::synth::SynthParameter;
+
+/// the direction of the stereo parts (insert chains, reverb, delay) in the sound field
+const STEREO_SPREAD: f32 = 30.0;
+
+/// first-order AmbiX (ACN channel order W Y Z X, SN3D normalization) gains for a direction
+fn direction_gains(azimuth: f32, elevation: f32) -> [f32; 4] {
+    let (azi_sin, azi_cos) = azimuth.to_radians().sin_cos();
+    let (elev_sin, elev_cos) = elevation.to_radians().sin_cos();
+    [1.0, azi_sin * elev_cos, elev_sin, azi_cos * elev_cos]
+}
+
+/**
+ * Places a mono voice in a first-order ambisonic sound field, to be decoded
+ * to whatever speaker layout downstream. Azimuth is in degrees, counterclockwise
+ * (0 is the front, 90 left), elevation in degrees up from the horizon.
+ * Changes of direction are ramped over a block.
+ */
+pub struct AmbisonicEncoder {
+    azimuth: f32,
+    elevation: f32,
+    stereo_position: f32,
+    gains: [f32; 4],
+    prev_gains: [f32; 4],
+}
+
+impl Default for AmbisonicEncoder {
+    fn default() -> Self {
+        AmbisonicEncoder::new()
+    }
+}
+
+impl AmbisonicEncoder {
+    pub fn new() -> Self {
+        let gains = direction_gains(0.0, 0.0);
+        AmbisonicEncoder {
+            azimuth: 0.0,
+            elevation: 0.0,
+            stereo_position: 0.0,
+            gains,
+            prev_gains: gains,
+        }
+    }
+
+    /// the stereo position maps to 90 (left) .. -90 (right) degrees,
+    /// the azimuth parameter overrides it
+    pub fn set_parameter(&mut self, par: SynthParameter, value: f32) {
+        match par {
+            SynthParameter::StereoPosition => {
+                self.stereo_position = value;
+                self.azimuth = value.clamp(-1.0, 1.0) * -90.0;
+            },
+            SynthParameter::Azimuth => self.azimuth = value,
+            SynthParameter::Elevation => self.elevation = value.clamp(-90.0, 90.0),
+            _ => return,
+        };
+        self.gains = direction_gains(self.azimuth, self.elevation);
+    }
+
+    /// the last stereo position, for voices that end up being panned in stereo after all
+    pub fn stereo_position(&self) -> f32 {
+        self.stereo_position
+    }
+
+    /// adds the encoded block to the four channels
+    pub fn process_block(&mut self, block: [f32; 128], bformat: &mut [[f32; 128]; 4]) {
+        for (channel, (gain, prev_gain)) in bformat.iter_mut().zip(self.gains.iter().zip(self.prev_gains.iter())) {
+            for (i, (out, sample)) in channel.iter_mut().zip(block.iter()).enumerate() {
+                let ramp = (i + 1) as f32 / 128.0;
+                *out += sample * (ramp * gain + (1.0 - ramp) * prev_gain);
+            }
+        }
+        self.prev_gains = self.gains;
+    }
+}
+
+/// the stereo parts of the mix are added as two sources, left and right of the front
+pub fn encode_stereo(block: &[[f32; 128]; 2], bformat: &mut [[f32; 128]; 4]) {
+    let gains = [direction_gains(STEREO_SPREAD, 0.0), direction_gains(-STEREO_SPREAD, 0.0)];
+    for (side, side_gains) in block.iter().zip(gains.iter()) {
+        for (channel, gain) in bformat.iter_mut().zip(side_gains.iter()) {
+            for (out, sample) in channel.iter_mut().zip(side.iter()) {
+                *out += sample * gain;
+            }
+        }
+    }
+}
+
+/// a stereo version to listen to, two cardioids pointing left and right
+pub fn decode_stereo(bformat: &[[f32; 128]; 4]) -> [[f32; 128]; 2] {
+    let mut out_buf = [[0.0; 128]; 2];
+    for i in 0..128 {
+        out_buf[0][i] = 0.5 * (bformat[0][i] + bformat[1][i]);
+        out_buf[1][i] = 0.5 * (bformat[0][i] - bformat[1][i]);
+    }
+    out_buf
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    fn encode(encoder: &mut AmbisonicEncoder) -> [[f32; 128]; 4] {
+        let mut bformat = [[0.0; 128]; 4];
+        // twice, past the ramp
+        encoder.process_block([1.0; 128], &mut bformat);
+        bformat = [[0.0; 128]; 4];
+        encoder.process_block([1.0; 128], &mut bformat);
+        bformat
+    }
+
+    #[test]
+    fn ambisonic_encoder_test_directions() {
+        let mut encoder = AmbisonicEncoder::new();
+
+        // front
+        let bformat = encode(&mut encoder);
+        assert_approx_eq::assert_approx_eq!(bformat[0][64], 1.0, 0.0001);
+        assert_approx_eq::assert_approx_eq!(bformat[1][64], 0.0, 0.0001);
+        assert_approx_eq::assert_approx_eq!(bformat[2][64], 0.0, 0.0001);
+        assert_approx_eq::assert_approx_eq!(bformat[3][64], 1.0, 0.0001);
+
+        // hard left, same as 90 degrees
+        encoder.set_parameter(SynthParameter::StereoPosition, -1.0);
+        let bformat = encode(&mut encoder);
+        assert_approx_eq::assert_approx_eq!(bformat[1][64], 1.0, 0.0001);
+        assert_approx_eq::assert_approx_eq!(bformat[3][64], 0.0, 0.0001);
+
+        // straight up
+        encoder.set_parameter(SynthParameter::Elevation, 90.0);
+        let bformat = encode(&mut encoder);
+        assert_approx_eq::assert_approx_eq!(bformat[0][64], 1.0, 0.0001);
+        assert_approx_eq::assert_approx_eq!(bformat[1][64], 0.0, 0.0001);
+        assert_approx_eq::assert_approx_eq!(bformat[2][64], 1.0, 0.0001);
+    }
+
+    #[test]
+    fn ambisonic_encoder_test_ramp() {
+        let mut encoder = AmbisonicEncoder::new();
+        encoder.set_parameter(SynthParameter::Azimuth, 90.0);
+
+        let mut bformat = [[0.0; 128]; 4];
+        encoder.process_block([1.0; 128], &mut bformat);
+
+        // from front to left over the block
+        assert!(bformat[1][0] < 0.1);
+        assert_approx_eq::assert_approx_eq!(bformat[1][127], 1.0, 0.0001);
+    }
+
+    #[test]
+    fn ambisonic_test_stereo_decode() {
+        let mut encoder = AmbisonicEncoder::new();
+        encoder.set_parameter(SynthParameter::Azimuth, 90.0);
+        let bformat = encode(&mut encoder);
+
+        let stereo = decode_stereo(&bformat);
+        assert_approx_eq::assert_approx_eq!(stereo[0][64], 1.0, 0.0001);
+        assert_approx_eq::assert_approx_eq!(stereo[1][64], 0.0, 0.0001);
+    }
+}
@@ -0,0 +1,255 @@
+use crate::ruffbox::synth::spectral::fft;
+use crate::ruffbox::synth::SynthParameter;
+
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+/// in meters, an average head
+const HEAD_RADIUS: f32 = 0.0875;
+const SPEED_OF_SOUND: f32 = 343.0;
+/// degrees between the stored directions
+const AZIMUTH_STEP: f32 = 5.0;
+
+/**
+ * Head-related impulse responses for directions all around the head (on the
+ * horizontal plane), every five degrees. They're derived from a spherical
+ * head model (Brown & Duda), head shadow plus the interaural time difference,
+ * so there's no measured set to ship.
+ */
+pub struct HrirTable {
+    ir_len: usize,
+    // per direction, left and right ear
+    irs: Vec<[Vec<f32>; 2]>,
+}
+
+impl HrirTable {
+    pub fn new(sr: f32) -> Self {
+        // the time sound takes around the head, in samples
+        let head_delay = HEAD_RADIUS / SPEED_OF_SOUND * sr;
+        // so the far ear is at least a few samples in, and the ringing of the near one fits
+        let pre_delay = head_delay + 4.0;
+        let max_delay = pre_delay + head_delay * PI / 2.0;
+        let ir_len = (max_delay as usize + 24).next_power_of_two();
+
+        let directions = (360.0 / AZIMUTH_STEP) as usize;
+        let irs = (0..directions).map(|d| {
+            let azimuth = d as f32 * AZIMUTH_STEP;
+            [HrirTable::ear_response(HrirTable::ear_angle(azimuth, -90.0), sr, head_delay, pre_delay, ir_len),
+             HrirTable::ear_response(HrirTable::ear_angle(azimuth, 90.0), sr, head_delay, pre_delay, ir_len)]
+        }).collect();
+
+        HrirTable { ir_len, irs }
+    }
+
+    /// the angle between the source and the ear, 0..180 degrees
+    fn ear_angle(azimuth: f32, ear: f32) -> f32 {
+        let diff = (azimuth - ear).rem_euclid(360.0);
+        if diff > 180.0 { 360.0 - diff } else { diff }
+    }
+
+    /// designed in the frequency domain, at twice the length, then truncated
+    fn ear_response(angle: f32, sr: f32, head_delay: f32, pre_delay: f32, ir_len: usize) -> Vec<f32> {
+        let fft_len = ir_len * 2;
+        let theta = angle.to_radians();
+
+        // head shadow, a high shelf that's +6dB facing the source and
+        // lowest at 150 degrees, with the corner at c / a
+        let w0 = SPEED_OF_SOUND / HEAD_RADIUS;
+        let alpha = 1.05 + 0.95 * (angle / 150.0 * PI).cos();
+
+        // the ear facing the source hears it first
+        let delay = pre_delay + if theta < PI / 2.0 {
+            -head_delay * theta.cos()
+        } else {
+            head_delay * (theta - PI / 2.0)
+        };
+
+        let mut re = vec![0.0; fft_len];
+        let mut im = vec![0.0; fft_len];
+        for k in 0..=fft_len / 2 {
+            let x = 2.0 * PI * k as f32 * sr / fft_len as f32 / (2.0 * w0);
+            // (1 + j alpha x) / (1 + j x)
+            let denom = 1.0 + x * x;
+            let h_re = (1.0 + alpha * x * x) / denom;
+            let h_im = (alpha * x - x) / denom;
+
+            let (sin, cos) = (-2.0 * PI * k as f32 * delay / fft_len as f32).sin_cos();
+            re[k] = h_re * cos - h_im * sin;
+            im[k] = if k == fft_len / 2 { 0.0 } else { h_re * sin + h_im * cos };
+            if k > 0 && k < fft_len / 2 {
+                re[fft_len - k] = re[k];
+                im[fft_len - k] = -im[k];
+            }
+        }
+        fft(&mut re, &mut im, true);
+
+        // fade out the last quarter, so the truncation doesn't click
+        let fade_len = ir_len / 4;
+        re.truncate(ir_len);
+        for (i, tap) in re.iter_mut().enumerate() {
+            *tap /= fft_len as f32;
+            if i >= ir_len - fade_len {
+                *tap *= 0.5 + 0.5 * (PI * (i - (ir_len - fade_len)) as f32 / fade_len as f32).cos();
+            }
+        }
+        re
+    }
+
+    /// the responses for a direction, interpolated between the stored ones
+    fn fill(&self, azimuth: f32, ir: &mut [Vec<f32>; 2]) {
+        let pos = azimuth.rem_euclid(360.0) / AZIMUTH_STEP;
+        let idx = pos.floor() as usize % self.irs.len();
+        let next = (idx + 1) % self.irs.len();
+        let frac = pos.fract();
+
+        for (ear, ear_ir) in ir.iter_mut().enumerate() {
+            for (k, tap) in ear_ir.iter_mut().enumerate() {
+                *tap = self.irs[idx][ear][k] * (1.0 - frac) + self.irs[next][ear][k] * frac;
+            }
+        }
+    }
+}
+
+/**
+ * Renders a mono voice for headphones, placed by convolving it
+ * with the head-related impulse responses of its direction.
+ * A change of direction is crossfaded over a block.
+ */
+pub struct BinauralPanner {
+    table: Arc<HrirTable>,
+    azimuth: f32,
+    ir: [Vec<f32>; 2],
+    prev_ir: [Vec<f32>; 2],
+    crossfade: bool,
+    // the last inputs, oldest first, the current block at the end
+    history: Vec<f32>,
+}
+
+impl BinauralPanner {
+    pub fn new(table: &Arc<HrirTable>) -> Self {
+        let ir_len = table.ir_len;
+        let mut ir = [vec![0.0; ir_len], vec![0.0; ir_len]];
+        table.fill(0.0, &mut ir);
+
+        BinauralPanner {
+            table: table.clone(), // just the reference ...
+            azimuth: 0.0,
+            prev_ir: ir.clone(),
+            ir,
+            crossfade: false,
+            history: vec![0.0; ir_len - 1 + 128],
+        }
+    }
+
+    /// in degrees, 0 is straight ahead, 90 to the right, -90 to the left
+    pub fn set_azimuth(&mut self, azimuth: f32) {
+        if azimuth == self.azimuth {
+            return;
+        }
+        self.azimuth = azimuth;
+        std::mem::swap(&mut self.ir, &mut self.prev_ir);
+        self.table.fill(azimuth, &mut self.ir);
+        self.crossfade = true;
+    }
+
+    /// the stereo position maps to -90 (left) .. 90 (right) degrees, the azimuth
+    /// parameter goes all around, counterclockwise like in ambisonics
+    pub fn set_parameter(&mut self, par: SynthParameter, value: f32) {
+        match par {
+            SynthParameter::StereoPosition => self.set_azimuth(value.clamp(-1.0, 1.0) * 90.0),
+            SynthParameter::Azimuth => self.set_azimuth(-value),
+            _ => (),
+        };
+    }
+
+    fn convolve(ir: &[f32], window: &[f32]) -> f32 {
+        ir.iter().zip(window.iter().rev()).map(|(h, x)| h * x).sum()
+    }
+
+    pub fn process_block(&mut self, block: [f32; 128]) -> [[f32; 128]; 2] {
+        let ir_len = self.table.ir_len;
+        let mut out_buf = [[0.0; 128]; 2];
+
+        self.history.copy_within(128.., 0);
+        self.history[ir_len - 1..].copy_from_slice(&block);
+
+        for (ear, out) in out_buf.iter_mut().enumerate() {
+            for (i, sample) in out.iter_mut().enumerate() {
+                let window = &self.history[i..i + ir_len];
+                *sample = BinauralPanner::convolve(&self.ir[ear], window);
+                if self.crossfade {
+                    let gain = (i + 1) as f32 / 128.0;
+                    *sample = gain * *sample + (1.0 - gain) * BinauralPanner::convolve(&self.prev_ir[ear], window);
+                }
+            }
+        }
+        self.crossfade = false;
+
+        out_buf
+    }
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    fn impulse_response(azimuth: f32) -> [[f32; 128]; 2] {
+        let table = Arc::new(HrirTable::new(44100.0));
+        let mut panner = BinauralPanner::new(&table);
+        panner.set_azimuth(azimuth);
+        // get past the crossfade
+        panner.process_block([0.0; 128]);
+
+        let mut block = [0.0; 128];
+        block[0] = 1.0;
+        panner.process_block(block)
+    }
+
+    fn peak_position(ir: &[f32; 128]) -> usize {
+        ir.iter().enumerate().fold((0, 0.0), |(pos, peak), (i, s)| if s.abs() > peak { (i, s.abs()) } else { (pos, peak) }).0
+    }
+
+    #[test]
+    fn binaural_test_front() {
+        let out = impulse_response(0.0);
+        for (left, right) in out[0].iter().zip(out[1].iter()) {
+            assert_approx_eq::assert_approx_eq!(left, right, 0.00001);
+        }
+        // unity at low frequencies
+        assert_approx_eq::assert_approx_eq!(out[0].iter().sum::<f32>(), 1.0, 0.01);
+    }
+
+    #[test]
+    fn binaural_test_right() {
+        let out = impulse_response(90.0);
+
+        // the right ear hears it earlier, and louder
+        let left_peak = peak_position(&out[0]);
+        let right_peak = peak_position(&out[1]);
+        assert!(right_peak < left_peak);
+        // about 0.66ms
+        assert!(((left_peak - right_peak) as i32 - 29).abs() <= 2);
+
+        let energy = |ir: &[f32; 128]| ir.iter().map(|s| s * s).sum::<f32>();
+        assert!(energy(&out[1]) > 2.0 * energy(&out[0]));
+    }
+
+    #[test]
+    fn binaural_test_pan_mapping() {
+        let table = Arc::new(HrirTable::new(44100.0));
+        let mut panned = BinauralPanner::new(&table);
+        panned.set_parameter(SynthParameter::StereoPosition, -1.0);
+        let mut direct = BinauralPanner::new(&table);
+        direct.set_azimuth(-90.0);
+
+        let mut block = [0.0; 128];
+        block[3] = 1.0;
+        let a = panned.process_block(block);
+        let b = direct.process_block(block);
+        for i in 0..128 {
+            assert_approx_eq::assert_approx_eq!(a[0][i], b[0][i], 0.00001);
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use crate::ruffbox::synth::Effect;
 use crate::ruffbox::synth::SynthParameter;
+use crate::ruffbox::synth::flush_denormal;
 use crate::ruffbox::synth::filters::*;
 
 pub struct MonoDelay {
@@ -45,7 +46,7 @@ impl Effect for MonoDelay {
         for i in 0..128 {
             let buf_out = self.buffer[self.buffer_idx];
 
-            self.buffer[self.buffer_idx] = (self.dampening_filter.process_sample(buf_out) * self.feedback) + block[i];
+            self.buffer[self.buffer_idx] = flush_denormal((self.dampening_filter.process_sample(buf_out) * self.feedback) + block[i]);
 
             out_buf[i] = self.buffer[self.buffer_idx];
 
@@ -1,11 +1,17 @@
 use crate::ruffbox::synth::Effect;
 use crate::ruffbox::synth::SynthParameter;
+use crate::ruffbox::synth::flush_denormal;
+use crate::ruffbox::synth::oversampling::{Oversampler, MAX_FACTOR};
+
+use std::f32::consts::PI;
 
 /**
  * Three-pole, 18dB/octave filter with tanh distortion
  * Adapted from CSound via Soundpipe
  *
  * My all-time favourite lowpass :D
+ *
+ * The distortion can be oversampled (2x or 4x) to reduce aliasing.
  */
 pub struct Lpf18 {
     // user parameters
@@ -28,6 +34,7 @@ pub struct Lpf18 {
     aout: f32,
     lastin: f32,
     samplerate: f32,
+    oversampler: Oversampler,
 }
 
 impl Lpf18 {
@@ -56,6 +63,7 @@ impl Lpf18 {
             aout: 0.0,
             lastin: 0.0,
             samplerate: sr,
+            oversampler: Oversampler::new(),
         }
     }
 
@@ -65,9 +73,9 @@ impl Lpf18 {
         self.ay31 = self.ay2;
         
         self.lastin = sample - (self.kres * self.aout).tanh();
-        self.ay1 = self.kp1h * (self.lastin + self.ax1) - self.kp * self.ay1;
-        self.ay2 = self.kp1h * (self.ay1 + self.ay11) - self.kp * self.ay2;
-        self.aout = self.kp1h * (self.ay2 + self.ay31) - self.kp * self.aout;
+        self.ay1 = flush_denormal(self.kp1h * (self.lastin + self.ax1) - self.kp * self.ay1);
+        self.ay2 = flush_denormal(self.kp1h * (self.ay1 + self.ay11) - self.kp * self.ay2);
+        self.aout = flush_denormal(self.kp1h * (self.ay2 + self.ay31) - self.kp * self.aout);
         
         (self.aout * self.value).tanh()           
     }
@@ -80,10 +88,13 @@ impl Effect for Lpf18 {
             SynthParameter::LowpassCutoffFrequency => self.cutoff = value, 
             SynthParameter::LowpassQFactor => self.res = value,
             SynthParameter::LowpassFilterDistortion => self.dist = value,
+            SynthParameter::Oversampling => self.oversampler.set_factor(value),
             _ => (),
         };
 
-        self.kfcn = 2.0 * self.cutoff * (1.0 / self.samplerate);
+        // the coefficients depend on the rate the filter actually runs at
+        let samplerate = self.samplerate * self.oversampler.factor() as f32;
+        self.kfcn = 2.0 * self.cutoff * (1.0 / samplerate);
         self.kp = ((-2.7528 * self.kfcn + 3.0429) * self.kfcn + 1.718) * self.kfcn - 0.9984;
         self.kp1 = self.kp + 1.0;
         self.kp1h = 0.5 * self.kp1;
@@ -96,6 +107,16 @@ impl Effect for Lpf18 {
 
     // start sample isn't really needed either ... 
     fn process_block(&mut self, block: [f32; 128], _start_sample: usize) -> [f32; 128] {
+        if self.oversampler.factor() > 1 {
+            let mut upsampled = [0.0; 128 * MAX_FACTOR];
+            self.oversampler.upsample(&block, &mut upsampled);
+            let factor = self.oversampler.factor();
+            for sample in upsampled.iter_mut().take(128 * factor) {
+                *sample = self.process_sample(*sample);
+            }
+            return self.oversampler.downsample(&upsampled);
+        }
+
         let mut out_buf: [f32; 128] = [0.0; 128];
 
         for i in 0..128 {
@@ -105,9 +126,9 @@ impl Effect for Lpf18 {
             self.ay31 = self.ay2;
 
             self.lastin = block[i] - (self.kres * self.aout).tanh();
-            self.ay1 = self.kp1h * (self.lastin + self.ax1) - self.kp * self.ay1;
-            self.ay2 = self.kp1h * (self.ay1 + self.ay11) - self.kp * self.ay2;
-            self.aout = self.kp1h * (self.ay2 + self.ay31) - self.kp * self.aout;
+            self.ay1 = flush_denormal(self.kp1h * (self.lastin + self.ax1) - self.kp * self.ay1);
+            self.ay2 = flush_denormal(self.kp1h * (self.ay1 + self.ay11) - self.kp * self.ay2);
+            self.aout = flush_denormal(self.kp1h * (self.ay2 + self.ay31) - self.kp * self.aout);
 
             out_buf[i] = (self.aout * self.value).tanh();            
         }
@@ -115,3 +136,103 @@ impl Effect for Lpf18 {
         out_buf
     }
 }
+
+/**
+ * One-pole, one-zero highpass at about 10Hz, removes DC offsets
+ * (i.e. from asymmetric distortion or feedback).
+ */
+pub struct DcBlocker {
+    coef: f32,
+    last_in: f32,
+    last_out: f32,
+}
+
+impl DcBlocker {
+    pub fn new(sr: f32) -> Self {
+        DcBlocker {
+            coef: 1.0 - (2.0 * PI * 10.0 / sr),
+            last_in: 0.0,
+            last_out: 0.0,
+        }
+    }
+}
+
+impl Effect for DcBlocker {
+    fn set_parameter(&mut self, _par: SynthParameter, _value: f32) {}
+
+    fn finish(&mut self) {}
+    fn is_finished(&self) -> bool { false } // it's never finished ..
+
+    fn process_block(&mut self, block: [f32; 128], _start_sample: usize) -> [f32; 128] {
+        let mut out_buf: [f32; 128] = [0.0; 128];
+
+        for i in 0..128 {
+            self.last_out = flush_denormal(block[i] - self.last_in + self.coef * self.last_out);
+            self.last_in = block[i];
+            out_buf[i] = self.last_out;
+        }
+
+        out_buf
+    }
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn dc_blocker_test_remove_offset() {
+        let mut blocker = DcBlocker::new(44100.0);
+
+        let mut out = [0.0; 128];
+        for _ in 0..500 {
+            out = blocker.process_block([0.5; 128], 0);
+        }
+        assert_approx_eq::assert_approx_eq!(out[127], 0.0, 0.0001);
+    }
+
+    #[test]
+    fn lpf18_test_oversampled_cutoff() {
+        // the cutoff stays the same when oversampling
+        let rms = |factor: f32, freq: f32| {
+            let mut filter = Lpf18::new(1000.0, 0.1, 0.0, 44100.0);
+            filter.set_parameter(SynthParameter::Oversampling, factor);
+            let mut block = [0.0; 128];
+            let mut count = 0;
+            let mut sum = 0.0;
+            for b in 0..40 {
+                for s in block.iter_mut() {
+                    *s = 0.5 * (2.0 * PI * freq * count as f32 / 44100.0).sin();
+                    count += 1;
+                }
+                let out = filter.process_block(block, 0);
+                if b >= 20 {
+                    sum += out.iter().map(|s| s * s).sum::<f32>();
+                }
+            }
+            (sum / (20.0 * 128.0)).sqrt()
+        };
+
+        for freq in [200.0, 1000.0, 4000.0].iter() {
+            assert_approx_eq::assert_approx_eq!(rms(1.0, *freq), rms(4.0, *freq), 0.01);
+        }
+    }
+
+    #[test]
+    fn lpf18_test_no_denormals() {
+        let mut filter = Lpf18::new(500.0, 0.8, 0.0, 44100.0);
+
+        let mut block = [0.0; 128];
+        block[0] = 1.0;
+        filter.process_block(block, 0);
+
+        // the ringing decays into zeros, not into the denormal range
+        let mut out = [0.0; 128];
+        for _ in 0..5000 {
+            out = filter.process_block([0.0; 128], 0);
+        }
+        assert!(out.iter().all(|s| *s == 0.0 || s.is_normal()));
+    }
+}
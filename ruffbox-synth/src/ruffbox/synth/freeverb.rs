@@ -1,4 +1,5 @@
 use crate::ruffbox::synth::SynthParameter;
+use crate::ruffbox::synth::flush_denormal;
 
 struct FreeverbDefaultTuning;
 
@@ -75,7 +76,7 @@ impl Allpass {
         let buf_out: f32 = self.delay_buffer[self.delay_idx];
                
         let out = (-1.0 * sample) + buf_out;
-        self.delay_buffer[self.delay_idx] = sample + (buf_out * self.feedback);
+        self.delay_buffer[self.delay_idx] = flush_denormal(sample + (buf_out * self.feedback));
         
         // increment delay idx
         self.delay_idx += 1;
@@ -120,8 +121,8 @@ impl Comb {
     pub fn process_sample(&mut self, sample: f32) -> f32 {
         
         let out = self.delay_buffer[self.delay_idx];
-        self.filterstore = (out * self.damp2) + (self.filterstore * self.damp1);
-        self.delay_buffer[self.delay_idx] = sample + (self.filterstore * self.feedback);
+        self.filterstore = flush_denormal((out * self.damp2) + (self.filterstore * self.damp1));
+        self.delay_buffer[self.delay_idx] = flush_denormal(sample + (self.filterstore * self.feedback));
 
         // increment delay idx
         self.delay_idx += 1;
@@ -0,0 +1,122 @@
+use std::collections::VecDeque;
+
+/// how the gain moves within a gate segment
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum GateShape {
+    /// holds the level for the whole segment
+    Square,
+    /// ducks by the level at the start, then recovers linearly (saw-down sidechain pump)
+    Pump,
+}
+
+struct GateSegment {
+    start: f64,
+    length: f64,
+    level: f32,
+    shape: GateShape,
+}
+
+/**
+ * A tempo-synced gain LFO on a bus. It doesn't know about the tempo itself,
+ * the scheduler sends a segment per step (or beat), so it follows every
+ * tempo change. Outside of any segment, the gate is open.
+ */
+pub struct TempoGate {
+    // upcoming segments, ordered by start time
+    segments: VecDeque<GateSegment>,
+    current: Option<GateSegment>,
+    gain: f32,
+    // a little smoothing, against clicks on hard steps
+    slew_coef: f32,
+}
+
+impl TempoGate {
+    pub fn new(sr: f32) -> Self {
+        TempoGate {
+            segments: VecDeque::new(),
+            current: None,
+            gain: 1.0,
+            slew_coef: (-1.0 / (0.001 * sr)).exp(),
+        }
+    }
+
+    /// timestamp and length in seconds, the level is 0..1
+    pub fn schedule(&mut self, timestamp: f64, length: f64, level: f32, shape: GateShape) {
+        let pos = self.segments.iter().position(|seg| seg.start > timestamp).unwrap_or(self.segments.len());
+        self.segments.insert(pos, GateSegment { start: timestamp, length, level: level.clamp(0.0, 1.0), shape });
+    }
+
+    fn target(&mut self, time: f64) -> f32 {
+        while self.segments.front().is_some_and(|seg| seg.start <= time) {
+            self.current = self.segments.pop_front();
+        }
+
+        match &self.current {
+            Some(seg) if time < seg.start + seg.length => match seg.shape {
+                GateShape::Square => seg.level,
+                GateShape::Pump => 1.0 - seg.level * (1.0 - ((time - seg.start) / seg.length) as f32),
+            },
+            _ => 1.0,
+        }
+    }
+
+    pub fn process(&mut self, block: [[f32; 128]; 2], block_start: f64, sec_per_sample: f64) -> [[f32; 128]; 2] {
+        let mut out_buf = block;
+        let [left, right] = &mut out_buf;
+
+        for (i, (l, r)) in left.iter_mut().zip(right.iter_mut()).enumerate() {
+            let target = self.target(block_start + i as f64 * sec_per_sample);
+            self.gain = target + self.slew_coef * (self.gain - target);
+            *l *= self.gain;
+            *r *= self.gain;
+        }
+
+        out_buf
+    }
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    const SEC_PER_SAMPLE: f64 = 1.0 / 44100.0;
+
+    fn render(gate: &mut TempoGate, blocks: usize) -> Vec<f32> {
+        let mut out = Vec::new();
+        for b in 0..blocks {
+            out.extend_from_slice(&gate.process([[1.0; 128]; 2], (b * 128) as f64 * SEC_PER_SAMPLE, SEC_PER_SAMPLE)[0]);
+        }
+        out
+    }
+
+    #[test]
+    fn gate_test_square_steps() {
+        let mut gate = TempoGate::new(44100.0);
+
+        // open, closed, then nothing more
+        gate.schedule(0.0, 256.0 * SEC_PER_SAMPLE, 1.0, GateShape::Square);
+        gate.schedule(256.0 * SEC_PER_SAMPLE, 512.0 * SEC_PER_SAMPLE, 0.0, GateShape::Square);
+        let out = render(&mut gate, 12);
+
+        // the steps are smoothed a little
+        assert_approx_eq::assert_approx_eq!(out[200], 1.0, 0.0001);
+        assert_approx_eq::assert_approx_eq!(out[700], 0.0, 0.01);
+        // open again past the last segment
+        assert_approx_eq::assert_approx_eq!(out[1400], 1.0, 0.01);
+    }
+
+    #[test]
+    fn gate_test_pump() {
+        let mut gate = TempoGate::new(44100.0);
+        gate.schedule(0.0, 1024.0 * SEC_PER_SAMPLE, 1.0, GateShape::Pump);
+        let out = render(&mut gate, 8);
+
+        // ducked at the start, back up towards the end
+        assert!(out[..256].iter().cloned().fold(1.0, f32::min) < 0.2);
+        assert_approx_eq::assert_approx_eq!(out[512], 0.5, 0.1);
+        assert!(out[1000] > 0.9);
+    }
+}
@@ -0,0 +1,240 @@
+use crate::ruffbox::synth::*;
+use crate::ruffbox::synth::oscillators::*;
+use crate::ruffbox::synth::envelopes::*;
+use crate::ruffbox::synth::filters::*;
+use crate::ruffbox::synth::routing::Balance2;
+
+/// the building blocks of custom voices
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NodeType {
+    Sine,       // value is the ratio to the voice's frequency
+    Saw,        // value is the ratio to the voice's frequency
+    Square,     // value is the ratio to the voice's frequency
+    Lowpass,    // value is the cutoff frequency, input a is filtered
+    Envelope,   // input a is shaped by the voice's attack, sustain and release
+    Add,        // a + b
+    Multiply,   // a * b
+    Constant,   // value
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NodeDefinition {
+    pub node_type: NodeType,
+    pub inputs: [Option<usize>; 2],
+    pub value: f32,
+}
+
+/// A custom voice, a list of nodes that can only take inputs from nodes
+/// defined before them (so there can't be any cycles), the last node
+/// being the output.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VoiceDefinition {
+    nodes: Vec<NodeDefinition>,
+}
+
+impl VoiceDefinition {
+    pub fn new() -> Self {
+        VoiceDefinition {
+            nodes: Vec::new(),
+        }
+    }
+
+    /// add a node, returns its index
+    pub fn add_node(&mut self, node_type: NodeType, input_a: Option<usize>, input_b: Option<usize>, value: f32) -> Result<usize, String> {
+        for input in [input_a, input_b].iter().flatten() {
+            if *input >= self.nodes.len() {
+                return Err(format!("node {} doesn't exist (yet)", input));
+            }
+        }
+
+        self.nodes.push(NodeDefinition { node_type, inputs: [input_a, input_b], value });
+        Ok(self.nodes.len() - 1)
+    }
+}
+
+enum Node {
+    Oscillator(Box<dyn Source + Send>, f32),
+    Lowpass(Box<Lpf18>, Option<usize>),
+    Envelope(ASREnvelope, Option<usize>),
+    Add(Option<usize>, Option<usize>),
+    Multiply(Option<usize>, Option<usize>),
+    Constant(f32),
+}
+
+impl Node {
+    fn from_definition(definition: &NodeDefinition, sr: f32) -> Self {
+        let [a, b] = definition.inputs;
+        match definition.node_type {
+            NodeType::Sine => Node::Oscillator(Box::new(SineOsc::new(440.0 * definition.value, 1.0, sr)), definition.value),
+            NodeType::Saw => Node::Oscillator(Box::new(LFSaw::new(440.0 * definition.value, 1.0, sr)), definition.value),
+            NodeType::Square => Node::Oscillator(Box::new(LFSquare::new(440.0 * definition.value, 0.5, 1.0, sr)), definition.value),
+            NodeType::Lowpass => Node::Lowpass(Box::new(Lpf18::new(definition.value, 0.4, 0.0, sr)), a),
+            NodeType::Envelope => Node::Envelope(ASREnvelope::new(sr, 1.0, 0.05, 0.1, 0.05), a),
+            NodeType::Add => Node::Add(a, b),
+            NodeType::Multiply => Node::Multiply(a, b),
+            NodeType::Constant => Node::Constant(definition.value),
+        }
+    }
+
+    fn set_parameter(&mut self, par: SynthParameter, val: f32) {
+        match self {
+            // the level is applied at the voice's output
+            Node::Oscillator(osc, ratio) => match par {
+                SynthParameter::PitchFrequency => osc.set_parameter(par, val * *ratio),
+                SynthParameter::Pulsewidth => osc.set_parameter(par, val),
+                _ => (),
+            },
+            Node::Lowpass(filter, _) => filter.set_parameter(par, val),
+            Node::Envelope(envelope, _) => match par {
+                SynthParameter::Attack | SynthParameter::Sustain | SynthParameter::Release => envelope.set_parameter(par, val),
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+}
+
+/// a voice built from a VoiceDefinition, with envelope and panning like the other synths
+pub struct GraphVoice {
+    nodes: Vec<Node>,
+    outputs: Vec<[f32; 128]>,
+    envelope: ASREnvelope,
+    balance: Balance2,
+    reverb: f32,
+    delay: f32,
+}
+
+impl GraphVoice {
+    pub fn new(definition: &VoiceDefinition, sr: f32) -> Self {
+        GraphVoice {
+            nodes: definition.nodes.iter().map(|node| Node::from_definition(node, sr)).collect(),
+            outputs: vec![[0.0; 128]; definition.nodes.len()],
+            envelope: ASREnvelope::new(sr, 0.3, 0.05, 0.1, 0.05),
+            balance: Balance2::new(),
+            reverb: 0.0,
+            delay: 0.0,
+        }
+    }
+}
+
+impl StereoSynth for GraphVoice {
+    fn set_parameter(&mut self, par: SynthParameter, val: f32) {
+        for node in self.nodes.iter_mut() {
+            node.set_parameter(par, val);
+        }
+        self.envelope.set_parameter(par, val);
+        self.balance.set_parameter(par, val);
+        match par {
+            SynthParameter::ReverbMix => self.reverb = val,
+            SynthParameter::DelayMix => self.delay = val,
+            _ => (),
+        };
+    }
+
+    fn finish(&mut self) {
+        self.envelope.finish();
+    }
+
+    fn is_finished(&self) -> bool {
+        self.envelope.is_finished()
+    }
+
+    fn get_next_block(&mut self, start_sample: usize) -> [[f32; 128]; 2] {
+        let silence = [0.0; 128];
+
+        // nodes only refer to nodes before them, so everything
+        // a node needs has already been calculated
+        for idx in 0..self.nodes.len() {
+            let (done, rest) = self.outputs.split_at_mut(idx);
+            let input = |i: Option<usize>| i.map_or(&silence, |i| &done[i]);
+
+            rest[0] = match &mut self.nodes[idx] {
+                Node::Oscillator(osc, _) => osc.get_next_block(start_sample),
+                Node::Lowpass(filter, a) => filter.process_block(*input(*a), start_sample),
+                Node::Envelope(envelope, a) => envelope.process_block(*input(*a), start_sample),
+                Node::Add(a, b) => {
+                    let mut out = *input(*a);
+                    for (o, x) in out.iter_mut().zip(input(*b).iter()) {
+                        *o += x;
+                    }
+                    out
+                },
+                Node::Multiply(a, b) => {
+                    let mut out = *input(*a);
+                    for (o, x) in out.iter_mut().zip(input(*b).iter()) {
+                        *o *= x;
+                    }
+                    out
+                },
+                Node::Constant(value) => {
+                    let mut out = [0.0; 128];
+                    for o in out.iter_mut().skip(start_sample) {
+                        *o = *value;
+                    }
+                    out
+                },
+            };
+        }
+
+        let out = self.outputs.last().cloned().unwrap_or([0.0; 128]);
+        self.balance.process_block(self.envelope.process_block(out, start_sample))
+    }
+
+    fn reverb_level(&self) -> f32 {
+        self.reverb
+    }
+
+    fn delay_level(&self) -> f32 {
+        self.delay
+    }
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use std::f32::consts::PI;
+
+    #[test]
+    fn voice_definition_test_no_forward_refs() {
+        let mut voice = VoiceDefinition::new();
+        assert!(voice.add_node(NodeType::Lowpass, Some(0), None, 1000.0).is_err());
+        assert_eq!(voice.add_node(NodeType::Sine, None, None, 1.0), Ok(0));
+        assert_eq!(voice.add_node(NodeType::Lowpass, Some(0), None, 1000.0), Ok(1));
+    }
+
+    #[test]
+    fn graph_voice_test_ring_mod() {
+        let mut voice = VoiceDefinition::new();
+        let carrier = voice.add_node(NodeType::Sine, None, None, 1.0).unwrap();
+        let modulator = voice.add_node(NodeType::Sine, None, None, 2.0).unwrap();
+        let ring = voice.add_node(NodeType::Multiply, Some(carrier), Some(modulator), 0.0).unwrap();
+        let half = voice.add_node(NodeType::Constant, None, None, 0.5).unwrap();
+        voice.add_node(NodeType::Multiply, Some(ring), Some(half), 0.0).unwrap();
+
+        let mut synth = GraphVoice::new(&voice, 44100.0);
+        synth.set_parameter(SynthParameter::PitchFrequency, 440.0);
+        synth.set_parameter(SynthParameter::StereoPosition, -1.0);
+        synth.set_parameter(SynthParameter::Level, 1.0);
+        synth.set_parameter(SynthParameter::Attack, 0.0);
+        synth.set_parameter(SynthParameter::Sustain, 1.0);
+        synth.set_parameter(SynthParameter::Release, 0.0);
+
+        let out = synth.get_next_block(0);
+
+        for (i, out) in out[0].iter().enumerate() {
+            let t = i as f32 * (1.0 / 44100.0);
+            let comp = (2.0 * PI * 440.0 * t).sin() * (2.0 * PI * 880.0 * t).sin() * 0.5;
+            assert_approx_eq::assert_approx_eq!(*out, comp, 0.00001);
+        }
+    }
+
+    #[test]
+    fn graph_voice_test_empty() {
+        let mut synth = GraphVoice::new(&VoiceDefinition::new(), 44100.0);
+        let out = synth.get_next_block(0);
+        assert_approx_eq::assert_approx_eq!(out[0][0], 0.0, 0.00001);
+    }
+}
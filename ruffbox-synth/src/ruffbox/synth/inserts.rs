@@ -0,0 +1,505 @@
+use crate::ruffbox::synth::Effect;
+use crate::ruffbox::synth::SynthParameter;
+use crate::ruffbox::synth::flush_denormal;
+use crate::ruffbox::synth::filters::*;
+use crate::ruffbox::synth::spectral::SpectralFreeze;
+use crate::ruffbox::synth::noise::Noise;
+use crate::ruffbox::synth::oversampling::{Oversampler, MAX_FACTOR};
+
+use std::f32::consts::PI;
+
+/// the effects that can be put into an insert chain
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub enum InsertType {
+    BitCrusher,
+    Lowpass,
+    Compressor,
+    LoFi,
+    Freeze,
+    PitchShift,
+}
+
+/**
+ * Reduces the bit depth, the number of bits is continuous
+ * so it can be swept smoothly. Can be oversampled (2x or 4x)
+ * to reduce aliasing.
+ */
+pub struct BitCrusher {
+    bits: f32,
+    oversampler: Oversampler,
+}
+
+impl BitCrusher {
+    pub fn new() -> Self {
+        BitCrusher {
+            bits: 8.0,
+            oversampler: Oversampler::new(),
+        }
+    }
+}
+
+impl Effect for BitCrusher {
+    fn set_parameter(&mut self, par: SynthParameter, value: f32) {
+        match par {
+            SynthParameter::BitDepth => self.bits = value.clamp(1.0, 24.0),
+            SynthParameter::Oversampling => self.oversampler.set_factor(value),
+            _ => (),
+        };
+    }
+
+    fn finish(&mut self) {}
+    fn is_finished(&self) -> bool { false } // it's never finished ..
+
+    fn process_block(&mut self, block: [f32; 128], _start_sample: usize) -> [f32; 128] {
+        let steps = 2.0_f32.powf(self.bits - 1.0);
+
+        let mut upsampled = [0.0; 128 * MAX_FACTOR];
+        self.oversampler.upsample(&block, &mut upsampled);
+        for sample in upsampled.iter_mut().take(128 * self.oversampler.factor()) {
+            *sample = (*sample * steps).round() / steps;
+        }
+        self.oversampler.downsample(&upsampled)
+    }
+}
+
+/**
+ * Simple feed-forward compressor with fixed attack and release,
+ * threshold in dBFS.
+ */
+pub struct Compressor {
+    threshold: f32,
+    ratio: f32,
+    envelope: f32,
+    attack_coef: f32,
+    release_coef: f32,
+}
+
+impl Compressor {
+    pub fn new(sr: f32) -> Self {
+        Compressor {
+            threshold: -20.0,
+            ratio: 4.0,
+            envelope: 0.0,
+            attack_coef: (-1.0 / (0.005 * sr)).exp(),
+            release_coef: (-1.0 / (0.1 * sr)).exp(),
+        }
+    }
+}
+
+impl Effect for Compressor {
+    fn set_parameter(&mut self, par: SynthParameter, value: f32) {
+        match par {
+            SynthParameter::CompressorThreshold => self.threshold = value.min(0.0),
+            SynthParameter::CompressorRatio => self.ratio = value.max(1.0),
+            _ => (),
+        };
+    }
+
+    fn finish(&mut self) {}
+    fn is_finished(&self) -> bool { false } // it's never finished ..
+
+    fn process_block(&mut self, block: [f32; 128], _start_sample: usize) -> [f32; 128] {
+        let mut out_buf: [f32; 128] = [0.0; 128];
+
+        for i in 0..128 {
+            let level = block[i].abs();
+            let coef = if level > self.envelope { self.attack_coef } else { self.release_coef };
+            self.envelope = flush_denormal(coef * self.envelope + (1.0 - coef) * level);
+
+            let level_db = 20.0 * self.envelope.max(0.000001).log10();
+            let gain_db = if level_db > self.threshold {
+                (self.threshold - level_db) * (1.0 - 1.0 / self.ratio)
+            } else {
+                0.0
+            };
+
+            out_buf[i] = block[i] * 10.0_f32.powf(gain_db / 20.0);
+        }
+
+        out_buf
+    }
+}
+
+/**
+ * Worn tape/vinyl character: wow and flutter (a modulated delay line),
+ * crackle and a narrowing bandwidth, all controlled by a single
+ * age parameter (0..1).
+ */
+pub struct LoFi {
+    age: f32,
+    buffer: Vec<f32>,
+    buffer_idx: usize,
+    wow_phase: f32,
+    flutter_phase: f32,
+    lowpass: Lpf18,
+    hp_coef: f32,
+    hp_last_in: f32,
+    hp_last_out: f32,
+    noise: Noise,
+    samplerate: f32,
+}
+
+impl LoFi {
+    const BUFFER_SIZE: usize = 2048;
+    const BASE_DELAY: f32 = 30.0; // samples
+    const WOW_FREQ: f32 = 0.5;
+    const WOW_DEPTH: f32 = 20.0; // samples, at full age
+    const FLUTTER_FREQ: f32 = 6.0;
+    const FLUTTER_DEPTH: f32 = 3.0; // samples, at full age
+    const CRACKLE_DENSITY: f32 = 0.0005; // chance per sample, at full age
+
+    pub fn new(sr: f32) -> Self {
+        let mut lofi = LoFi {
+            age: 0.0,
+            buffer: vec![0.0; LoFi::BUFFER_SIZE],
+            buffer_idx: 0,
+            wow_phase: 0.0,
+            flutter_phase: 0.0,
+            lowpass: Lpf18::new(18000.0, 0.1, 0.0, sr),
+            hp_coef: 1.0,
+            hp_last_in: 0.0,
+            hp_last_out: 0.0,
+            noise: Noise::new(),
+            samplerate: sr,
+        };
+        lofi.set_parameter(SynthParameter::Age, 0.0);
+        lofi
+    }
+}
+
+impl Effect for LoFi {
+    fn set_parameter(&mut self, par: SynthParameter, value: f32) {
+        if let SynthParameter::Age = par {
+            self.age = value.clamp(0.0, 1.0);
+            self.lowpass.set_parameter(SynthParameter::LowpassCutoffFrequency, 18000.0 - self.age * 15000.0);
+            let hp_cutoff = 20.0 + self.age * 280.0;
+            let rc = 1.0 / (2.0 * PI * hp_cutoff);
+            self.hp_coef = rc / (rc + 1.0 / self.samplerate);
+        }
+    }
+
+    fn finish(&mut self) {}
+    fn is_finished(&self) -> bool { false } // it's never finished ..
+
+    fn process_block(&mut self, block: [f32; 128], _start_sample: usize) -> [f32; 128] {
+        let mut out_buf: [f32; 128] = [0.0; 128];
+
+        for i in 0..128 {
+            self.buffer[self.buffer_idx] = block[i];
+
+            // wow and flutter
+            let delay = LoFi::BASE_DELAY
+                + (self.wow_phase.sin() * LoFi::WOW_DEPTH + self.flutter_phase.sin() * LoFi::FLUTTER_DEPTH) * self.age;
+            let read_pos = (self.buffer_idx as f32 - delay + LoFi::BUFFER_SIZE as f32) % LoFi::BUFFER_SIZE as f32;
+            let idx = read_pos.floor() as usize;
+            let frac = read_pos - read_pos.floor();
+            let mut sample = self.buffer[idx] * (1.0 - frac) + self.buffer[(idx + 1) % LoFi::BUFFER_SIZE] * frac;
+
+            self.wow_phase = (self.wow_phase + 2.0 * PI * LoFi::WOW_FREQ / self.samplerate) % (2.0 * PI);
+            self.flutter_phase = (self.flutter_phase + 2.0 * PI * LoFi::FLUTTER_FREQ / self.samplerate) % (2.0 * PI);
+            self.buffer_idx = (self.buffer_idx + 1) % LoFi::BUFFER_SIZE;
+
+            // crackle
+            if self.noise.uniform() < LoFi::CRACKLE_DENSITY * self.age {
+                sample += (self.noise.uniform() - 0.5) * self.age;
+            }
+
+            // bandwidth
+            sample = self.lowpass.process_sample(sample);
+            self.hp_last_out = flush_denormal(self.hp_coef * (self.hp_last_out + sample - self.hp_last_in));
+            self.hp_last_in = sample;
+
+            out_buf[i] = self.hp_last_out;
+        }
+
+        out_buf
+    }
+}
+
+/**
+ * Granular pitch shifter: two taps sweep through a delay line at the
+ * speed needed for the shift (in semitones), crossfading so that one
+ * is always faded out while it jumps back. Unlike the playback rate,
+ * this doesn't change the length of a sound.
+ */
+pub struct PitchShifter {
+    ratio: f32,
+    buffer: Vec<f32>,
+    buffer_idx: usize,
+    phase: f32,
+}
+
+impl PitchShifter {
+    const BUFFER_SIZE: usize = 4096;
+    const GRAIN_SIZE: f32 = 2048.0; // samples
+
+    pub fn new() -> Self {
+        PitchShifter {
+            ratio: 1.0,
+            buffer: vec![0.0; PitchShifter::BUFFER_SIZE],
+            buffer_idx: 0,
+            phase: 0.0,
+        }
+    }
+
+    fn read(&self, delay: f32) -> f32 {
+        let read_pos = (self.buffer_idx as f32 - delay + PitchShifter::BUFFER_SIZE as f32) % PitchShifter::BUFFER_SIZE as f32;
+        let idx = read_pos.floor() as usize;
+        let frac = read_pos - read_pos.floor();
+        self.buffer[idx] * (1.0 - frac) + self.buffer[(idx + 1) % PitchShifter::BUFFER_SIZE] * frac
+    }
+}
+
+impl Effect for PitchShifter {
+    fn set_parameter(&mut self, par: SynthParameter, value: f32) {
+        if let SynthParameter::PitchShift = par {
+            self.ratio = 2.0_f32.powf(value.clamp(-24.0, 24.0) / 12.0);
+        }
+    }
+
+    fn finish(&mut self) {}
+    fn is_finished(&self) -> bool { false } // it's never finished ..
+
+    fn process_block(&mut self, block: [f32; 128], _start_sample: usize) -> [f32; 128] {
+        let mut out_buf: [f32; 128] = [0.0; 128];
+
+        for i in 0..128 {
+            self.buffer[self.buffer_idx] = block[i];
+
+            // the squared sine windows of both taps add up to one
+            let phase_b = (self.phase + 0.5) % 1.0;
+            let gain_a = (PI * self.phase).sin().powi(2);
+            let gain_b = (PI * phase_b).sin().powi(2);
+
+            out_buf[i] = self.read(self.phase * PitchShifter::GRAIN_SIZE) * gain_a
+                + self.read(phase_b * PitchShifter::GRAIN_SIZE) * gain_b;
+
+            // a shrinking delay reads faster than it's written
+            self.phase = (self.phase + (1.0 - self.ratio) / PitchShifter::GRAIN_SIZE + 1.0) % 1.0;
+            self.buffer_idx = (self.buffer_idx + 1) % PitchShifter::BUFFER_SIZE;
+        }
+
+        out_buf
+    }
+}
+
+/// peak envelope of a stereo signal, fast attack, slow release
+pub struct EnvelopeFollower {
+    level: f32,
+    attack_coef: f32,
+    release_coef: f32,
+}
+
+impl EnvelopeFollower {
+    pub fn new(sr: f32) -> Self {
+        EnvelopeFollower {
+            level: 0.0,
+            attack_coef: (-1.0 / (0.01 * sr)).exp(),
+            release_coef: (-1.0 / (0.15 * sr)).exp(),
+        }
+    }
+
+    pub fn process_block(&mut self, block: &[[f32; 128]; 2]) {
+        for (left, right) in block[0].iter().zip(block[1].iter()) {
+            let peak = left.abs().max(right.abs());
+            let coef = if peak > self.level { self.attack_coef } else { self.release_coef };
+            self.level = flush_denormal(coef * self.level + (1.0 - coef) * peak);
+        }
+    }
+
+    pub fn level(&self) -> f32 {
+        self.level
+    }
+}
+
+/// a chain of effects a bus runs through, one instance per channel
+pub struct InsertChain {
+    effects: Vec<[Box<dyn Effect + Send>; 2]>,
+    follower: EnvelopeFollower,
+    pub input: [[f32; 128]; 2],
+    samplerate: f32,
+}
+
+impl InsertChain {
+    pub fn new(sr: f32) -> Self {
+        InsertChain {
+            effects: Vec::new(),
+            follower: EnvelopeFollower::new(sr),
+            input: [[0.0; 128]; 2],
+            samplerate: sr,
+        }
+    }
+
+    fn create(insert_type: InsertType, sr: f32) -> Box<dyn Effect + Send> {
+        match insert_type {
+            InsertType::BitCrusher => Box::new(BitCrusher::new()),
+            InsertType::Lowpass => Box::new(Lpf18::new(19500.0, 0.01, 0.01, sr)),
+            InsertType::Compressor => Box::new(Compressor::new(sr)),
+            InsertType::LoFi => Box::new(LoFi::new(sr)),
+            InsertType::Freeze => Box::new(SpectralFreeze::new(sr)),
+            InsertType::PitchShift => Box::new(PitchShifter::new()),
+        }
+    }
+
+    pub fn add(&mut self, insert_type: InsertType) {
+        let sr = self.samplerate;
+        self.effects.push([InsertChain::create(insert_type, sr), InsertChain::create(insert_type, sr)]);
+    }
+
+    /// set a parameter on the effect at the given position in the chain
+    pub fn set_parameter(&mut self, idx: usize, par: SynthParameter, value: f32) {
+        if let Some(effect) = self.effects.get_mut(idx) {
+            effect[0].set_parameter(par, value);
+            effect[1].set_parameter(par, value);
+        }
+    }
+
+    /// run the accumulated input through the chain and clear it
+    pub fn process(&mut self) -> [[f32; 128]; 2] {
+        let mut out_buf = self.input;
+
+        for effect in self.effects.iter_mut() {
+            out_buf[0] = effect[0].process_block(out_buf[0], 0);
+            out_buf[1] = effect[1].process_block(out_buf[1], 0);
+        }
+
+        self.follower.process_block(&out_buf);
+        self.input = [[0.0; 128]; 2];
+        out_buf
+    }
+
+    /// the current envelope of the chain's output
+    pub fn level(&self) -> f32 {
+        self.follower.level()
+    }
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn bitcrusher_test_quantize() {
+        let mut crush = BitCrusher::new();
+        crush.set_parameter(SynthParameter::BitDepth, 2.0);
+
+        let mut block = [0.0; 128];
+        block[0] = 0.3;
+        block[1] = -0.8;
+
+        let out = crush.process_block(block, 0);
+        assert_approx_eq::assert_approx_eq!(out[0], 0.5, 0.00001);
+        assert_approx_eq::assert_approx_eq!(out[1], -1.0, 0.00001);
+    }
+
+    #[test]
+    fn compressor_test_reduce_loud_signal() {
+        let mut comp = Compressor::new(44100.0);
+        comp.set_parameter(SynthParameter::CompressorThreshold, -20.0);
+        comp.set_parameter(SynthParameter::CompressorRatio, 10.0);
+
+        let block = [1.0; 128];
+        let mut out = [0.0; 128];
+        for _ in 0..100 {
+            out = comp.process_block(block, 0);
+        }
+
+        // 20dB over the threshold, 10:1 leaves 2dB
+        assert_approx_eq::assert_approx_eq!(out[127], 10.0_f32.powf(-18.0 / 20.0), 0.01);
+
+        let mut comp = Compressor::new(44100.0);
+        let block = [0.01; 128];
+        let out = comp.process_block(block, 0);
+        assert_approx_eq::assert_approx_eq!(out[127], 0.01, 0.00001);
+    }
+
+    #[test]
+    fn lofi_test_crackle() {
+        let block = [0.0; 128];
+
+        // no crackle when new
+        let mut lofi = LoFi::new(44100.0);
+        for _ in 0..100 {
+            let out = lofi.process_block(block, 0);
+            assert!(out.iter().all(|s| *s == 0.0));
+        }
+
+        lofi.set_parameter(SynthParameter::Age, 1.0);
+        let mut crackles = 0;
+        for _ in 0..100 {
+            let out = lofi.process_block(block, 0);
+            assert!(out.iter().all(|s| s.abs() <= 1.0));
+            crackles += out.iter().filter(|s| s.abs() > 0.0).count();
+        }
+        assert!(crackles > 0);
+    }
+
+    #[test]
+    fn pitch_shifter_test_octave_up() {
+        let zero_crossings = |shift: f32| {
+            let mut shifter = PitchShifter::new();
+            shifter.set_parameter(SynthParameter::PitchShift, shift);
+
+            let mut crossings = 0;
+            let mut last = 0.0;
+            let mut count = 0;
+            let mut block = [0.0; 128];
+            for b in 0..400 {
+                for s in block.iter_mut() {
+                    *s = (2.0 * PI * 220.0 * count as f32 / 44100.0).sin();
+                    count += 1;
+                }
+                let out = shifter.process_block(block, 0);
+                // skip the initial delay
+                if b > 50 {
+                    for s in out.iter() {
+                        if (*s > 0.0) != (last > 0.0) {
+                            crossings += 1;
+                        }
+                        last = *s;
+                    }
+                }
+            }
+            crossings as f32
+        };
+
+        let unshifted = zero_crossings(0.0);
+        let shifted = zero_crossings(12.0);
+        assert!((shifted / unshifted - 2.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn envelope_follower_test_attack_release() {
+        let mut follower = EnvelopeFollower::new(44100.0);
+
+        for _ in 0..100 {
+            follower.process_block(&[[0.5; 128], [-0.8; 128]]);
+        }
+        assert_approx_eq::assert_approx_eq!(follower.level(), 0.8, 0.001);
+
+        // 150ms later, the level has fallen to about a third
+        for _ in 0..52 {
+            follower.process_block(&[[0.0; 128]; 2]);
+        }
+        assert_approx_eq::assert_approx_eq!(follower.level(), 0.8 * (-1.0_f32).exp(), 0.01);
+    }
+
+    #[test]
+    fn insert_chain_test_empty_passthrough() {
+        let mut chain = InsertChain::new(44100.0);
+
+        chain.input[0][0] = 0.5;
+        let out = chain.process();
+        assert_approx_eq::assert_approx_eq!(out[0][0], 0.5, 0.00001);
+        assert_approx_eq::assert_approx_eq!(chain.input[0][0], 0.0, 0.00001);
+
+        chain.add(InsertType::BitCrusher);
+        chain.set_parameter(0, SynthParameter::BitDepth, 2.0);
+        chain.input[0][0] = 0.3;
+        let out = chain.process();
+        assert_approx_eq::assert_approx_eq!(out[0][0], 0.5, 0.00001);
+    }
+}
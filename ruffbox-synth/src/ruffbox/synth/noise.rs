@@ -0,0 +1,39 @@
+/**
+ * A cheap xorshift noise source, for anything that needs random values
+ * on the audio thread (crackle, random phases, dither).
+ */
+pub struct Noise {
+    state: u32,
+}
+
+impl Noise {
+    pub fn new() -> Self {
+        Noise { state: 0x1234_5678 }
+    }
+
+    /// 0..1
+    pub fn uniform(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state as f32 / u32::MAX as f32
+    }
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_noise_range() {
+        let mut noise = Noise::new();
+        let values: Vec<f32> = (0..10000).map(|_| noise.uniform()).collect();
+        assert!(values.iter().all(|value| (0.0..=1.0).contains(value)));
+
+        // roughly even
+        let mean = values.iter().sum::<f32>() / values.len() as f32;
+        assert!((mean - 0.5).abs() < 0.02);
+    }
+}
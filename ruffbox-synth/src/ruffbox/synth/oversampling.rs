@@ -0,0 +1,221 @@
+use std::f32::consts::PI;
+
+/// taps of the half-band filters, odd so there's a center tap
+const NUM_TAPS: usize = 31;
+/// the highest factor, four being two 2x stages
+pub const MAX_FACTOR: usize = 4;
+
+/// blackman-windowed half-band lowpass
+fn halfband_taps() -> Vec<f32> {
+    let center = (NUM_TAPS / 2) as f32;
+    let mut taps: Vec<f32> = (0..NUM_TAPS).map(|k| {
+        let x = k as f32 - center;
+        let sinc = if x == 0.0 { 1.0 } else { (PI * x / 2.0).sin() / (PI * x / 2.0) };
+        let phase = 2.0 * PI * k as f32 / (NUM_TAPS - 1) as f32;
+        let window = 0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos();
+        sinc * window
+    }).collect();
+
+    let sum: f32 = taps.iter().sum();
+    for tap in taps.iter_mut() {
+        *tap /= sum;
+    }
+    taps
+}
+
+/**
+ * One 2x stage, split into polyphase components so that
+ * no multiplications are wasted on stuffed zeros or on
+ * samples that are thrown away.
+ */
+struct HalfbandStage {
+    even_taps: Vec<f32>,
+    odd_taps: Vec<f32>,
+    // upsampling, the most recent input first
+    up_history: Vec<f32>,
+    // downsampling, even and odd input samples, the most recent first
+    down_even: Vec<f32>,
+    down_odd: Vec<f32>,
+}
+
+impl HalfbandStage {
+    fn new() -> Self {
+        let taps = halfband_taps();
+        let even_taps: Vec<f32> = taps.iter().step_by(2).cloned().collect();
+        let odd_taps: Vec<f32> = taps.iter().skip(1).step_by(2).cloned().collect();
+        HalfbandStage {
+            up_history: vec![0.0; even_taps.len()],
+            down_even: vec![0.0; even_taps.len()],
+            down_odd: vec![0.0; odd_taps.len()],
+            even_taps,
+            odd_taps,
+        }
+    }
+
+    fn dot(taps: &[f32], history: &[f32]) -> f32 {
+        taps.iter().zip(history.iter()).map(|(t, h)| t * h).sum()
+    }
+
+    /// one sample in, two out
+    fn upsample(&mut self, sample: f32) -> (f32, f32) {
+        self.up_history.rotate_right(1);
+        self.up_history[0] = sample;
+
+        // times two, to make up for the stuffed zeros
+        (2.0 * HalfbandStage::dot(&self.even_taps, &self.up_history),
+         2.0 * HalfbandStage::dot(&self.odd_taps, &self.up_history))
+    }
+
+    /// two samples in, one out
+    fn downsample(&mut self, first: f32, second: f32) -> f32 {
+        self.down_even.rotate_right(1);
+        self.down_even[0] = first;
+
+        let out = HalfbandStage::dot(&self.even_taps, &self.down_even)
+            + HalfbandStage::dot(&self.odd_taps, &self.down_odd);
+
+        self.down_odd.rotate_right(1);
+        self.down_odd[0] = second;
+
+        out
+    }
+}
+
+/**
+ * Runs a nonlinear stage at 2x or 4x the samplerate, to keep the
+ * harmonics it generates from folding back as aliasing. Upsample a block,
+ * process the 128 * factor samples at the higher rate, downsample again.
+ */
+pub struct Oversampler {
+    factor: usize,
+    stages: [HalfbandStage; 2],
+}
+
+impl Default for Oversampler {
+    fn default() -> Self {
+        Oversampler::new()
+    }
+}
+
+impl Oversampler {
+    pub fn new() -> Self {
+        Oversampler {
+            factor: 1,
+            stages: [HalfbandStage::new(), HalfbandStage::new()],
+        }
+    }
+
+    /// 1, 2 or 4, anything else is rounded down to the next of those
+    pub fn set_factor(&mut self, factor: f32) {
+        self.factor = if factor >= 4.0 {
+            4
+        } else if factor >= 2.0 {
+            2
+        } else {
+            1
+        };
+    }
+
+    pub fn factor(&self) -> usize {
+        self.factor
+    }
+
+    /// fills the first 128 * factor samples of the output
+    pub fn upsample(&mut self, block: &[f32; 128], out: &mut [f32; 128 * MAX_FACTOR]) {
+        for i in 0..128 {
+            match self.factor {
+                1 => out[i] = block[i],
+                2 => {
+                    let (a, b) = self.stages[0].upsample(block[i]);
+                    out[2 * i] = a;
+                    out[2 * i + 1] = b;
+                },
+                _ => {
+                    let (a, b) = self.stages[0].upsample(block[i]);
+                    let (a1, a2) = self.stages[1].upsample(a);
+                    let (b1, b2) = self.stages[1].upsample(b);
+                    out[4 * i] = a1;
+                    out[4 * i + 1] = a2;
+                    out[4 * i + 2] = b1;
+                    out[4 * i + 3] = b2;
+                },
+            }
+        }
+    }
+
+    /// takes the first 128 * factor samples of the input
+    pub fn downsample(&mut self, input: &[f32; 128 * MAX_FACTOR]) -> [f32; 128] {
+        let mut out_buf: [f32; 128] = [0.0; 128];
+
+        for i in 0..128 {
+            out_buf[i] = match self.factor {
+                1 => input[i],
+                2 => self.stages[0].downsample(input[2 * i], input[2 * i + 1]),
+                _ => {
+                    let a = self.stages[1].downsample(input[4 * i], input[4 * i + 1]);
+                    let b = self.stages[1].downsample(input[4 * i + 2], input[4 * i + 3]);
+                    self.stages[0].downsample(a, b)
+                },
+            };
+        }
+
+        out_buf
+    }
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    fn roundtrip_rms(factor: f32, freq: f32) -> f32 {
+        let mut oversampler = Oversampler::new();
+        oversampler.set_factor(factor);
+
+        let mut upsampled = [0.0; 128 * MAX_FACTOR];
+        let mut block = [0.0; 128];
+        let mut count = 0;
+        let mut sum = 0.0;
+        for b in 0..20 {
+            for s in block.iter_mut() {
+                *s = (2.0 * PI * freq * count as f32 / 44100.0).sin();
+                count += 1;
+            }
+            oversampler.upsample(&block, &mut upsampled);
+            let out = oversampler.downsample(&upsampled);
+            // skip the filter delay
+            if b > 0 {
+                sum += out.iter().map(|s| s * s).sum::<f32>();
+            }
+        }
+        (sum / (19.0 * 128.0)).sqrt()
+    }
+
+    #[test]
+    fn oversampler_test_passband() {
+        let sine_rms = 0.5_f32.sqrt();
+        assert_approx_eq::assert_approx_eq!(roundtrip_rms(1.0, 1000.0), sine_rms, 0.001);
+        assert_approx_eq::assert_approx_eq!(roundtrip_rms(2.0, 1000.0), sine_rms, 0.01);
+        assert_approx_eq::assert_approx_eq!(roundtrip_rms(4.0, 1000.0), sine_rms, 0.01);
+    }
+
+    #[test]
+    fn oversampler_test_reject_images() {
+        let mut oversampler = Oversampler::new();
+        oversampler.set_factor(2.0);
+        assert_eq!(oversampler.factor(), 2);
+
+        // a tone at 3/4 of the upsampled nyquist only exists as an image,
+        // it shouldn't make it through downsampling
+        let mut upsampled = [0.0; 128 * MAX_FACTOR];
+        let mut out = [0.0; 128];
+        for b in 0..10 {
+            for (i, s) in upsampled.iter_mut().take(256).enumerate() {
+                *s = (2.0 * PI * 0.375 * (b * 256 + i) as f32).sin();
+            }
+            out = oversampler.downsample(&upsampled);
+        }
+        assert!(out.iter().all(|s| s.abs() < 0.01));
+    }
+}
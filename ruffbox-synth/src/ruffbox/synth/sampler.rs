@@ -3,8 +3,50 @@ use crate::ruffbox::synth::Source;
 use crate::ruffbox::synth::SynthState;
 use crate::ruffbox::synth::SynthParameter;
 
+use std::f32::consts::PI;
 use std::sync::Arc;
 
+/// how samples are read between the stored ones when repitched,
+/// trading cpu for fidelity
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interpolation {
+    None,
+    Linear,
+    Cubic,
+    Sinc,
+}
+
+impl Interpolation {
+    /// 0 = none, 1 = linear, 2 = cubic, 3 = sinc
+    pub fn from_value(value: f32) -> Self {
+        match value.round() as i32 {
+            i32::MIN..=0 => Interpolation::None,
+            1 => Interpolation::Linear,
+            2 => Interpolation::Cubic,
+            _ => Interpolation::Sinc,
+        }
+    }
+}
+
+/// Where a sample loops, in frames of the sample, for pads and drones to be held
+/// as long as the envelope says. The end of the loop is faded into the bit
+/// before the start, so the loop doesn't click.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LoopPoints {
+    pub start: usize,
+    pub end: usize,
+    pub crossfade: usize,
+}
+
+impl LoopPoints {
+    /// The crossfade is shortened to what there is before the start, and to the loop.
+    pub fn new(start: usize, end: usize, crossfade: usize) -> Self {
+        let end = end.max(start + 1);
+        LoopPoints { start, end, crossfade: crossfade.min(start).min(end - start) }
+    }
+}
+
 /**
  * a very simple sample player ...
  */
@@ -14,10 +56,17 @@ pub struct Sampler {
     buffer_ref: Arc<Vec<f32>>,
     buffer_len: usize,
     playback_rate: f32,
+    base_rate: f32,
     frac_index_increment: f32,
     state: SynthState,
     level: f32,
+    trim: f32,
     repeat: bool,
+    interpolation: Interpolation,
+    /// in buffer positions (that is, after the padding)
+    loop_points: Option<LoopPoints>,
+    /// loop between the loop points (the whole sample if there are none)
+    looping: bool,
 }
 
 impl Sampler {    
@@ -28,18 +77,74 @@ impl Sampler {
             buffer_ref: buf.clone(), // just the reference is cloned, not the whole buffer !
             buffer_len: buf.len() - 3, // to account for interpolation
             playback_rate: 1.0,
+            base_rate: 1.0,
             frac_index_increment: 1.0,
             state: SynthState::Fresh,
             level: 1.0,
+            trim: 1.0,
             repeat: repeat,
+            interpolation: Interpolation::Cubic,
+            loop_points: None,
+            looping: false,
         }
     }
 
+    /// gain correction that's independent of the level parameter
+    pub fn set_trim(&mut self, trim: f32) {
+        self.trim = trim;
+    }
+
+    pub fn set_interpolation(&mut self, interpolation: Interpolation) {
+        self.interpolation = interpolation;
+    }
+
+    /// where the sample loops, once looping is switched on per event
+    pub fn set_loop_points(&mut self, points: LoopPoints) {
+        // one in front for interpolation
+        let end = (points.end + 1).min(self.buffer_len);
+        self.loop_points = Some(LoopPoints::new((points.start + 1).min(end - 1), end, points.crossfade));
+    }
+
+    /// the ratio of the buffer's samplerate to the processing samplerate
+    pub fn set_base_rate(&mut self, base_rate: f32) {
+        self.base_rate = base_rate;
+        self.frac_index_increment = self.playback_rate * base_rate;
+    }
+
+    /// the stored sample, silence outside of the buffer
+    fn sample_at(&self, idx: isize) -> f32 {
+        if idx < 0 {
+            0.0
+        } else {
+            self.buffer_ref.get(idx as usize).cloned().unwrap_or(0.0)
+        }
+    }
+
+    /// windowed sinc, lowered in cutoff when playing faster so it doesn't alias
+    fn sinc_interpolate(&self, idx: usize, frac: f32) -> f32 {
+        const MAX_HALF_WIDTH: f32 = 64.0;
+        let cutoff = (1.0 / self.frac_index_increment.abs()).min(1.0);
+        let half_width = (8.0 / cutoff).min(MAX_HALF_WIDTH);
+        let taps = half_width.ceil() as isize;
+
+        let mut out = 0.0;
+        for k in (1 - taps)..=taps {
+            let x = k as f32 - frac;
+            if x.abs() >= half_width {
+                continue;
+            }
+            let sinc = if x == 0.0 { 1.0 } else { (PI * cutoff * x).sin() / (PI * cutoff * x) };
+            let window = 0.5 + 0.5 * (PI * x / half_width).cos();
+            out += self.sample_at(idx as isize + k) * cutoff * sinc * window;
+        }
+        out
+    }
+
     fn get_next_block_no_interp(&mut self, start_sample: usize) -> [f32; 128] {
         let mut out_buf: [f32; 128] = [0.0; 128];
 
         for i in start_sample..128 {            
-            out_buf[i] = self.buffer_ref[self.index] * self.level;
+            out_buf[i] = self.buffer_ref[self.index] * self.level * self.trim;
             
             if self.index < self.buffer_len {
                 self.index = self.index + 1;
@@ -60,25 +165,29 @@ impl Sampler {
         let mut out_buf: [f32; 128] = [0.0; 128];
 
         for i in start_sample..128 {
-            // get sample:
-            let idx = self.frac_index.floor();
-            let frac = self.frac_index - idx;             
-            let idx_u = idx as usize;
-
-            // 4-point, 3rd-order Hermite
-            let y_m1 = self.buffer_ref[idx_u - 1];
-            let y_0 = self.buffer_ref[idx_u];
-            let y_1 = self.buffer_ref[idx_u + 1];
-            let y_2 = self.buffer_ref[idx_u + 2];
-
-            let c0 = y_0;
-            let c1 = 0.5 * (y_1 - y_m1);
-            let c2 = y_m1 - 2.5 * y_0 + 2.0 * y_1 - 0.5 * y_2;
-            let c3 = 0.5 * (y_2 - y_m1) + 1.5 * (y_0 - y_1);
+            let mut sample = self.read(self.frac_index);
+
+            // towards the loop end, fade over to what comes before the loop start,
+            // so that's where the jump back to the start continues from
+            let active_loop = self.active_loop();
+            if let Some(points) = active_loop {
+                let fade_start = (points.end - points.crossfade) as f32;
+                if self.frac_index >= fade_start && points.crossfade > 0 {
+                    let t = (self.frac_index - fade_start) / points.crossfade as f32;
+                    let before_start = self.read(self.frac_index - (points.end - points.start) as f32);
+                    // equal power, the loop and what's before its start aren't the same
+                    sample = sample * (t * PI * 0.5).cos() + before_start * (t * PI * 0.5).sin();
+                }
+            }
             
-            out_buf[i] = (((c3 * frac + c2) * frac + c1) * frac + c0) * self.level ;
-                        
-            if ((self.frac_index + self.frac_index_increment) as usize) < self.buffer_len {                
+            out_buf[i] = sample * self.level * self.trim;
+
+            if let Some(points) = active_loop {
+                self.frac_index += self.frac_index_increment;
+                if self.frac_index >= points.end as f32 {
+                    self.frac_index -= (points.end - points.start) as f32;
+                }
+            } else if ((self.frac_index + self.frac_index_increment) as usize) < self.buffer_len {                
                 self.frac_index = self.frac_index + self.frac_index_increment;
             } else {
                 if self.repeat {
@@ -92,6 +201,41 @@ impl Sampler {
         
         out_buf
     }
+
+    /// the loop that's played, if looping is on
+    fn active_loop(&self) -> Option<LoopPoints> {
+        if !self.looping {
+            return None;
+        }
+        Some(self.loop_points.unwrap_or(LoopPoints { start: 1, end: self.buffer_len, crossfade: 0 }))
+    }
+
+    /// the sample at a (fractional) buffer position, interpolated
+    fn read(&self, position: f32) -> f32 {
+        let idx = position.floor();
+        let frac = position - idx;
+        let idx_u = idx as usize;
+
+        match self.interpolation {
+            Interpolation::None => self.buffer_ref[idx_u],
+            Interpolation::Linear => self.buffer_ref[idx_u] * (1.0 - frac) + self.buffer_ref[idx_u + 1] * frac,
+            Interpolation::Cubic => {
+                // 4-point, 3rd-order Hermite
+                let y_m1 = self.buffer_ref[idx_u - 1];
+                let y_0 = self.buffer_ref[idx_u];
+                let y_1 = self.buffer_ref[idx_u + 1];
+                let y_2 = self.buffer_ref[idx_u + 2];
+
+                let c0 = y_0;
+                let c1 = 0.5 * (y_1 - y_m1);
+                let c2 = y_m1 - 2.5 * y_0 + 2.0 * y_1 - 0.5 * y_2;
+                let c3 = 0.5 * (y_2 - y_m1) + 1.5 * (y_0 - y_1);
+
+                ((c3 * frac + c2) * frac + c1) * frac + c0
+            },
+            Interpolation::Sinc => self.sinc_interpolate(idx_u, frac),
+        }
+    }
 }
 
 impl Source for Sampler {
@@ -105,11 +249,17 @@ impl Source for Sampler {
             },            
             SynthParameter::PlaybackRate => {
                 self.playback_rate = value;
-                self.frac_index_increment = 1.0 * value;
+                self.frac_index_increment = value * self.base_rate;
             },
             SynthParameter::Level => {
                 self.level = value;
             },
+            SynthParameter::Interpolation => {
+                self.interpolation = Interpolation::from_value(value);
+            },
+            SynthParameter::PlaybackLoop => {
+                self.looping = value > 0.0;
+            },
            _ => (),
         };
     }
@@ -126,10 +276,101 @@ impl Source for Sampler {
     }
     
     fn get_next_block(&mut self, start_sample: usize) -> [f32; 128] {
-        if self.playback_rate == 1.0 {
+        // loops are crossfaded on the way
+        if self.frac_index_increment == 1.0 && !self.looping {
             self.get_next_block_no_interp(start_sample)
         } else {
             self.get_next_block_interp(start_sample)
         }
     }   
 }
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    // padded like the loaded samples, one in front, three behind
+    fn padded(samples: &[f32]) -> Arc<Vec<f32>> {
+        let mut buf = vec![0.0];
+        buf.extend_from_slice(samples);
+        buf.extend_from_slice(&[0.0, 0.0, 0.0]);
+        Arc::new(buf)
+    }
+
+    #[test]
+    fn sampler_test_interpolation_modes() {
+        let ramp: Vec<f32> = (0..100).map(|i| i as f32).collect();
+        let buf = padded(&ramp);
+
+        let play = |interpolation: Interpolation| {
+            let mut sampler = Sampler::with_buffer_ref(&buf, false);
+            sampler.set_parameter(SynthParameter::PlaybackRate, 0.5);
+            sampler.set_interpolation(interpolation);
+            sampler.get_next_block(0)
+        };
+
+        let out = play(Interpolation::None);
+        assert_approx_eq::assert_approx_eq!(out[2], 1.0, 0.00001);
+        assert_approx_eq::assert_approx_eq!(out[3], 1.0, 0.00001);
+
+        let out = play(Interpolation::Linear);
+        assert_approx_eq::assert_approx_eq!(out[3], 1.5, 0.00001);
+
+        let out = play(Interpolation::Cubic);
+        assert_approx_eq::assert_approx_eq!(out[3], 1.5, 0.00001);
+    }
+
+    #[test]
+    fn sampler_test_sinc_interpolation() {
+        let sine: Vec<f32> = (0..400).map(|i| (2.0 * PI * 441.0 * i as f32 / 44100.0).sin()).collect();
+        let buf = padded(&sine);
+
+        let mut sampler = Sampler::with_buffer_ref(&buf, false);
+        sampler.set_parameter(SynthParameter::PlaybackRate, 0.5);
+        sampler.set_parameter(SynthParameter::Interpolation, 3.0);
+
+        let out = sampler.get_next_block(0);
+
+        // away from the edges, it's the sine at half speed
+        for (i, sample) in out.iter().enumerate().skip(20) {
+            let comp = (2.0 * PI * 441.0 * (i as f32 * 0.5) / 44100.0).sin();
+            assert_approx_eq::assert_approx_eq!(*sample, comp, 0.001);
+        }
+    }
+    #[test]
+    fn sampler_test_crossfaded_loop() {
+        // a ramp up to the loop end, the loop starts at 100, fading in from 80
+        let ramp: Vec<f32> = (0..300).map(|i| i as f32).collect();
+        let buf = padded(&ramp);
+
+        let mut sampler = Sampler::with_buffer_ref(&buf, false);
+        sampler.set_loop_points(LoopPoints::new(100, 200, 20));
+        sampler.set_parameter(SynthParameter::PlaybackStart, 150.0 / 300.0);
+        sampler.set_parameter(SynthParameter::PlaybackLoop, 1.0);
+        let out = sampler.get_next_block(0);
+
+        // up to the fade, the sample as it is ...
+        assert_approx_eq::assert_approx_eq!(out[30], 179.0, 0.0001);
+        // ... halfway through, both parts at -3dB ...
+        let half = (PI * 0.25).cos();
+        assert_approx_eq::assert_approx_eq!(out[41], 190.0 * half + 90.0 * half, 0.0001);
+        // ... then on from the loop start, without a jump
+        assert_approx_eq::assert_approx_eq!(out[51], 100.0, 0.0001);
+        assert_approx_eq::assert_approx_eq!(out[52], 101.0, 0.0001);
+        assert!(!sampler.is_finished());
+
+        // and again, as long as it's played
+        let out = sampler.get_next_block(0);
+        assert_approx_eq::assert_approx_eq!(out[23], 100.0, 0.0001);
+    }
+
+    #[test]
+    fn sampler_test_loop_points() {
+        // the crossfade can't start before the sample, and fits into the loop
+        assert_eq!(LoopPoints::new(10, 50, 100), LoopPoints { start: 10, end: 50, crossfade: 10 });
+        assert_eq!(LoopPoints::new(100, 150, 100), LoopPoints { start: 100, end: 150, crossfade: 50 });
+        assert_eq!(LoopPoints::new(100, 50, 100).end, 101);
+    }
+}
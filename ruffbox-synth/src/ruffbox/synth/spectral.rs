@@ -0,0 +1,244 @@
+use crate::ruffbox::synth::Effect;
+use crate::ruffbox::synth::SynthParameter;
+use crate::ruffbox::synth::noise::Noise;
+
+use std::f32::consts::PI;
+
+/// in-place iterative radix-2 fft, the length has to be a power of two,
+/// the inverse isn't normalized
+pub fn fft(re: &mut [f32], im: &mut [f32], inverse: bool) {
+    let n = re.len();
+
+    // bit reversal permutation
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut len = 2;
+    while len <= n {
+        let angle = sign * 2.0 * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (w_im, w_re) = (angle * k as f32).sin_cos();
+                let a = start + k;
+                let b = a + len / 2;
+                let t_re = re[b] * w_re - im[b] * w_im;
+                let t_im = re[b] * w_im + im[b] * w_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+/**
+ * Spectral freeze: keeps analyzing its input, and once frozen keeps
+ * playing the last spectrum (with random phases) on top of the input
+ * until released. Smear averages the spectrum over time (0..1), so the
+ * frozen sound is less of a snapshot.
+ */
+pub struct SpectralFreeze {
+    frozen: bool,
+    smear: f32,
+    in_ring: Vec<f32>,
+    in_pos: usize,
+    out_ring: Vec<f32>,
+    out_pos: usize,
+    hop_count: usize,
+    window: Vec<f32>,
+    magnitudes: Vec<f32>,
+    re: Vec<f32>,
+    im: Vec<f32>,
+    wet_gain: f32,
+    fade_step: f32,
+    noise: Noise,
+}
+
+impl SpectralFreeze {
+    const FRAME_SIZE: usize = 1024;
+    const HOP_SIZE: usize = 256;
+    // squared hann windows at 75% overlap add up to 1.5
+    const OVERLAP_GAIN: f32 = 1.5;
+
+    pub fn new(sr: f32) -> Self {
+        let n = SpectralFreeze::FRAME_SIZE;
+        SpectralFreeze {
+            frozen: false,
+            smear: 0.0,
+            in_ring: vec![0.0; n],
+            in_pos: 0,
+            out_ring: vec![0.0; n],
+            out_pos: 0,
+            hop_count: 0,
+            window: (0..n).map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / n as f32).cos()).collect(),
+            magnitudes: vec![0.0; n / 2 + 1],
+            re: vec![0.0; n],
+            im: vec![0.0; n],
+            wet_gain: 0.0,
+            fade_step: 1.0 / (0.05 * sr), // 50ms fade in and out
+            noise: Noise::new(),
+        }
+    }
+
+    fn process_frame(&mut self) {
+        let n = SpectralFreeze::FRAME_SIZE;
+
+        if !self.frozen {
+            for i in 0..n {
+                self.re[i] = self.in_ring[(self.in_pos + i) % n] * self.window[i];
+                self.im[i] = 0.0;
+            }
+            fft(&mut self.re, &mut self.im, false);
+            for k in 0..=n / 2 {
+                let magnitude = (self.re[k] * self.re[k] + self.im[k] * self.im[k]).sqrt();
+                self.magnitudes[k] = self.smear * self.magnitudes[k] + (1.0 - self.smear) * magnitude;
+            }
+        }
+
+        // nothing to resynthesize
+        if self.wet_gain <= 0.0 && !self.frozen {
+            return;
+        }
+
+        for k in 0..=n / 2 {
+            let phase = if k == 0 || k == n / 2 { 0.0 } else { self.noise.uniform() * 2.0 * PI };
+            let (sin, cos) = phase.sin_cos();
+            self.re[k] = self.magnitudes[k] * cos;
+            self.im[k] = self.magnitudes[k] * sin;
+            if k > 0 && k < n / 2 {
+                self.re[n - k] = self.re[k];
+                self.im[n - k] = -self.im[k];
+            }
+        }
+        fft(&mut self.re, &mut self.im, true);
+
+        let scale = 1.0 / (n as f32 * SpectralFreeze::OVERLAP_GAIN);
+        for i in 0..n {
+            self.out_ring[(self.out_pos + i) % n] += self.re[i] * self.window[i] * scale;
+        }
+    }
+}
+
+impl Effect for SpectralFreeze {
+    fn set_parameter(&mut self, par: SynthParameter, value: f32) {
+        match par {
+            SynthParameter::Freeze => self.frozen = value > 0.5,
+            SynthParameter::Smear => self.smear = value.clamp(0.0, 0.99),
+            _ => (),
+        };
+    }
+
+    fn finish(&mut self) {}
+    fn is_finished(&self) -> bool { false } // it's never finished ..
+
+    fn process_block(&mut self, block: [f32; 128], _start_sample: usize) -> [f32; 128] {
+        let n = SpectralFreeze::FRAME_SIZE;
+        let mut out_buf: [f32; 128] = [0.0; 128];
+
+        for i in 0..128 {
+            self.in_ring[self.in_pos] = block[i];
+            self.in_pos = (self.in_pos + 1) % n;
+
+            self.hop_count += 1;
+            if self.hop_count == SpectralFreeze::HOP_SIZE {
+                self.hop_count = 0;
+                self.process_frame();
+            }
+
+            self.wet_gain = if self.frozen {
+                (self.wet_gain + self.fade_step).min(1.0)
+            } else {
+                (self.wet_gain - self.fade_step).max(0.0)
+            };
+
+            out_buf[i] = block[i] + self.out_ring[self.out_pos] * self.wet_gain;
+            self.out_ring[self.out_pos] = 0.0;
+            self.out_pos = (self.out_pos + 1) % n;
+        }
+
+        out_buf
+    }
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn fft_test_roundtrip() {
+        let orig: Vec<f32> = (0..64).map(|i| (i as f32 * 0.3).sin() + 0.1 * i as f32).collect();
+        let mut re = orig.clone();
+        let mut im = vec![0.0; 64];
+
+        fft(&mut re, &mut im, false);
+        fft(&mut re, &mut im, true);
+
+        for i in 0..64 {
+            assert_approx_eq::assert_approx_eq!(re[i] / 64.0, orig[i], 0.0001);
+        }
+    }
+
+    #[test]
+    fn fft_test_single_bin() {
+        let mut re: Vec<f32> = (0..16).map(|i| (2.0 * PI * 2.0 * i as f32 / 16.0).cos()).collect();
+        let mut im = vec![0.0; 16];
+
+        fft(&mut re, &mut im, false);
+
+        assert_approx_eq::assert_approx_eq!(re[2], 8.0, 0.0001);
+        assert_approx_eq::assert_approx_eq!(re[14], 8.0, 0.0001);
+        assert_approx_eq::assert_approx_eq!(re[3], 0.0, 0.0001);
+    }
+
+    #[test]
+    fn spectral_freeze_test_hold() {
+        let mut freeze = SpectralFreeze::new(44100.0);
+
+        let mut block = [0.0; 128];
+        let mut count = 0;
+        for _ in 0..40 {
+            for s in block.iter_mut() {
+                *s = (2.0 * PI * 440.0 * count as f32 / 44100.0).sin();
+                count += 1;
+            }
+            let out = freeze.process_block(block, 0);
+            // dry until frozen
+            assert_approx_eq::assert_approx_eq!(out[5], block[5], 0.00001);
+        }
+
+        freeze.set_parameter(SynthParameter::Freeze, 1.0);
+
+        // the sound keeps going without input
+        let silence = [0.0; 128];
+        let mut energy = 0.0;
+        for _ in 0..100 {
+            let out = freeze.process_block(silence, 0);
+            energy += out.iter().map(|s| s * s).sum::<f32>();
+        }
+        assert!(energy > 1.0);
+
+        // and fades out once released
+        freeze.set_parameter(SynthParameter::Freeze, 0.0);
+        for _ in 0..40 {
+            freeze.process_block(silence, 0);
+        }
+        let out = freeze.process_block(silence, 0);
+        assert!(out.iter().all(|s| *s == 0.0));
+    }
+}
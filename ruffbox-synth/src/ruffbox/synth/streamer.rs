@@ -0,0 +1,156 @@
+// parent imports
+use crate::ruffbox::synth::Source;
+use crate::ruffbox::synth::SynthState;
+use crate::ruffbox::synth::SynthParameter;
+use crate::ruffbox::streams::{SampleStream, CHUNK_FRAMES};
+
+use parking_lot::Mutex;
+use std::sync::Arc;
+
+/**
+ * plays a streamed sample, asking for the chunks it needs next as it goes
+ * (what isn't there in time is played as silence)
+ */
+pub struct Streamer {
+    stream: Arc<Mutex<SampleStream>>,
+    length: usize,
+    /// in frames of the stream
+    position: f64,
+    playback_rate: f32,
+    base_rate: f32,
+    samplerate: f32,
+    state: SynthState,
+    level: f32,
+}
+
+impl Streamer {
+    pub fn with_stream(stream: &Arc<Mutex<SampleStream>>, sr: f32) -> Streamer {
+        let length = stream.lock().frames();
+        Streamer {
+            stream: stream.clone(),
+            length,
+            position: 0.0,
+            playback_rate: 1.0,
+            base_rate: 1.0,
+            samplerate: sr,
+            state: SynthState::Fresh,
+            level: 1.0,
+        }
+    }
+
+    /// the ratio of the stream's samplerate to the processing samplerate
+    pub fn set_base_rate(&mut self, base_rate: f32) {
+        self.base_rate = base_rate;
+    }
+
+    /// ask for the chunk the voice starts in, to be there at the given time
+    pub fn prefetch(&self, timestamp: f64) {
+        self.stream.lock().request(self.position as usize, timestamp);
+    }
+
+    fn increment(&self) -> f64 {
+        (self.playback_rate * self.base_rate) as f64
+    }
+}
+
+impl Source for Streamer {
+
+    fn set_parameter(&mut self, par: SynthParameter, value: f32) {
+        match par {
+            SynthParameter::PlaybackStart => {
+                self.position = (self.length as f32 * value.max(0.0)) as f64;
+            },
+            SynthParameter::PlaybackRate => {
+                self.playback_rate = value;
+            },
+            SynthParameter::Level => {
+                self.level = value;
+            },
+            _ => (),
+        };
+    }
+
+    fn finish(&mut self) {
+        self.state = SynthState::Finished;
+    }
+
+    fn is_finished(&self) -> bool {
+        matches!(self.state, SynthState::Finished)
+    }
+
+    fn get_next_block(&mut self, start_sample: usize) -> [f32; 128] {
+        let mut out_buf: [f32; 128] = [0.0; 128];
+        let increment = self.increment();
+        let mut stream = self.stream.lock();
+
+        // the chunk that's playing is needed now, the next one once it's reached
+        let now = stream.now();
+        stream.request(self.position as usize, now);
+        if increment > 0.0 {
+            let next_chunk = (self.position as usize / CHUNK_FRAMES + 1) * CHUNK_FRAMES;
+            let seconds = (next_chunk as f64 - self.position) / increment / self.samplerate as f64;
+            stream.request(next_chunk, now + seconds);
+        }
+
+        for sample in out_buf.iter_mut().skip(start_sample) {
+            if self.position < 0.0 || self.position >= self.length as f64 {
+                self.state = SynthState::Finished;
+                break;
+            }
+
+            // linear interpolation, streams are mostly played at their own rate
+            let idx = self.position.floor() as usize;
+            let frac = (self.position - idx as f64) as f32;
+            match (stream.frame(idx), stream.frame(idx + 1)) {
+                (Some(current), Some(next)) => *sample = (current * (1.0 - frac) + next * frac) * self.level,
+                _ => stream.underrun(),
+            }
+
+            self.position += increment;
+        }
+
+        out_buf
+    }
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn streamer_test_chunks() {
+        let stream = Arc::new(Mutex::new(SampleStream::new(CHUNK_FRAMES + 200)));
+        let mut streamer = Streamer::with_stream(&stream, 44100.0);
+        streamer.set_parameter(SynthParameter::PlaybackStart, CHUNK_FRAMES as f32 / (CHUNK_FRAMES + 200) as f32);
+
+        // the chunk it starts in is asked for ahead of time ...
+        streamer.prefetch(1.0);
+        assert_eq!(stream.lock().next_request(), Some(1));
+
+        // ... and played once it's there
+        let ramp: Vec<f32> = (0..200).map(|i| i as f32 / 200.0).collect();
+        stream.lock().load_chunk(1, &ramp);
+        let block = streamer.get_next_block(0);
+        assert_eq!(&block[..], &ramp[..128]);
+        assert!(!streamer.is_finished());
+
+        // the end of the stream is the end of the voice
+        streamer.get_next_block(0);
+        assert!(streamer.is_finished());
+        assert_eq!(stream.lock().underruns(), 0);
+    }
+
+    #[test]
+    fn streamer_test_underrun() {
+        let stream = Arc::new(Mutex::new(SampleStream::new(CHUNK_FRAMES * 2)));
+        let mut streamer = Streamer::with_stream(&stream, 44100.0);
+
+        // nothing's there yet, it plays silence and asks for it
+        let block = streamer.get_next_block(64);
+        assert!(block.iter().all(|sample| *sample == 0.0));
+        assert_eq!(stream.lock().underruns(), 64);
+        assert_eq!(stream.lock().next_request(), Some(0));
+    }
+}
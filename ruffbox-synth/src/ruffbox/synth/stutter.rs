@@ -0,0 +1,134 @@
+/**
+ * Beat repeat on the master bus: keeps the last few seconds of the output,
+ * and once triggered, loops the last fragment (i.e. an eighth note) a
+ * number of times instead of the live output, then lets it through again.
+ */
+pub struct StereoStutter {
+    history: [Vec<f32>; 2],
+    write_pos: usize,
+    fragment: [Vec<f32>; 2],
+    fragment_pos: usize,
+    // samples of repetition left
+    remaining: usize,
+    // the start of the next stutter, as a sample offset into the next block, length and repeats
+    pending: Option<(usize, usize, usize)>,
+    fade_len: usize,
+}
+
+impl StereoStutter {
+    pub fn with_max_capacity_sec(capacity: f32, sr: f32) -> Self {
+        let len = (capacity * sr) as usize;
+        StereoStutter {
+            history: [vec![0.0; len], vec![0.0; len]],
+            write_pos: 0,
+            fragment: [Vec::new(), Vec::new()],
+            fragment_pos: 0,
+            remaining: 0,
+            pending: None,
+            fade_len: (0.002 * sr) as usize, // 2ms, against clicks at the loop points
+        }
+    }
+
+    /// start repeating at the given sample of the next block, the length is in samples,
+    /// limited by what's kept
+    pub fn trigger(&mut self, offset: usize, length: usize, repeats: usize) {
+        let length = length.min(self.history[0].len()).max(2 * self.fade_len + 1);
+        self.pending = Some((offset.min(127), length, repeats));
+    }
+
+    fn start(&mut self, length: usize, repeats: usize) {
+        let history_len = self.history[0].len();
+        let start = self.write_pos + history_len - length;
+        for (fragment, history) in self.fragment.iter_mut().zip(self.history.iter()) {
+            fragment.clear();
+            fragment.extend((0..length).map(|i| history[(start + i) % history_len]));
+        }
+        self.fragment_pos = 0;
+        self.remaining = length * repeats;
+    }
+
+    fn fade(&self) -> f32 {
+        let len = self.fragment[0].len();
+        let from_end = len - 1 - self.fragment_pos;
+        let edge = self.fragment_pos.min(from_end);
+        if edge < self.fade_len {
+            edge as f32 / self.fade_len as f32
+        } else {
+            1.0
+        }
+    }
+
+    pub fn process(&mut self, block: [[f32; 128]; 2]) -> [[f32; 128]; 2] {
+        let mut out_buf = block;
+
+        for i in 0..128 {
+            if let Some((offset, length, repeats)) = self.pending {
+                if offset == i {
+                    self.pending = None;
+                    self.start(length, repeats);
+                }
+            }
+
+            for (history, channel) in self.history.iter_mut().zip(block.iter()) {
+                history[self.write_pos] = channel[i];
+            }
+            self.write_pos = (self.write_pos + 1) % self.history[0].len();
+
+            if self.remaining > 0 {
+                let fade = self.fade();
+                for (out, fragment) in out_buf.iter_mut().zip(self.fragment.iter()) {
+                    out[i] = fragment[self.fragment_pos] * fade;
+                }
+                self.fragment_pos = (self.fragment_pos + 1) % self.fragment[0].len();
+                self.remaining -= 1;
+            }
+        }
+
+        out_buf
+    }
+}
+
+// TEST TEST TEST
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn stutter_test_repeat() {
+        let mut stutter = StereoStutter::with_max_capacity_sec(1.0, 44100.0);
+
+        // a ramp, so it's easy to see what's played
+        let mut count = 0.0;
+        let mut next_block = || {
+            let mut block = [[0.0; 128]; 2];
+            for s in block[0].iter_mut() {
+                *s = count;
+                count += 1.0;
+            }
+            block
+        };
+
+        for _ in 0..4 {
+            let block = next_block();
+            assert_eq!(stutter.process(block), block);
+        }
+
+        // the last 256 samples, twice, from the middle of the next block
+        stutter.trigger(64, 256, 2);
+        let mut out = Vec::new();
+        for _ in 0..6 {
+            out.extend_from_slice(&stutter.process(next_block())[0]);
+        }
+
+        // live until the trigger
+        assert_eq!(out[63], 575.0);
+        // past the fade in, it's what was played 256 samples before
+        assert_eq!(out[64 + 100], 320.0 + 100.0);
+        // the second time around
+        assert_eq!(out[64 + 256 + 100], 320.0 + 100.0);
+        // and live again
+        assert_eq!(stutter.remaining, 0);
+        assert_eq!(out[64 + 512 + 10], 512.0 + 64.0 + 512.0 + 10.0);
+    }
+}
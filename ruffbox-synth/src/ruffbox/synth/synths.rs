@@ -3,10 +3,13 @@ use crate::ruffbox::synth::oscillators::*;
 use crate::ruffbox::synth::envelopes::*;
 use crate::ruffbox::synth::filters::*;
 use crate::ruffbox::synth::routing::Balance2;
-use crate::ruffbox::synth::sampler::Sampler;
+use crate::ruffbox::synth::sampler::{Interpolation, LoopPoints, Sampler};
+use crate::ruffbox::synth::streamer::Streamer;
+use crate::ruffbox::streams::SampleStream;
 use crate::ruffbox::synth::StereoSynth;
 use crate::ruffbox::synth::SynthParameter;
 
+use parking_lot::Mutex;
 use std::sync::Arc;
 
 /// a sinusoidal synth with envelope etc.
@@ -189,6 +192,8 @@ impl StereoSynth for LFSquareSynth {
 
 /// a sampler with envelope etc.
 pub struct StereoSampler {
+    buffer_len: usize,
+    samplerate: f32,
     sampler: Sampler,
     envelope: ASREnvelope,
     filter: Lpf18,
@@ -202,6 +207,8 @@ impl StereoSampler {
         let dur = (buf.len() as f32 / sr) - 0.0002;
         
         StereoSampler {
+            buffer_len: buf.len(),
+            samplerate: sr,
             sampler: Sampler::with_buffer_ref(buf, true),
             envelope: ASREnvelope::new(sr, 1.0, 0.0001, dur, 0.0001),
             filter: Lpf18::new(19500.0, 0.01, 0.01, sr),
@@ -210,6 +217,24 @@ impl StereoSampler {
             delay: 0.0,
         }
     }
+
+    pub fn set_trim(&mut self, trim: f32) {
+        self.sampler.set_trim(trim);
+    }
+
+    pub fn set_interpolation(&mut self, interpolation: Interpolation) {
+        self.sampler.set_interpolation(interpolation);
+    }
+
+    pub fn set_loop_points(&mut self, points: LoopPoints) {
+        self.sampler.set_loop_points(points);
+    }
+
+    /// for buffers that aren't at the processing samplerate
+    pub fn set_buffer_samplerate(&mut self, buffer_sr: f32) {
+        self.sampler.set_base_rate(buffer_sr / self.samplerate);
+        self.envelope.set_parameter(SynthParameter::Sustain, (self.buffer_len as f32 / buffer_sr) - 0.0002);
+    }
 }
 
 impl StereoSynth for StereoSampler {
@@ -249,3 +274,82 @@ impl StereoSynth for StereoSampler {
         self.delay
     }
 }
+
+/// a streamed sample with envelope etc., like the sampler
+pub struct StereoStreamer {
+    stream_len: usize,
+    samplerate: f32,
+    streamer: Streamer,
+    envelope: ASREnvelope,
+    filter: Lpf18,
+    balance: Balance2,
+    reverb: f32,
+    delay: f32,
+}
+
+impl StereoStreamer {
+    pub fn with_stream(stream: &Arc<Mutex<SampleStream>>, sr: f32) -> StereoStreamer {
+        let stream_len = stream.lock().frames();
+        let dur = (stream_len as f32 / sr) - 0.0002;
+
+        StereoStreamer {
+            stream_len,
+            samplerate: sr,
+            streamer: Streamer::with_stream(stream, sr),
+            envelope: ASREnvelope::new(sr, 1.0, 0.0001, dur, 0.0001),
+            filter: Lpf18::new(19500.0, 0.01, 0.01, sr),
+            balance: Balance2::new(),
+            reverb: 0.0,
+            delay: 0.0,
+        }
+    }
+
+    /// for streams that aren't at the processing samplerate
+    pub fn set_buffer_samplerate(&mut self, buffer_sr: f32) {
+        self.streamer.set_base_rate(buffer_sr / self.samplerate);
+        self.envelope.set_parameter(SynthParameter::Sustain, (self.stream_len as f32 / buffer_sr) - 0.0002);
+    }
+}
+
+impl StereoSynth for StereoStreamer {
+    fn set_parameter(&mut self, par: SynthParameter, val: f32) {
+        self.streamer.set_parameter(par, val);
+        self.filter.set_parameter(par, val);
+        self.envelope.set_parameter(par, val);
+        self.balance.set_parameter(par, val);
+
+        match par {
+            SynthParameter::ReverbMix => self.reverb = val,
+            SynthParameter::DelayMix => self.delay = val,
+            _ => (),
+        };
+    }
+
+    fn finish(&mut self) {
+        self.envelope.finish();
+    }
+
+    fn is_finished(&self) -> bool {
+        // the stream ends before the envelope if it starts later on
+        self.envelope.is_finished() || self.streamer.is_finished()
+    }
+
+    fn get_next_block(&mut self, start_sample: usize) -> [[f32; 128]; 2] {
+        let mut out: [f32; 128] = self.streamer.get_next_block(start_sample);
+        out = self.filter.process_block(out, start_sample);
+        out = self.envelope.process_block(out, start_sample);
+        self.balance.process_block(out)
+    }
+
+    fn reverb_level(&self) -> f32 {
+        self.reverb
+    }
+
+    fn delay_level(&self) -> f32 {
+        self.delay
+    }
+
+    fn prefetch(&mut self, timestamp: f64) {
+        self.streamer.prefetch(timestamp);
+    }
+}